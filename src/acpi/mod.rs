@@ -13,7 +13,9 @@ use crate::{
     paging::{PageFlags, PhysicalAddress, RmmA, RmmArch},
 };
 
-use self::{hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
+use self::{
+    hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, srat::Srat, xsdt::Xsdt,
+};
 
 pub mod hpet;
 pub mod madt;
@@ -21,6 +23,7 @@ mod rsdp;
 mod rsdt;
 mod rxsdt;
 pub mod sdt;
+pub mod srat;
 mod xsdt;
 
 unsafe fn map_linearly(addr: PhysicalAddress, len: usize, mapper: &mut crate::paging::PageMapper) {
@@ -143,6 +146,8 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
             }
         }
 
+        // The NUMA topology must be known before the APs start allocating.
+        Srat::init();
         // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
         // to initialize enumerated processors to userspace?
         Madt::init();
@@ -0,0 +1,186 @@
+use alloc::vec::Vec;
+use core::mem;
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    memory::numa::{self, NodeId},
+    paging::PhysicalAddress,
+};
+
+use super::{find_sdt, sdt::Sdt};
+
+/// The System Resource Affinity Table, describing which NUMA node (proximity domain) each CPU
+/// and each physical memory range belongs to.
+#[derive(Clone, Copy, Debug)]
+pub struct Srat {
+    sdt: &'static Sdt,
+}
+
+/// SRAT Processor Local APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratLocalApic {
+    pub proximity_domain_low: u8,
+    pub apic_id: u8,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    pub local_sapic_eid: u8,
+    pub proximity_domain_high: [u8; 3],
+    pub clock_domain: u32,
+}
+
+/// SRAT Memory Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratMemory {
+    pub proximity_domain: u32,
+    _reserved: u16,
+    pub base: u64,
+    pub length: u64,
+    _reserved2: u32,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+/// SRAT Processor Local x2APIC Affinity
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SratX2Apic {
+    _reserved: u16,
+    pub proximity_domain: u32,
+    pub x2apic_id: u32,
+    /// Flags. 1 means that the entry is enabled
+    pub flags: u32,
+    pub clock_domain: u32,
+    _reserved2: u32,
+}
+
+/// SRAT Entries
+#[derive(Debug)]
+pub enum SratEntry {
+    LocalApic(&'static SratLocalApic),
+    Memory(&'static SratMemory),
+    X2Apic(&'static SratX2Apic),
+    Invalid(u8, usize),
+    Unknown(u8),
+}
+
+const FLAG_ENABLED: u32 = 1;
+
+impl Srat {
+    /// Parse the SRAT if present, and register the NUMA topology it describes. Must be called
+    /// before the APs are started.
+    pub fn init() {
+        let srat_sdt = find_sdt("SRAT");
+        let srat = if srat_sdt.len() == 1 {
+            Srat::new(srat_sdt[0])
+        } else {
+            println!("Unable to find SRAT");
+            return;
+        };
+        let Some(srat) = srat else {
+            return;
+        };
+
+        // Proximity domains are arbitrary numbers, and are assigned nodes in order of appearance.
+        let mut domains = Vec::new();
+        let mut node_for = |domain: u32| {
+            let index = match domains.iter().position(|&d| d == domain) {
+                Some(index) => index,
+                None => {
+                    domains.push(domain);
+                    domains.len() - 1
+                }
+            };
+            NodeId::new(index).unwrap_or_else(|| {
+                log::warn!("NUMA proximity domain {domain} exceeds node limit, using node 0");
+                NodeId::ZERO
+            })
+        };
+
+        let mut memory = Vec::new();
+        let mut cpus = Vec::new();
+
+        // On x86, logical CPU IDs are the APIC IDs. Other architectures describe their CPUs using
+        // entries not yet parsed here, so that all of their CPUs remain on node 0.
+        for entry in srat.iter() {
+            match entry {
+                SratEntry::LocalApic(cpu) if cpu.flags & FLAG_ENABLED != 0 => {
+                    let [b1, b2, b3] = cpu.proximity_domain_high;
+                    let domain = u32::from_le_bytes([cpu.proximity_domain_low, b1, b2, b3]);
+                    cpus.push((LogicalCpuId::new(cpu.apic_id.into()), node_for(domain)));
+                }
+                SratEntry::X2Apic(cpu) if cpu.flags & FLAG_ENABLED != 0 => {
+                    let node = node_for(cpu.proximity_domain);
+                    cpus.push((LogicalCpuId::new(cpu.x2apic_id), node));
+                }
+                SratEntry::Memory(range) if range.flags & FLAG_ENABLED != 0 => {
+                    let base = PhysicalAddress::new(range.base as usize);
+                    let node = node_for(range.proximity_domain);
+                    memory.push((base, range.length as usize, node));
+                }
+                _ => (),
+            }
+        }
+        println!("  SRAT: {} proximity domains", domains.len());
+
+        // SAFETY: No APs have been started yet.
+        unsafe {
+            numa::init(memory, cpus);
+        }
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Srat> {
+        // Skip the table revision and reserved fields.
+        if &sdt.signature == b"SRAT" && sdt.data_len() >= 12 {
+            Some(Srat { sdt })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> SratIter {
+        SratIter {
+            sdt: self.sdt,
+            i: 12,
+        }
+    }
+}
+
+pub struct SratIter {
+    sdt: &'static Sdt,
+    i: usize,
+}
+
+impl Iterator for SratIter {
+    type Item = SratEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i + 1 >= self.sdt.data_len() {
+            return None;
+        }
+        let entry_type = unsafe { *(self.sdt.data_address() as *const u8).add(self.i) };
+        let entry_len = unsafe { *(self.sdt.data_address() as *const u8).add(self.i + 1) } as usize;
+
+        if entry_len < 2 || self.i + entry_len > self.sdt.data_len() {
+            return None;
+        }
+        let addr = self.sdt.data_address() + self.i + 2;
+        let item = match entry_type {
+            0x0 if entry_len == mem::size_of::<SratLocalApic>() + 2 => {
+                SratEntry::LocalApic(unsafe { &*(addr as *const SratLocalApic) })
+            }
+            0x1 if entry_len == mem::size_of::<SratMemory>() + 2 => {
+                SratEntry::Memory(unsafe { &*(addr as *const SratMemory) })
+            }
+            0x2 if entry_len == mem::size_of::<SratX2Apic>() + 2 => {
+                SratEntry::X2Apic(unsafe { &*(addr as *const SratX2Apic) })
+            }
+            0x0..=0x2 => SratEntry::Invalid(entry_type, entry_len),
+            _ => SratEntry::Unknown(entry_type),
+        };
+        self.i += entry_len;
+
+        Some(item)
+    }
+}
@@ -0,0 +1,11 @@
+//! Decoding of the memory accesses done by faulting instructions, for trap-and-emulate.
+
+use crate::memory::AccessInfo;
+
+/// Decode the access done by the user instruction at `ip`, or None if it is not recognized.
+///
+/// Not implemented yet. For most data aborts, the syndrome (ESR_EL1.ISV and the fields it
+/// validates) already describes the access, which should be used instead of the instruction.
+pub fn decode_access(_ip: usize) -> Option<AccessInfo> {
+    None
+}
@@ -21,6 +21,37 @@ static GICC_IAR: u32 = 0x000c;
 static GICC_CTLR: u32 = 0x0000;
 static GICC_PMR: u32 = 0x0004;
 
+/// Classes of interrupt IDs, common to all GIC versions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GicIrqKind {
+    /// Software generated interrupts, IDs 0..=15. Used for IPIs.
+    Sgi,
+    /// Private peripheral interrupts, IDs 16..=31. Banked per CPU, e.g. the generic timer.
+    Ppi,
+    /// Shared peripheral interrupts, IDs 32..=1019. Routed to a single CPU at a time.
+    Spi,
+}
+
+impl GicIrqKind {
+    /// Classify `irq`, or None if it is not implemented by a distributor supporting `nirqs`
+    /// interrupts, or is one of the special IDs 1020..=1023.
+    pub fn of(irq: u32, nirqs: u32) -> Option<Self> {
+        match irq {
+            _ if irq >= nirqs => None,
+            0..=15 => Some(Self::Sgi),
+            16..=31 => Some(Self::Ppi),
+            32..=1019 => Some(Self::Spi),
+            _ => None,
+        }
+    }
+    pub fn is_assignable(irq: u32, nirqs: u32) -> bool {
+        matches!(Self::of(irq, nirqs), Some(Self::Ppi | Self::Spi))
+    }
+    pub fn is_per_cpu(irq: u32, nirqs: u32) -> bool {
+        matches!(Self::of(irq, nirqs), Some(Self::Sgi | Self::Ppi))
+    }
+}
+
 pub struct GenericInterruptController {
     pub gic_dist_if: GicDistIf,
     pub gic_cpu_if: GicCpuIf,
@@ -144,6 +175,12 @@ impl InterruptController for GenericInterruptController {
             Some(self.irq_range.0 + hwirq as usize)
         }
     }
+    fn irq_is_assignable(&self, hwirq: u32) -> bool {
+        GicIrqKind::is_assignable(hwirq, self.gic_dist_if.nirqs)
+    }
+    fn irq_is_per_cpu(&self, hwirq: u32) -> bool {
+        GicIrqKind::is_per_cpu(hwirq, self.gic_dist_if.nirqs)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -2,7 +2,10 @@ use alloc::vec::Vec;
 use core::arch::asm;
 use fdt::{node::NodeProperty, Fdt};
 
-use super::{gic::GicDistIf, InterruptController};
+use super::{
+    gic::{GicDistIf, GicIrqKind},
+    InterruptController,
+};
 use crate::dtb::irqchip::{InterruptHandler, IrqDesc};
 use syscall::{
     error::{Error, EINVAL},
@@ -137,6 +140,12 @@ impl InterruptController for GicV3 {
             Some(self.irq_range.0 + hwirq as usize)
         }
     }
+    fn irq_is_assignable(&self, hwirq: u32) -> bool {
+        GicIrqKind::is_assignable(hwirq, self.gic_dist_if.nirqs)
+    }
+    fn irq_is_per_cpu(&self, hwirq: u32) -> bool {
+        GicIrqKind::is_per_cpu(hwirq, self.gic_dist_if.nirqs)
+    }
 }
 
 #[derive(Debug)]
@@ -207,6 +207,16 @@ impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.elr_el1
     }
+    fn set_ip(&mut self, ip: usize) {
+        self.iret.elr_el1 = ip;
+    }
+    // Not needed until decode_access recognizes any instructions on AArch64.
+    fn reg(&self, _reg: u8) -> Option<usize> {
+        None
+    }
+    fn set_reg(&mut self, _reg: u8, _value: usize) -> Option<()> {
+        None
+    }
     fn recover_and_efault(&mut self) {
         // Set the return value to nonzero to indicate usercopy failure (EFAULT), and emulate the
         // return instruction by setting the return pointer to the saved LR value.
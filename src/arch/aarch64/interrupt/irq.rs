@@ -16,6 +16,9 @@ exception_stack!(irq_at_el0, |_stack| {
     } else {
         println!("unexpected irq num {}", irq);
     }
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 });
 
 exception_stack!(irq_at_el1, |_stack| {
@@ -27,6 +30,9 @@ exception_stack!(irq_at_el1, |_stack| {
     } else {
         println!("unexpected irq num {}", irq);
     }
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 });
 
 //TODO
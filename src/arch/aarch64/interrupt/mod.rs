@@ -36,6 +36,11 @@ pub unsafe fn enable_and_nop() {
     asm!("nop");
 }
 
+/// Mask the interrupts at or below priority level `level`, see [`crate::irql`].
+pub unsafe fn set_priority_mask(_level: u8) {
+    // TODO: Use the GIC priority mask register, once interrupts are assigned priorities.
+}
+
 /// Halt instruction
 #[inline(always)]
 pub unsafe fn halt() {
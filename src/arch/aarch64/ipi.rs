@@ -3,6 +3,7 @@
 pub enum IpiKind {
     Wakeup = 0x40,
     Tlb = 0x41,
+    Switch = 0x42,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -7,6 +7,9 @@ pub mod consts;
 /// Debugging support
 pub mod debug;
 
+/// Decoding of faulting instructions
+pub mod decode;
+
 /// Devices
 pub mod device;
 
@@ -22,6 +25,9 @@ pub mod misc;
 /// Paging
 pub mod paging;
 
+/// Performance monitoring counters
+pub mod pmu;
+
 pub mod rmm;
 
 /// Initialization and start function
@@ -5,5 +5,9 @@ bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE = 1 << 2;
         const DEV_MEM = 2 << 2;
+        // Hardware management of the access flag and dirty state (FEAT_HAFDBS) is optional, and
+        // not enabled.
+        const ACCESSED = 0;
+        const DIRTY = 0;
     }
 }
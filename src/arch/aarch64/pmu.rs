@@ -0,0 +1,16 @@
+/// Read the current `(cycles, instructions)` counter values of this CPU. Not yet implemented on
+/// aarch64.
+pub fn read() -> Option<(u64, u64)> {
+    None
+}
+
+/// Mask covering the implemented counter width, for computing deltas across wraparound.
+pub fn counter_mask() -> u64 {
+    u64::MAX
+}
+
+/// Read a cheap, monotonically increasing cycle timestamp of this CPU. Not yet implemented on
+/// aarch64.
+pub fn timestamp() -> Option<u64> {
+    None
+}
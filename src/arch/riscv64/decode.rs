@@ -0,0 +1,89 @@
+//! Decoding of the memory accesses done by faulting instructions, for trap-and-emulate.
+//!
+//! Covers the integer loads and stores of the base ISA, and their compressed forms.
+
+use crate::memory::{read_instruction_bytes, AccessInfo, AccessOperand};
+
+/// Decode the access done by the user instruction at `ip`, or None if it is not recognized.
+pub fn decode_access(ip: usize) -> Option<AccessInfo> {
+    let mut bytes = [0_u8; 4];
+    let len = read_instruction_bytes(ip, &mut bytes)?;
+    if len < 2 {
+        return None;
+    }
+    let low = u16::from_le_bytes([bytes[0], bytes[1]]);
+
+    if low & 0b11 != 0b11 {
+        return decode_compressed(low);
+    }
+    if len < 4 {
+        return None;
+    }
+    decode(u32::from_le_bytes(bytes))
+}
+
+fn decode(inst: u32) -> Option<AccessInfo> {
+    let opcode = inst & 0x7F;
+    let funct3 = (inst >> 12) & 0b111;
+    let rd = ((inst >> 7) & 0x1F) as u8;
+    let rs2 = ((inst >> 20) & 0x1F) as u8;
+
+    let (size, is_write, sign_extend, reg) = match (opcode, funct3) {
+        // LOAD: lb, lh, lw, ld, lbu, lhu, lwu
+        (0x03, 0) => (1, false, true, rd),
+        (0x03, 1) => (2, false, true, rd),
+        (0x03, 2) => (4, false, true, rd),
+        (0x03, 3) => (8, false, false, rd),
+        (0x03, 4) => (1, false, false, rd),
+        (0x03, 5) => (2, false, false, rd),
+        (0x03, 6) => (4, false, false, rd),
+        // STORE: sb, sh, sw, sd
+        (0x23, 0) => (1, true, false, rs2),
+        (0x23, 1) => (2, true, false, rs2),
+        (0x23, 2) => (4, true, false, rs2),
+        (0x23, 3) => (8, true, false, rs2),
+        _ => return None,
+    };
+
+    Some(AccessInfo {
+        size,
+        is_write,
+        sign_extend,
+        operand: AccessOperand::Reg(reg),
+        reg_size: 8,
+        len: 4,
+    })
+}
+
+fn decode_compressed(inst: u16) -> Option<AccessInfo> {
+    let quadrant = inst & 0b11;
+    let funct3 = inst >> 13;
+    // Registers x8 to x15, as encoded by the CL and CS formats.
+    let reg_short = ((inst >> 2) & 0b111) as u8 + 8;
+    // Full register numbers, as encoded by the CI and CSS formats.
+    let rd = ((inst >> 7) & 0x1F) as u8;
+    let rs2 = ((inst >> 2) & 0x1F) as u8;
+
+    let (size, is_write, sign_extend, reg) = match (quadrant, funct3) {
+        // c.lw, c.ld, c.sw, c.sd
+        (0b00, 0b010) => (4, false, true, reg_short),
+        (0b00, 0b011) => (8, false, false, reg_short),
+        (0b00, 0b110) => (4, true, false, reg_short),
+        (0b00, 0b111) => (8, true, false, reg_short),
+        // c.lwsp, c.ldsp (rd must not be x0), c.swsp, c.sdsp
+        (0b10, 0b010) if rd != 0 => (4, false, true, rd),
+        (0b10, 0b011) if rd != 0 => (8, false, false, rd),
+        (0b10, 0b110) => (4, true, false, rs2),
+        (0b10, 0b111) => (8, true, false, rs2),
+        _ => return None,
+    };
+
+    Some(AccessInfo {
+        size,
+        is_write,
+        sign_extend,
+        operand: AccessOperand::Reg(reg),
+        reg_size: 8,
+        len: 2,
+    })
+}
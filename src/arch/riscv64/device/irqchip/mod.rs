@@ -11,6 +11,8 @@ mod clint;
 
 // pub mod clint; // actual clint.rs off limits if SBI is present
 
+pub use self::plic::set_boot_hart_threshold as set_plic_threshold;
+
 pub fn new_irqchip(ic_str: &str) -> Option<Box<dyn InterruptController>> {
     if ic_str.contains("riscv,cpu-intc") {
         Some(Box::new(hlic::Hlic::new()))
@@ -2,7 +2,12 @@ use crate::{
     arch::{device::irqchip::hlic, start::BOOT_HART_ID},
     dtb::irqchip::{InterruptController, InterruptHandler, IrqDesc, IRQ_CHIP},
 };
-use core::{mem, num::NonZero, sync::atomic::Ordering};
+use core::{
+    mem,
+    num::NonZero,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
 use fdt::Fdt;
 use log::{error, info};
 use syscall::{Error, Io, Mmio, ENODEV};
@@ -73,6 +78,19 @@ impl PlicRegs {
     }
 }
 
+// The PLIC routing external interrupts to the boot hart, and the context of that hart.
+static BOOT_HART_PLIC: AtomicPtr<PlicRegs> = AtomicPtr::new(ptr::null_mut());
+static BOOT_HART_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the priority threshold of the boot hart, masking the external interrupts with a priority at
+/// or below `priority`. Does nothing before the PLIC has been initialized.
+pub fn set_boot_hart_threshold(priority: usize) {
+    let regs = BOOT_HART_PLIC.load(Ordering::Acquire);
+    if let Some(regs) = unsafe { regs.as_mut() } {
+        regs.set_priority_threshold(BOOT_HART_CONTEXT.load(Ordering::Relaxed), priority);
+    }
+}
+
 pub struct Plic {
     regs: *mut PlicRegs,
     ndev: usize,
@@ -152,6 +170,9 @@ impl InterruptController for Plic {
         let regs = unsafe { self.regs.as_mut().unwrap() };
         regs.set_priority_threshold(self.context, 0);
 
+        BOOT_HART_CONTEXT.store(self.context, Ordering::Relaxed);
+        BOOT_HART_PLIC.store(self.regs, Ordering::Release);
+
         Ok(())
     }
 
@@ -6,7 +6,7 @@ use crate::{
     arch::{device::irqchip, start::BOOT_HART_ID},
     memory::GenericPfFlags,
     panic::stack_trace,
-    ptrace, syscall,
+    ptrace,
     syscall::flag::*,
 };
 
@@ -141,14 +141,14 @@ unsafe fn handle_interrupt(interrupt: usize) {
     // For now all the interrupts go to boot hart so this suffices...
     let hart: usize = BOOT_HART_ID.load(Ordering::Relaxed);
     irqchip::hlic::interrupt(hart, interrupt);
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 }
 
 unsafe fn handle_user_exception(scause: usize, regs: &mut InterruptStack) {
     if scause == USERMODE_ECALL {
-        let r = &mut regs.registers;
-        regs.iret.sepc += 4; // skip ecall
-        let ret = syscall::syscall(r.x17, r.x10, r.x11, r.x12, r.x13, r.x14);
-        r.x10 = ret;
+        super::syscall::handle_ecall(regs);
         return;
     }
 
@@ -201,10 +201,41 @@ impl InterruptStack {
     pub fn set_singlestep(&mut self, _singlestep: bool) {}
 }
 
+// The registers x1 to x31, in order.
+const _: () = assert!(size_of::<Registers>() == size_of::<[usize; 31]>());
+impl Registers {
+    fn as_array(&self) -> &[usize; 31] {
+        unsafe { &*(self as *const Self).cast::<[usize; 31]>() }
+    }
+    fn as_array_mut(&mut self) -> &mut [usize; 31] {
+        unsafe { &mut *(self as *mut Self).cast::<[usize; 31]>() }
+    }
+}
+
 impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.sepc
     }
+    fn set_ip(&mut self, ip: usize) {
+        self.iret.sepc = ip;
+    }
+    fn reg(&self, reg: u8) -> Option<usize> {
+        match reg {
+            // x0 is hardwired to zero.
+            0 => Some(0),
+            1..=31 => Some(self.registers.as_array()[usize::from(reg) - 1]),
+            _ => None,
+        }
+    }
+    fn set_reg(&mut self, reg: u8, value: usize) -> Option<()> {
+        match reg {
+            // Writes to x0 are discarded.
+            0 => (),
+            1..=31 => self.registers.as_array_mut()[usize::from(reg) - 1] = value,
+            _ => return None,
+        }
+        Some(())
+    }
     fn recover_and_efault(&mut self) {
         // Set the return value to nonzero to indicate usercopy failure (EFAULT), and emulate the
         // return instruction by setting the return pointer to the saved LR value.
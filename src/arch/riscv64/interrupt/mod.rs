@@ -28,9 +28,15 @@ pub unsafe fn enable() {
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
+///
+/// The intended idiom is to check the wakeup condition with interrupts disabled, and then call
+/// this function. The leading fence ensures that all memory accesses made while checking the
+/// condition are ordered before interrupts are enabled and the hart parks, so a wakeup signaled
+/// in between is not lost. `wfi` itself also returns if an interrupt became pending while
+/// interrupts were still disabled.
 #[inline(always)]
 pub unsafe fn enable_and_halt() {
-    asm!("csrsi sstatus, 1 << 1", "wfi")
+    asm!("fence rw, rw", "csrsi sstatus, 1 << 1", "wfi")
 }
 
 /// Set interrupts and nop
@@ -41,6 +47,15 @@ pub unsafe fn enable_and_nop() {
     asm!("csrsi sstatus, 1 << 1", "nop")
 }
 
+/// Mask the external interrupts at or below priority level `level`, see [`crate::irql`]. All of
+/// them are routed to the boot hart, so this does nothing on the other harts.
+pub unsafe fn set_priority_mask(level: u8) {
+    if crate::percpu::PercpuBlock::current().cpu_id == crate::cpu_set::LogicalCpuId::BSP {
+        // All sources have the same priority, so any nonzero level masks them all.
+        crate::arch::device::irqchip::set_plic_threshold(if level > 0 { 7 } else { 0 });
+    }
+}
+
 /// Halt instruction
 #[inline(always)]
 pub unsafe fn halt() {
@@ -1 +1,107 @@
+use super::InterruptStack;
+use crate::syscall::{
+    self,
+    error::{Error, ENOSYS},
+    number::*,
+};
+
 pub use super::handler::enter_usermode;
+
+type Handler = fn(usize, usize, usize, usize, usize, usize) -> usize;
+
+/// Number of slots in [`SYSCALL_TABLE`]. Syscall numbers carry class bits above their low bits,
+/// so a slot is selected by the number modulo this length and records the full number it serves.
+const SYSCALL_TABLE_LEN: usize = 1024;
+
+/// Every syscall number implemented by [`syscall::syscall`].
+const IMPLEMENTED: &[usize] = &[
+    SYS_WRITE2,
+    SYS_WRITE,
+    SYS_FMAP,
+    SYS_GETDENTS,
+    SYS_FUTIMENS,
+    SYS_READ2,
+    SYS_READ,
+    SYS_FPATH,
+    SYS_FSTAT,
+    SYS_FSTATVFS,
+    SYS_DUP,
+    SYS_DUP2,
+    SYS_SENDFD,
+    SYS_LSEEK,
+    SYS_FCHMOD,
+    SYS_FCHOWN,
+    SYS_FCNTL,
+    SYS_FEVENT,
+    SYS_FRENAME,
+    SYS_FUNMAP,
+    SYS_FSYNC,
+    SYS_FTRUNCATE,
+    SYS_CLOSE,
+    SYS_OPEN,
+    SYS_RMDIR,
+    SYS_UNLINK,
+    SYS_YIELD,
+    SYS_NANOSLEEP,
+    SYS_CLOCK_GETTIME,
+    SYS_FUTEX,
+    SYS_GETPID,
+    SYS_GETPGID,
+    SYS_GETPPID,
+    SYS_EXIT,
+    SYS_KILL,
+    SYS_SIGENQUEUE,
+    SYS_SIGDEQUEUE,
+    SYS_WAITPID,
+    SYS_IOPL,
+    SYS_GETEGID,
+    SYS_GETENS,
+    SYS_GETEUID,
+    SYS_GETGID,
+    SYS_GETNS,
+    SYS_GETUID,
+    SYS_MPROTECT,
+    SYS_MKNS,
+    SYS_SETPGID,
+    SYS_SETREUID,
+    SYS_SETRENS,
+    SYS_SETREGID,
+    SYS_VIRTTOPHYS,
+    SYS_MREMAP,
+];
+
+/// Ecall dispatch table, built at compile time. Empty slots hold [`enosys`] and `usize::MAX`.
+static SYSCALL_TABLE: [(usize, Handler); SYSCALL_TABLE_LEN] = {
+    let mut table = [(usize::MAX, enosys as Handler); SYSCALL_TABLE_LEN];
+    let mut i = 0;
+    while i < IMPLEMENTED.len() {
+        let number = IMPLEMENTED[i];
+        let slot = number % SYSCALL_TABLE_LEN;
+        assert!(
+            table[slot].0 == usize::MAX,
+            "syscall numbers collide in the dispatch table"
+        );
+        table[slot] = (number, syscall::syscall as Handler);
+        i += 1;
+    }
+    table
+};
+
+fn enosys(_a: usize, _b: usize, _c: usize, _d: usize, _e: usize, _f: usize) -> usize {
+    Error::mux(Err(Error::new(ENOSYS)))
+}
+
+/// Handle an `ecall` from usermode. The syscall number is passed in `a7`, the arguments in `a0`
+/// through `a4`, and the return value is written back to `a0`.
+pub unsafe fn handle_ecall(regs: &mut InterruptStack) {
+    // Resume after the ecall instruction, rather than executing it again.
+    regs.iret.sepc += 4;
+
+    let r = &mut regs.registers;
+    let number = r.x17;
+    let handler = match SYSCALL_TABLE.get(number % SYSCALL_TABLE_LEN) {
+        Some(&(slot_number, handler)) if slot_number == number => handler,
+        _ => enosys,
+    };
+    r.x10 = handler(number, r.x10, r.x11, r.x12, r.x13, r.x14);
+}
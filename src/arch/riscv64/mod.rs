@@ -3,17 +3,20 @@ pub mod macros;
 
 pub mod consts;
 pub mod debug;
+pub mod decode;
 pub mod device;
 pub mod interrupt;
 pub mod ipi;
 pub mod misc;
 pub mod paging;
+pub mod pmu;
 pub mod rmm;
 mod sbi;
 pub mod start;
 pub mod stop;
 pub mod time;
 
+// The modes the hardware supports are probed during boot, see `paging::init`.
 pub use ::rmm::RiscV64Sv48Arch as CurrentRmmArch;
 use core::arch::asm;
 
@@ -5,6 +5,8 @@ pub struct Entry(u64);
 bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE =        1 << 4;
+        const ACCESSED =        1 << 6;
+        const DIRTY =           1 << 7;
         const DEV_MEM =         0;
         const WRITE_COMBINING = 0;
     }
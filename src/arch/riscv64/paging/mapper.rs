@@ -1,6 +1,6 @@
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 
-use super::RmmA;
+use super::{RmmA, RmmArch};
 
 pub use rmm::{Flusher, PageFlush, PageFlushAll};
 
@@ -27,3 +27,9 @@ impl Drop for InactiveFlusher {
         ipi(IpiKind::Tlb, IpiTarget::Other);
     }
 }
+
+/// Flushes the user-space entries from this CPU's TLB. As ASIDs are not used, user entries cannot
+/// be told apart from kernel ones, so this flushes the entire TLB.
+pub unsafe fn flush_user_tlb() {
+    RmmA::invalidate_all();
+}
@@ -1,5 +1,8 @@
 #![allow(unused)]
 
+use core::arch::asm;
+use spin::Once;
+
 pub use super::CurrentRmmArch as RmmA;
 pub use rmm::{Arch as RmmArch, PageFlags, PhysicalAddress, TableKind, VirtualAddress};
 
@@ -16,10 +19,125 @@ pub const ENTRY_COUNT: usize = RmmA::PAGE_ENTRIES;
 pub const PAGE_SIZE: usize = RmmA::PAGE_SIZE;
 pub const PAGE_MASK: usize = RmmA::PAGE_OFFSET_MASK;
 
+/// Paging modes, with the values of the corresponding `satp.MODE` encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    pub fn from_satp(satp: usize) -> Option<Self> {
+        match satp >> 60 {
+            8 => Some(Self::Sv39),
+            9 => Some(Self::Sv48),
+            10 => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+    /// Number of page table levels walked when translating an address.
+    pub fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+    /// Number of significant bits in a virtual address.
+    pub fn address_bits(self) -> usize {
+        RmmA::PAGE_SHIFT + self.levels() * RmmA::PAGE_ENTRY_SHIFT
+    }
+    /// The mode with one more level, if any.
+    fn wider(self) -> Option<Self> {
+        match self {
+            Self::Sv39 => Some(Self::Sv48),
+            Self::Sv48 => Some(Self::Sv57),
+            Self::Sv57 => None,
+        }
+    }
+}
+
+static PAGING_MODE: Once<PagingMode> = Once::new();
+static WIDEST_PAGING_MODE: Once<PagingMode> = Once::new();
+
+/// Paging mode in use, detected at boot, defaulting to Sv39 before then.
+pub fn paging_mode() -> PagingMode {
+    PAGING_MODE.get().copied().unwrap_or(PagingMode::Sv39)
+}
+/// Widest paging mode the hardware supports, probed at boot, defaulting to Sv39 before then.
+pub fn widest_paging_mode() -> PagingMode {
+    WIDEST_PAGING_MODE
+        .get()
+        .copied()
+        .unwrap_or(PagingMode::Sv39)
+}
+
+/// Whether the hart accepts `mode`, which must be one level wider than the mode in use, by
+/// attempting to write it to `satp`. Writes of unsupported modes have no effect at all, so reading
+/// `satp` back tells whether it is supported.
+///
+/// The probe temporarily switches to a new root table whose last entry points to the current root.
+/// All kernel mappings lie in the top of the address space, so they translate the same way in the
+/// wider mode, and the kernel keeps running until `satp` is restored.
+unsafe fn probe_paging_mode(mode: PagingMode, satp: usize) -> bool {
+    const PPN_MASK: usize = (1 << 44) - 1;
+    const PTE_VALID: usize = 1;
+
+    let Some(frame) = crate::memory::allocate_frame() else {
+        return false;
+    };
+    let root = RmmA::phys_to_virt(frame.base()).data() as *mut usize;
+    core::ptr::write_bytes(root, 0, ENTRY_COUNT);
+    root.add(ENTRY_COUNT - 1)
+        .write(((satp & PPN_MASK) << 10) | PTE_VALID);
+
+    let probe = ((mode as usize) << 60)
+        | (satp & !(0xF << 60) & !PPN_MASK)
+        | (frame.base().data() >> RmmA::PAGE_SHIFT);
+    let accepted: usize;
+    asm!(
+        "sfence.vma",
+        "csrw satp, {probe}",
+        "sfence.vma",
+        "csrr {accepted}, satp",
+        "csrw satp, {satp}",
+        "sfence.vma",
+        probe = in(reg) probe,
+        satp = in(reg) satp,
+        accepted = out(reg) accepted,
+    );
+
+    crate::memory::deallocate_frame(frame);
+    PagingMode::from_satp(accepted) == Some(mode)
+}
+
 #[cold]
 pub unsafe fn init() {
     // Assuming SBI already set up PMAs correctly for us
     // TODO: detect Svpbmt present/enabled and override device memory with PBMT=IO
+
+    let satp: usize;
+    asm!("csrr {}, satp", out(reg) satp);
+
+    let mode = PagingMode::from_satp(satp).unwrap_or_else(|| {
+        // Every implementation that supports paging at all supports Sv39.
+        log::warn!("unknown paging mode in satp {:#x}, assuming Sv39", satp);
+        PagingMode::Sv39
+    });
+    PAGING_MODE.call_once(|| mode);
+
+    // Supporting a mode implies supporting all narrower ones, so only wider ones need probing.
+    let mut widest = mode;
+    while let Some(wider) = widest.wider() {
+        if !probe_paging_mode(wider, satp) {
+            break;
+        }
+        widest = wider;
+    }
+    WIDEST_PAGING_MODE.call_once(|| widest);
+
+    log::info!("Paging mode: {:?}, widest supported: {:?}", mode, widest);
 }
 
 /// Page
@@ -49,14 +167,27 @@ impl Page {
     pub fn next(self) -> Page {
         self.next_by(1)
     }
+    #[track_caller]
     pub fn next_by(self, n: usize) -> Page {
-        Self {
-            number: self.number + n,
-        }
+        self.checked_next_by(n).expect("overflow in Page::next_by")
     }
+    /// Returns the page `n` pages after this one, or `None` if its address would overflow.
+    pub fn checked_next_by(self, n: usize) -> Option<Page> {
+        let number = self.number.checked_add(n)?;
+        number.checked_mul(PAGE_SIZE)?;
 
+        Some(Self { number })
+    }
+
+    #[track_caller]
     pub fn offset_from(self, other: Self) -> usize {
-        self.number - other.number
+        self.checked_offset_from(other)
+            .expect("overflow in Page::offset_from")
+    }
+    /// Returns the number of pages from `other` to this page, or `None` if `other` comes after
+    /// this page.
+    pub fn checked_offset_from(self, other: Self) -> Option<usize> {
+        self.number.checked_sub(other.number)
     }
 }
 
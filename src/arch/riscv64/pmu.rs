@@ -0,0 +1,27 @@
+use core::arch::asm;
+
+/// Read the current `(cycles, instructions)` counter values of this hart. Supervisor-mode access
+/// to the `cycle` and `instret` CSRs is enabled by the SBI implementation.
+pub fn read() -> Option<(u64, u64)> {
+    let (cycles, instructions): (u64, u64);
+    unsafe {
+        asm!("rdcycle {}", out(reg) cycles, options(nomem, nostack));
+        asm!("rdinstret {}", out(reg) instructions, options(nomem, nostack));
+    }
+    Some((cycles, instructions))
+}
+
+/// Mask covering the implemented counter width, for computing deltas across wraparound.
+pub fn counter_mask() -> u64 {
+    u64::MAX
+}
+
+/// Read a cheap, monotonically increasing cycle timestamp of this hart, for measuring short
+/// intervals.
+pub fn timestamp() -> Option<u64> {
+    let cycles: u64;
+    unsafe {
+        asm!("rdcycle {}", out(reg) cycles, options(nomem, nostack));
+    }
+    Some(cycles)
+}
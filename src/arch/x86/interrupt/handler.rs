@@ -324,7 +324,14 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
-                $code
+                unsafe fn handler() {
+                    $code
+                }
+                handler();
+
+                // Interrupts only arrive in usermode or in the idle loop, so no locks are held.
+                $crate::softirq::run_pending();
+                $crate::context::switch::check_kstack_canary();
             }
 
             core::arch::asm!(concat!(
@@ -435,10 +442,47 @@ unsafe extern "C" fn usercopy_trampoline() {
     );
 }
 
+impl InterruptStack {
+    fn reg_mut(&mut self, reg: u8) -> Option<&mut usize> {
+        Some(match reg {
+            0 => &mut self.scratch.eax,
+            1 => &mut self.scratch.ecx,
+            2 => &mut self.scratch.edx,
+            3 => &mut self.preserved.ebx,
+            // Only present when interrupted from userspace, which is the case for emulation.
+            4 => &mut self.iret.esp,
+            5 => &mut self.preserved.ebp,
+            6 => &mut self.preserved.esi,
+            7 => &mut self.preserved.edi,
+            _ => return None,
+        })
+    }
+}
+
 impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.eip
     }
+    fn set_ip(&mut self, ip: usize) {
+        self.iret.eip = ip;
+    }
+    fn reg(&self, reg: u8) -> Option<usize> {
+        Some(match reg {
+            0 => self.scratch.eax,
+            1 => self.scratch.ecx,
+            2 => self.scratch.edx,
+            3 => self.preserved.ebx,
+            4 => self.iret.esp,
+            5 => self.preserved.ebp,
+            6 => self.preserved.esi,
+            7 => self.preserved.edi,
+            _ => return None,
+        })
+    }
+    fn set_reg(&mut self, reg: u8, value: usize) -> Option<()> {
+        *self.reg_mut(reg)? = value;
+        Some(())
+    }
     fn recover_and_efault(&mut self) {
         // Unlike on x86_64, Protected Mode interrupts will not save/restore esp and ss unless
         // privilege rings changed, which they won't here as we are catching a kernel-induced page
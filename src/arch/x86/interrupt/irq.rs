@@ -170,6 +170,9 @@ interrupt_stack!(pit_stack, |_stack| {
 
     // Switch after a sufficient amount of time since the last switch.
     context::switch::tick();
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 });
 
 interrupt!(keyboard, || {
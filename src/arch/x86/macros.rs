@@ -32,10 +32,10 @@ macro_rules! default_irqs(
         irqs!([
             // interrupt vectors below 32 are exceptions
             // vectors 32..=47 are used for standard 8259 pic irqs.
-            // 48 and 49 are used for the local APIC timer and error register, respectively.
+            ($idt, 48, irq_48), ($idt, 49, irq_49),
             ($idt, 50, irq_50), ($idt, 51, irq_51), ($idt, 52, irq_52), ($idt, 53, irq_53), ($idt, 54, irq_54), ($idt, 55, irq_55), ($idt, 56, irq_56), ($idt, 57, irq_57), ($idt, 58, irq_58), ($idt, 59, irq_59),
             ($idt, 60, irq_60), ($idt, 61, irq_61), ($idt, 62, irq_62), ($idt, 63, irq_63),
-            // 64..=67 used for IPI
+            ($idt, 64, irq_64), ($idt, 65, irq_65), ($idt, 66, irq_66), ($idt, 67, irq_67),
             ($idt, 68, irq_68), ($idt, 69, irq_69),
             ($idt, 70, irq_70), ($idt, 71, irq_71), ($idt, 72, irq_72), ($idt, 73, irq_73), ($idt, 74, irq_74), ($idt, 75, irq_75), ($idt, 76, irq_76), ($idt, 77, irq_77), ($idt, 78, irq_78), ($idt, 79, irq_79),
             ($idt, 80, irq_80), ($idt, 81, irq_81), ($idt, 82, irq_82), ($idt, 83, irq_83), ($idt, 84, irq_84), ($idt, 85, irq_85), ($idt, 86, irq_86), ($idt, 87, irq_87), ($idt, 88, irq_88), ($idt, 89, irq_89),
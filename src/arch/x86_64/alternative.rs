@@ -296,5 +296,17 @@ pub fn kfx_size() -> usize {
     }
 }
 
+/// Offset of the upper halves of YMM0-15 within the save area, if AVX state is enabled.
+pub fn ymm_upper_offset() -> Option<usize> {
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    {
+        xsave::info()?.ymm_upper_offset.map(|offset| offset as usize)
+    }
+    #[cfg(cpu_feature_never = "xsave")]
+    {
+        None
+    }
+}
+
 pub const FXSAVE_SIZE: usize = 512;
 pub const XSAVE_HEADER_SIZE: usize = 64;
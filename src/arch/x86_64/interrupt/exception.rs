@@ -36,6 +36,8 @@ interrupt_stack!(debug, @paranoid, |stack| {
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
+    crate::context::switch::handle_watchdog_nmi(|| stack.dump());
+
     #[cfg(feature = "profiling")]
     crate::profiling::nmi_handler(stack);
 
@@ -423,7 +423,14 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
-                $code
+                unsafe fn handler() {
+                    $code
+                }
+                handler();
+
+                // Interrupts only arrive in usermode or in the idle loop, so no locks are held.
+                $crate::softirq::run_pending();
+                $crate::context::switch::check_kstack_canary();
             }
 
             core::arch::asm!(concat!(
@@ -516,10 +523,62 @@ macro_rules! interrupt_error {
     };
 }
 
+impl InterruptStack {
+    fn reg_mut(&mut self, reg: u8) -> Option<&mut usize> {
+        Some(match reg {
+            0 => &mut self.scratch.rax,
+            1 => &mut self.scratch.rcx,
+            2 => &mut self.scratch.rdx,
+            3 => &mut self.preserved.rbx,
+            4 => &mut self.iret.rsp,
+            5 => &mut self.preserved.rbp,
+            6 => &mut self.scratch.rsi,
+            7 => &mut self.scratch.rdi,
+            8 => &mut self.scratch.r8,
+            9 => &mut self.scratch.r9,
+            10 => &mut self.scratch.r10,
+            11 => &mut self.scratch.r11,
+            12 => &mut self.preserved.r12,
+            13 => &mut self.preserved.r13,
+            14 => &mut self.preserved.r14,
+            15 => &mut self.preserved.r15,
+            _ => return None,
+        })
+    }
+}
+
 impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.rip
     }
+    fn set_ip(&mut self, ip: usize) {
+        self.iret.rip = ip;
+    }
+    fn reg(&self, reg: u8) -> Option<usize> {
+        Some(match reg {
+            0 => self.scratch.rax,
+            1 => self.scratch.rcx,
+            2 => self.scratch.rdx,
+            3 => self.preserved.rbx,
+            4 => self.iret.rsp,
+            5 => self.preserved.rbp,
+            6 => self.scratch.rsi,
+            7 => self.scratch.rdi,
+            8 => self.scratch.r8,
+            9 => self.scratch.r9,
+            10 => self.scratch.r10,
+            11 => self.scratch.r11,
+            12 => self.preserved.r12,
+            13 => self.preserved.r13,
+            14 => self.preserved.r14,
+            15 => self.preserved.r15,
+            _ => return None,
+        })
+    }
+    fn set_reg(&mut self, reg: u8, value: usize) -> Option<()> {
+        *self.reg_mut(reg)? = value;
+        Some(())
+    }
     fn recover_and_efault(&mut self) {
         // We were inside a usercopy function that failed. This is handled by setting rax to a
         // nonzero value, and emulating the ret instruction.
@@ -170,6 +170,9 @@ interrupt_stack!(pit_stack, |_stack| {
 
     // Switch after a sufficient amount of time since the last switch.
     context::switch::tick();
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 });
 
 interrupt!(keyboard, || {
@@ -296,6 +299,9 @@ interrupt_error!(generic_irq, |_stack, code| {
     irq_trigger((code as i32).wrapping_add(128) as u8);
 
     lapic_eoi();
+
+    crate::softirq::run_pending();
+    crate::context::switch::check_kstack_canary();
 });
 
 core::arch::global_asm!("
@@ -24,4 +24,6 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
     {
         x86::msr::wrmsr(x86::msr::IA32_TSC_AUX, cpu_id.get().into());
     }
+
+    crate::arch::pmu::init();
 }
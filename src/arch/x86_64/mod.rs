@@ -67,4 +67,4 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
 }
 pub use arch_copy_to_user as arch_copy_from_user;
 
-pub use alternative::kfx_size;
+pub use alternative::{kfx_size, ymm_upper_offset};
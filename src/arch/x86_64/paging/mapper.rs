@@ -1,6 +1,6 @@
 use crate::ipi::{ipi, IpiKind, IpiTarget};
 
-use super::RmmA;
+use super::{RmmA, RmmArch, TableKind};
 
 pub use rmm::{Flusher, PageFlush, PageFlushAll};
 
@@ -20,3 +20,10 @@ impl Drop for InactiveFlusher {
         ipi(IpiKind::Tlb, IpiTarget::Other);
     }
 }
+
+/// Flushes the user-space entries from this CPU's TLB. Reloading CR3 keeps the kernel mappings
+/// marked global, unlike a full flush. PCIDs are not used, so this always drops all other
+/// entries.
+pub unsafe fn flush_user_tlb() {
+    RmmA::set_table(TableKind::User, RmmA::table(TableKind::User));
+}
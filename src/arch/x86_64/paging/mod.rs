@@ -14,6 +14,8 @@ pub mod entry {
     bitflags! {
         pub struct EntryFlags: usize {
             const NO_CACHE =        1 << 4;
+            const ACCESSED =        1 << 5;
+            const DIRTY =           1 << 6;
             const HUGE_PAGE =       1 << 7;
             const GLOBAL =          1 << 8;
             const DEV_MEM =         0;
@@ -96,13 +98,26 @@ impl Page {
     pub fn next(self) -> Page {
         self.next_by(1)
     }
+    #[track_caller]
     pub fn next_by(self, n: usize) -> Page {
-        Self {
-            number: self.number + n,
-        }
+        self.checked_next_by(n).expect("overflow in Page::next_by")
+    }
+    /// Returns the page `n` pages after this one, or `None` if its address would overflow.
+    pub fn checked_next_by(self, n: usize) -> Option<Page> {
+        let number = self.number.checked_add(n)?;
+        number.checked_mul(PAGE_SIZE)?;
+
+        Some(Self { number })
     }
+    #[track_caller]
     pub fn offset_from(self, other: Self) -> usize {
-        self.number - other.number
+        self.checked_offset_from(other)
+            .expect("overflow in Page::offset_from")
+    }
+    /// Returns the number of pages from `other` to this page, or `None` if `other` comes after
+    /// this page.
+    pub fn checked_offset_from(self, other: Self) -> Option<usize> {
+        self.number.checked_sub(other.number)
     }
 }
 impl Debug for Page {
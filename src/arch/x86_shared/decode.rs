@@ -0,0 +1,152 @@
+//! Decoding of the memory accesses done by faulting instructions, for trap-and-emulate.
+//!
+//! Only the plain `mov` forms compilers emit for volatile accesses are recognized, i.e. loads and
+//! stores between a register and memory, stores of immediates, and zero or sign extending loads.
+//! Anything else, including string instructions and accesses to the legacy high byte registers,
+//! is left undecoded.
+
+use core::cmp;
+
+use crate::memory::{read_instruction_bytes, AccessInfo, AccessOperand};
+
+/// Maximum length of an x86 instruction.
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Decode the access done by the user instruction at `ip`, or None if it is not recognized.
+pub fn decode_access(ip: usize) -> Option<AccessInfo> {
+    let mut bytes = [0_u8; MAX_INSTRUCTION_LEN];
+    let len = read_instruction_bytes(ip, &mut bytes)?;
+    decode(&bytes[..len])
+}
+
+fn decode(bytes: &[u8]) -> Option<AccessInfo> {
+    let mut pos = 0;
+    let mut operand_size_override = false;
+
+    // Legacy prefixes. The address size and segment overrides do not affect the access itself.
+    loop {
+        match *bytes.get(pos)? {
+            0x66 => operand_size_override = true,
+            0x67 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => (),
+            _ => break,
+        }
+        pos += 1;
+    }
+
+    // REX prefix, which must directly precede the opcode.
+    #[cfg(target_arch = "x86_64")]
+    let rex = match *bytes.get(pos)? {
+        byte @ 0x40..=0x4F => {
+            pos += 1;
+            byte
+        }
+        _ => 0,
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let rex = 0_u8;
+
+    let rex_w = rex & 0b1000 != 0;
+    let rex_r = rex & 0b0100 != 0;
+
+    let full_size = if rex_w {
+        8
+    } else if operand_size_override {
+        2
+    } else {
+        4
+    };
+
+    let opcode = *bytes.get(pos)?;
+    // Whether the register operand is a byte register, rather than being extended into.
+    let byte_reg = matches!(opcode, 0x88 | 0x8A);
+    // Immediates are at most 32 bits, and sign extended for 64-bit stores.
+    let imm_full_size = usize::from(cmp::min(full_size, 4));
+
+    // (size, is_write, sign_extend, has register operand, immediate size)
+    let (size, is_write, sign_extend, has_reg, imm_size) = match opcode {
+        0x88 => (1, true, false, true, 0),
+        0x89 => (full_size, true, false, true, 0),
+        0x8A => (1, false, false, true, 0),
+        0x8B => (full_size, false, false, true, 0),
+        0xC6 => (1, true, false, false, 1),
+        0xC7 => (full_size, true, false, false, imm_full_size),
+        #[cfg(target_arch = "x86_64")]
+        0x63 if rex_w => (4, false, true, true, 0),
+        0x0F => {
+            pos += 1;
+            match *bytes.get(pos)? {
+                0xB6 => (1, false, false, true, 0),
+                0xB7 => (2, false, false, true, 0),
+                0xBE => (1, false, true, true, 0),
+                0xBF => (2, false, true, true, 0),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    // Plain moves write the register with the size of the access, whereas extending loads write
+    // the full operand size.
+    let reg_size = if opcode == 0x0F || opcode == 0x63 {
+        full_size
+    } else {
+        size
+    };
+    pos += 1;
+
+    let modrm = *bytes.get(pos)?;
+    pos += 1;
+    let mode = modrm >> 6;
+    let reg_field = (modrm >> 3) & 0b111;
+    let rm = modrm & 0b111;
+
+    // Register to register moves do not access memory.
+    if mode == 0b11 {
+        return None;
+    }
+    // The immediate forms are only mov with /0.
+    if !has_reg && reg_field != 0 {
+        return None;
+    }
+
+    let mut disp_size = match mode {
+        0b01 => 1,
+        0b10 => 4,
+        // Absolute, or RIP-relative on x86_64.
+        _ if rm == 0b101 => 4,
+        _ => 0,
+    };
+    if rm == 0b100 {
+        let sib = *bytes.get(pos)?;
+        pos += 1;
+        if mode == 0b00 && sib & 0b111 == 0b101 {
+            disp_size = 4;
+        }
+    }
+    pos += disp_size;
+    let imm = bytes.get(pos..pos + imm_size)?;
+    pos += imm_size;
+
+    let operand = if has_reg {
+        // Without REX, byte registers 4 to 7 are AH, CH, DH and BH rather than the low bytes of
+        // SP, BP, SI and DI.
+        if byte_reg && rex == 0 && reg_field >= 4 {
+            return None;
+        }
+        AccessOperand::Reg(reg_field | if rex_r { 0b1000 } else { 0 })
+    } else {
+        let mut buf = [0_u8; 8];
+        buf[..imm_size].copy_from_slice(imm);
+        let bits = imm_size as u32 * 8;
+        let imm = ((u64::from_le_bytes(buf) << (64 - bits)) as i64 >> (64 - bits)) as u64;
+        AccessOperand::Imm(imm)
+    };
+
+    Some(AccessInfo {
+        size,
+        is_write,
+        sign_extend,
+        operand,
+        reg_size,
+        len: pos as u8,
+    })
+}
@@ -331,7 +331,8 @@ pub unsafe fn init(active_table: &mut KernelMapper) {
     );
 
     // map the legacy PC-compatible IRQs (0-15) to 32-47, just like we did with 8259 PIC (if it
-    // wouldn't have been disabled due to this I/O APIC)
+    // wouldn't have been disabled due to this I/O APIC), except for the PIT, which drives the
+    // scheduler tick and is thus kept above the device interrupts.
     for legacy_irq in 0..=15 {
         let (gsi, trigger_mode, polarity) = match get_override(legacy_irq) {
             Some(over) => (over.gsi, over.trigger_mode, over.polarity),
@@ -378,7 +379,11 @@ pub unsafe fn init(active_table: &mut KernelMapper) {
                 TriggerMode::Level => ApicTriggerMode::Level,
                 TriggerMode::ConformsToSpecs => ApicTriggerMode::Edge,
             },
-            vector: 32 + legacy_irq,
+            vector: if legacy_irq == 0 {
+                crate::idt::PIT_VECTOR
+            } else {
+                32 + legacy_irq
+            },
         };
         apic.map(redir_tbl_index, map_info);
     }
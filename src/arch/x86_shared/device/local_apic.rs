@@ -161,6 +161,16 @@ impl LocalApic {
         self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b100 << 8));
     }
 
+    /// Sets the Task Priority Register, masking all interrupts whose priority class (the upper
+    /// four bits of the vector) is at or below its upper four bits.
+    pub unsafe fn set_tpr(&mut self, tpr: u32) {
+        if self.x2 {
+            wrmsr(IA32_X2APIC_TPR, u64::from(tpr));
+        } else {
+            self.write(0x80, tpr);
+        }
+    }
+
     pub unsafe fn eoi(&mut self) {
         if self.x2 {
             wrmsr(IA32_X2APIC_EOI, 0);
@@ -244,7 +254,7 @@ impl LocalApic {
         }
     }
     unsafe fn setup_error_int(&mut self) {
-        let vector = 49u32;
+        let vector = u32::from(crate::idt::LAPIC_ERROR_VECTOR);
         self.set_lvt_error(vector);
     }
 }
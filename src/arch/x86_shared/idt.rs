@@ -20,6 +20,15 @@ use spin::RwLock;
 
 pub static INIT_IDT: SyncUnsafeCell<[IdtEntry; 32]> = SyncUnsafeCell::new([IdtEntry::new(); 32]);
 
+// The vectors above all device interrupts, in task priority classes 14 and 15, so that raising
+// the priority level to mask devices (see `crate::irql`) leaves the scheduler tick and the IPIs
+// deliverable. Device interrupts are allocated below `SYSTEM_VECTORS_START`.
+pub const SYSTEM_VECTORS_START: u8 = 0xE0;
+/// The PIT, when routed through the I/O APIC. With the legacy PIC, it is on vector 32 instead.
+pub const PIT_VECTOR: u8 = 0xE0;
+pub const LAPIC_TIMER_VECTOR: u8 = 0xE2;
+pub const LAPIC_ERROR_VECTOR: u8 = 0xFE;
+
 pub type IdtEntries = [IdtEntry; 256];
 pub type IdtReservations = [AtomicU32; 8];
 
@@ -232,8 +241,13 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
         current_idt[i + 32].set_func(mem::transmute(__generic_interrupts_start as usize + i * 8));
     }
 
+    #[cfg(target_arch = "x86")]
+    use_default_irqs!(current_idt);
+
     // reserve bits 31:0, i.e. the first 32 interrupts, which are reserved for exceptions
     *current_reservations[0].get_mut() |= 0x0000_0000_FFFF_FFFF;
+    // reserve bits 255:224, the system vectors
+    *current_reservations[7].get_mut() |= 0xFFFF_FFFF;
 
     if cpu_id == LogicalCpuId::BSP {
         // Set up IRQs
@@ -253,31 +267,19 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
         current_idt[45].set_func(irq::fpu);
         current_idt[46].set_func(irq::ata1);
         current_idt[47].set_func(irq::ata2);
-        current_idt[48].set_func(irq::lapic_timer);
-        current_idt[49].set_func(irq::lapic_error);
+        current_idt[usize::from(PIT_VECTOR)].set_func(irq::pit_stack);
+        current_idt[usize::from(LAPIC_TIMER_VECTOR)].set_func(irq::lapic_timer);
 
-        // reserve bits 49:32, which are for the standard IRQs, and for the local apic timer and error.
-        *current_reservations[1].get_mut() |= 0x0003_FFFF;
-    } else {
-        // TODO: use_default_irqs! but also the legacy IRQs that are only needed on one CPU
-        current_idt[49].set_func(irq::lapic_error);
-
-        // reserve bit 49
-        *current_reservations[1].get_mut() |= 1 << 17;
+        // reserve bits 47:32, which are for the standard IRQs
+        *current_reservations[1].get_mut() |= 0x0000_FFFF;
     }
-
-    #[cfg(target_arch = "x86")]
-    use_default_irqs!(current_idt);
+    current_idt[usize::from(LAPIC_ERROR_VECTOR)].set_func(irq::lapic_error);
 
     // Set IPI handlers
     current_idt[IpiKind::Wakeup as usize].set_func(ipi::wakeup);
     current_idt[IpiKind::Switch as usize].set_func(ipi::switch);
     current_idt[IpiKind::Tlb as usize].set_func(ipi::tlb);
     current_idt[IpiKind::Pit as usize].set_func(ipi::pit);
-    idt.set_reserved_mut(IpiKind::Wakeup as u8, true);
-    idt.set_reserved_mut(IpiKind::Switch as u8, true);
-    idt.set_reserved_mut(IpiKind::Tlb as u8, true);
-    idt.set_reserved_mut(IpiKind::Pit as u8, true);
 
     #[cfg(target_arch = "x86")]
     {
@@ -1,4 +1,9 @@
-use crate::{context, device::local_apic::the_local_apic, percpu::PercpuBlock};
+use crate::{
+    context,
+    device::local_apic::the_local_apic,
+    irql::{self, Irql},
+    percpu::PercpuBlock,
+};
 
 interrupt!(wakeup, || {
     the_local_apic().eoi();
@@ -13,7 +18,10 @@ interrupt!(tlb, || {
 interrupt!(switch, || {
     the_local_apic().eoi();
 
-    let _ = context::switch();
+    // Contexts cannot be switched at a raised IRQL, in which case the next tick will.
+    if irql::current() == Irql::PASSIVE {
+        let _ = context::switch();
+    }
 });
 
 interrupt!(pit, || {
@@ -30,6 +30,16 @@ pub unsafe fn enable_and_nop() {
     core::arch::asm!("sti; nop", options(nomem, nostack));
 }
 
+/// Mask the interrupts whose vector is in priority class `class` or below, see [`crate::irql`].
+#[inline(always)]
+pub unsafe fn set_priority_mask(class: u8) {
+    // CR8 is an alias of the upper four bits of the local APIC task priority register.
+    #[cfg(target_arch = "x86_64")]
+    core::arch::asm!("mov cr8, {}", in(reg) u64::from(class), options(nostack));
+    #[cfg(target_arch = "x86")]
+    super::device::local_apic::the_local_apic().set_tpr(u32::from(class) << 4);
+}
+
 /// Halt instruction
 #[inline(always)]
 pub unsafe fn halt() {
@@ -1,13 +1,15 @@
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum IpiKind {
-    Wakeup = 0x40,
-    Tlb = 0x41,
-    Switch = 0x42,
-    Pit = 0x43,
+    // The scheduler tick, in the same priority class as the PIT, see `crate::idt`.
+    Pit = 0xE1,
+
+    Wakeup = 0xF0,
+    Tlb = 0xF1,
+    Switch = 0xF2,
 
     #[cfg(feature = "profiling")]
-    Profile = 0x44,
+    Profile = 0xF3,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -53,3 +55,17 @@ pub fn ipi_single(kind: IpiKind, target: LogicalCpuId) {
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
 pub fn ipi_single(_kind: IpiKind, _target: LogicalCpuId) {}
+
+/// Sends a non-maskable interrupt to `target`, which reaches it even with interrupts disabled.
+#[cfg(feature = "multi_core")]
+pub fn ipi_nmi(target: LogicalCpuId) {
+    use crate::device::local_apic::the_local_apic;
+
+    unsafe {
+        // TODO: Distinguish between logical and physical CPU IDs
+        the_local_apic().ipi_nmi(target.get());
+    }
+}
+
+#[cfg(not(feature = "multi_core"))]
+pub fn ipi_nmi(_target: LogicalCpuId) {}
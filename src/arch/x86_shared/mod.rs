@@ -4,6 +4,9 @@ pub mod cpuid;
 /// Debugging support
 pub mod debug;
 
+/// Decoding of faulting instructions
+pub mod decode;
+
 /// Devices
 pub mod device;
 
@@ -17,6 +20,9 @@ pub mod interrupt;
 /// Inter-processor interrupts
 pub mod ipi;
 
+/// Performance monitoring counters
+pub mod pmu;
+
 /// Page table isolation
 pub mod pti;
 
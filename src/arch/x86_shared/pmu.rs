@@ -0,0 +1,73 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::arch::cpuid::cpuid;
+
+const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+
+/// Fixed counter 0 counts retired instructions, and fixed counter 1 unhalted core cycles.
+const FIXED_CTR_INSTRUCTIONS: u32 = 0;
+const FIXED_CTR_CYCLES: u32 = 1;
+
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Enable the fixed-function performance counters on this CPU, if supported. Many hypervisors do
+/// not expose a PMU, in which case [`read`] will simply return `None`.
+pub unsafe fn init() {
+    let Some(info) = cpuid().get_performance_monitoring_info() else {
+        return;
+    };
+    if info.version_id() < 2 || info.fixed_function_counters() < 2 {
+        return;
+    }
+
+    // Count in both ring 0 and ring 3, for both counters (4 bits each).
+    wrmsr(IA32_FIXED_CTR_CTRL, rdmsr(IA32_FIXED_CTR_CTRL) | 0x33);
+    wrmsr(
+        IA32_PERF_GLOBAL_CTRL,
+        rdmsr(IA32_PERF_GLOBAL_CTRL)
+            | (1 << (32 + FIXED_CTR_INSTRUCTIONS))
+            | (1 << (32 + FIXED_CTR_CYCLES)),
+    );
+
+    let width = u32::from(info.fixed_function_counters_bit_width());
+    COUNTER_MASK.store(u64::MAX >> (64 - width.clamp(1, 64)), Ordering::Relaxed);
+    AVAILABLE.store(true, Ordering::Relaxed);
+}
+
+unsafe fn rdpmc(counter: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdpmc", in("ecx") counter, out("eax") low, out("edx") high, options(nomem, nostack));
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Read the current `(cycles, instructions)` counter values of this CPU, or `None` if
+/// performance counters are unavailable.
+pub fn read() -> Option<(u64, u64)> {
+    if !AVAILABLE.load(Ordering::Relaxed) {
+        return None;
+    }
+    // Bit 30 selects the fixed-function counters.
+    unsafe {
+        Some((
+            rdpmc((1 << 30) | FIXED_CTR_CYCLES),
+            rdpmc((1 << 30) | FIXED_CTR_INSTRUCTIONS),
+        ))
+    }
+}
+
+/// Mask covering the implemented counter width, for computing deltas across wraparound.
+pub fn counter_mask() -> u64 {
+    COUNTER_MASK.load(Ordering::Relaxed)
+}
+
+/// Read a cheap, monotonically increasing cycle timestamp of this CPU, for measuring short
+/// intervals. Unlike [`read`], this does not depend on a PMU being available.
+pub fn timestamp() -> Option<u64> {
+    Some(unsafe { x86::time::rdtsc() })
+}
@@ -43,6 +43,7 @@ impl<T, const ALIGN: usize> AlignedBox<T, ALIGN> {
             if ptr.is_null() {
                 return Err(Enomem);
             }
+            debug_assert_eq!(ptr as usize % ALIGN, 0, "allocator ignored alignment");
             Self {
                 inner: Unique::new_unchecked(ptr.cast()),
             }
@@ -63,6 +64,7 @@ impl<T, const ALIGN: usize> AlignedBox<[T], ALIGN> {
             if ptr.is_null() {
                 return Err(Enomem);
             }
+            debug_assert_eq!(ptr as usize % ALIGN, 0, "allocator ignored alignment");
             Self {
                 inner: Unique::new_unchecked(core::ptr::slice_from_raw_parts_mut(ptr.cast(), len)),
             }
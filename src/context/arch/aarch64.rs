@@ -1,23 +1,28 @@
 use crate::{
     arch::{device::cpu::registers::control_regs, interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    context::{context::Kstack, memory::Table, switch::NO_OWNER},
     percpu::PercpuBlock,
     syscall::FloatRegisters,
 };
-use core::{arch::asm, mem, mem::offset_of, ptr, sync::atomic::AtomicBool};
+use core::{arch::asm, mem, mem::offset_of, ptr, sync::atomic::AtomicU32};
 use rmm::TableKind;
 use spin::Once;
 use syscall::{EnvRegisters, Error, Result, ENOMEM};
 
 /// This must be used by the kernel to ensure that context switches are done atomically
-/// Compare and exchange this to true when beginning a context switch on any CPU
-/// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
+/// Compare and exchange this from `NO_OWNER` to the current CPU ID when beginning a context switch
+/// on any CPU
+/// The `Context::switch_to` function will set it back to `NO_OWNER`, allowing other CPU's to switch
 /// This must be done, as no locks can be held on the stack during switch
-pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+pub static CONTEXT_SWITCH_LOCK: AtomicU32 = AtomicU32::new(NO_OWNER);
 
 // 512 bytes for registers, extra bytes for fpcr and fpsr
 pub const KFX_ALIGN: usize = 16;
 
+/// Initialize a freshly allocated FX save area. The all-zero state is the architectural default
+/// here, so nothing needs to be done.
+pub fn init_kfx(_kfx: &mut [u8]) {}
+
 #[derive(Clone, Debug)]
 pub struct Context {
     elr_el1: usize,
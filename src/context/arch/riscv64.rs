@@ -3,20 +3,24 @@ use crate::{
         interrupt::InterruptStack,
         paging::{PageMapper, ENTRY_COUNT},
     },
-    context::{context::Kstack, memory::Table},
+    context::{context::Kstack, memory::Table, switch::NO_OWNER},
     memory::{KernelMapper, RmmA},
     percpu::PercpuBlock,
     syscall::FloatRegisters,
 };
-use core::{mem::offset_of, sync::atomic::AtomicBool};
+use core::{mem::offset_of, sync::atomic::AtomicU32};
 use rmm::{Arch, TableKind, VirtualAddress};
 use spin::Once;
 use syscall::{error::*, EnvRegisters};
 
-pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+pub static CONTEXT_SWITCH_LOCK: AtomicU32 = AtomicU32::new(NO_OWNER);
 
 pub const KFX_ALIGN: usize = 16;
 
+/// Initialize a freshly allocated FX save area. The all-zero state is the architectural default
+/// here, so nothing needs to be done.
+pub fn init_kfx(_kfx: &mut [u8]) {}
+
 #[derive(Clone, Debug, Default)]
 pub struct Context {
     sp: usize,
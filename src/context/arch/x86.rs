@@ -1,4 +1,4 @@
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
 
 use crate::{
     gdt::{pcr, GDT_USER_FS, GDT_USER_GS},
@@ -8,7 +8,7 @@ use crate::{
 
 use crate::{
     arch::{interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    context::{context::Kstack, memory::Table, switch::NO_OWNER},
     memory::RmmA,
 };
 use core::mem::offset_of;
@@ -17,15 +17,35 @@ use spin::Once;
 use syscall::{error::*, EnvRegisters};
 
 /// This must be used by the kernel to ensure that context switches are done atomically
-/// Compare and exchange this to true when beginning a context switch on any CPU
-/// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
+/// Compare and exchange this from `NO_OWNER` to the current CPU ID when beginning a context switch
+/// on any CPU
+/// The `Context::switch_to` function will set it back to `NO_OWNER`, allowing other CPU's to switch
 /// This must be done, as no locks can be held on the stack during switch
-pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+pub static CONTEXT_SWITCH_LOCK: AtomicU32 = AtomicU32::new(NO_OWNER);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 
 pub const KFX_ALIGN: usize = 16;
 
+/// Default x87 control word after FNINIT: all exceptions masked, 64-bit precision, round to
+/// nearest.
+const DEFAULT_FCW: u16 = 0x037F;
+/// Default MXCSR after reset: all SIMD exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// Initialize a freshly allocated (zeroed) FX save area with the architectural default FPU
+/// state, so that the first FXRSTOR/XRSTOR of a new context does not load an all-exceptions
+/// unmasked control word. The remaining legacy region, as well as the XSAVE header (XSTATE_BV =
+/// 0, i.e. all components in their init state), are correct when zeroed.
+pub fn init_kfx(kfx: &mut [u8]) {
+    if let Some(fcw) = kfx.get_mut(0..2) {
+        fcw.copy_from_slice(&DEFAULT_FCW.to_ne_bytes());
+    }
+    if let Some(mxcsr) = kfx.get_mut(24..28) {
+        mxcsr.copy_from_slice(&DEFAULT_MXCSR.to_ne_bytes());
+    }
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Context {
@@ -314,28 +334,34 @@ unsafe extern "cdecl" fn switch_to_inner() {
 pub fn setup_new_utable() -> Result<Table> {
     use crate::memory::KernelMapper;
 
-    let utable = unsafe {
-        PageMapper::create(TableKind::User, crate::memory::TheFrameAllocator)
-            .ok_or(Error::new(ENOMEM))?
+    // Constructed right away, so that the table is freed again if copying the kernel mappings
+    // fails below.
+    let table = Table {
+        utable: unsafe {
+            PageMapper::create(TableKind::User, crate::memory::TheFrameAllocator)
+                .ok_or(Error::new(ENOMEM))?
+        },
     };
 
     {
         let active_ktable = KernelMapper::lock();
 
-        let copy_mapping = |p4_no| unsafe {
-            let entry = active_ktable
-                .table()
-                .entry(p4_no)
-                .unwrap_or_else(|| panic!("expected kernel PML {} to be mapped", p4_no));
+        let copy_mapping = |p4_no| -> Result<()> {
+            let Some(entry) = active_ktable.table().entry(p4_no) else {
+                // The kernel mappings are set up during boot, so this should never be reached.
+                debug_assert!(false, "expected kernel PML {} to be mapped", p4_no);
+                return Err(Error::new(ENOMEM));
+            };
 
-            utable.table().set_entry(p4_no, entry)
+            unsafe { table.utable.table().set_entry(p4_no, entry) };
+            Ok(())
         };
 
         // Copy higher half (kernel) mappings
         for i in 512..1024 {
-            copy_mapping(i);
+            copy_mapping(i)?;
         }
     }
 
-    Ok(Table { utable })
+    Ok(table)
 }
@@ -1,13 +1,13 @@
 use core::{
     ptr::{addr_of, addr_of_mut},
-    sync::atomic::AtomicBool,
+    sync::atomic::AtomicU32,
 };
 
 use crate::syscall::FloatRegisters;
 
 use crate::{
     arch::{interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    context::{context::Kstack, memory::Table, switch::NO_OWNER},
     memory::RmmA,
 };
 use core::mem::offset_of;
@@ -17,19 +17,102 @@ use syscall::{error::*, EnvRegisters};
 use x86::msr;
 
 /// This must be used by the kernel to ensure that context switches are done atomically
-/// Compare and exchange this to true when beginning a context switch on any CPU
-/// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
+/// Compare and exchange this from `NO_OWNER` to the current CPU ID when beginning a context switch
+/// on any CPU
+/// The `Context::switch_to` function will set it back to `NO_OWNER`, allowing other CPU's to switch
 /// This must be done, as no locks can be held on the stack during switch
-pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+pub static CONTEXT_SWITCH_LOCK: AtomicU32 = AtomicU32::new(NO_OWNER);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 
+/// Size of the legacy FXSAVE region, which starts every save area.
+const LEGACY_FX_SIZE: usize = 512;
+/// Offset of XSTATE_BV in the XSAVE header following the legacy region.
+const XSTATE_BV_OFFSET: usize = LEGACY_FX_SIZE;
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_X87_SSE: u64 = XSTATE_X87 | XSTATE_SSE;
+const XSTATE_AVX: u64 = 1 << 2;
+/// Size of the upper halves of YMM0-15.
+const YMM_UPPER_SIZE: usize = 16 * 16;
+
+/// Size of the portable FPU state, see [`PortableFloatRegisters`].
+pub const PORTABLE_FX_SIZE: usize = LEGACY_FX_SIZE + YMM_UPPER_SIZE;
+
+const _: () = assert!(core::mem::size_of::<FloatRegisters>() <= LEGACY_FX_SIZE);
+
+/// Serialization of the FPU state into a fixed layout, independent of which XSAVE components the
+/// CPU has enabled: the 512-byte legacy FXSAVE region (x87, MXCSR and XMM0-15), followed by the
+/// upper 128 bits of YMM0-15. Reserved bytes, as well as components that are not enabled or are
+/// in their init state, serialize as zeros.
+pub trait PortableFloatRegisters {
+    /// Serializes the legacy region, leaving the upper halves of YMM0-15 zeroed.
+    fn to_portable_bytes(&self) -> [u8; PORTABLE_FX_SIZE];
+    /// Deserializes the legacy region, ignoring the upper halves of YMM0-15.
+    fn from_portable_bytes(bytes: &[u8; PORTABLE_FX_SIZE]) -> Self;
+}
+impl PortableFloatRegisters for FloatRegisters {
+    fn to_portable_bytes(&self) -> [u8; PORTABLE_FX_SIZE] {
+        let mut out = [0_u8; PORTABLE_FX_SIZE];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self as *const FloatRegisters).cast::<u8>(),
+                out.as_mut_ptr(),
+                core::mem::size_of::<FloatRegisters>(),
+            );
+        }
+        for range in legacy_reserved_ranges() {
+            out[range].fill(0);
+        }
+        out
+    }
+    fn from_portable_bytes(bytes: &[u8; PORTABLE_FX_SIZE]) -> Self {
+        unsafe { bytes.as_ptr().cast::<FloatRegisters>().read_unaligned() }
+    }
+}
+
+/// Byte ranges of the legacy region that are reserved, or available to software, and never part
+/// of the portable state: the byte after the abridged tag word, the upper 48 bits of each ST
+/// register, and the area after XMM15.
+fn legacy_reserved_ranges() -> impl Iterator<Item = core::ops::Range<usize>> {
+    core::iter::once(5..6)
+        .chain((0..8).map(|i| 32 + i * 16 + 10..32 + (i + 1) * 16))
+        .chain(core::iter::once(416..LEGACY_FX_SIZE))
+}
+/// Byte ranges of the legacy region belonging to the x87 component, other than the control word:
+/// FSW, FTW, FOP, FIP, FDP and ST0-7. Their init state is all zeros.
+fn legacy_x87_ranges() -> impl Iterator<Item = core::ops::Range<usize>> {
+    [2..24, 32..160].into_iter()
+}
+/// Byte range of XMM0-15 in the legacy region, the SSE component apart from MXCSR, which is saved
+/// and restored whenever SSE or AVX is enabled, whether in its init state or not.
+const LEGACY_XMM_RANGE: core::ops::Range<usize> = 160..416;
+
 #[cfg(cpu_feature_never = "xsave")]
 pub const KFX_ALIGN: usize = 16;
 
 #[cfg(not(cpu_feature_never = "xsave"))]
 pub const KFX_ALIGN: usize = 64;
 
+/// Default x87 control word after FNINIT: all exceptions masked, 64-bit precision, round to
+/// nearest.
+const DEFAULT_FCW: u16 = 0x037F;
+/// Default MXCSR after reset: all SIMD exceptions masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// Initialize a freshly allocated (zeroed) FX save area with the architectural default FPU
+/// state, so that the first FXRSTOR/XRSTOR of a new context does not load an all-exceptions
+/// unmasked control word. The remaining legacy region, as well as the XSAVE header (XSTATE_BV =
+/// 0, i.e. all components in their init state), are correct when zeroed.
+pub fn init_kfx(kfx: &mut [u8]) {
+    if let Some(fcw) = kfx.get_mut(0..2) {
+        fcw.copy_from_slice(&DEFAULT_FCW.to_ne_bytes());
+    }
+    if let Some(mxcsr) = kfx.get_mut(24..28) {
+        mxcsr.copy_from_slice(&DEFAULT_MXCSR.to_ne_bytes());
+    }
+}
+
 // TODO: stack guarding?
 
 #[derive(Clone, Debug)]
@@ -117,7 +200,8 @@ impl Context {
 }
 impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
-        let mut regs = unsafe { self.kfx.as_ptr().cast::<FloatRegisters>().read() };
+        let legacy = self.legacy_fx();
+        let mut regs = unsafe { legacy.as_ptr().cast::<FloatRegisters>().read_unaligned() };
         regs._reserved = 0;
         let mut new_st = regs.st_space;
         for st in &mut new_st {
@@ -146,6 +230,73 @@ impl super::Context {
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
+        self.mark_legacy_in_use();
+    }
+
+    fn xstate_bv(&self) -> Option<u64> {
+        let bytes = self.kfx.get(XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8)?;
+        Some(u64::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+    fn set_xstate_bv(&mut self, xstate_bv: u64) {
+        self.kfx[XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8].copy_from_slice(&xstate_bv.to_ne_bytes());
+    }
+
+    /// Returns the legacy region as XRSTOR would load it. The x87 and SSE components are not
+    /// written by XSAVE while in their init state, as indicated by their XSTATE_BV bits being
+    /// clear, so the save area may hold stale values for them.
+    fn legacy_fx(&self) -> [u8; LEGACY_FX_SIZE] {
+        let mut legacy = [0_u8; LEGACY_FX_SIZE];
+        legacy.copy_from_slice(&self.kfx[..LEGACY_FX_SIZE]);
+
+        let Some(xstate_bv) = self.xstate_bv() else {
+            // FXSAVE always writes the whole region.
+            return legacy;
+        };
+        if xstate_bv & XSTATE_X87 == 0 {
+            legacy[0..2].copy_from_slice(&DEFAULT_FCW.to_ne_bytes());
+            for range in legacy_x87_ranges() {
+                legacy[range].fill(0);
+            }
+        }
+        if xstate_bv & XSTATE_SSE == 0 {
+            legacy[LEGACY_XMM_RANGE].fill(0);
+        }
+        legacy
+    }
+    /// Marks the x87 and SSE components as in use, as XRSTOR would otherwise load their init
+    /// state, rather than what was written to the legacy region.
+    fn mark_legacy_in_use(&mut self) {
+        if let Some(xstate_bv) = self.xstate_bv() {
+            self.set_xstate_bv(xstate_bv | XSTATE_X87_SSE);
+        }
+    }
+
+    /// Serializes the FPU state, including the upper halves of YMM0-15 if AVX is in use, see
+    /// [`PortableFloatRegisters`].
+    pub fn get_portable_fx_regs(&self) -> [u8; PORTABLE_FX_SIZE] {
+        let mut out = self.get_fx_regs().to_portable_bytes();
+
+        if let Some(offset) = crate::arch::ymm_upper_offset()
+            && self.xstate_bv().map_or(false, |bv| bv & XSTATE_AVX != 0)
+        {
+            out[LEGACY_FX_SIZE..].copy_from_slice(&self.kfx[offset..offset + YMM_UPPER_SIZE]);
+        }
+        out
+    }
+
+    /// Restores FPU state serialized by [`Self::get_portable_fx_regs`]. Parts of the state the
+    /// CPU does not support are ignored.
+    pub fn set_portable_fx_regs(&mut self, portable: &[u8; PORTABLE_FX_SIZE]) {
+        self.set_fx_regs(FloatRegisters::from_portable_bytes(portable));
+
+        let Some(xstate_bv) = self.xstate_bv() else {
+            // FXSAVE only, there is no header.
+            return;
+        };
+        if let Some(offset) = crate::arch::ymm_upper_offset() {
+            self.kfx[offset..offset + YMM_UPPER_SIZE].copy_from_slice(&portable[LEGACY_FX_SIZE..]);
+            self.set_xstate_bv(xstate_bv | XSTATE_AVX);
+        }
     }
 
     pub fn set_userspace_io_allowed(&mut self, allowed: bool) {
@@ -392,34 +543,44 @@ unsafe extern "sysv64" fn switch_to_inner(_prev: &mut Context, _next: &mut Conte
 pub fn setup_new_utable() -> Result<Table> {
     use crate::memory::{KernelMapper, TheFrameAllocator};
 
-    let utable = unsafe {
-        PageMapper::create(TableKind::User, TheFrameAllocator).ok_or(Error::new(ENOMEM))?
+    // Constructed right away, so that the table is freed again if copying the kernel mappings
+    // fails below.
+    let table = Table {
+        utable: unsafe {
+            PageMapper::create(TableKind::User, TheFrameAllocator).ok_or(Error::new(ENOMEM))?
+        },
     };
 
     {
         let active_ktable = KernelMapper::lock();
 
-        let copy_mapping = |p4_no| unsafe {
-            let entry = active_ktable
+        let copy_mapping = |p4_no| -> Result<()> {
+            let Some(entry) = active_ktable
                 .table()
                 .entry(p4_no)
-                .unwrap_or_else(|| panic!("expected kernel PML {} to be mapped", p4_no));
-
-            utable.table().set_entry(p4_no, entry)
+                .filter(|entry| entry.present())
+            else {
+                // The kernel mappings are set up during boot, so this should never be reached.
+                debug_assert!(false, "expected kernel PML {} to be mapped", p4_no);
+                return Err(Error::new(ENOMEM));
+            };
+
+            unsafe { table.utable.table().set_entry(p4_no, entry) };
+            Ok(())
         };
         // TODO: Just copy all 256 mappings? Or copy KERNEL_PML4+KERNEL_PERCPU_PML4 (needed for
         // paranoid ISRs which can occur anywhere; we don't want interrupts to triple fault!) and
         // map lazily via page faults in the kernel.
 
         // Copy kernel image mapping
-        copy_mapping(crate::KERNEL_PML4);
+        copy_mapping(crate::KERNEL_PML4)?;
 
         // Copy kernel heap mapping
-        copy_mapping(crate::KERNEL_HEAP_PML4);
+        copy_mapping(crate::KERNEL_HEAP_PML4)?;
 
         // Copy physmap mapping
-        copy_mapping(crate::PHYS_PML4);
+        copy_mapping(crate::PHYS_PML4)?;
     }
 
-    Ok(Table { utable })
+    Ok(table)
 }
@@ -1,8 +1,9 @@
 use alloc::{borrow::Cow, collections::VecDeque, sync::Arc, vec::Vec};
 use core::{
-    cmp::Ordering,
+    cmp::{self, Ordering},
     mem::{self, size_of},
     num::NonZeroUsize,
+    sync::atomic::{self, AtomicU32},
 };
 use spin::RwLock;
 use syscall::{RtSigInfo, SigProcControl, Sigcontrol};
@@ -12,7 +13,7 @@ use crate::{
     common::aligned_box::AlignedBox,
     context::{self, arch, file::FileDescriptor},
     cpu_set::{LogicalCpuId, LogicalCpuSet},
-    ipi::{ipi, IpiKind, IpiTarget},
+    ipi::{ipi_single, IpiKind},
     memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame},
     paging::{RmmA, RmmArch},
     percpu::PercpuBlock,
@@ -22,7 +23,6 @@ use crate::{
 use crate::syscall::error::{Error, Result, EAGAIN, ESRCH};
 
 use super::{
-    empty_cr3,
     memory::{AddrSpaceWrapper, GrantFileRef},
     process::{Process, ProcessId},
 };
@@ -136,18 +136,32 @@ pub struct Context {
     pub status_reason: &'static str,
     /// Context running or not
     pub running: bool,
-    /// Current CPU ID
+    /// CPU whose run queue this context is in, which is also the CPU it is running on while
+    /// running, see [`run_queue`](super::run_queue). `None` for contexts not yet queued.
     pub cpu_id: Option<LogicalCpuId>,
+    /// The CPU this context was most recently switched to on, or `u32::MAX` if it has never
+    /// run. Unlike `cpu_id`, this can be read from other CPUs without holding the context lock;
+    /// use [`Context::last_cpu`] to read it.
+    last_cpu: AtomicU32,
     /// Time this context was switched to
     pub switch_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
+    /// Performance counter values `(cycles, instructions)` when this context was switched to, if
+    /// performance counters are available.
+    pub pmu_switch: Option<(u64, u64)>,
+    /// Cycles spent while scheduled, see [`Context::cycles`].
+    cycles: u64,
+    /// Instructions retired while scheduled, see [`Context::instructions`].
+    instructions: u64,
     /// Scheduler CPU affinity. If set, [`cpu_id`] can except [`None`] never be anything else than
     /// this value.
     pub sched_affinity: LogicalCpuSet,
     /// Keeps track of whether this context is currently handling a syscall. Only up-to-date when
     /// not running.
     pub inside_syscall: bool,
+    /// Nesting depth of page fault handling in this context. Only up-to-date when not running.
+    pub page_fault_depth: u32,
 
     #[cfg(feature = "syscall_debug")]
     pub syscall_debug_info: crate::syscall::debug::SyscallDebugInfo,
@@ -180,7 +194,21 @@ pub struct Context {
     /// interrupts or syscalls occur. This flag is set for all contexts but kmain.
     pub userspace: bool,
     pub being_sigkilled: bool,
+    /// Stopped by a debugger, see [`Context::freeze`]. Independent of `status`, which is left
+    /// untouched, so that the context resumes in the same state when thawed.
+    frozen: bool,
     pub fmap_ret: Option<Frame>,
+    /// Restartable sequence registered by userspace, if any.
+    pub rseq: Option<RseqRegion>,
+}
+
+/// A userspace critical section, which is restarted from `abort` if the context is preempted, or
+/// a signal is delivered, while executing inside `[start, end)`.
+#[derive(Clone, Copy, Debug)]
+pub struct RseqRegion {
+    pub start: usize,
+    pub end: usize,
+    pub abort: usize,
 }
 
 #[derive(Debug)]
@@ -202,6 +230,13 @@ pub struct SignalState {
 
 impl Context {
     pub fn new(pid: ProcessId, process: Arc<RwLock<Process>>) -> Result<Context> {
+        // The allocation is aligned to KFX_ALIGN, as required by fxsave/xsave in switch_to, which
+        // otherwise fault.
+        let mut kfx =
+            AlignedBox::<[u8], { arch::KFX_ALIGN }>::try_zeroed_slice(crate::arch::kfx_size())?;
+        debug_assert_eq!(kfx.as_ptr() as usize % arch::KFX_ALIGN, 0, "misaligned kfx");
+        arch::init_kfx(&mut kfx);
+
         let this = Context {
             pid,
             process,
@@ -212,15 +247,20 @@ impl Context {
             status_reason: "",
             running: false,
             cpu_id: None,
+            last_cpu: AtomicU32::new(u32::MAX),
             switch_time: 0,
             cpu_time: 0,
+            pmu_switch: None,
+            cycles: 0,
+            instructions: 0,
             sched_affinity: LogicalCpuSet::all(),
             inside_syscall: false,
+            page_fault_depth: 0,
             syscall_head: Some(RaiiFrame::allocate()?),
             syscall_tail: Some(RaiiFrame::allocate()?),
             wake: None,
             arch: arch::Context::new(),
-            kfx: AlignedBox::<[u8], { arch::KFX_ALIGN }>::try_zeroed_slice(crate::arch::kfx_size())?,
+            kfx,
             kstack: None,
             addr_space: None,
             name: Cow::Borrowed(""),
@@ -228,6 +268,8 @@ impl Context {
             userspace: false,
             fmap_ret: None,
             being_sigkilled: false,
+            frozen: false,
+            rseq: None,
 
             #[cfg(feature = "syscall_debug")]
             syscall_debug_info: crate::syscall::debug::SyscallDebugInfo::default(),
@@ -256,14 +298,72 @@ impl Context {
         }
     }
 
+    /// Number of CPU cycles spent while scheduled, up to the last time this context was switched
+    /// away from. Zero where performance counters are unavailable.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    /// Number of instructions retired while scheduled, up to the last time this context was
+    /// switched away from. Zero where performance counters are unavailable.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    /// Add the performance counter deltas since this context was switched to.
+    pub fn account_pmu(&mut self, now: Option<(u64, u64)>) {
+        if let (Some((cycles, instructions)), Some((start_cycles, start_instructions))) =
+            (now, self.pmu_switch.take())
+        {
+            let mask = crate::arch::pmu::counter_mask();
+            self.cycles += cycles.wrapping_sub(start_cycles) & mask;
+            self.instructions += instructions.wrapping_sub(start_instructions) & mask;
+        }
+    }
+
+    /// Keep the context from being scheduled until [`thaw`](Self::thaw) is called, whatever its
+    /// status, unless it is being killed by SIGKILL, which overrides this. If it is running on
+    /// another CPU, that CPU is asked to switch away, which saves its full state. If it is the
+    /// current context, it stops before returning to userspace. Returns whether the context has
+    /// already stopped, see [`is_stopped`](Self::is_stopped).
+    pub fn freeze(&mut self) -> bool {
+        self.frozen = true;
+
+        if let Some(cpu_id) = self.cpu_id.filter(|_| self.running)
+            && cpu_id != crate::cpu_id()
+        {
+            ipi_single(IpiKind::Switch, cpu_id);
+        }
+        self.is_stopped()
+    }
+    /// Allow a frozen context to be scheduled again, and return true if it was frozen.
+    pub fn thaw(&mut self) -> bool {
+        if !core::mem::replace(&mut self.frozen, false) {
+            return false;
+        }
+        if self.status.is_runnable()
+            && let Some(cpu_id) = self.cpu_id
+            && cpu_id != crate::cpu_id()
+        {
+            ipi_single(IpiKind::Wakeup, cpu_id);
+        }
+        true
+    }
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+    /// Whether the context is frozen and no longer running, so that its saved state, such as
+    /// that returned by the register accessors, is coherent and remains so until it is thawed.
+    pub fn is_stopped(&self) -> bool {
+        self.frozen && !self.running
+    }
+
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.unblock_no_ipi() {
             // TODO: Only send IPI if currently running?
             if let Some(cpu_id) = self.cpu_id {
                 if cpu_id != crate::cpu_id() {
-                    // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
+                    // Send IPI to the CPU whose run queue it is in, if not the current CPU
+                    ipi_single(IpiKind::Wakeup, cpu_id);
                 }
             }
 
@@ -354,6 +454,17 @@ impl Context {
         }
     }
 
+    /// Returns the CPU this context was last switched to on, if it has ever run.
+    pub fn last_cpu(&self) -> Option<LogicalCpuId> {
+        match self.last_cpu.load(atomic::Ordering::Acquire) {
+            u32::MAX => None,
+            id => Some(LogicalCpuId::new(id)),
+        }
+    }
+    pub fn set_last_cpu(&self, cpu_id: LogicalCpuId) {
+        self.last_cpu.store(cpu_id.get(), atomic::Ordering::Release);
+    }
+
     pub fn is_current_context(&self) -> bool {
         self.running && self.cpu_id == Some(crate::cpu_id())
     }
@@ -361,6 +472,8 @@ impl Context {
     pub fn addr_space(&self) -> Result<&Arc<AddrSpaceWrapper>> {
         self.addr_space.as_ref().ok_or(Error::new(ESRCH))
     }
+    /// Replace the address space of this context, returning the previous one. If this is the
+    /// current context, the new address space is switched to immediately, as in exec.
     pub fn set_addr_space(
         &mut self,
         addr_space: Option<Arc<AddrSpaceWrapper>>,
@@ -372,7 +485,6 @@ impl Context {
         };
 
         if self.is_current_context() {
-            // TODO: Share more code with context::arch::switch_to.
             let this_percpu = PercpuBlock::current();
 
             if let Some(ref prev_addrsp) = self.addr_space {
@@ -380,29 +492,11 @@ impl Context {
                     &this_percpu.current_addrsp.borrow().as_ref().unwrap(),
                     prev_addrsp
                 ));
-                prev_addrsp
-                    .acquire_read()
-                    .used_by
-                    .atomic_clear(this_percpu.cpu_id);
             }
 
-            let _old_addrsp = core::mem::replace(
-                &mut *this_percpu.current_addrsp.borrow_mut(),
-                addr_space.clone(),
-            );
-
-            if let Some(ref new) = addr_space {
-                let new_addrsp = new.acquire_read();
-                new_addrsp.used_by.atomic_set(this_percpu.cpu_id);
-
-                unsafe {
-                    new_addrsp.table.utable.make_current();
-                }
-            } else {
-                unsafe {
-                    crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
-                }
-            }
+            // The context is done with its previous address space, so there is no point in keeping
+            // it loaded lazily.
+            let _old_addrsp = unsafe { this_percpu.switch_addrsp(addr_space.clone(), false) };
         } else {
             assert!(!self.running);
         }
@@ -432,6 +526,19 @@ impl Context {
         };
         Some(unsafe { &mut *kstack.initial_top().sub(size_of::<InterruptStack>()).cast() })
     }
+    /// Redirects the userspace instruction pointer to the abort handler, if it was interrupted
+    /// inside the registered restartable sequence.
+    pub fn rseq_abort(&mut self) {
+        let Some(rseq) = self.rseq else {
+            return;
+        };
+        let Some(regs) = self.regs_mut() else {
+            return;
+        };
+        if (rseq.start..rseq.end).contains(&regs.instr_pointer()) {
+            regs.set_instr_pointer(rseq.abort);
+        }
+    }
     pub fn sigcontrol(&mut self) -> Option<(&Sigcontrol, &SigProcControl, &mut SignalState)> {
         Some(Self::sigcontrol_raw(self.sig.as_mut()?))
     }
@@ -545,27 +652,61 @@ impl Drop for BorrowedHtBuf {
     }
 }
 
+/// Value written at the lowest address of every kernel stack, see [`Kstack::canary_address`].
+#[cfg(feature = "kstack_canary")]
+pub const KSTACK_CANARY: usize = 0x57AC_CA9A_C0DE_5AFE_u64 as usize;
+
+/// Kernel stack of a context, allocated eagerly and accessed through the physmap.
+///
+/// The stack cannot be faulted in lazily. A page fault from kernel mode is delivered on the
+/// current stack, so touching a missing stack page would fault again while pushing the interrupt
+/// frame, escalating to a double fault. Moving the page fault handler to an IST stack does not
+/// help either, as it may block (e.g. waiting for a scheme fmap), while IST stacks are per-CPU
+/// and would be reused by the next fault on the same CPU.
 pub struct Kstack {
-    /// naturally aligned, order 4
+    /// naturally aligned, order `order`
     base: Frame,
+    order: u32,
 }
 impl Kstack {
+    /// Order of the default kernel stack size, 64 KiB.
+    const DEFAULT_ORDER: u32 = 4;
+
     pub fn new() -> Result<Self, Enomem> {
-        Ok(Self {
-            base: allocate_p2frame(4).ok_or(Enomem)?,
-        })
+        Self::with_order(Self::DEFAULT_ORDER)
+    }
+    /// Allocates a kernel stack of at least `size` bytes, and never less than the default size.
+    pub fn with_size(size: usize) -> Result<Self, Enomem> {
+        let pages = size.div_ceil(PAGE_SIZE).next_power_of_two();
+        Self::with_order(cmp::max(pages.trailing_zeros(), Self::DEFAULT_ORDER))
+    }
+    fn with_order(order: u32) -> Result<Self, Enomem> {
+        let kstack = Self {
+            base: allocate_p2frame(order).ok_or(Enomem)?,
+            order,
+        };
+        #[cfg(feature = "kstack_canary")]
+        unsafe {
+            (kstack.canary_address() as *mut usize).write(KSTACK_CANARY);
+        }
+        Ok(kstack)
+    }
+    /// Address of the canary, the first word that an overflowing stack overwrites.
+    #[cfg(feature = "kstack_canary")]
+    pub fn canary_address(&self) -> usize {
+        RmmA::phys_to_virt(self.base.base()).data()
     }
     pub fn initial_top(&self) -> *mut u8 {
-        unsafe { (RmmA::phys_to_virt(self.base.base()).data() as *mut u8).add(PAGE_SIZE << 4) }
+        unsafe { (RmmA::phys_to_virt(self.base.base()).data() as *mut u8).add(self.len()) }
     }
     pub fn len(&self) -> usize {
-        PAGE_SIZE << 4
+        PAGE_SIZE << self.order
     }
 }
 
 impl Drop for Kstack {
     fn drop(&mut self) {
-        unsafe { deallocate_p2frame(self.base, 4) }
+        unsafe { deallocate_p2frame(self.base, self.order) }
     }
 }
 impl core::fmt::Debug for Kstack {
@@ -1,31 +1,68 @@
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    collections::BTreeMap,
+    string::ToString,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use arrayvec::ArrayVec;
 use core::{
     cmp,
     fmt::Debug,
     num::NonZeroUsize,
-    sync::atomic::{AtomicU32, Ordering},
+    ops::{Deref, Range},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
-use rmm::{Arch as _, PageFlush};
+use rmm::{Arch as _, PageEntry, PageFlush, PageTable};
 use spin::{RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
 use syscall::{error::*, flag::MapFlags, GrantFlags, MunmapFlags};
 
 use crate::{
     arch::paging::PAGE_SIZE,
     context::arch::setup_new_utable,
-    cpu_set::LogicalCpuSet,
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
     memory::{
         deallocate_frame, deallocate_p2frame, get_page_info, init_frame, the_zeroed_frame,
-        AddRefError, Enomem, Frame, PageInfo, RaiiFrame, RefCount, RefKind,
+        numa::{self, NodeHint, NumaPolicy},
+        AccessDirtyBits, AddRefError, ArchIntCtx, Enomem, Frame, FrameFlags, PageInfo, RaiiFrame,
+        RefCount, RefKind,
     },
-    paging::{Page, PageFlags, PageMapper, RmmA, TableKind, VirtualAddress},
+    paging::{Page, PageFlags, PageMapper, PhysicalAddress, RmmA, TableKind, VirtualAddress},
     percpu::PercpuBlock,
-    scheme::{self, KernelSchemes},
+    scheme::{self, FunmapRange, KernelSchemes},
+    syscall::usercopy::UserSliceRo,
 };
 
-use super::{context::HardBlockedReason, file::FileDescription};
+use super::{
+    context::HardBlockedReason,
+    file::FileDescription,
+    swap::{self, SwappedPage},
+    userfault::{UserFaultHandler, UserFaultRegistration},
+    write_barrier::{WriteBarrier, WriteBarrierRegistration},
+};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
+/// Default maximum number of grants per address space, cf. Linux's `vm.max_map_count`.
+pub const MAX_MAP_COUNT_DEFAULT: usize = 65_536;
+/// Maximum number of pages requested by a single [`AddrSpaceWrapper::fadvise_willneed`] call,
+/// so that prefetching cannot flood the scheme or evict what is being prefetched for.
+pub const FADVISE_WILLNEED_MAX_PAGES: usize = 64;
+/// Number of times to poll for all CPUs to acknowledge a TLB shootdown, before warning that an IPI
+/// may have been lost or a CPU hung. Polls are counted rather than time measured, as the clock may
+/// not advance while the CPU driving it is waiting as well, and as a CPU may legitimately run with
+/// interrupts disabled for a while.
+const SHOOTDOWN_WARN_SPINS: usize = 1 << 26;
+/// Number of polls after which a CPU that still has not acknowledged a TLB shootdown is assumed to
+/// be hung, and the kernel panics rather than risk freeing frames that are still accessible.
+const SHOOTDOWN_PANIC_SPINS: usize = 1 << 30;
+/// Length of an access generation, in nanoseconds.
+const ACCESS_GENERATION_NANOS: u128 = 1_000_000_000;
+
+/// Returns the current access generation, which advances every [`ACCESS_GENERATION_NANOS`].
+/// Grants record the generation of their last page fault, as a cheap grant-granular
+/// approximation of LRU, without scanning accessed bits.
+pub fn access_generation() -> u64 {
+    (crate::time::monotonic() / ACCESS_GENERATION_NANOS) as u64
+}
 
 pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
     PageFlags::new()
@@ -34,6 +71,40 @@ pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
         .write(flags.contains(MapFlags::PROT_WRITE))
     //TODO: PROT_READ
 }
+/// Adjustment applied to the protection flags of every mmap and mprotect in an address space, see
+/// [`AddrSpaceWrapper::set_map_flags_mask`]. For instance, clearing PROT_EXEC guarantees that no
+/// new executable mappings appear, regardless of what the code running in the address space
+/// requests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapFlagsMask {
+    /// Protection flags removed from every request.
+    pub clear: MapFlags,
+    /// Protection flags added to every request.
+    pub force: MapFlags,
+}
+impl MapFlagsMask {
+    /// Leaves all requests unchanged.
+    pub const NONE: Self = Self {
+        clear: MapFlags::empty(),
+        force: MapFlags::empty(),
+    };
+    /// The flags a mask can clear or force.
+    const ADJUSTABLE: MapFlags = MapFlags::PROT_READ
+        .union(MapFlags::PROT_WRITE)
+        .union(MapFlags::PROT_EXEC);
+
+    pub fn apply(self, flags: MapFlags) -> MapFlags {
+        (flags & !self.clear) | self.force
+    }
+    /// Whether `self` never allows any flag that `other` does not, i.e. it clears at least the
+    /// flags `other` does, and forces at most those. Forcing more flags, such as PROT_EXEC, would
+    /// instead make more mappings executable.
+    fn is_at_least_as_strict_as(self, other: Self) -> bool {
+        self.clear.contains(other.clear) && other.force.contains(self.force)
+    }
+}
+/// Protection flags of a mapping. Whether it is MAP_SHARED or MAP_PRIVATE is not part of the page
+/// flags, but follows from the provider of its grant, see [`AddrSpace::flags_at`].
 pub fn map_flags(page_flags: PageFlags<RmmA>) -> MapFlags {
     let mut flags = MapFlags::PROT_READ;
     if page_flags.has_write() {
@@ -59,39 +130,135 @@ impl UnmapResult {
         else {
             return Ok(());
         };
-
-        let (scheme_id, number) = match description.write() {
-            ref desc => (desc.scheme, desc.number),
+        let range = FunmapRange {
+            offset: base_offset,
+            size: self.size,
+            flags: self.flags,
         };
+        funmap_and_close(description, &[range])
+    }
+}
+/// Tells the scheme that `ranges` of a file are no longer mapped, and closes the file if these
+/// were its last references.
+fn funmap_and_close(
+    description: Arc<RwLock<FileDescription>>,
+    ranges: &[FunmapRange],
+) -> Result<()> {
+    let (scheme_id, number) = match description.write() {
+        ref desc => (desc.scheme, desc.number),
+    };
 
-        let funmap_result = scheme::schemes()
-            .get(scheme_id)
-            .cloned()
-            .ok_or(Error::new(ENODEV))
-            .and_then(|scheme| scheme.kfunmap(number, base_offset, self.size, self.flags));
-
-        if let Ok(fd) = Arc::try_unwrap(description) {
-            fd.into_inner().try_close()?;
-        }
-        funmap_result?;
+    let funmap_result = scheme::schemes()
+        .get(scheme_id)
+        .cloned()
+        .ok_or(Error::new(ENODEV))
+        .and_then(|scheme| scheme.kfunmap_batch(number, ranges));
 
-        Ok(())
+    if let Ok(fd) = Arc::try_unwrap(description) {
+        fd.into_inner().try_close()?;
     }
+    funmap_result?;
+
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct AddrSpaceWrapper {
     inner: RwLock<AddrSpace>,
-    pub tlb_ack: AtomicU32,
+    /// CPUs that have been asked to flush their TLB for this address space, and have not done so
+    /// yet. Each CPU clears its own bit once it has flushed, see [`Flusher::flush`].
+    pub tlb_pending: LogicalCpuSet,
+    /// Set when the owning process starts exiting, after which no new grants can be created.
+    dying: AtomicBool,
+    /// CPUs that [`release_lazy_holders`](Self::release_lazy_holders) is waiting for, to stop
+    /// holding this address space lazily.
+    lazy_release: LogicalCpuSet,
 }
 impl AddrSpaceWrapper {
     pub fn new() -> Result<Arc<Self>> {
         Arc::try_new(Self {
             inner: RwLock::new(AddrSpace::new()?),
-            tlb_ack: AtomicU32::new(0),
+            tlb_pending: LogicalCpuSet::empty(),
+            dying: AtomicBool::new(false),
+            lazy_release: LogicalCpuSet::empty(),
         })
         .map_err(|_| Error::new(ENOMEM))
     }
+    /// Mark this address space as being torn down, so that subsequent mmap, mprotect and borrow
+    /// operations fail with ESRCH, rather than racing with the teardown.
+    pub fn mark_dying(self: &Arc<Self>) {
+        {
+            // Taking the lock ensures any operation that has already checked the flag completes
+            // first, as the flag is only checked with the lock held.
+            let _guard = self.acquire_write();
+            self.dying.store(true, Ordering::SeqCst);
+        }
+        self.release_lazy_holders();
+    }
+    /// Make all other CPUs stop holding this address space lazily (see
+    /// [`PercpuBlock::switch_addrsp`]), and wait until they have. Must be called before dropping
+    /// what may be the last context reference, so that the address space is freed by the caller,
+    /// rather than by a CPU going idle, where unmapping files cannot block.
+    ///
+    /// CPUs can only start holding it lazily again after running a context that uses it.
+    pub fn release_lazy_holders(self: &Arc<Self>) {
+        {
+            let mut guard = self.acquire_write();
+
+            let current_cpu_id = crate::cpu_id();
+            for cpu_id in guard.used_by.iter_mut() {
+                if cpu_id != current_cpu_id {
+                    self.lazy_release.atomic_set(cpu_id);
+                }
+            }
+            let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+            flusher.state.dirty = true;
+            flusher.flush();
+        }
+
+        // CPUs holding it lazily drop their reference when handling the shootdown, see
+        // `LazyAddrSpace::release`, while the others respond either then or in `remove_cpu`. The
+        // caller still holds a reference meanwhile, so none of theirs can be the last one.
+        while !self.lazy_release.is_empty() {
+            PercpuBlock::current().maybe_handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
+    }
+    /// Whether [`release_lazy_holders`](Self::release_lazy_holders) is waiting for `cpu_id`.
+    pub fn lazy_release_requested(&self, cpu_id: LogicalCpuId) -> bool {
+        self.lazy_release.atomic_contains(cpu_id)
+    }
+    /// Respond to [`release_lazy_holders`](Self::release_lazy_holders) from a CPU that does not
+    /// hold this address space lazily.
+    pub fn ack_lazy_release(&self, cpu_id: LogicalCpuId) {
+        self.lazy_release.atomic_clear(cpu_id);
+    }
+    pub fn is_dying(&self) -> bool {
+        self.dying.load(Ordering::Relaxed)
+    }
+    /// Remove `cpu_id` from the CPUs using this address space, once its table is no longer
+    /// loaded there. Shootdowns started in the meantime may still be waiting for this CPU, and
+    /// are acknowledged while waiting for the lock, since they cannot concern its TLB anymore.
+    pub fn remove_cpu(&self, cpu_id: LogicalCpuId) {
+        let my_percpu = PercpuBlock::current();
+
+        loop {
+            self.tlb_pending.atomic_clear(cpu_id);
+
+            match self.inner.try_read() {
+                Some(guard) => {
+                    guard.used_by.atomic_clear(cpu_id);
+                    drop(guard);
+                    self.lazy_release.atomic_clear(cpu_id);
+                    return;
+                }
+                None => {
+                    my_percpu.maybe_handle_tlb_shootdown();
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
     pub fn acquire_read(&self) -> RwLockReadGuard<'_, AddrSpace> {
         let my_percpu = PercpuBlock::current();
 
@@ -143,50 +310,80 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// Handler that faults on not-yet-present pages in the registered spans are forwarded to.
+    pub userfault: Option<UserFaultRegistration>,
+    /// Strict W^X policy: mappings can never be writable and executable at once, and grants that
+    /// have ever been writable can never become executable, or vice versa. Violating mmap and
+    /// mprotect calls fail with EACCES.
+    pub strict_wx: bool,
+    /// Which NUMA node faulted in pages are allocated from.
+    pub numa_policy: NumaPolicy,
+    /// Adjustment applied to the protection flags of every mmap and mprotect call.
+    pub map_flags_mask: MapFlagsMask,
+    /// Write barrier that the first writes to pages in the armed spans are passed to, see
+    /// [`super::write_barrier`].
+    pub write_barrier: Option<WriteBarrierRegistration>,
 }
 impl AddrSpaceWrapper {
-    /// Attempt to clone an existing address space so that all mappings are copied (CoW).
+    /// Attempt to clone an existing address space, where each grant is inherited according to its
+    /// [`CloneStrategy`] (CoW by default for private memory).
     pub fn try_clone(&self) -> Result<Arc<AddrSpaceWrapper>> {
         let mut guard = self.acquire_write();
         let guard = &mut *guard;
 
+        // Pages being swapped out have no swap slot yet that the clone could refer to.
+        guard.cancel_swap_outs(PageSpan::new(
+            Page::containing_address(VirtualAddress::new(0)),
+            crate::USER_END_OFFSET / PAGE_SIZE,
+        ))?;
+
         let mut new_arc = AddrSpaceWrapper::new()?;
 
         let new =
             Arc::get_mut(&mut new_arc).expect("expected new address space Arc not to be aliased");
 
         let this_mapper = &mut guard.table.utable;
-        let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
 
         for (grant_base, grant_info) in guard.grants.iter() {
-            let new_grant = match grant_info.provider {
-                // No, your temporary UserScheme mappings will not be kept across forks.
+            // No, your temporary UserScheme mappings will not be kept across forks, regardless of
+            // the clone strategy.
+            if matches!(
+                grant_info.provider,
                 Provider::External {
                     is_pinned_userscheme_borrow: true,
                     ..
-                }
-                | Provider::AllocatedShared {
+                } | Provider::AllocatedShared {
                     is_pinned_userscheme_borrow: true,
                     ..
-                } => continue,
+                }
+            ) {
+                continue;
+            }
 
-                // No, physically contiguous driver memory won't either.
-                Provider::Allocated {
-                    phys_contiguous: true,
-                    ..
-                } => continue,
+            let new_grant = match (grant_info.clone_strategy, &grant_info.provider) {
+                (CloneStrategy::DontInherit, _) => continue,
 
-                Provider::PhysBorrowed { base } => Grant::physmap(
+                (
+                    CloneStrategy::Share,
+                    Provider::PhysBorrowed {
+                        base,
+                        private: false,
+                    },
+                ) => Grant::physmap(
                     base.clone(),
                     PageSpan::new(grant_base, grant_info.page_count),
                     grant_info.flags,
                     &mut new.inner.get_mut().table.utable,
                     &mut NopFlusher,
                 )?,
-                Provider::Allocated {
-                    ref cow_file_ref,
-                    phys_contiguous: false,
-                } => Grant::copy_mappings(
+                (
+                    CloneStrategy::Cow,
+                    Provider::Allocated {
+                        cow_file_ref,
+                        phys_contiguous: false,
+                    },
+                ) => Grant::copy_mappings(
                     grant_base,
                     grant_base,
                     grant_info.page_count,
@@ -199,10 +396,36 @@ impl AddrSpaceWrapper {
                         cow_file_ref: cow_file_ref.clone(),
                     },
                 )?,
+                (
+                    CloneStrategy::Cow | CloneStrategy::EagerCopy,
+                    Provider::PhysBorrowed { base, .. },
+                ) => Grant::copy_from_phys(
+                    *base,
+                    PageSpan::new(grant_base, grant_info.page_count),
+                    grant_info.flags,
+                    grant_info.clone_strategy,
+                    this_mapper,
+                    &mut new.inner.get_mut().table.utable,
+                    &mut NopFlusher,
+                )?,
+                (
+                    CloneStrategy::EagerCopy,
+                    Provider::Allocated {
+                        cow_file_ref,
+                        phys_contiguous: false,
+                    },
+                ) => Grant::copy_eagerly(
+                    grant_base,
+                    grant_base,
+                    grant_info.page_count,
+                    grant_info.flags,
+                    this_mapper,
+                    &mut new.inner.get_mut().table.utable,
+                    &mut NopFlusher,
+                    cow_file_ref.clone(),
+                )?,
                 // TODO: Merge Allocated and AllocatedShared, and make CopyMappingsMode a field?
-                Provider::AllocatedShared {
-                    is_pinned_userscheme_borrow: false,
-                } => Grant::copy_mappings(
+                (CloneStrategy::Share, Provider::AllocatedShared { .. }) => Grant::copy_mappings(
                     grant_base,
                     grant_base,
                     grant_info.page_count,
@@ -214,35 +437,84 @@ impl AddrSpaceWrapper {
                     CopyMappingsMode::Borrowed,
                 )?,
 
+                // Accesses from the clone are forwarded to the same handler.
+                (CloneStrategy::Share, Provider::Emulated { handler }) => Grant::emulated(
+                    PageSpan::new(grant_base, grant_info.page_count),
+                    grant_info.flags,
+                    Arc::clone(handler),
+                ),
+
                 // MAP_SHARED grants are retained by reference, across address space clones (the
                 // "fork" analogue from monolithic kernels).
-                Provider::External {
-                    ref address_space,
-                    src_base,
-                    ..
-                } => Grant::borrow_grant(
-                    Arc::clone(&address_space),
-                    src_base,
+                (
+                    CloneStrategy::Share,
+                    Provider::External {
+                        address_space,
+                        src_base,
+                        ..
+                    },
+                ) => Grant::borrow_grant(
+                    Arc::clone(address_space),
+                    *src_base,
                     grant_base,
                     grant_info,
                     &mut new.inner.get_mut().table.utable,
                     &mut NopFlusher,
                     false,
                 )?,
-                Provider::FmapBorrowed { .. } => continue,
+
+                // Not reachable as long as strategies are only changed after checking
+                // can_have_clone_strategy, but never inheriting is always safe.
+                _ => continue,
             };
 
+            // Swapped out pages are not in the page tables, so the clone refers to the same swap
+            // slots instead, and both read them back into their own frames.
+            if matches!(
+                grant_info.clone_strategy,
+                CloneStrategy::Cow | CloneStrategy::EagerCopy
+            ) {
+                guard.grants.page_states.share(
+                    PageSpan::new(grant_base, grant_info.page_count),
+                    &mut new.inner.get_mut().grants.page_states,
+                    grant_base,
+                );
+            }
             new.inner.get_mut().grants.insert(new_grant);
         }
+        for span in guard.grants.reserved_ranges() {
+            new.inner.get_mut().grants.reserve_range(span)?;
+        }
+        new.inner.get_mut().grants.guard_gaps = guard.grants.guard_gaps;
+        new.inner.get_mut().strict_wx = guard.strict_wx;
+        new.inner.get_mut().numa_policy = guard.numa_policy;
+        new.inner.get_mut().map_flags_mask = guard.map_flags_mask;
         Ok(new_arc)
     }
     pub fn mprotect(&self, requested_span: PageSpan, flags: MapFlags) -> Result<()> {
         let mut guard = self.acquire_write();
         let guard = &mut *guard;
 
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let flags = guard.map_flags_mask.apply(flags);
+
+        // Threads sharing this address space serialize on the write lock, and the lock is only
+        // released once every CPU using the address space has acknowledged the shootdown (see
+        // the end of this function). Hence a thread can never observe the new flags of a racing
+        // mprotect while a stale, more permissive TLB entry still exists on another CPU.
+        //
+        // Upgrades are shot down just like downgrades. A stale, less permissive entry surviving
+        // until then only results in a fault, which blocks on the lock and is then resolved
+        // according to the new flags by correct_inner, rather than failing.
         let mapper = &mut guard.table.utable;
-        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
 
+        // Validate all grants before modifying any of them, so that the operation either
+        // succeeds or leaves the address space untouched.
+        //
         // TODO: Remove allocation (might require BTreeMap::set_key or interior mutability).
         let regions = guard
             .grants
@@ -250,117 +522,1159 @@ impl AddrSpaceWrapper {
             .map(|(base, info)| {
                 if info.is_pinned() {
                     Err(Error::new(EBUSY))
+                } else if !info.can_have_flags(flags)
+                    || (guard.strict_wx && info.violates_wx(flags))
+                {
+                    Err(Error::new(EACCES))
                 } else {
                     Ok(PageSpan::new(base, info.page_count))
                 }
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>>>()?;
+        guard
+            .grants
+            .check_map_count(UserGrants::split_count(&regions, requested_span))?;
 
-        for grant_span_res in regions {
-            let grant_span = grant_span_res?;
+        let write_barrier = &guard.write_barrier;
+        let unpin = false;
+        guard
+            .grants
+            .for_each_conflict_split(requested_span, unpin, |grants, mut grant| {
+                let new_flags = grant
+                    .info
+                    .flags()
+                    // TODO: Require a capability in order to map executable memory?
+                    .execute(flags.contains(MapFlags::PROT_EXEC))
+                    .write(flags.contains(MapFlags::PROT_WRITE));
+
+                // TODO: Allow enabling/disabling read access on architectures which allow it. On
+                // x86_64 with protection keys (although only enforced by userspace), and AArch64 (I
+                // think), execute-only memory is also supported.
+
+                grant.remap(mapper, &mut flusher, new_flags);
+                // Upgrades must not make pages writable behind the back of the write barrier.
+                if let Some(registration) = write_barrier
+                    && new_flags.has_write()
+                    && grant.info.is_owned()
+                {
+                    write_protect_armed(registration, mapper, &mut flusher, grant.span());
+                }
+                //log::info!("Mprotect grant became {:#?}", grant);
+                grants.insert(grant);
+            });
+        // Complete the shootdown before the write lock is released.
+        flusher.flush();
+        Ok(())
+    }
+    /// Changes how the grants in `requested_span` are inherited by future clones of this address
+    /// space, splitting grants at the span boundaries as necessary. Either all grants are changed,
+    /// or none of them are.
+    pub fn set_clone_strategy(
+        &self,
+        requested_span: PageSpan,
+        strategy: CloneStrategy,
+    ) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
 
-            let grant = guard
-                .grants
-                .remove(grant_span.base)
-                .expect("grant cannot magically disappear while we hold the lock!");
-            //log::info!("Mprotecting {:#?} to {:#?} in {:#?}", grant, flags, grant_span);
-            let intersection = grant_span.intersection(requested_span);
+        let regions = guard
+            .grants
+            .conflicts(requested_span)
+            .map(|(base, info)| {
+                if info.is_pinned() {
+                    Err(Error::new(EBUSY))
+                } else if !info.can_have_clone_strategy(strategy) {
+                    Err(Error::new(EINVAL))
+                } else {
+                    Ok(PageSpan::new(base, info.page_count))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        guard
+            .grants
+            .check_map_count(UserGrants::split_count(&regions, requested_span))?;
 
-            let (before, mut grant, after) = grant
-                .extract(intersection)
-                .expect("failed to extract grant");
-            //log::info!("Sliced into\n\n{:#?}\n\n{:#?}\n\n{:#?}", before, grant, after);
+        let unpin = false;
+        guard
+            .grants
+            .for_each_conflict_split(requested_span, unpin, |grants, mut grant| {
+                grant.info.clone_strategy = strategy;
+                grants.insert(grant);
+            });
+        Ok(())
+    }
+    /// Sets which NUMA node pages faulted in from now on are allocated from. Pages already
+    /// present are not migrated.
+    pub fn set_numa_policy(&self, policy: NumaPolicy) -> Result<()> {
+        if let NumaPolicy::Bind(node) = policy
+            && node.get() >= numa::node_count()
+        {
+            return Err(Error::new(EINVAL));
+        }
+        self.acquire_write().numa_policy = policy;
+        Ok(())
+    }
+    /// Sets the adjustment applied to the protection flags of every future mmap and mprotect
+    /// call. Existing mappings are unaffected. Only protection flags can be adjusted, and unless
+    /// `privileged`, the new mask can only clear more flags and force fewer than the current one,
+    /// so that a sandbox cannot loosen it.
+    pub fn set_map_flags_mask(&self, mask: MapFlagsMask, privileged: bool) -> Result<()> {
+        if !MapFlagsMask::ADJUSTABLE.contains(mask.clear | mask.force) {
+            return Err(Error::new(EINVAL));
+        }
+        let mut guard = self.acquire_write();
+        if !privileged && !mask.is_at_least_as_strict_as(guard.map_flags_mask) {
+            return Err(Error::new(EPERM));
+        }
+        guard.map_flags_mask = mask;
+        Ok(())
+    }
+    /// Points the physmap grant exactly covering `span` at the physical memory starting at
+    /// `new_base`, e.g. after a device BAR has been relocated. Pages already mapped are remapped
+    /// to the corresponding new frames, keeping their flags. Copies of the grant in other address
+    /// spaces still refer to the old frames.
+    pub fn remap_physmap(&self, span: PageSpan, new_base: Frame) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
 
-            if let Some(before) = before {
-                guard.grants.insert(before);
-            }
-            if let Some(after) = after {
-                guard.grants.insert(after);
-            }
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
 
-            if !grant.info.can_have_flags(flags) {
-                guard.grants.insert(grant);
-                return Err(Error::new(EACCES));
-            }
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+
+        let Some((grant_span, info)) = guard.grants.containing_span_mut(span.base) else {
+            return Err(Error::new(EINVAL));
+        };
+        if grant_span.base != span.base || grant_span.count != span.count {
+            return Err(Error::new(EINVAL));
+        }
+        // Private copies do not refer to the device anymore, once written.
+        let Provider::PhysBorrowed {
+            ref mut base,
+            private: false,
+        } = info.provider
+        else {
+            return Err(Error::new(EINVAL));
+        };
+        new_base
+            .checked_next_by(span.count)
+            .ok_or(Error::new(EINVAL))?;
 
-            let new_flags = grant
-                .info
-                .flags()
-                // TODO: Require a capability in order to map executable memory?
-                .execute(flags.contains(MapFlags::PROT_EXEC))
-                .write(flags.contains(MapFlags::PROT_WRITE));
+        for i in 0..span.count {
+            if let Some(page_info) = get_page_info(new_base.next_by(i)) {
+                log::warn!(
+                    "Driver tried to remap physmap to the allocator-frame {new_base:?} (info {page_info:?})!"
+                );
+                return Err(Error::new(EPERM));
+            }
+        }
 
-            // TODO: Allow enabling/disabling read access on architectures which allow it. On
-            // x86_64 with protection keys (although only enforced by userspace), and AArch64 (I
-            // think), execute-only memory is also supported.
+        for (i, page) in span.pages().enumerate() {
+            unsafe {
+                // Pages not yet mapped will be faulted in from the new base.
+                let Some((old_phys, flags, flush)) = mapper.unmap_phys(page.start_address(), false)
+                else {
+                    continue;
+                };
+                flush.ignore();
+                flusher.queue(Frame::containing(old_phys), None, TlbShootdownActions::MOVE);
 
-            grant.remap(mapper, &mut flusher, new_flags);
-            //log::info!("Mprotect grant became {:#?}", grant);
-            guard.grants.insert(grant);
+                // The page table containing the entry is still present, so this cannot fail.
+                mapper
+                    .map_phys(page.start_address(), new_base.next_by(i).base(), flags)
+                    .expect("failed to remap physmap page")
+                    .ignore();
+            }
         }
+        *base = new_base;
+
         Ok(())
     }
-    #[must_use = "needs to notify files"]
-    pub fn munmap(&self, requested_span: PageSpan, unpin: bool) -> Result<Vec<UnmapResult>> {
+    /// Marks the resident, exclusively owned pages of the anonymous private grants in
+    /// `requested_span` as freeable (MADV_FREE). Unlike munmap, the contents are retained until
+    /// [`reclaim_freeable`](Self::reclaim_freeable) actually discards them, and writing to a page
+    /// before then cancels the free.
+    pub fn madvise_free(&self, requested_span: PageSpan) -> Result<()> {
         let mut guard = self.acquire_write();
         let guard = &mut *guard;
 
-        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
-        AddrSpace::munmap_inner(
-            &mut guard.grants,
-            &mut guard.table.utable,
-            &mut flusher,
-            requested_span,
-            unpin,
-        )
-    }
-    pub fn r#move(
-        &self,
-        mut src_opt: Option<(&AddrSpaceWrapper, &mut AddrSpace)>,
-        src_span: PageSpan,
-        requested_dst_base: Option<Page>,
-        new_page_count: usize,
-        new_flags: MapFlags,
-        notify_files: &mut Vec<UnmapResult>,
-    ) -> Result<Page> {
-        let dst_lock = self;
-        let mut dst = dst_lock.acquire_write();
-        let dst = &mut *dst;
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
 
-        let mut src_owned_opt = src_opt.as_mut().map(|(aw, a)| {
-            (
-                &mut a.grants,
-                &mut a.table.utable,
-                Flusher::with_cpu_set(&mut a.used_by, &aw.tlb_ack),
-            )
-        });
-        let mut src_opt = src_owned_opt
-            .as_mut()
-            .map(|(g, m, f)| (&mut *g, &mut *m, &mut *f));
-        let mut dst_flusher = Flusher::with_cpu_set(&mut dst.used_by, &dst_lock.tlb_ack);
+        // Validate the whole range first, so that either all or none of the pages are marked.
+        for (_, info) in guard.grants.conflicts(requested_span) {
+            if info.is_pinned() {
+                return Err(Error::new(EBUSY));
+            }
+            if !matches!(
+                info.provider,
+                Provider::Allocated {
+                    cow_file_ref: None,
+                    phys_contiguous: false,
+                }
+            ) {
+                return Err(Error::new(EINVAL));
+            }
+        }
 
-        let dst_base = match requested_dst_base {
-            Some(base) if new_flags.contains(MapFlags::MAP_FIXED_NOREPLACE) => {
-                if dst
-                    .grants
-                    .conflicts(PageSpan::new(base, new_page_count))
-                    .next()
-                    .is_some()
-                {
-                    return Err(Error::new(EEXIST));
+        for (base, info) in guard.grants.conflicts(requested_span) {
+            let span = PageSpan::new(base, info.page_count).intersection(requested_span);
+
+            for page in span.pages() {
+                let Some((phys, _)) = mapper.translate(page.start_address()) else {
+                    // Lazy pages have no contents to retain in the first place.
+                    continue;
+                };
+                let frame = Frame::containing(phys);
+                let Some(page_info) = get_page_info(frame) else {
+                    continue;
+                };
+                // CoW-shared frames are already readonly, and cannot be discarded by this address
+                // space alone anyway.
+                if page_info.refcount() != Some(RefCount::One) {
+                    continue;
                 }
+                page_info.insert_flags(FrameFlags::FREEABLE);
 
-                base
+                // Revoke write access so that the next write faults, and cancels the free.
+                let Some((old_flags, _, flush)) = (unsafe {
+                    mapper.remap_with(page.start_address(), |flags| flags.write(false))
+                }) else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                // Also start tracking writes through mappings that later become writable again
+                // without faulting, e.g. by mprotect.
+                let was_dirty = unsafe { mapper.clear_dirty(page.start_address()) }
+                    .map(|flush| unsafe { flush.ignore() })
+                    .is_some();
+                if old_flags.has_write() || was_dirty {
+                    flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+                }
             }
-            Some(base) if new_flags.contains(MapFlags::MAP_FIXED) => {
-                let unpin = false;
-                notify_files.append(&mut AddrSpace::munmap_inner(
-                    &mut dst.grants,
-                    &mut dst.table.utable,
-                    &mut dst_flusher,
-                    PageSpan::new(base, new_page_count),
-                    unpin,
-                )?);
+        }
+        Ok(())
+    }
+    /// Discards the contents of all pages previously marked by
+    /// [`madvise_free`](Self::madvise_free) that have not been written to since, returning the
+    /// number of frames released. Subsequent accesses to those pages will fault in zeroed memory.
+    pub fn reclaim_freeable(&self) -> usize {
+        self.reclaim_freeable_up_to(usize::MAX)
+    }
+    /// Like [`reclaim_freeable`](Self::reclaim_freeable), but stops after releasing `target`
+    /// frames, visiting grants that have least recently faulted first.
+    pub fn reclaim_freeable_up_to(&self, target: usize) -> usize {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+        let mut reclaimed = 0;
+
+        let mut candidates = guard
+            .grants
+            .iter()
+            .filter(|(_, info)| {
+                matches!(
+                    info.provider,
+                    Provider::Allocated {
+                        cow_file_ref: None,
+                        phys_contiguous: false,
+                    }
+                )
+            })
+            .map(|(base, info)| (info.access_generation, PageSpan::new(base, info.page_count)))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(generation, _)| *generation);
+
+        for (_, span) in candidates {
+            if reclaimed >= target {
+                break;
+            }
+            for page in span.pages() {
+                if reclaimed >= target {
+                    break;
+                }
+                let Some((phys, _)) = mapper.translate(page.start_address()) else {
+                    continue;
+                };
+                let frame = Frame::containing(phys);
+                let Some(page_info) = get_page_info(frame) else {
+                    continue;
+                };
+                if page_info.refcount() != Some(RefCount::One)
+                    || !page_info.flags().contains(FrameFlags::FREEABLE)
+                {
+                    continue;
+                }
+                if mapper.is_dirty(page.start_address()) {
+                    // Written to without faulting since it was marked, which cancels the free.
+                    page_info.remove_flags(FrameFlags::FREEABLE);
+                    continue;
+                }
+                let Some((_, _, flush)) = (unsafe { mapper.unmap_phys(page.start_address(), true) })
+                else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                flusher.queue(frame, None, TlbShootdownActions::FREE);
+                reclaimed += 1;
+            }
+        }
+        reclaimed
+    }
+    /// Returns the resident pages of the anonymous grants in `requested_span` that have been
+    /// written to since the previous call, or since they were mapped, and clears their dirty bits.
+    /// Checkpointing can thus collect only the pages changed since the last snapshot.
+    ///
+    /// The dirty bit belongs to a mapping rather than to a frame, so pages of shared grants are
+    /// only returned if they were written to through this address space. The shootdown completes
+    /// before this returns, so any write that is not recorded for the next call happened before
+    /// this call returned. Where the hardware has no dirty bit, writes are tracked by revoking
+    /// write access instead, see [`AccessDirtyBits`].
+    pub fn collect_dirty(&self, requested_span: PageSpan) -> Result<Vec<(Page, Frame)>> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+        let mut dirty = Vec::new();
+
+        for (base, info) in guard.grants.conflicts(requested_span) {
+            if !matches!(
+                info.provider,
+                Provider::Allocated { .. } | Provider::AllocatedShared { .. }
+            ) {
+                continue;
+            }
+            let span = PageSpan::new(base, info.page_count).intersection(requested_span);
+            for page in span.pages() {
+                let Some((phys, _)) = mapper.translate(page.start_address()) else {
+                    continue;
+                };
+                if !mapper.is_dirty(page.start_address()) {
+                    continue;
+                }
+                if let Some(flush) = unsafe { mapper.clear_dirty(page.start_address()) } {
+                    unsafe {
+                        flush.ignore();
+                    }
+                }
+                let frame = Frame::containing(phys);
+
+                // Reclaim relies on the dirty bit to tell whether a page marked with MADV_FREE has
+                // been written to since, which cancels the free.
+                if let Some(page_info) = get_page_info(frame) {
+                    page_info.remove_flags(FrameFlags::FREEABLE);
+                }
+                flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+                dirty.push((page, frame));
+            }
+        }
+        Ok(dirty)
+    }
+    /// Writes the resident pages of the anonymous private grants in `requested_span` to the swap
+    /// backend (see [`swap`]), and frees their frames, returning the number of frames freed. The
+    /// contents are read back in when the pages are next accessed. Pages shared with other grants
+    /// or address spaces, including CoW pages, are skipped, as are pinned grants.
+    ///
+    /// The pages are written without the address space locked, and accessing one in the meantime
+    /// maps it back, so that it is not swapped out after all.
+    ///
+    /// Fails with ENODEV if no swap backend is set. If writing a page fails, it and the pages not
+    /// yet written are mapped back, and the error is returned.
+    pub fn swap_out(&self, requested_span: PageSpan) -> Result<usize> {
+        let backend = swap::backend().ok_or(Error::new(ENODEV))?;
+
+        let mut guard = self.acquire_write();
+        let guard_ref = &mut *guard;
+
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mapper = &mut guard_ref.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard_ref.used_by, &self.tlb_pending);
+
+        // Unmap all pages first, as they can only be written out once no CPU can modify them
+        // anymore.
+        let mut unmapped = Vec::new();
+        for (base, info) in guard_ref.grants.conflicts(requested_span) {
+            if info.is_pinned()
+                || !matches!(
+                    info.provider,
+                    Provider::Allocated {
+                        cow_file_ref: None,
+                        phys_contiguous: false,
+                    }
+                )
+            {
+                continue;
+            }
+            let span = PageSpan::new(base, info.page_count).intersection(requested_span);
+            for page in span.pages() {
+                let Some((phys, _)) = mapper.translate(page.start_address()) else {
+                    continue;
+                };
+                let frame = Frame::containing(phys);
+                if get_page_info(frame).and_then(|pi| pi.refcount()) != Some(RefCount::One) {
+                    continue;
+                }
+                let Some((_, _, flush)) =
+                    (unsafe { mapper.unmap_phys(page.start_address(), false) })
+                else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                flusher.queue(frame, None, TlbShootdownActions::MOVE);
+                unmapped.push((
+                    page,
+                    Arc::new(SwappingOut {
+                        frame,
+                        mapped_back: AtomicBool::new(false),
+                    }),
+                ));
+            }
+        }
+        flusher.flush();
+        drop(flusher);
+
+        // Like for fmap and swapping in, the backend is not waited for with the address space
+        // locked. The pages are marked, so that accessing them in the meantime maps them back.
+        for (page, swapping_out) in &unmapped {
+            guard_ref
+                .grants
+                .page_states
+                .insert(*page, PageState::SwappingOut(Arc::clone(swapping_out)));
+        }
+        drop(guard);
+
+        let mut result = Ok(());
+        let mut written = Vec::new();
+        for (page, swapping_out) in unmapped {
+            // Pages that are entirely zero are faulted back in zeroed anyway.
+            let swapped = if result.is_err() || frame_is_zeroed(swapping_out.frame) {
+                None
+            } else {
+                match SwappedPage::write(Arc::clone(&backend), swapping_out.frame) {
+                    Ok(swapped) => Some(swapped),
+                    Err(error) => {
+                        result = Err(error);
+                        None
+                    }
+                }
+            };
+            written.push((page, swapping_out, swapped, result.is_ok()));
+        }
+
+        let mut guard = self.acquire_write();
+        let mut freed = 0;
+
+        for (page, swapping_out, swapped, keep) in written {
+            // Pages accessed, unmapped or moved in the meantime no longer refer to the same
+            // state. Dropping it then releases the slot, and the frame unless it was mapped back.
+            if !matches!(
+                guard.grants.page_states.get(page),
+                Some(PageState::SwappingOut(current)) if Arc::ptr_eq(current, &swapping_out)
+            ) {
+                continue;
+            }
+            if !keep {
+                // Otherwise the page remains marked, and is mapped back when next accessed.
+                let _ = cancel_swap_out(&mut guard, page);
+                continue;
+            }
+            match swapped {
+                Some(swapped) => guard
+                    .grants
+                    .page_states
+                    .insert(page, PageState::Swapped(Arc::new(swapped))),
+                None => guard.grants.page_states.remove(page),
+            };
+            freed += 1;
+        }
+
+        result.map(|()| freed)
+    }
+    /// Asks the schemes backing the fmap grants in `requested_span` to provide the pages that are
+    /// not yet present, without waiting for them, so that later accesses do not fault. Each page
+    /// is mapped when its scheme provides it, see [`fill_fmap_prefetch`](Self::fill_fmap_prefetch).
+    /// At most [`FADVISE_WILLNEED_MAX_PAGES`] pages are requested, and the number of pages
+    /// requested is returned. Each page is mapped with the same frame handling as when faulted
+    /// in, see [`take_fmap_frame`].
+    pub fn fadvise_willneed(self: &Arc<Self>, requested_span: PageSpan) -> Result<usize> {
+        let mut requests = Vec::new();
+        {
+            let guard = self.acquire_read();
+            if self.is_dying() {
+                return Err(Error::new(ESRCH));
+            }
+
+            'grants: for (base, info) in guard.grants.conflicts(requested_span) {
+                let Provider::FmapBorrowed { ref file_ref, .. } = info.provider else {
+                    continue;
+                };
+                let span = PageSpan::new(base, info.page_count).intersection(requested_span);
+                for page in span.pages() {
+                    if requests.len() >= FADVISE_WILLNEED_MAX_PAGES {
+                        break 'grants;
+                    }
+                    if guard.table.utable.translate(page.start_address()).is_some() {
+                        continue;
+                    }
+                    requests.push((
+                        page,
+                        Arc::clone(&file_ref.description),
+                        file_ref.base_offset + page.offset_from(base) * PAGE_SIZE,
+                        map_flags(info.flags()),
+                    ));
+                }
+            }
+        }
+
+        // Resolve every scheme before sending anything, so that failing to do so for any page
+        // leaves no request behind.
+        let requests = requests
+            .into_iter()
+            .map(|(page, description, offset, flags)| {
+                let (scheme_id, scheme_number) = match description.read() {
+                    ref desc => (desc.scheme, desc.number),
+                };
+                let user_inner = scheme::schemes()
+                    .get(scheme_id)
+                    .and_then(|s| {
+                        if let KernelSchemes::User(user) = s {
+                            user.inner.upgrade()
+                        } else {
+                            None
+                        }
+                    })
+                    .ok_or(Error::new(EBADF))?;
+                Ok((user_inner, scheme_number, page, description, offset, flags))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Requests that have been sent cannot be taken back. Should a scheme stop accepting them,
+        // only report the pages that are actually going to be provided.
+        let mut requested = 0;
+        for (user_inner, scheme_number, page, description, offset, flags) in requests {
+            let result = user_inner.request_fmap_prefetch(
+                scheme_number,
+                offset,
+                flags,
+                Arc::downgrade(self),
+                page,
+                Arc::downgrade(&description),
+            );
+            match result {
+                Ok(()) => requested += 1,
+                Err(err) if requested == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(requested)
+    }
+    /// Maps `frame` at `page`, once a scheme has provided it in response to
+    /// [`fadvise_willneed`](Self::fadvise_willneed). Does nothing if the page has become present in
+    /// the meantime, and fails if the page no longer belongs to the same fmap grant at the same
+    /// file offset.
+    ///
+    /// The reference taken by [`take_fmap_frame`] is consumed, either by the mapping, or by
+    /// releasing it if the frame ends up not being mapped.
+    pub fn fill_fmap_prefetch(
+        &self,
+        page: Page,
+        description: &Weak<RwLock<FileDescription>>,
+        offset: usize,
+        frame: Frame,
+    ) -> Result<()> {
+        let result = self.fill_fmap_prefetch_inner(page, description, offset, frame);
+        if !matches!(result, Ok(true)) {
+            release_fmap_frame(frame);
+        }
+        result.map(|_| ())
+    }
+    fn fill_fmap_prefetch_inner(
+        &self,
+        page: Page,
+        description: &Weak<RwLock<FileDescription>>,
+        offset: usize,
+        frame: Frame,
+    ) -> Result<bool> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let (grant_span, info) = guard
+            .grants
+            .containing_span(page)
+            .ok_or(Error::new(EFAULT))?;
+        let Provider::FmapBorrowed { ref file_ref, .. } = info.provider else {
+            return Err(Error::new(EINVAL));
+        };
+        if Arc::as_ptr(&file_ref.description) != description.as_ptr()
+            || file_ref.base_offset + page.offset_from(grant_span.base) * PAGE_SIZE != offset
+        {
+            return Err(Error::new(EINVAL));
+        }
+        if guard.table.utable.translate(page.start_address()).is_some() {
+            return Ok(false);
+        }
+
+        let flush = unsafe {
+            guard
+                .table
+                .utable
+                .map_phys(page.start_address(), frame.base(), info.flags())
+        }
+        .ok_or(Error::new(ENOMEM))?;
+        // The page was not present before, so there is nothing to flush.
+        unsafe { flush.ignore() };
+
+        Ok(true)
+    }
+    /// Unmaps the resident pages of the fmap grants in `requested_span` that have not been
+    /// written to, returning how many were dropped. The contents remain with the scheme, which is
+    /// asked for them again on the next access.
+    pub fn fadvise_dontneed(&self, requested_span: PageSpan) -> Result<usize> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+        let mut dropped = 0;
+
+        for (base, info) in guard.grants.conflicts(requested_span) {
+            if !matches!(info.provider, Provider::FmapBorrowed { .. }) || info.is_pinned() {
+                continue;
+            }
+            let span = PageSpan::new(base, info.page_count).intersection(requested_span);
+            for page in span.pages() {
+                if mapper.is_dirty(page.start_address()) {
+                    continue;
+                }
+                let Some((phys, _, flush)) =
+                    (unsafe { mapper.unmap_phys(page.start_address(), false) })
+                else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+    /// Exports the pages in `requested_span` as a [`GrantHandle`], which can then be mapped into
+    /// other address spaces using [`import_grant`](Self::import_grant). The span must be entirely
+    /// covered by anonymous shared (MAP_SHARED) grants with the same protection. Pages that have
+    /// not yet been written to are populated, so that every mapping of the handle sees the same
+    /// frames.
+    pub fn export_grant(&self, requested_span: PageSpan) -> Result<GrantHandle> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mut grant_flags = None;
+        let mut covered = 0;
+        for (base, info) in guard.grants.conflicts(requested_span) {
+            if !matches!(info.provider, Provider::AllocatedShared { .. }) {
+                return Err(Error::new(EINVAL));
+            }
+            if info.is_pinned() {
+                return Err(Error::new(EBUSY));
+            }
+            if map_flags(*grant_flags.get_or_insert(info.flags())) != map_flags(info.flags()) {
+                return Err(Error::new(EINVAL));
+            }
+            covered += PageSpan::new(base, info.page_count)
+                .intersection(requested_span)
+                .count;
+        }
+        let flags = grant_flags.ok_or(Error::new(EINVAL))?;
+        if covered != requested_span.count {
+            return Err(Error::new(EFAULT));
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
+
+        // Dropping the handle early releases the references taken so far, if a page fails.
+        let mut handle = GrantHandle {
+            frames: Vec::with_capacity(requested_span.count),
+            flags,
+        };
+
+        for page in requested_span.pages() {
+            let frame = match mapper.translate(page.start_address()) {
+                Some((phys, _)) => {
+                    let frame = Frame::containing(phys);
+                    let info = get_page_info(frame).expect("allocated frames need a PageInfo");
+
+                    match info.add_ref(RefKind::Shared) {
+                        Ok(()) => frame,
+                        // Still CoW-mapped, e.g. the zeroed frame, so needs its own frame first.
+                        Err(AddRefError::CowToShared) => {
+                            let CowResult {
+                                new_frame,
+                                old_frame,
+                            } = cow(frame, info, RefKind::Shared)
+                                .map_err(|_| Error::new(ENOMEM))?;
+
+                            unsafe {
+                                mapper
+                                    .remap_with_full(page.start_address(), |_, _| {
+                                        (new_frame.base(), flags)
+                                    })
+                                    .expect("page did exist")
+                                    .2
+                                    .ignore();
+                            }
+                            // Only upgrades permissions if the frame was reused, otherwise the
+                            // stale entries are flushed before the old frame is released.
+                            if let Some(old_frame) = old_frame {
+                                flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                            }
+                            new_frame
+                        }
+                        Err(AddRefError::SharedToCow) => unreachable!(),
+                        Err(AddRefError::RcOverflow) => return Err(Error::new(ENOMEM)),
+                    }
+                }
+                None => {
+                    let raii_frame = RaiiFrame::allocate()?;
+                    let frame = raii_frame.get();
+
+                    unsafe {
+                        // The page was not present before, so there is nothing to flush.
+                        mapper
+                            .map_phys(page.start_address(), frame.base(), flags)
+                            .ok_or(Error::new(ENOMEM))?
+                            .ignore();
+                    }
+                    // The reference is now owned by the page tables.
+                    core::mem::forget(raii_frame);
+
+                    get_page_info(frame)
+                        .expect("allocated frames need a PageInfo")
+                        .add_ref(RefKind::Shared)
+                        .expect("must be possible if previously One");
+                    frame
+                }
+            };
+            handle.frames.push(frame);
+        }
+
+        Ok(handle)
+    }
+    /// Maps the frames of `handle` into this address space, at `requested_base` if provided,
+    /// following the same placement rules as mmap. The requested protection must not exceed that
+    /// of the exported grant. The mapping is shared, and is independent of the handle once made.
+    pub fn import_grant(
+        &self,
+        handle: &GrantHandle,
+        requested_base: Option<Page>,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+    ) -> Result<Page> {
+        let page_count = NonZeroUsize::new(handle.page_count()).ok_or(Error::new(EINVAL))?;
+        if (flags.contains(MapFlags::PROT_WRITE) && !handle.flags.has_write())
+            || (flags.contains(MapFlags::PROT_EXEC) && !handle.flags.has_execute())
+        {
+            return Err(Error::new(EACCES));
+        }
+
+        let mut guard = self.acquire_write();
+        guard.mmap(
+            self,
+            requested_base,
+            page_count,
+            flags,
+            notify_files_out,
+            |base, page_flags, mapper, flusher| {
+                let span = PageSpan::new(base, page_count.get());
+
+                for (i, (page, &frame)) in span.pages().zip(&handle.frames).enumerate() {
+                    let info = get_page_info(frame).expect("exported frames need a PageInfo");
+                    let mapped = info.add_ref(RefKind::Shared).is_ok()
+                        && match unsafe {
+                            mapper.map_phys(page.start_address(), frame.base(), page_flags)
+                        } {
+                            Some(flush) => {
+                                unsafe {
+                                    flush.ignore();
+                                }
+                                flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+                                true
+                            }
+                            None => {
+                                // The handle still holds its own reference.
+                                let _ = info.remove_ref();
+                                false
+                            }
+                        };
+                    if mapped {
+                        continue;
+                    }
+
+                    for page in span.pages().take(i) {
+                        if let Some((phys, _, flush)) =
+                            unsafe { mapper.unmap_phys(page.start_address(), false) }
+                        {
+                            unsafe {
+                                flush.ignore();
+                            }
+                            flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                        }
+                    }
+                    return Err(Error::new(ENOMEM));
+                }
+
+                Ok(Grant {
+                    base,
+                    info: GrantInfo {
+                        page_count: span.count,
+                        flags: page_flags,
+                        ever_flags: page_flags,
+                        mapped: true,
+                        access_generation: access_generation(),
+                        clone_strategy: CloneStrategy::Share,
+                        provider: Provider::AllocatedShared {
+                            is_pinned_userscheme_borrow: false,
+                        },
+                    },
+                })
+            },
+        )
+    }
+    /// Maps the pages of the anonymous shared (MAP_SHARED) grants in `existing_span` a second
+    /// time at `new_base`, with the same protection, so that writes through either mapping are
+    /// visible through the other, e.g. for ring buffers that wrap around. The alias is a separate
+    /// grant, and as the frames are reference counted, unmapping either mapping leaves the other
+    /// intact. Pages not yet faulted in are populated first, so that both refer to the same
+    /// frames.
+    ///
+    /// Anonymous private grants are converted to shared ones first, for the part of them within
+    /// `existing_span`, since otherwise either mapping would stop seeing writes through the other
+    /// once the pages are CoW-shared. Consequently, clones of this address space share those
+    /// pages with it afterwards, like MAP_SHARED memory, rather than receiving a private copy,
+    /// unless the grant was excluded from clones altogether.
+    /// Fails with EEXIST if the destination is not free.
+    pub fn map_alias(&self, existing_span: PageSpan, new_base: Page) -> Result<()> {
+        self.share_anonymous(existing_span)?;
+        let handle = self.export_grant(existing_span)?;
+
+        let mut notify_files = Vec::new();
+        self.import_grant(
+            &handle,
+            Some(new_base),
+            handle.flags() | MapFlags::MAP_FIXED_NOREPLACE,
+            &mut notify_files,
+        )?;
+        // Nothing is unmapped when mapping with MAP_FIXED_NOREPLACE.
+        debug_assert!(notify_files.is_empty());
+
+        Ok(())
+    }
+    /// Converts the anonymous private grants in `requested_span` to shared ones, splitting them at
+    /// its boundaries, see [`map_alias`](Self::map_alias). The frames become shared once exported.
+    fn share_anonymous(&self, requested_span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mut regions = Vec::new();
+        for (base, info) in guard.grants.conflicts(requested_span) {
+            match info.provider {
+                Provider::Allocated {
+                    cow_file_ref: None,
+                    phys_contiguous: false,
+                } if info.is_pinned() => return Err(Error::new(EBUSY)),
+                Provider::Allocated {
+                    cow_file_ref: None,
+                    phys_contiguous: false,
+                } => regions.push(PageSpan::new(base, info.page_count)),
+                Provider::AllocatedShared { .. } => (),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+        if regions.is_empty() {
+            return Ok(());
+        }
+        guard
+            .grants
+            .check_map_count(UserGrants::split_count(&regions, requested_span))?;
+
+        let unpin = false;
+        guard
+            .grants
+            .for_each_conflict_split(requested_span, unpin, |grants, mut grant| {
+                if let Provider::Allocated { .. } = grant.info.provider {
+                    grant.info.provider = Provider::AllocatedShared {
+                        is_pinned_userscheme_borrow: false,
+                    };
+                    if grant.info.clone_strategy != CloneStrategy::DontInherit {
+                        grant.info.clone_strategy = CloneStrategy::Share;
+                    }
+                }
+                grants.insert(grant);
+            });
+        Ok(())
+    }
+    #[must_use = "needs to notify files"]
+    pub fn munmap(&self, requested_span: PageSpan, unpin: bool) -> Result<Vec<UnmapResult>> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        // Unmapping a large range can free many frames, which are better freed after a single
+        // shootdown at the end.
+        let mut flusher =
+            DeferredFlusher::new(Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending));
+        AddrSpace::munmap_inner(
+            &mut guard.grants,
+            &mut guard.table.utable,
+            &mut flusher,
+            requested_span,
+            unpin,
+        )
+    }
+    /// Maps a copy-on-write snapshot of `src_span` from `src` into this address space, placed
+    /// according to `requested_dst_base` and `flags` as for [`AddrSpace::mmap`], which each of
+    /// the resulting grants is mapped through. Only (non-contiguous) anonymous Allocated memory
+    /// can be snapshotted, and the source pages are write-protected, so that later writes by
+    /// either side diverge rather than leak into the other's view.
+    pub fn map_cow_from(
+        &self,
+        src: &AddrSpaceWrapper,
+        src_span: PageSpan,
+        requested_dst_base: Option<Page>,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+    ) -> Result<Page> {
+        if core::ptr::eq(self, src) {
+            return Err(Error::new(EBUSY));
+        }
+
+        // Both address spaces are locked in address order, so that snapshots taken in opposite
+        // directions at the same time cannot deadlock.
+        let src_first = (src as *const Self) < (self as *const Self);
+        let (mut src_guard, mut dst_guard) = if src_first {
+            let src_guard = src.acquire_write();
+            (src_guard, self.acquire_write())
+        } else {
+            let dst_guard = self.acquire_write();
+            (src.acquire_write(), dst_guard)
+        };
+        let src_guard = &mut *src_guard;
+        let dst_guard = &mut *dst_guard;
+
+        let fixed = flags.intersects(MapFlags::MAP_FIXED | MapFlags::MAP_FIXED_NOREPLACE);
+        let dst_span = match requested_dst_base {
+            // The destination must lie within userspace, like the source.
+            Some(dst_base) if fixed => src_span
+                .count
+                .checked_mul(PAGE_SIZE)
+                .and_then(|size| PageSpan::validate(dst_base.start_address(), size))
+                .ok_or(Error::new(EINVAL))?,
+            _ => dst_guard
+                .grants
+                .find_free(dst_guard.mmap_min, src_span.count)
+                .ok_or(Error::new(ENOMEM))?,
+        };
+        // The grants are mapped one by one, and must not replace each other.
+        let piece_flags = if fixed {
+            flags
+        } else {
+            flags | MapFlags::MAP_FIXED_NOREPLACE
+        };
+
+        // The whole source span must be covered by snapshottable grants, without holes.
+        let mut pieces = Vec::new();
+        let mut cursor = src_span.base;
+        for (base, info) in src_guard.grants.conflicts(src_span) {
+            if base > cursor || info.is_pinned() {
+                return Err(Error::new(EINVAL));
+            }
+            let Provider::Allocated {
+                ref cow_file_ref,
+                phys_contiguous: false,
+            } = info.provider
+            else {
+                return Err(Error::new(EINVAL));
+            };
+            let piece = PageSpan::new(base, info.page_count).intersection(src_span);
+            pieces.push((piece, cow_file_ref.clone()));
+            cursor = piece.end();
+        }
+        if cursor != src_span.end() {
+            return Err(Error::new(EINVAL));
+        }
+        // Pages being swapped out have no swap slot yet that the snapshot could refer to.
+        src_guard.cancel_swap_outs(src_span)?;
+
+        let src_mapper = &mut src_guard.table.utable;
+        let mut src_flusher = Flusher::with_cpu_set(&mut src_guard.used_by, &src.tlb_pending);
+
+        for (piece, cow_file_ref) in pieces {
+            let piece_dst_base = dst_span.base.next_by(piece.base.offset_from(src_span.base));
+            let result = dst_guard.mmap(
+                self,
+                Some(piece_dst_base),
+                NonZeroUsize::new(piece.count).expect("grants are never empty"),
+                piece_flags,
+                notify_files_out,
+                |dst_base, page_flags, dst_mapper, dst_flusher| {
+                    Ok(Grant::copy_mappings(
+                        piece.base,
+                        dst_base,
+                        piece.count,
+                        page_flags,
+                        src_mapper,
+                        dst_mapper,
+                        &mut src_flusher,
+                        dst_flusher,
+                        CopyMappingsMode::Owned { cow_file_ref },
+                    )?)
+                },
+            );
+            if let Err(error) = result {
+                // Undo the part of the snapshot mapped so far.
+                let mut notify_files = AddrSpace::munmap_inner(
+                    &mut dst_guard.grants,
+                    &mut dst_guard.table.utable,
+                    &mut Flusher::with_cpu_set(&mut dst_guard.used_by, &self.tlb_pending),
+                    PageSpan::between(dst_span.base, piece_dst_base),
+                    false,
+                )?;
+                notify_files_out.append(&mut notify_files);
+                return Err(error);
+            }
+        }
+        drop(src_flusher);
+
+        // Like in try_clone, swapped out pages refer to the same swap slots in the snapshot. This
+        // is only done once nothing can fail anymore, as the slots are then referenced by both.
+        src_guard.grants.page_states.share(
+            src_span,
+            &mut dst_guard.grants.page_states,
+            dst_span.base,
+        );
+
+        Ok(dst_span.base)
+    }
+    pub fn r#move(
+        &self,
+        mut src_opt: Option<(&AddrSpaceWrapper, &mut AddrSpace)>,
+        src_span: PageSpan,
+        requested_dst_base: Option<Page>,
+        new_page_count: usize,
+        new_flags: MapFlags,
+        notify_files: &mut Vec<UnmapResult>,
+    ) -> Result<Page> {
+        let dst_lock = self;
+        // Neither span may wrap around, as the offsets computed below would otherwise overflow.
+        src_span
+            .base
+            .checked_next_by(cmp::max(src_span.count, new_page_count))
+            .ok_or(Error::new(EINVAL))?;
+        if let Some(base) = requested_dst_base {
+            base.checked_next_by(new_page_count)
+                .ok_or(Error::new(EINVAL))?;
+        }
+
+        let mut dst = dst_lock.acquire_write();
+        let dst = &mut *dst;
+
+        if dst_lock.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
+        let mut src_owned_opt = src_opt.as_mut().map(|(aw, a)| {
+            (
+                &mut a.grants,
+                &mut a.table.utable,
+                Flusher::with_cpu_set(&mut a.used_by, &aw.tlb_pending),
+            )
+        });
+        let mut src_opt = src_owned_opt
+            .as_mut()
+            .map(|(g, m, f)| (&mut *g, &mut *m, &mut *f));
+        let mut dst_flusher = Flusher::with_cpu_set(&mut dst.used_by, &dst_lock.tlb_pending);
+
+        // Validate the source before unmapping anything at a fixed destination, so that a failed
+        // move leaves both address spaces untouched.
+        {
+            let src_grants = src_opt.as_ref().map_or(&dst.grants, |(g, _, _)| &**g);
+
+            if src_grants
+                .conflicts(src_span)
+                .any(|(_, g)| !g.can_extract(false))
+            {
+                return Err(Error::new(EBUSY));
+            }
+            if src_grants
+                .conflicts(src_span)
+                .any(|(_, g)| !g.can_have_flags(new_flags))
+            {
+                return Err(Error::new(EPERM));
+            }
+            if dst.strict_wx
+                && src_grants
+                    .conflicts(src_span)
+                    .any(|(_, g)| g.violates_wx(new_flags))
+            {
+                return Err(Error::new(EACCES));
+            }
+        }
+
+        let dst_base = match requested_dst_base {
+            Some(base) if new_flags.contains(MapFlags::MAP_FIXED_NOREPLACE) => {
+                if dst
+                    .grants
+                    .conflicts(PageSpan::new(base, new_page_count))
+                    .next()
+                    .is_some()
+                {
+                    return Err(Error::new(EEXIST));
+                }
+
+                base
+            }
+            Some(base) if new_flags.contains(MapFlags::MAP_FIXED) => {
+                if PageSpan::new(base, new_page_count).intersects(src_span) {
+                    return Err(Error::new(EBUSY));
+                }
+                let unpin = false;
+                notify_files.append(&mut AddrSpace::munmap_inner(
+                    &mut dst.grants,
+                    &mut dst.table.utable,
+                    &mut dst_flusher,
+                    PageSpan::new(base, new_page_count),
+                    unpin,
+                )?);
 
                 base
             }
@@ -377,18 +1691,6 @@ impl AddrSpaceWrapper {
             |(g, m, f)| (&mut *g, &mut *m, &mut *f),
         );
 
-        if src_grants
-            .conflicts(src_span)
-            .any(|(_, g)| !g.can_extract(false))
-        {
-            return Err(Error::new(EBUSY));
-        }
-        if src_grants
-            .conflicts(src_span)
-            .any(|(_, g)| !g.can_have_flags(new_flags))
-        {
-            return Err(Error::new(EPERM));
-        }
         if PageSpan::new(dst_base, new_page_count).intersects(src_span) {
             return Err(Error::new(EBUSY));
         }
@@ -452,95 +1754,479 @@ impl AddrSpaceWrapper {
                 src_grants.insert(after);
             }
 
-            let dst_grant_base = dst_base.next_by(middle.base.offset_from(src_span.base));
-            let middle_span = middle.span();
+            let dst_grant_base = dst_base.next_by(middle.base.offset_from(src_span.base));
+            let middle_span = middle.span();
+
+            // Only resident pages are moved along with the page tables, so carry over the state of
+            // the other pages as well.
+            let page_states = src_grants.page_states.take(middle_span);
+            dst.grants.page_states.put(dst_grant_base, page_states);
+
+            let mut src_opt = src_opt
+                .as_mut()
+                .map(|(g, m, f)| (&mut *g, &mut *m, &mut *f));
+
+            dst.grants.insert(match src_opt.as_mut() {
+                Some((_, other_mapper, other_flusher)) => middle.transfer(
+                    dst_grant_base,
+                    page_flags(new_flags),
+                    other_mapper,
+                    Some(&mut dst.table.utable),
+                    other_flusher,
+                    &mut dst_flusher,
+                )?,
+                None => middle.transfer(
+                    dst_grant_base,
+                    page_flags(new_flags),
+                    &mut dst.table.utable,
+                    None,
+                    &mut dst_flusher,
+                    &mut NopFlusher,
+                )?,
+            });
+
+            prev_grant_end = middle_span.base.next_by(middle_span.count);
+            let pages_advanced = prev_grant_end.offset_from(remaining_src_span.base);
+            remaining_src_span =
+                PageSpan::new(prev_grant_end, remaining_src_span.count - pages_advanced);
+        }
+
+        if prev_grant_end < src_span.base.next_by(new_page_count) {
+            let last_hole_span = PageSpan::new(
+                dst_base.next_by(prev_grant_end.offset_from(src_span.base)),
+                new_page_count - prev_grant_end.offset_from(src_span.base),
+            );
+            dst.grants.insert(Grant::zeroed(
+                last_hole_span,
+                page_flags(new_flags),
+                &mut dst.table.utable,
+                &mut dst_flusher,
+                false,
+            )?);
+        }
+
+        Ok(dst_base)
+    }
+    /// Checks that `addr` lies within an executable grant, e.g. before using it as a userspace
+    /// entry point such as a signal handler.
+    pub fn check_executable(&self, addr: VirtualAddress) -> Result<()> {
+        let guard = self.acquire_read();
+
+        match guard.grants.contains(Page::containing_address(addr)) {
+            Some((_, info)) if info.flags().has_execute() => Ok(()),
+            _ => Err(Error::new(EFAULT)),
+        }
+    }
+    /// Borrows a page from user memory, requiring that the frame be Allocated and read/write. This
+    /// is intended to be used for user-kernel shared memory.
+    pub fn borrow_frame_enforce_rw_allocated(self: &Arc<Self>, page: Page) -> Result<RaiiFrame> {
+        let mut guard = self.acquire_write();
+
+        let (_start_page, info) = guard.grants.contains(page).ok_or(Error::new(EINVAL))?;
+
+        if !info.can_have_flags(MapFlags::PROT_READ | MapFlags::PROT_WRITE) {
+            return Err(Error::new(EPERM));
+        }
+        if !matches!(info.provider, Provider::Allocated { .. }) {
+            return Err(Error::new(EPERM));
+        }
+
+        let frame = if let Some((f, fl)) = guard.table.utable.translate(page.start_address())
+            && fl.has_write()
+        {
+            Frame::containing(f)
+        } else {
+            let (frame, flush, new_guard) = correct_inner(self, guard, page, AccessMode::Write, 0)
+                .map_err(|_| Error::new(ENOMEM))?;
+            flush.flush();
+            guard = new_guard;
+
+            frame
+        };
+
+        let frame = match get_page_info(frame)
+            .expect("missing page info for Allocated grant")
+            .add_ref(RefKind::Shared)
+        {
+            Ok(_) => Ok(unsafe { RaiiFrame::new_unchecked(frame) }),
+            Err(AddRefError::RcOverflow) => Err(Error::new(ENOMEM)),
+            Err(AddRefError::SharedToCow) => unreachable!(),
+            Err(AddRefError::CowToShared) => unreachable!(
+                "if it was CoW, it was read-only, but in that case we already called correct_inner"
+            ),
+        };
+        drop(guard);
+
+        frame
+    }
+
+    /// Copies the memory at `src` in this address space, which need not be the current one, to
+    /// `dst`, e.g. for debuggers. Pages that are valid but not yet present are faulted in, as if
+    /// accessed by the address space itself. Returns the number of bytes copied before the first
+    /// page that cannot be read, or EFAULT if that is the first page.
+    pub fn read_foreign(self: &Arc<Self>, src: VirtualAddress, dst: &mut [u8]) -> Result<usize> {
+        self.access_foreign(src, dst.len(), AccessMode::Read, |ptr, range| unsafe {
+            dst[range.clone()].copy_from_slice(core::slice::from_raw_parts(ptr, range.len()));
+        })
+    }
+    /// Like [`read_foreign`](Self::read_foreign), but copies `src` to the memory at `dst` in this
+    /// address space, which must be writable. Copy-on-write pages are copied first, and armed write
+    /// barriers are honored.
+    pub fn write_foreign(self: &Arc<Self>, dst: VirtualAddress, src: &[u8]) -> Result<usize> {
+        self.access_foreign(dst, src.len(), AccessMode::Write, |ptr, range| unsafe {
+            core::slice::from_raw_parts_mut(ptr, range.len()).copy_from_slice(&src[range]);
+        })
+    }
+    /// Calls `copy` with the kernel address of each part of the `len` bytes at `addr` that lies
+    /// within a single page, along with the range of that part relative to `addr`, until reaching
+    /// a page that does not permit `access`.
+    fn access_foreign(
+        self: &Arc<Self>,
+        addr: VirtualAddress,
+        len: usize,
+        access: AccessMode,
+        mut copy: impl FnMut(*mut u8, Range<usize>),
+    ) -> Result<usize> {
+        addr.data().checked_add(len).ok_or(Error::new(EFAULT))?;
+
+        let mut guard = self.acquire_write();
+        let mut done = 0;
+
+        while done < len {
+            let virt = VirtualAddress::new(addr.data() + done);
+            let page = Page::containing_address(virt);
+
+            let present = guard
+                .virt_to_phys(virt)
+                .filter(|(_, flags)| access != AccessMode::Write || flags.has_write());
+            let frame = match present {
+                Some((frame, _)) => frame,
+                None => {
+                    // Only the userspace fault handler can fill the pages it is registered for.
+                    if guard.userfault_handler_for(page, access).is_some() {
+                        break;
+                    }
+                    match correct_inner(self, guard, page, access, 0) {
+                        Ok((frame, flush, new_guard)) => {
+                            flush.flush();
+                            guard = new_guard;
+                            frame
+                        }
+                        Err(_) => break,
+                    }
+                }
+            };
+
+            let offset = virt.data() % PAGE_SIZE;
+            let chunk = cmp::min(PAGE_SIZE - offset, len - done);
+            let ptr = unsafe { RmmA::phys_to_virt(frame.base()).data() + offset } as *mut u8;
+            copy(ptr, done..done + chunk);
+
+            if access == AccessMode::Write {
+                // The write went through the physmap, so record it like the fault path and the
+                // CPU would have, cancelling MADV_FREE and marking the page dirty.
+                if let Some(info) = get_page_info(frame) {
+                    info.remove_flags(FrameFlags::FREEABLE | FrameFlags::CLEAN);
+                }
+                unsafe { guard.table.utable.mark_dirty(page.start_address()) };
+            }
+
+            done += chunk;
+        }
+
+        if done == 0 && len > 0 {
+            return Err(Error::new(EFAULT));
+        }
+        Ok(done)
+    }
+
+    /// Attach `handler` as the userspace fault handler of this address space, without any spans
+    /// registered yet. Fails with EBUSY if a live handler is already attached.
+    pub fn userfault_attach(&self, handler: Arc<UserFaultHandler>) -> Result<()> {
+        let mut guard = self.acquire_write();
+
+        if guard
+            .userfault
+            .as_ref()
+            .is_some_and(|registration| registration.handler.is_alive())
+        {
+            return Err(Error::new(EBUSY));
+        }
+        guard.userfault = Some(UserFaultRegistration {
+            handler,
+            spans: Vec::new(),
+        });
+
+        Ok(())
+    }
+    /// Detach `handler`, and wake all contexts blocked on it, whose faults will then fail.
+    pub fn userfault_detach(&self, handler: &Arc<UserFaultHandler>) {
+        {
+            let mut guard = self.acquire_write();
+            if guard
+                .userfault
+                .as_ref()
+                .is_some_and(|registration| Arc::ptr_eq(&registration.handler, handler))
+            {
+                guard.userfault = None;
+            }
+        }
+        handler.kill();
+    }
+    /// Register `span` for userspace fault handling, or unregister a previously registered span.
+    /// Contexts blocked on an unregistered span are woken, and will be handled in-kernel.
+    pub fn userfault_register(
+        &self,
+        handler: &Arc<UserFaultHandler>,
+        span: PageSpan,
+        register: bool,
+    ) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let registration = guard
+            .userfault
+            .as_mut()
+            .filter(|registration| Arc::ptr_eq(&registration.handler, handler))
+            .ok_or(Error::new(EBADF))?;
+
+        if register {
+            if span.is_empty() {
+                return Err(Error::new(EINVAL));
+            }
+            if registration.spans.iter().any(|s| s.intersects(span)) {
+                return Err(Error::new(EEXIST));
+            }
+            registration.spans.push(span);
+        } else {
+            let idx = registration
+                .spans
+                .iter()
+                .position(|s| s.base == span.base && s.count == span.count)
+                .ok_or(Error::new(EINVAL))?;
+            registration.spans.swap_remove(idx);
+            drop(guard);
+
+            handler.wake_span(span);
+        }
+
+        Ok(())
+    }
+    /// Resolve a forwarded fault by mapping a new page with the contents of `src`, and wake the
+    /// contexts blocked on it. Fails with EEXIST if the page has already been mapped.
+    pub fn userfault_fill(
+        &self,
+        handler: &Arc<UserFaultHandler>,
+        page: Page,
+        src: UserSliceRo,
+    ) -> Result<()> {
+        let frame = RaiiFrame::allocate()?;
+
+        // Copy before locking the address space, since the source may be located within it.
+        src.copy_to_slice(unsafe {
+            core::slice::from_raw_parts_mut(
+                RmmA::phys_to_virt(frame.get().base()).data() as *mut u8,
+                PAGE_SIZE,
+            )
+        })?;
+
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+
+        let registration = guard
+            .userfault
+            .as_ref()
+            .filter(|registration| Arc::ptr_eq(&registration.handler, handler))
+            .ok_or(Error::new(EBADF))?;
+        if !registration.covers(page) {
+            return Err(Error::new(EINVAL));
+        }
+        let (_, info) = guard.grants.contains(page).ok_or(Error::new(EFAULT))?;
+        if !matches!(
+            info.provider,
+            Provider::Allocated {
+                phys_contiguous: false,
+                ..
+            }
+        ) {
+            return Err(Error::new(EINVAL));
+        }
+        if guard.table.utable.translate(page.start_address()).is_some()
+            || guard.grants.is_swapped(page)
+        {
+            return Err(Error::new(EEXIST));
+        }
+
+        let armed = guard
+            .write_barrier
+            .as_ref()
+            .is_some_and(|registration| registration.covers(page));
+        let flags = info.flags().write(info.flags().has_write() && !armed);
 
-            let mut src_opt = src_opt
-                .as_mut()
-                .map(|(g, m, f)| (&mut *g, &mut *m, &mut *f));
+        unsafe {
+            // The page was not present before, so there is nothing to flush.
+            guard
+                .table
+                .utable
+                .map_phys(page.start_address(), frame.get().base(), flags)
+                .ok_or(Error::new(ENOMEM))?
+                .ignore();
+        }
+        // The reference is now owned by the page tables.
+        core::mem::forget(frame);
 
-            dst.grants.insert(match src_opt.as_mut() {
-                Some((_, other_mapper, other_flusher)) => middle.transfer(
-                    dst_grant_base,
-                    page_flags(new_flags),
-                    other_mapper,
-                    Some(&mut dst.table.utable),
-                    other_flusher,
-                    &mut dst_flusher,
-                )?,
-                None => middle.transfer(
-                    dst_grant_base,
-                    page_flags(new_flags),
-                    &mut dst.table.utable,
-                    None,
-                    &mut dst_flusher,
-                    &mut NopFlusher,
-                )?,
-            });
+        drop(guard);
+        handler.wake(page);
 
-            prev_grant_end = middle_span.base.next_by(middle_span.count);
-            let pages_advanced = prev_grant_end.offset_from(remaining_src_span.base);
-            remaining_src_span =
-                PageSpan::new(prev_grant_end, remaining_src_span.count - pages_advanced);
+        Ok(())
+    }
+    /// Map `page_count` pages at `base` as an emulated grant, of which every access is forwarded
+    /// to `handler` rather than backed by memory, see [`Provider::Emulated`]. Existing mappings
+    /// are never replaced.
+    pub fn userfault_map_emulated(
+        &self,
+        handler: &Arc<UserFaultHandler>,
+        base: Page,
+        page_count: NonZeroUsize,
+        flags: MapFlags,
+    ) -> Result<()> {
+        let mut guard = self.acquire_write();
+        if !guard
+            .userfault
+            .as_ref()
+            .is_some_and(|registration| Arc::ptr_eq(&registration.handler, handler))
+        {
+            return Err(Error::new(EBADF));
         }
 
-        if prev_grant_end < src_span.base.next_by(new_page_count) {
-            let last_hole_span = PageSpan::new(
-                dst_base.next_by(prev_grant_end.offset_from(src_span.base)),
-                new_page_count - prev_grant_end.offset_from(src_span.base),
-            );
-            dst.grants.insert(Grant::zeroed(
-                last_hole_span,
-                page_flags(new_flags),
-                &mut dst.table.utable,
-                &mut dst_flusher,
-                false,
-            )?);
-        }
+        let mut notify_files = Vec::new();
+        guard.mmap(
+            self,
+            Some(base),
+            page_count,
+            flags | MapFlags::MAP_FIXED_NOREPLACE,
+            &mut notify_files,
+            |base, page_flags, _, _| {
+                Ok(Grant::emulated(
+                    PageSpan::new(base, page_count.get()),
+                    page_flags,
+                    Arc::clone(handler),
+                ))
+            },
+        )?;
+        // Nothing is unmapped when mapping with MAP_FIXED_NOREPLACE.
+        debug_assert!(notify_files.is_empty());
 
-        Ok(dst_base)
+        Ok(())
     }
-    /// Borrows a page from user memory, requiring that the frame be Allocated and read/write. This
-    /// is intended to be used for user-kernel shared memory.
-    pub fn borrow_frame_enforce_rw_allocated(self: &Arc<Self>, page: Page) -> Result<RaiiFrame> {
-        let mut guard = self.acquire_write();
 
-        let (_start_page, info) = guard.grants.contains(page).ok_or(Error::new(EINVAL))?;
+    /// Attach `barrier` as the write barrier of this address space, without any spans armed yet,
+    /// or detach the current one. Pages that were write-protected for the previous barrier become
+    /// writable again on the next write.
+    pub fn set_write_barrier(&self, barrier: Option<Arc<dyn WriteBarrier>>) {
+        self.acquire_write().write_barrier = barrier.map(|barrier| WriteBarrierRegistration {
+            barrier,
+            spans: Vec::new(),
+        });
+    }
+    /// Arm the write barrier for `span`, write-protecting its present pages, so that the next
+    /// write to each page of an owned grant in it is passed to the barrier. Arming a span again,
+    /// e.g. after the barrier has recorded the previous writes, write-protects the pages again.
+    /// Fails with EINVAL if no barrier is attached.
+    pub fn write_barrier_arm(&self, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
 
-        if !info.can_have_flags(MapFlags::PROT_READ | MapFlags::PROT_WRITE) {
-            return Err(Error::new(EPERM));
+        if self.is_dying() {
+            return Err(Error::new(ESRCH));
         }
-        if !matches!(info.provider, Provider::Allocated { .. }) {
-            return Err(Error::new(EPERM));
+        let registration = guard.write_barrier.as_mut().ok_or(Error::new(EINVAL))?;
+        if span.is_empty() {
+            return Err(Error::new(EINVAL));
         }
-
-        let frame = if let Some((f, fl)) = guard.table.utable.translate(page.start_address())
-            && fl.has_write()
+        if !registration
+            .spans
+            .iter()
+            .any(|s| s.base == span.base && s.count == span.count)
         {
-            Frame::containing(f)
-        } else {
-            let (frame, flush, new_guard) = correct_inner(self, guard, page, AccessMode::Write, 0)
-                .map_err(|_| Error::new(ENOMEM))?;
-            flush.flush();
-            guard = new_guard;
+            registration.spans.push(span);
+        }
 
-            frame
-        };
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_pending);
 
-        let frame = match get_page_info(frame)
-            .expect("missing page info for Allocated grant")
-            .add_ref(RefKind::Shared)
-        {
-            Ok(_) => Ok(unsafe { RaiiFrame::new_unchecked(frame) }),
-            Err(AddRefError::RcOverflow) => Err(Error::new(ENOMEM)),
-            Err(AddRefError::SharedToCow) => unreachable!(),
-            Err(AddRefError::CowToShared) => unreachable!(
-                "if it was CoW, it was read-only, but in that case we already called correct_inner"
-            ),
+        for (base, info) in guard.grants.conflicts(span) {
+            if info.is_owned() {
+                let grant_span = PageSpan::new(base, info.page_count).intersection(span);
+                write_protect_armed(registration, mapper, &mut flusher, grant_span);
+            }
+        }
+        Ok(())
+    }
+    /// Disarm a span previously armed using [`write_barrier_arm`](Self::write_barrier_arm). Its
+    /// pages are no longer passed to the barrier, and become writable on the next write.
+    pub fn write_barrier_disarm(&self, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let registration = guard.write_barrier.as_mut().ok_or(Error::new(EINVAL))?;
+        let idx = registration
+            .spans
+            .iter()
+            .position(|s| s.base == span.base && s.count == span.count)
+            .ok_or(Error::new(EINVAL))?;
+        registration.spans.swap_remove(idx);
+
+        Ok(())
+    }
+}
+/// Write-protect the present pages in `span` that the write barrier of `registration` is armed
+/// for, so that the next write to them faults.
+fn write_protect_armed(
+    registration: &WriteBarrierRegistration,
+    mapper: &mut PageMapper,
+    flusher: &mut Flusher,
+    span: PageSpan,
+) {
+    for page in span.pages().filter(|page| registration.covers(*page)) {
+        let Some((old_flags, phys, flush)) =
+            (unsafe { mapper.remap_with(page.start_address(), |flags| flags.write(false)) })
+        else {
+            continue;
         };
-        drop(guard);
+        unsafe {
+            flush.ignore();
+        }
+        if old_flags.has_write() {
+            flusher.queue(
+                Frame::containing(phys),
+                None,
+                TlbShootdownActions::REVOKE_WRITE,
+            );
+        }
+    }
+}
+/// The address space of the current context, looked up once, for code performing several
+/// operations on it. As it holds a reference, it remains valid across context switches, and keeps
+/// referring to the same address space even if the context switches to another one meanwhile.
+#[derive(Clone, Debug)]
+pub struct CurrentAddrSpace(Arc<AddrSpaceWrapper>);
+impl CurrentAddrSpace {
+    /// Fails with ESRCH if the current context has no address space.
+    pub fn get() -> Result<Self> {
+        AddrSpace::current().map(Self)
+    }
+    pub fn read(&self) -> RwLockReadGuard<'_, AddrSpace> {
+        self.0.acquire_read()
+    }
+    pub fn write(&self) -> RwLockWriteGuard<'_, AddrSpace> {
+        self.0.acquire_write()
+    }
+    pub fn into_inner(self) -> Arc<AddrSpaceWrapper> {
+        self.0
+    }
+}
+impl Deref for CurrentAddrSpace {
+    type Target = Arc<AddrSpaceWrapper>;
 
-        frame
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 impl AddrSpace {
@@ -552,99 +2238,194 @@ impl AddrSpace {
             .ok_or(Error::new(ESRCH))
     }
 
+    /// Maps back the pages in `span` that are being swapped out, see [`PageState::SwappingOut`].
+    fn cancel_swap_outs(&mut self, span: PageSpan) -> Result<(), Enomem> {
+        for page in self.grants.page_states.pages_in(span) {
+            cancel_swap_out(self, page)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of pages spanned by owned (Allocated) grants, regardless of whether
+    /// they have been faulted in.
+    pub fn allocated_pages(&self) -> usize {
+        self.grants
+            .iter()
+            .filter(|(_, info)| matches!(info.provider, Provider::Allocated { .. }))
+            .map(|(_, info)| info.page_count)
+            .sum()
+    }
+    /// Returns the number of pages currently present in this address space's page tables, i.e.
+    /// the resident set size in pages. Unlike the global frame counters, this walks the page
+    /// tables, so it is meant for occasional monitoring rather than hot paths.
+    pub fn resident_pages(&self) -> usize {
+        self.grants
+            .iter()
+            .flat_map(|(base, info)| PageSpan::new(base, info.page_count).pages())
+            .filter(|page| self.table.utable.translate(page.start_address()).is_some())
+            .count()
+    }
+    /// Returns a snapshot of the CPUs that currently have this address space loaded.
+    ///
+    /// CPUs are added before they load the page table and removed only after they have switched
+    /// away, so the snapshot may contain CPUs that have just left, but never misses a CPU that can
+    /// observe the address space. When holding the write lock, no CPU can be added.
+    pub fn active_cpus(&self) -> impl Iterator<Item = LogicalCpuId> {
+        self.used_by.iter()
+    }
+    /// Translates `addr` to the frame it is currently mapped to, along with the flags of that
+    /// mapping. Returns `None` if `addr` is not within any grant, or if the page is valid but not
+    /// yet present (e.g. lazily allocated), in which case it must first be faulted in.
+    pub fn virt_to_phys(&self, addr: VirtualAddress) -> Option<(Frame, PageFlags<RmmA>)> {
+        let page = Page::containing_address(addr);
+        self.grants.containing_span(page)?;
+
+        #[cfg(feature = "fault_injection")]
+        if crate::memory::fault_injection::should_fail_translation() {
+            return None;
+        }
+        let (phys, flags) = self.table.utable.translate(page.start_address())?;
+        Some((Frame::containing(phys), flags))
+    }
+    /// Returns the protection and sharing flags of the grant containing `addr`, or `None` if
+    /// `addr` is not within any grant. Unlike [`virt_to_phys`](Self::virt_to_phys), this does not
+    /// depend on whether the page is present.
+    pub fn flags_at(&self, addr: VirtualAddress) -> Option<MapFlags> {
+        let (_, info) = self.grants.contains(Page::containing_address(addr))?;
+
+        let sharing = if info.is_shared() {
+            MapFlags::MAP_SHARED
+        } else {
+            MapFlags::MAP_PRIVATE
+        };
+        Some(map_flags(info.flags()) | sharing)
+    }
+
+    /// Returns the userspace fault handler that a fault on `page` should be forwarded to, if the
+    /// page lies within a registered span, is not yet present, and the access is permitted.
+    fn userfault_handler_for(
+        &self,
+        page: Page,
+        access: AccessMode,
+    ) -> Option<Arc<UserFaultHandler>> {
+        let registration = self
+            .userfault
+            .as_ref()
+            .filter(|registration| registration.covers(page))?;
+        let (_, info) = self.grants.containing_span(page)?;
+
+        let permitted = match access {
+            AccessMode::Read => true,
+            AccessMode::Write => info.flags().has_write(),
+            AccessMode::InstrFetch => info.flags().has_execute(),
+        };
+        if !permitted
+            || !matches!(info.provider, Provider::Allocated { .. })
+            || self.table.utable.translate(page.start_address()).is_some()
+            || self.grants.is_swapped(page)
+        {
+            return None;
+        }
+
+        Some(Arc::clone(&registration.handler))
+    }
+
+    /// Returns the number of grants, which is a measure of address space fragmentation.
+    pub fn grant_count(&self) -> usize {
+        self.grants.len()
+    }
+    /// Returns the maximum number of grants, beyond which new mappings fail with ENOMEM.
+    pub fn max_map_count(&self) -> usize {
+        self.grants.max_map_count
+    }
+    /// Sets the maximum number of grants. Existing grants are kept even if there are more.
+    pub fn set_max_map_count(&mut self, max_map_count: usize) {
+        self.grants.max_map_count = max_map_count;
+    }
+    /// Returns whether non-fixed mappings are placed with an unmapped guard page on either side.
+    pub fn guard_gaps(&self) -> bool {
+        self.grants.guard_gaps
+    }
+    /// Sets whether non-fixed mappings are placed with an unmapped guard page on either side, so
+    /// that overflowing one mapping faults rather than corrupting its neighbor. Only affects
+    /// mappings created afterwards.
+    pub fn set_guard_gaps(&mut self, guard_gaps: bool) {
+        self.grants.guard_gaps = guard_gaps;
+    }
+    /// Merges adjacent mergeable grants, returning how many grants were eliminated.
+    pub fn compact(&mut self) -> usize {
+        self.grants.compact()
+    }
+    /// Reserves a free region of at least `size` bytes for a future large mapping, such as a
+    /// growable arena, without backing it. Other mappings will not be placed in the region unless
+    /// explicitly requested using MAP_FIXED, until it is released using
+    /// [`release_reservation`](Self::release_reservation).
+    pub fn reserve_hint(&mut self, size: usize) -> Result<Page> {
+        let page_count = size.div_ceil(PAGE_SIZE);
+        if page_count == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let span = self
+            .grants
+            .find_free(self.mmap_min, page_count)
+            .ok_or(Error::new(ENOMEM))?;
+        self.grants.reserve_range(span)?;
+        Ok(span.base)
+    }
+    /// Releases (part of) a region reserved using [`reserve_hint`](Self::reserve_hint). Grants
+    /// already placed within it are kept.
+    pub fn release_reservation(&mut self, span: PageSpan) -> Result<()> {
+        self.grants.unreserve_range(span)
+    }
+
     pub fn new() -> Result<Self> {
         Ok(Self {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
             used_by: LogicalCpuSet::empty(),
+            userfault: None,
+            strict_wx: false,
+            numa_policy: NumaPolicy::default(),
+            map_flags_mask: MapFlagsMask::NONE,
+            write_barrier: None,
         })
     }
     fn munmap_inner(
         this_grants: &mut UserGrants,
         this_mapper: &mut PageMapper,
-        this_flusher: &mut Flusher,
-        mut requested_span: PageSpan,
+        this_flusher: &mut impl GenericFlusher,
+        requested_span: PageSpan,
         unpin: bool,
     ) -> Result<Vec<UnmapResult>> {
         let mut notify_files = Vec::new();
 
-        let next = |grants: &mut UserGrants, span: PageSpan| {
-            grants
-                .conflicts(span)
-                .map(|(base, info)| {
-                    if info.is_pinned() && !unpin {
-                        Err(Error::new(EBUSY))
-                    } else if !info.can_extract(unpin) {
-                        Err(Error::new(EINVAL))
-                    } else {
-                        Ok(PageSpan::new(base, info.page_count))
-                    }
-                })
-                .next()
-        };
-
-        while let Some(conflicting_span_res) = next(this_grants, requested_span) {
-            let conflicting_span = conflicting_span_res?;
-
-            let mut grant = this_grants
-                .remove(conflicting_span.base)
-                .expect("conflicting region didn't exist");
-            if unpin {
-                grant.info.unpin();
-            }
-
-            let intersection = conflicting_span.intersection(requested_span);
-
-            requested_span = {
-                // In the following diagrams [---> indicates a range of
-                // base..base+count where the [ is at the base and > is at
-                // base+count. In other words, the [ is part of the range and
-                // the > is not part of the range.
-                if conflicting_span.end() < requested_span.end() {
-                    // [------>     conflicting_span
-                    //    [-------> requested_span
-                    //        [---> next requested_span
-                    // or
-                    //    [---->    conflicting_span
-                    // [----------> requested_span
-                    //         [--> next requested_span
-                    PageSpan::new(
-                        conflicting_span.end(),
-                        requested_span.end().offset_from(conflicting_span.end()),
-                    )
-                } else {
-                    // [----------> conflicting_span
-                    //    [----->   requested_span
-                    //              next requested_span
-                    // or
-                    //   [--------> conflicting_span
-                    // [-------->   requested_span
-                    //              next requested_span
-                    PageSpan::empty()
-                }
-            };
-
-            let (before, grant, after) = grant
-                .extract(intersection)
-                .expect("conflicting region shared no common parts");
-
-            // Keep untouched regions
-            if let Some(before) = before {
-                this_grants.insert(before);
-            }
-            if let Some(after) = after {
-                this_grants.insert(after);
+        // Validate all grants before unmapping any of them, so that a pinned grant in the middle
+        // of the span does not result in a partial unmap.
+        for (_, info) in this_grants.conflicts(requested_span) {
+            if info.is_pinned() && !unpin {
+                return Err(Error::new(EBUSY));
+            } else if !info.can_extract(unpin) {
+                return Err(Error::new(EINVAL));
             }
+        }
+        // Punching a hole into a single grant is the only way unmapping can increase the count.
+        if let Some((grant_span, _)) = this_grants.containing_span(requested_span.base)
+            && grant_span.base < requested_span.base
+            && grant_span.end() > requested_span.end()
+        {
+            this_grants.check_map_count(1)?;
+        }
+        this_grants.page_states.discard(requested_span);
 
-            // Remove irrelevant region
+        this_grants.for_each_conflict_split(requested_span, unpin, |_, grant| {
             let unmap_result = grant.unmap(this_mapper, this_flusher);
 
             // Notify scheme that holds grant
             if unmap_result.file_desc.is_some() {
                 notify_files.push(unmap_result);
             }
-        }
+        });
 
         Ok(notify_files)
     }
@@ -665,9 +2446,40 @@ impl AddrSpace {
         flags: MapFlags,
         notify_files_out: &mut Vec<UnmapResult>,
         map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut Flusher) -> Result<Grant>,
+    ) -> Result<Page> {
+        self.mmap_aligned(
+            dst_lock,
+            requested_base_opt,
+            page_count,
+            PAGE_SIZE,
+            flags,
+            notify_files_out,
+            map,
+        )
+    }
+    /// Like [`mmap`](Self::mmap), but unless the mapping is fixed, its base is chosen to be a
+    /// multiple of `align`, see [`UserGrants::find_free_aligned`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn mmap_aligned(
+        &mut self,
+        dst_lock: &AddrSpaceWrapper,
+        requested_base_opt: Option<Page>,
+        page_count: NonZeroUsize,
+        align: usize,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+        map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut Flusher) -> Result<Grant>,
     ) -> Result<Page> {
         debug_assert_eq!(dst_lock.inner.as_mut_ptr(), self as *mut Self);
 
+        if dst_lock.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+        let flags = self.map_flags_mask.apply(flags);
+        if self.strict_wx && flags.contains(MapFlags::PROT_WRITE | MapFlags::PROT_EXEC) {
+            return Err(Error::new(EACCES));
+        }
+
         let selected_span = match requested_base_opt {
             // TODO: Rename MAP_FIXED+MAP_FIXED_NOREPLACE to MAP_FIXED and
             // MAP_FIXED_REPLACE/MAP_REPLACE?
@@ -678,13 +2490,25 @@ impl AddrSpace {
                     if self.grants.conflicts(requested_span).next().is_some() {
                         return Err(Error::new(EEXIST));
                     }
+                    self.grants.check_map_count(1)?;
                     requested_span
                 } else if flags.contains(MapFlags::MAP_FIXED) {
+                    // The replaced grants are removed, but the parts of them outside the requested
+                    // span are kept as separate grants, on top of the new grant.
+                    let regions = self
+                        .grants
+                        .conflicts(requested_span)
+                        .map(|(base, info)| PageSpan::new(base, info.page_count))
+                        .collect::<Vec<_>>();
+                    let added = 1 + UserGrants::split_count(&regions, requested_span);
+                    self.grants
+                        .check_map_count(added.saturating_sub(regions.len()))?;
+
                     let unpin = false;
                     let mut notify_files = Self::munmap_inner(
                         &mut self.grants,
                         &mut self.table.utable,
-                        &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+                        &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_pending),
                         requested_span,
                         unpin,
                     )?;
@@ -692,15 +2516,23 @@ impl AddrSpace {
 
                     requested_span
                 } else {
+                    self.grants.check_map_count(1)?;
                     self.grants
-                        .find_free_near(self.mmap_min, page_count.get(), Some(requested_base))
+                        .find_free_aligned(
+                            self.mmap_min,
+                            page_count.get(),
+                            align,
+                            Some(requested_base),
+                        )
                         .ok_or(Error::new(ENOMEM))?
                 }
             }
-            None => self
-                .grants
-                .find_free(self.mmap_min, page_count.get())
-                .ok_or(Error::new(ENOMEM))?,
+            None => {
+                self.grants.check_map_count(1)?;
+                self.grants
+                    .find_free_aligned(self.mmap_min, page_count.get(), align, None)
+                    .ok_or(Error::new(ENOMEM))?
+            }
         };
 
         // TODO: Threads share address spaces, so not only the inactive flusher should be sending
@@ -712,7 +2544,7 @@ impl AddrSpace {
             selected_span.base,
             page_flags(flags),
             &mut self.table.utable,
-            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_pending),
         )?;
         self.grants.insert(grant);
 
@@ -720,17 +2552,177 @@ impl AddrSpace {
     }
 }
 
+/// Frames of a shared grant, exported by [`AddrSpaceWrapper::export_grant`] so that they can be
+/// mapped into unrelated address spaces by [`AddrSpaceWrapper::import_grant`].
+///
+/// The handle holds its own reference to every frame, just like each mapping of them does. The
+/// frames are therefore freed only once the handle has been dropped and every mapping has been
+/// unmapped, in whichever order that happens. Wrap it in an `Arc` to hand it out to several
+/// importers.
+#[derive(Debug)]
+pub struct GrantHandle {
+    frames: Vec<Frame>,
+    flags: PageFlags<RmmA>,
+}
+impl GrantHandle {
+    pub fn page_count(&self) -> usize {
+        self.frames.len()
+    }
+    /// Returns the protection of the exported grant, the maximum an import may request.
+    pub fn flags(&self) -> MapFlags {
+        map_flags(self.flags)
+    }
+}
+impl Drop for GrantHandle {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            if get_page_info(frame)
+                .expect("exported frames need a PageInfo")
+                .remove_ref()
+                .is_none()
+            {
+                unsafe {
+                    deallocate_frame(frame);
+                }
+            }
+        }
+    }
+}
+
+/// State of a page that is neither described by the page tables, nor by the [`PageInfo`] of its
+/// frame, which is the case for pages that are not resident.
+#[derive(Debug)]
+pub enum PageState {
+    /// The contents were written to swap, and are read back in on the next access. The slot is
+    /// shared with the clones of the address space made since, which each read it back into their
+    /// own frame, and is released once none of them refers to it anymore.
+    Swapped(Arc<SwappedPage>),
+    /// The contents are being written to swap by [`AddrSpaceWrapper::swap_out`], which has
+    /// unmapped the page without holding the address space lock while writing. Accessing the page
+    /// in the meantime maps the frame back, and swap_out then discards what it wrote.
+    SwappingOut(Arc<SwappingOut>),
+}
+
+/// Frame of a page that is being swapped out, see [`PageState::SwappingOut`]. The frame is freed
+/// once the last reference is dropped, unless it was mapped back in.
+#[derive(Debug)]
+pub struct SwappingOut {
+    frame: Frame,
+    mapped_back: AtomicBool,
+}
+impl Drop for SwappingOut {
+    fn drop(&mut self) {
+        if !*self.mapped_back.get_mut() {
+            // The page tables held the only reference before the page was unmapped.
+            drop(unsafe { RaiiFrame::new_unchecked(self.frame) });
+        }
+    }
+}
+
+/// Sparse per-page metadata of the grants in an address space. Only pages whose state differs from
+/// the default (resident, or not yet faulted in) have an entry, so memory use is proportional to
+/// the number of such pages rather than to the size of the grants.
+///
+/// Entries are keyed by page rather than by the index within the grant, so that splitting and
+/// merging grants does not need to touch them. Operations that unmap or move pages must however
+/// discard or [`take`](Self::take) their entries.
+#[derive(Debug, Default)]
+pub struct PageStates {
+    inner: BTreeMap<Page, PageState>,
+}
+impl PageStates {
+    pub fn get(&self, page: Page) -> Option<&PageState> {
+        self.inner.get(&page)
+    }
+    pub fn insert(&mut self, page: Page, state: PageState) -> Option<PageState> {
+        self.inner.insert(page, state)
+    }
+    pub fn remove(&mut self, page: Page) -> Option<PageState> {
+        self.inner.remove(&page)
+    }
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+    /// Returns the pages in `span` that have an entry.
+    pub fn pages_in(&self, span: PageSpan) -> Vec<Page> {
+        self.inner
+            .range(span.base..span.end())
+            .map(|(page, _)| *page)
+            .collect()
+    }
+    /// Removes and returns the entries in `span`, as pairs of offset from the span base and state.
+    pub fn take(&mut self, span: PageSpan) -> Vec<(usize, PageState)> {
+        self.pages_in(span)
+            .into_iter()
+            .filter_map(|page| {
+                let state = self.inner.remove(&page)?;
+                Some((page.offset_from(span.base), state))
+            })
+            .collect()
+    }
+    /// Inserts entries previously [taken](Self::take), relative to `base`.
+    pub fn put(&mut self, base: Page, states: Vec<(usize, PageState)>) {
+        for (offset, state) in states {
+            self.inner.insert(base.next_by(offset), state);
+        }
+    }
+    /// Drops the entries in `span`, e.g. releasing their swap slots.
+    pub fn discard(&mut self, span: PageSpan) {
+        drop(self.take(span));
+    }
+    /// Copies the entries in `span` to `dst`, relative to `dst_base`, sharing their swap slots.
+    pub fn share(&self, span: PageSpan, dst: &mut PageStates, dst_base: Page) {
+        for (page, state) in self.inner.range(span.base..span.end()) {
+            let state = match state {
+                PageState::Swapped(swapped) => PageState::Swapped(Arc::clone(swapped)),
+                // Callers map these back first, see AddrSpace::cancel_swap_outs.
+                PageState::SwappingOut(_) => continue,
+            };
+            dst.inner
+                .insert(dst_base.next_by(page.offset_from(span.base)), state);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UserGrants {
     // Using a BTreeMap for it's range method.
     inner: BTreeMap<Page, GrantInfo>,
     // Using a BTreeMap for it's range method.
     holes: BTreeMap<VirtualAddress, usize>,
+    // Ranges excluded from the holes without being backed by grants, mapping base to page count.
+    // Grants may still be placed inside them explicitly, e.g. using MAP_FIXED.
+    reserved: BTreeMap<Page, usize>,
+    // Limit on the number of grants, as each one costs kernel memory.
+    max_map_count: usize,
+    // Whether find_free leaves a free page between new grants and their neighbors.
+    guard_gaps: bool,
+    // State of the pages not described by the page tables, see PageStates.
+    page_states: PageStates,
+    // Number of calls to conflicts, and of grants they yielded in total, see GrantStats. Atomic
+    // since conflicts is called with the address space only read-locked.
+    conflict_scans: AtomicUsize,
+    conflict_iterations: AtomicUsize,
     // TODO: Would an additional map ordered by (size,start) to allow for O(log n) allocations be
     // beneficial?
 }
 
-#[derive(Clone, Copy)]
+/// Counters describing the grant tree of an address space, to tell when merging or compacting the
+/// grants would pay off. A high number of iterations per scan indicates heavily fragmented grants.
+#[derive(Clone, Copy, Debug)]
+pub struct GrantStats {
+    /// Current number of grants.
+    pub entries: usize,
+    /// Number of [`UserGrants::conflicts`] calls since the address space was created.
+    pub conflict_scans: usize,
+    /// Total number of grants yielded by those calls.
+    pub conflict_iterations: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct PageSpan {
     pub base: Page,
     pub count: usize,
@@ -773,6 +2765,9 @@ impl PageSpan {
     pub fn intersects(&self, with: PageSpan) -> bool {
         !self.intersection(with).is_empty()
     }
+    pub fn contains(&self, page: Page) -> bool {
+        self.base <= page && page < self.end()
+    }
     pub fn slice(&self, inner_span: PageSpan) -> (Option<PageSpan>, PageSpan, Option<PageSpan>) {
         (self.before(inner_span), inner_span, self.after(inner_span))
     }
@@ -833,15 +2828,44 @@ impl UserGrants {
             inner: BTreeMap::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET))
                 .collect::<BTreeMap<_, _>>(),
+            reserved: BTreeMap::new(),
+            max_map_count: MAX_MAP_COUNT_DEFAULT,
+            guard_gaps: false,
+            page_states: PageStates::default(),
+            conflict_scans: AtomicUsize::new(0),
+            conflict_iterations: AtomicUsize::new(0),
+        }
+    }
+    /// Current number of grants, and how many grants [`Self::conflicts`] scanned so far.
+    pub fn stats(&self) -> GrantStats {
+        GrantStats {
+            entries: self.inner.len(),
+            conflict_scans: self.conflict_scans.load(Ordering::Relaxed),
+            conflict_iterations: self.conflict_iterations.load(Ordering::Relaxed),
         }
     }
     /// Returns the grant, if any, which occupies the specified page
     pub fn contains(&self, page: Page) -> Option<(Page, &GrantInfo)> {
+        self.containing_span(page).map(|(span, info)| (span.base, info))
+    }
+    /// Returns the span and info of the grant containing `page`, if any. The returned span is
+    /// guaranteed to contain `page`, so `page.offset_from(span.base)` is always in bounds.
+    pub fn containing_span(&self, page: Page) -> Option<(PageSpan, &GrantInfo)> {
         self.inner
             .range(..=page)
             .next_back()
-            .filter(|(base, info)| (**base..base.next_by(info.page_count)).contains(&page))
-            .map(|(base, info)| (*base, info))
+            .map(|(base, info)| (PageSpan::new(*base, info.page_count), info))
+            .filter(|(span, _)| span.contains(page))
+    }
+    /// Like [`Self::containing_span`], but allows modifying the grant info in place. Changing the
+    /// page count must go through [`Self::remove`] and [`Self::insert`] instead, as the holes need
+    /// to be kept in sync.
+    pub fn containing_span_mut(&mut self, page: Page) -> Option<(PageSpan, &mut GrantInfo)> {
+        self.inner
+            .range_mut(..=page)
+            .next_back()
+            .map(|(base, info)| (PageSpan::new(*base, info.page_count), info))
+            .filter(|(span, _)| span.contains(page))
     }
     /// Returns an iterator over all grants that occupy some part of the
     /// requested region
@@ -854,11 +2878,58 @@ impl UserGrants {
             .map(|(base, info)| PageSpan::new(base, info.page_count))
             .unwrap_or(span);
 
+        self.conflict_scans.fetch_add(1, Ordering::Relaxed);
+
         self.inner
             .range(start_span.base..)
             .take_while(move |(base, info)| PageSpan::new(**base, info.page_count).intersects(span))
+            .inspect(|_| {
+                self.conflict_iterations.fetch_add(1, Ordering::Relaxed);
+            })
             .map(|(base, info)| (*base, info))
     }
+    /// Splits the grants overlapping `span` at its boundaries, and calls `f` with each part within
+    /// the span in address order, after putting back the parts outside of it. `f` decides what
+    /// becomes of the part it is given, e.g. reinserting it after modifying it, or unmapping it.
+    /// The grants are unpinned first if `unpin` is set.
+    ///
+    /// The grants must have been validated with [`GrantInfo::can_extract`] beforehand.
+    pub fn for_each_conflict_split(
+        &mut self,
+        span: PageSpan,
+        unpin: bool,
+        mut f: impl FnMut(&mut Self, Grant),
+    ) {
+        let mut remaining = span;
+
+        // Look up the next grant every time rather than collecting them upfront, as reinserting
+        // a part may merge it with the next grant.
+        while !remaining.is_empty() {
+            let Some((grant_base, _)) = self.conflicts(remaining).next() else {
+                break;
+            };
+            let mut grant = self
+                .remove(grant_base)
+                .expect("conflicting grant cannot disappear");
+            if unpin {
+                grant.info.unpin();
+            }
+            let intersection = grant.span().intersection(remaining);
+
+            let (before, middle, after) = grant
+                .extract(intersection)
+                .expect("conflicting grant must intersect the span");
+            remaining = PageSpan::between(intersection.end(), span.end());
+
+            if let Some(before) = before {
+                self.insert(before);
+            }
+            if let Some(after) = after {
+                self.insert(after);
+            }
+            f(self, middle);
+        }
+    }
     // TODO: DEDUPLICATE CODE!
     pub fn conflicts_mut(
         &mut self,
@@ -877,15 +2948,30 @@ impl UserGrants {
             .take_while(move |(base, info)| PageSpan::new(**base, info.page_count).intersects(span))
             .map(|(base, info)| (*base, info))
     }
-    /// Return a free region with the specified size
+    /// Return a free region with the specified size, starting at `near` if the region there is
+    /// free and not below `min`.
     // TODO: Alignment (x86_64: 4 KiB, 2 MiB, or 1 GiB).
-    // TODO: Support finding grant close to a requested address?
     pub fn find_free_near(
         &self,
         min: usize,
         page_count: usize,
-        _near: Option<Page>,
+        near: Option<Page>,
+    ) -> Option<PageSpan> {
+        self.find_free_aligned(min, page_count, PAGE_SIZE, near)
+    }
+    /// Like [`find_free_near`](Self::find_free_near), but the returned span starts at a multiple
+    /// of `align`, a power of two no smaller than the page size, e.g. so that it can later be
+    /// mapped using huge pages. Holes too small to fit an aligned span are skipped, and `near` is
+    /// only used if it is aligned.
+    pub fn find_free_aligned(
+        &self,
+        min: usize,
+        page_count: usize,
+        align: usize,
+        near: Option<Page>,
     ) -> Option<PageSpan> {
+        debug_assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+
         // Get first available hole, but do reserve the page starting from zero as most compiled
         // languages cannot handle null pointers safely even if they point to valid memory. If an
         // application absolutely needs to map the 0th page, they will have to do so explicitly via
@@ -893,34 +2979,58 @@ impl UserGrants {
         // TODO: Allow explicitly allocating guard pages? Perhaps using mprotect or mmap with
         // PROT_NONE?
 
-        let (hole_start, _hole_size) = self
-            .holes
-            .iter()
-            .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
-            .find(|(hole_offset, hole_size)| {
-                let avail_size =
-                    if hole_offset.data() <= min && min <= hole_offset.data() + **hole_size {
-                        **hole_size - (min - hole_offset.data())
-                    } else {
-                        **hole_size
-                    };
-                page_count * PAGE_SIZE <= avail_size
-            })?;
+        // With guard gaps, keep a page free on either side of the new grant, unless the hole
+        // starts or ends at the bounds of the address space, where there is no neighbor.
+        let guard = if self.guard_gaps { PAGE_SIZE } else { 0 };
+        let size = page_count * PAGE_SIZE;
+
+        // The lowest aligned start within a hole, not below `from`, that the new grant fits at.
+        let fit = |hole_offset: VirtualAddress, hole_size: usize, from: usize| {
+            let hole_end = hole_offset.data() + hole_size;
+            let lead = if hole_offset.data() == 0 { 0 } else { guard };
+            let trail = if hole_end == crate::USER_END_OFFSET {
+                0
+            } else {
+                guard
+            };
+
+            let start =
+                cmp::max(hole_offset.data() + lead, from).checked_next_multiple_of(align)?;
+            let end = start.checked_add(size)?.checked_add(trail)?;
+            (end <= hole_end).then_some(start)
+        };
+
+        let near = near
+            .map(|near| near.start_address())
+            .filter(|near| near.data() >= min && near.data() % align == 0);
+        let near_start = near.and_then(|near| {
+            let (hole_offset, hole_size) = self.holes.range(..=near).next_back()?;
+            fit(*hole_offset, *hole_size, near.data()).filter(|&start| start == near.data())
+        });
+
+        let start = match near_start {
+            Some(start) => start,
+            None => self
+                .holes
+                .iter()
+                .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
+                .find_map(|(hole_offset, hole_size)| fit(*hole_offset, *hole_size, min))?,
+        };
         // Create new region
         Some(PageSpan::new(
-            Page::containing_address(VirtualAddress::new(cmp::max(hole_start.data(), min))),
+            Page::containing_address(VirtualAddress::new(start)),
             page_count,
         ))
     }
     pub fn find_free(&self, min: usize, page_count: usize) -> Option<PageSpan> {
         self.find_free_near(min, page_count, None)
     }
-    fn reserve(&mut self, base: Page, page_count: usize) {
+    fn reserve(holes: &mut BTreeMap<VirtualAddress, usize>, base: Page, page_count: usize) {
         let start_address = base.start_address();
         let size = page_count * PAGE_SIZE;
         let end_address = base.start_address().add(size);
 
-        let previous_hole = self.holes.range_mut(..start_address).next_back();
+        let previous_hole = holes.range_mut(..start_address).next_back();
 
         if let Some((hole_offset, hole_size)) = previous_hole {
             let prev_hole_end = hole_offset.data() + *hole_size;
@@ -937,16 +3047,15 @@ impl UserGrants {
             }
             if prev_hole_end > end_address.data() {
                 // The grant is splitting this hole in two, so insert the new one at the end.
-                self.holes
-                    .insert(end_address, prev_hole_end - end_address.data());
+                holes.insert(end_address, prev_hole_end - end_address.data());
             }
         }
 
         // Next hole
-        if let Some(hole_size) = self.holes.remove(&start_address) {
+        if let Some(hole_size) = holes.remove(&start_address) {
             let remainder = hole_size - size;
             if remainder > 0 {
-                self.holes.insert(end_address, remainder);
+                holes.insert(end_address, remainder);
             }
         }
     }
@@ -980,7 +3089,9 @@ impl UserGrants {
             .conflicts(PageSpan::new(grant.base, grant.info.page_count))
             .next()
             .is_none());
-        self.reserve(grant.base, grant.info.page_count);
+        Self::for_each_unreserved(&self.reserved, grant.span(), |part| {
+            Self::reserve(&mut self.holes, part.base, part.count)
+        });
 
         let before_region = self
             .inner
@@ -990,7 +3101,7 @@ impl UserGrants {
                 base.next_by(info.page_count) == grant.base
                     && info.can_be_merged_if_adjacent(&grant.info)
             })
-            .map(|(base, info)| (*base, info.page_count));
+            .map(|(base, info)| (*base, info.page_count, info.access_generation));
 
         let after_region = self
             .inner
@@ -1000,16 +3111,18 @@ impl UserGrants {
                 **base == grant.base.next_by(grant.info.page_count)
                     && info.can_be_merged_if_adjacent(&grant.info)
             })
-            .map(|(base, info)| (*base, info.page_count));
+            .map(|(base, info)| (*base, info.page_count, info.access_generation));
 
-        if let Some((before_base, before_page_count)) = before_region {
+        if let Some((before_base, before_page_count, before_generation)) = before_region {
             grant.base = before_base;
             grant.info.page_count += before_page_count;
+            grant.info.access_generation = grant.info.access_generation.max(before_generation);
 
             core::mem::forget(self.inner.remove(&before_base));
         }
-        if let Some((after_base, after_page_count)) = after_region {
+        if let Some((after_base, after_page_count, after_generation)) = after_region {
             grant.info.page_count += after_page_count;
+            grant.info.access_generation = grant.info.access_generation.max(after_generation);
 
             core::mem::forget(self.inner.remove(&after_base));
         }
@@ -1018,15 +3131,195 @@ impl UserGrants {
     }
     pub fn remove(&mut self, base: Page) -> Option<Grant> {
         let info = self.inner.remove(&base)?;
-        Self::unreserve(&mut self.holes, base, info.page_count);
+        // Parts inside reserved ranges are not given back to the holes.
+        Self::for_each_unreserved(&self.reserved, PageSpan::new(base, info.page_count), |part| {
+            Self::unreserve(&mut self.holes, part.base, part.count)
+        });
         Some(Grant { base, info })
     }
+    /// Calls `f` for each part of `span` not covered by a reserved range.
+    fn for_each_unreserved(
+        reserved: &BTreeMap<Page, usize>,
+        span: PageSpan,
+        mut f: impl FnMut(PageSpan),
+    ) {
+        let first = reserved
+            .range(..=span.base)
+            .next_back()
+            .map_or(span.base, |(base, _)| *base);
+        let mut cursor = span.base;
+
+        for (base, page_count) in reserved.range(first..span.end()) {
+            let reserved_span = PageSpan::new(*base, *page_count);
+            if !reserved_span.intersects(span) {
+                continue;
+            }
+            if reserved_span.base > cursor {
+                f(PageSpan::between(cursor, reserved_span.base));
+            }
+            cursor = cmp::max(cursor, reserved_span.end());
+        }
+        if cursor < span.end() {
+            f(PageSpan::between(cursor, span.end()));
+        }
+    }
+    /// Returns the parts of `span` not occupied by any grant.
+    fn unoccupied_parts(&self, span: PageSpan) -> Vec<PageSpan> {
+        let mut parts = Vec::new();
+        let mut cursor = span.base;
+
+        for (base, info) in self.conflicts(span) {
+            if base > cursor {
+                parts.push(PageSpan::between(cursor, base));
+            }
+            cursor = cmp::max(cursor, base.next_by(info.page_count));
+        }
+        if cursor < span.end() {
+            parts.push(PageSpan::between(cursor, span.end()));
+        }
+        parts
+    }
+    /// Excludes `span` from the regions handed out by [`find_free`](Self::find_free), without
+    /// creating a grant. Existing grants within the span are unaffected, and grants can still be
+    /// placed there explicitly, but once unmapped, their pages remain reserved until
+    /// [`unreserve_range`](Self::unreserve_range) is called.
+    ///
+    /// Fails with EEXIST if the span overlaps an already reserved range.
+    pub fn reserve_range(&mut self, span: PageSpan) -> Result<()> {
+        if span.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+        if self.reserved_conflicts(span).next().is_some() {
+            return Err(Error::new(EEXIST));
+        }
+        for part in self.unoccupied_parts(span) {
+            Self::reserve(&mut self.holes, part.base, part.count);
+        }
+        self.reserved.insert(span.base, span.count);
+        Ok(())
+    }
+    /// Gives back (part of) a range previously reserved using
+    /// [`reserve_range`](Self::reserve_range). The span must lie within a single reserved range,
+    /// which is split if necessary.
+    pub fn unreserve_range(&mut self, span: PageSpan) -> Result<()> {
+        let (reserved_base, reserved_count) = self
+            .reserved
+            .range(..=span.base)
+            .next_back()
+            .map(|(base, count)| (*base, *count))
+            .filter(|(base, count)| !span.is_empty() && span.end() <= base.next_by(*count))
+            .ok_or(Error::new(EINVAL))?;
+
+        let (before, _, after) = PageSpan::new(reserved_base, reserved_count).slice(span);
+        self.reserved.remove(&reserved_base);
+        for remaining in [before, after].into_iter().flatten() {
+            self.reserved.insert(remaining.base, remaining.count);
+        }
+        for part in self.unoccupied_parts(span) {
+            Self::unreserve(&mut self.holes, part.base, part.count);
+        }
+        Ok(())
+    }
+    pub fn reserved_ranges(&self) -> impl Iterator<Item = PageSpan> + '_ {
+        self.reserved
+            .iter()
+            .map(|(base, count)| PageSpan::new(*base, *count))
+    }
+    /// Returns a snapshot of the free regions that `find_free` allocates from, as pairs of start
+    /// address and size in bytes, in address order. Meant for debugging allocation failures, e.g.
+    /// telling a full address space apart from a fragmented one.
+    pub fn dump_holes(&self) -> Vec<(VirtualAddress, usize)> {
+        self.holes
+            .iter()
+            .map(|(address, size)| (*address, *size))
+            .collect()
+    }
+    /// Returns the total size of the free regions in bytes.
+    pub fn total_free_bytes(&self) -> usize {
+        self.holes.values().sum()
+    }
+    /// Whether the contents of `page` currently reside in swap, or are being written there.
+    pub fn is_swapped(&self, page: Page) -> bool {
+        self.page_states.get(page).is_some()
+    }
+    pub fn swapped(&self, page: Page) -> Option<Arc<SwappedPage>> {
+        match self.page_states.get(page)? {
+            PageState::Swapped(swapped) => Some(Arc::clone(swapped)),
+            PageState::SwappingOut(_) => None,
+        }
+    }
+    pub fn page_states(&self) -> &PageStates {
+        &self.page_states
+    }
+    fn reserved_conflicts(&self, span: PageSpan) -> impl Iterator<Item = PageSpan> + '_ {
+        let first = self
+            .reserved
+            .range(..=span.base)
+            .next_back()
+            .map_or(span.base, |(base, _)| *base);
+
+        self.reserved
+            .range(first..span.end())
+            .map(|(base, count)| PageSpan::new(*base, *count))
+            .filter(move |reserved_span| reserved_span.intersects(span))
+    }
     pub fn iter(&self) -> impl Iterator<Item = (Page, &GrantInfo)> + '_ {
         self.inner.iter().map(|(base, info)| (*base, info))
     }
     pub fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    /// Fails with ENOMEM if adding `additional` grants would exceed the maximum grant count.
+    /// Operations that do not add any grants always succeed, even above the limit.
+    pub fn check_map_count(&self, additional: usize) -> Result<()> {
+        if additional > 0 && self.len().saturating_add(additional) > self.max_map_count {
+            return Err(Error::new(ENOMEM));
+        }
+        Ok(())
+    }
+    /// Returns how many grants are added when splitting the grants `regions` at the boundaries of
+    /// `span`, before any merging.
+    fn split_count(regions: &[PageSpan], span: PageSpan) -> usize {
+        regions
+            .iter()
+            .map(|region| {
+                usize::from(region.base < span.base) + usize::from(region.end() > span.end())
+            })
+            .sum()
+    }
+    /// Merges all adjacent grants that can be merged, in a single pass, returning the number of
+    /// grants eliminated. This catches what the merging in `insert` missed, for example after
+    /// mprotect has split grants that later regain identical flags.
+    pub fn compact(&mut self) -> usize {
+        let mut eliminated = 0;
+        let mut cursor = self.inner.keys().next().copied();
+
+        while let Some(base) = cursor {
+            let info = &self.inner[&base];
+            let end = base.next_by(info.page_count);
+
+            match self.inner.get(&end) {
+                Some(next) if info.can_be_merged_if_adjacent(next) => {
+                    let next_page_count = next.page_count;
+                    let next_generation = next.access_generation;
+                    core::mem::forget(self.inner.remove(&end));
+
+                    let info = self
+                        .inner
+                        .get_mut(&base)
+                        .expect("grant cannot disappear during compaction");
+                    info.page_count += next_page_count;
+                    info.access_generation = info.access_generation.max(next_generation);
+                    eliminated += 1;
+                }
+                _ => cursor = self.inner.range(end..).next().map(|(base, _)| *base),
+            }
+        }
+        eliminated
+    }
     pub fn into_iter(self) -> impl Iterator<Item = Grant> {
         self.inner
             .into_iter()
@@ -1038,11 +3331,38 @@ impl UserGrants {
 pub struct GrantInfo {
     page_count: usize,
     flags: PageFlags<RmmA>,
+    // Every protection the grant has had since it was created, see `AddrSpace::strict_wx`.
+    ever_flags: PageFlags<RmmA>,
     // TODO: Rename to unmapped?
     mapped: bool,
+    clone_strategy: CloneStrategy,
+    // Value of `access_generation()` at the last fault in this grant, see `reclaim_freeable`.
+    access_generation: u64,
     pub(crate) provider: Provider,
 }
 
+/// What happens to a grant when its address space is cloned (the "fork" analogue).
+///
+/// Every grant is created with the default for its provider, but owned grants can be switched to
+/// another strategy, see [`AddrSpaceWrapper::set_clone_strategy`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloneStrategy {
+    /// Share all pages copy-on-write. Default for private anonymous and file mappings. Physically
+    /// borrowed grants that are ordinary memory rather than MMIO can also use this, so that the
+    /// clone reads the same memory until it first writes a page, which then gets its own copy.
+    Cow,
+    /// Keep referring to the same memory from both address spaces. Default for MAP_SHARED and
+    /// physically borrowed mappings.
+    Share,
+    /// Copy all present pages right away, rather than on the first write. Avoids CoW faults in
+    /// both address spaces, for memory known to be written soon after the clone. Physically
+    /// borrowed grants that are ordinary memory rather than MMIO can also use this, so that the
+    /// clone gets its own copy instead of sharing the underlying memory.
+    EagerCopy,
+    /// Leave the range unmapped in the new address space (MADV_DONTFORK).
+    DontInherit,
+}
+
 /// Enumeration of various types of grants.
 #[derive(Debug)]
 pub enum Provider {
@@ -1067,7 +3387,11 @@ pub enum Provider {
     /// The grant is not owned, but borrowed from physical memory frames that do not belong to the
     /// frame allocator. The kernel will forbid borrowing any physical memory range, that the
     /// memory map has indicated is regular allocatable RAM.
-    PhysBorrowed { base: Frame },
+    ///
+    /// If `private`, the grant is a copy of such a grant made when cloning it with
+    /// [`CloneStrategy::Cow`]. Its pages map the borrowed frames read-only until first written,
+    /// which copies them into owned frames that have a PageInfo, unlike the borrowed ones.
+    PhysBorrowed { base: Frame, private: bool },
 
     /// The memory is borrowed directly from another address space.
     External {
@@ -1078,6 +3402,10 @@ pub enum Provider {
 
     /// The memory is MAP_SHARED borrowed from a scheme.
     ///
+    /// The frames are shared with the scheme, which is thus responsible for zeroing the part of
+    /// the last page past the end of the file, and for not writing it back to the file. The
+    /// kernel cannot do so itself, as the file may have grown in the meantime.
+    ///
     /// Since the address space is not tracked here, all nonpresent pages must be present before
     /// the fmap operation completes, unless MAP_LAZY is specified. They are tracked using
     /// PageInfo, or treated as PhysBorrowed if any frame lacks a PageInfo.
@@ -1085,6 +3413,11 @@ pub enum Provider {
         file_ref: GrantFileRef,
         pin_refcount: usize,
     },
+
+    /// The grant is never backed by memory, and every access to it traps. Accesses are decoded
+    /// and forwarded to a userspace fault handler, which completes them on behalf of the faulting
+    /// context, see [`super::userfault`].
+    Emulated { handler: Arc<UserFaultHandler> },
 }
 
 #[derive(Debug)]
@@ -1099,6 +3432,26 @@ pub struct GrantFileRef {
     pub base_offset: usize,
 }
 
+/// Take a reference to `frame`, which a scheme has provided in response to an fmap request, on
+/// behalf of the fmap grant it is going to be mapped into. Unmapping the page drops it again, as
+/// does [`release_fmap_frame`] if the frame ends up not being mapped.
+///
+/// Must be called while the scheme's address space is locked, so that the scheme cannot unmap
+/// the frame in the meantime.
+pub fn take_fmap_frame(frame: Frame) -> Result<Frame> {
+    // Frames without a PageInfo are treated as PhysBorrowed, see Provider::FmapBorrowed.
+    if let Some(page_info) = get_page_info(frame) {
+        page_info
+            .add_ref(RefKind::Shared)
+            .map_err(|_| Error::new(EINVAL))?;
+    }
+    Ok(frame)
+}
+/// Drop the reference taken by [`take_fmap_frame`], for a frame that was not mapped.
+pub fn release_fmap_frame(frame: Frame) {
+    handle_free_action(frame, None);
+}
+
 impl Grant {
     // TODO: PageCount newtype, to avoid confusion between bytes and pages?
 
@@ -1140,7 +3493,10 @@ impl Grant {
             info: GrantInfo {
                 page_count: 1,
                 flags,
+                ever_flags: flags,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::Share,
                 provider: Provider::AllocatedShared {
                     is_pinned_userscheme_borrow: is_pinned,
                 },
@@ -1183,11 +3539,36 @@ impl Grant {
             info: GrantInfo {
                 page_count: span.count,
                 flags,
+                ever_flags: flags,
                 mapped: true,
-                provider: Provider::PhysBorrowed { base: phys },
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::Share,
+                provider: Provider::PhysBorrowed {
+                    base: phys,
+                    private: false,
+                },
             },
         })
     }
+    /// A grant of which every access is emulated by `handler`. Nothing is mapped.
+    pub fn emulated(
+        span: PageSpan,
+        flags: PageFlags<RmmA>,
+        handler: Arc<UserFaultHandler>,
+    ) -> Grant {
+        Grant {
+            base: span.base,
+            info: GrantInfo {
+                page_count: span.count,
+                flags,
+                ever_flags: flags,
+                mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::Share,
+                provider: Provider::Emulated { handler },
+            },
+        }
+    }
     pub fn zeroed_phys_contiguous(
         span: PageSpan,
         flags: PageFlags<RmmA>,
@@ -1224,7 +3605,10 @@ impl Grant {
             info: GrantInfo {
                 page_count: span.count,
                 flags,
+                ever_flags: flags,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::DontInherit,
                 provider: Provider::Allocated {
                     cow_file_ref: None,
                     phys_contiguous: true,
@@ -1268,7 +3652,14 @@ impl Grant {
             info: GrantInfo {
                 page_count: span.count,
                 flags,
+                ever_flags: flags,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: if shared {
+                    CloneStrategy::Share
+                } else {
+                    CloneStrategy::Cow
+                },
                 provider: if shared {
                     Provider::AllocatedShared {
                         is_pinned_userscheme_borrow: false,
@@ -1299,7 +3690,10 @@ impl Grant {
             info: GrantInfo {
                 page_count: src_info.page_count,
                 flags: src_info.flags,
+                ever_flags: src_info.flags,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::Share,
                 provider: Provider::External {
                     src_base,
                     address_space: src_address_space_lock,
@@ -1322,7 +3716,7 @@ impl Grant {
             let mut guard = src.addr_space_guard;
             let mut src_addrspace = &mut *guard;
             let mut src_flusher_state =
-                Flusher::with_cpu_set(&mut src_addrspace.used_by, &lock.tlb_ack).detach();
+                Flusher::with_cpu_set(&mut src_addrspace.used_by, &lock.tlb_pending).detach();
             for dst_page in span.pages() {
                 let src_page = src.src_base.next_by(dst_page.offset_from(span.base));
 
@@ -1449,7 +3843,10 @@ impl Grant {
             info: GrantInfo {
                 page_count: span.count,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::DontInherit,
                 flags: new_flags,
+                ever_flags: new_flags,
                 provider: Provider::FmapBorrowed {
                     file_ref,
                     pin_refcount: 0,
@@ -1475,10 +3872,20 @@ impl Grant {
         _allow_phys: bool,
         is_pinned_userscheme_borrow: bool,
     ) -> Result<Grant> {
+        // The source lock is held by the caller.
+        if src_address_space_lock.is_dying() {
+            return Err(Error::new(ESRCH));
+        }
+
         let flags = page_flags(map_flags);
 
         const MAX_EAGER_PAGES: usize = 4096;
 
+        if src_base.checked_next_by(page_count).is_none()
+            || dst_base.checked_next_by(page_count).is_none()
+        {
+            return Err(Error::new(EINVAL));
+        }
         let src_span = PageSpan::new(src_base, page_count);
         let mut prev_span = None;
 
@@ -1511,6 +3918,15 @@ impl Grant {
             if !src_grant.can_have_flags(map_flags) {
                 return Err(Error::new(EPERM));
             }
+            // The borrowed frames of private copies of physical memory would be indistinguishable
+            // from the copied ones in the borrowing address space. Emulated grants have no frames
+            // to borrow at all.
+            if matches!(
+                src_grant.provider,
+                Provider::PhysBorrowed { private: true, .. } | Provider::Emulated { .. }
+            ) {
+                return Err(Error::new(EINVAL));
+            }
 
             if let Provider::FmapBorrowed {
                 ref mut pin_refcount,
@@ -1579,7 +3995,10 @@ impl Grant {
             info: GrantInfo {
                 page_count,
                 flags,
+                ever_flags: flags,
                 mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::Share,
                 provider: Provider::External {
                     address_space: src_address_space_lock,
                     src_base,
@@ -1599,21 +4018,73 @@ impl Grant {
         dst_flusher: &mut impl GenericFlusher,
         mode: CopyMappingsMode,
     ) -> Result<Grant, Enomem> {
-        let (allows_writable, rk) = match mode {
-            CopyMappingsMode::Owned { .. } => (false, RefKind::Cow),
-            CopyMappingsMode::Borrowed => (true, RefKind::Shared),
+        let (allows_writable, rk, clone_strategy) = match mode {
+            CopyMappingsMode::Owned { .. } => (false, RefKind::Cow, CloneStrategy::Cow),
+            CopyMappingsMode::Borrowed => (true, RefKind::Shared, CloneStrategy::Share),
         };
 
-        // TODO: Page table iterator
+        // Present pages can never be more permissive than the grant itself, so if the grant is not
+        // writable, none of the source pages need to be write-protected (and shot down) before
+        // being shared CoW. This is the common case for text and rodata segments, where this
+        // avoids touching the source page tables altogether. Should the grant later be mprotected
+        // writable, the page fault handler will CoW the pages as usual, since their refcounts
+        // still indicate CoW sharing.
+        //
+        // The refcount of every present page is still incremented, one page at a time. Each frame
+        // is freed based on its own refcount, and it is also what makes a later write copy the
+        // page, so sharing the grant by reference instead would need the frames to be owned by
+        // something shared between the address spaces rather than by the grants themselves.
+        let src_is_readonly = !flags.has_write();
+
+        // Writable private memory that is entirely zero after fork, is typically memory that was
+        // never written in the first place, but only prefaulted, such as large heap reservations.
+        // Scanning every page only pays off for grants that are large enough to plausibly contain
+        // such pages, whereas small grants (stacks and data segments) are almost always dirty.
+        const ZEROED_DEDUP_MIN_PAGES: usize = 64;
+        let dedup_zeroed =
+            matches!(rk, RefKind::Cow) && !src_is_readonly && page_count >= ZEROED_DEDUP_MIN_PAGES;
+
+        let mut src_cursor = LeafCursor::new();
+        let mut dst_cursor = LeafCursor::new();
+
+        // A page can only be found zeroed once no thread can write to it anymore. Otherwise, a
+        // write to a page after it was checked could be missed by the child, while a later write
+        // to another page that was not yet write-protected would be seen, which is not a snapshot.
+        if dedup_zeroed {
+            for page_idx in 0..page_count {
+                let src_page = src_base.next_by(page_idx).start_address();
+                if let Some(phys) = unsafe { src_cursor.revoke_write(src_mapper, src_page) } {
+                    let frame = Frame::containing(phys);
+                    src_flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+                }
+            }
+            src_flusher.flush();
+        }
+
         for page_idx in 0..page_count {
             let src_page = src_base.next_by(page_idx);
             let dst_page = dst_base.next_by(page_idx).start_address();
 
             let src_frame = match rk {
+                RefKind::Cow if src_is_readonly || dedup_zeroed => {
+                    let Some((phys, _)) = src_cursor.translate(src_mapper, src_page.start_address())
+                    else {
+                        continue;
+                    };
+                    let frame = Frame::containing(phys);
+                    // If the page is entirely zero, the new address space can map the shared
+                    // zeroed frame instead. The source frame then stays exclusively owned, and is
+                    // made writable again by the next write fault.
+                    if dedup_zeroed && frame_is_zeroed(frame) {
+                        the_zeroed_frame().0
+                    } else {
+                        frame
+                    }
+                }
                 RefKind::Cow => {
-                    let Some((_, phys, flush)) = (unsafe {
-                        src_mapper.remap_with(src_page.start_address(), |flags| flags.write(false))
-                    }) else {
+                    let Some(phys) =
+                        (unsafe { src_cursor.revoke_write(src_mapper, src_page.start_address()) })
+                    else {
                         // Page is not mapped, let the page fault handler take care of that (initializing
                         // it to zero).
                         //
@@ -1621,19 +4092,18 @@ impl Grant {
                         // for read-only)?
                         continue;
                     };
-                    unsafe {
-                        flush.ignore();
-                    }
                     let frame = Frame::containing(phys);
                     src_flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
                     frame
                 }
                 RefKind::Shared => {
-                    if let Some((phys, _)) = src_mapper.translate(src_page.start_address()) {
+                    if let Some((phys, _)) =
+                        src_cursor.translate(src_mapper, src_page.start_address())
+                    {
                         Frame::containing(phys)
                     } else {
                         // TODO: Omit the unnecessary subsequent add_ref call.
-                        let new_frame = init_frame(RefCount::One).expect("TODO: handle OOM");
+                        let new_frame = init_frame(RefCount::One, None).expect("TODO: handle OOM");
                         let src_flush = unsafe {
                             src_mapper
                                 .map_phys(src_page.start_address(), new_frame.base(), flags)
@@ -1710,41 +4180,199 @@ impl Grant {
                 }
             };
 
-            let Some(map_result) = (unsafe {
-                dst_mapper.map_phys(
+            let mapped = unsafe {
+                dst_cursor.map_phys(
+                    dst_mapper,
                     dst_page,
                     src_frame.base(),
                     flags.write(flags.has_write() && allows_writable),
                 )
-            }) else {
+            };
+            if !mapped {
+                break;
+            }
+
+            dst_flusher.queue(src_frame, None, TlbShootdownActions::NEW_MAPPING);
+        }
+
+        Ok(Grant {
+            base: dst_base,
+            info: GrantInfo {
+                page_count,
+                flags,
+                ever_flags: flags,
+                mapped: true,
+                access_generation: access_generation(),
+                clone_strategy,
+                provider: match mode {
+                    CopyMappingsMode::Owned { cow_file_ref } => Provider::Allocated {
+                        cow_file_ref,
+                        phys_contiguous: false,
+                    },
+                    CopyMappingsMode::Borrowed => Provider::AllocatedShared {
+                        is_pinned_userscheme_borrow: false,
+                    },
+                },
+            },
+        })
+    }
+    /// Like [`copy_mappings`](Self::copy_mappings) in owned mode, but copies every present source
+    /// page into a new frame up front, leaving the source untouched. Nonpresent pages are left for
+    /// the page fault handler to populate, as usual.
+    pub fn copy_eagerly(
+        src_base: Page,
+        dst_base: Page,
+        page_count: usize,
+        flags: PageFlags<RmmA>,
+        src_mapper: &mut PageMapper,
+        dst_mapper: &mut PageMapper,
+        dst_flusher: &mut impl GenericFlusher,
+        cow_file_ref: Option<GrantFileRef>,
+    ) -> Result<Grant, Enomem> {
+        let mut src_cursor = LeafCursor::new();
+        let mut dst_cursor = LeafCursor::new();
+
+        for page_idx in 0..page_count {
+            let src_page = src_base.next_by(page_idx);
+            let dst_page = dst_base.next_by(page_idx).start_address();
+
+            let Some((phys, _)) = src_cursor.translate(src_mapper, src_page.start_address()) else {
+                continue;
+            };
+
+            let new_frame = init_frame(RefCount::One, None).map_err(|_| Enomem)?;
+            unsafe {
+                copy_frame_to_frame_directly(new_frame, Frame::containing(phys));
+            }
+
+            let mapped =
+                unsafe { dst_cursor.map_phys(dst_mapper, dst_page, new_frame.base(), flags) };
+            if !mapped {
+                unsafe {
+                    deallocate_frame(new_frame);
+                }
+                return Err(Enomem);
+            }
+
+            dst_flusher.queue(new_frame, None, TlbShootdownActions::NEW_MAPPING);
+        }
+
+        Ok(Grant {
+            base: dst_base,
+            info: GrantInfo {
+                page_count,
+                flags,
+                ever_flags: flags,
+                mapped: true,
+                access_generation: access_generation(),
+                clone_strategy: CloneStrategy::EagerCopy,
+                provider: Provider::Allocated {
+                    cow_file_ref,
+                    phys_contiguous: false,
+                },
+            },
+        })
+    }
+    /// Create a copy at `span` of the physically borrowed memory starting at `base`, which
+    /// `src_mapper` maps at the same pages. Used when cloning physically borrowed grants that are
+    /// ordinary memory rather than MMIO, and are therefore meant to diverge.
+    ///
+    /// With [`CloneStrategy::EagerCopy`], the result is an owned grant, and everything is copied
+    /// right away. With [`CloneStrategy::Cow`], it is a private physically borrowed grant, whose
+    /// pages keep referring to the borrowed frames until first written, see
+    /// [`Provider::PhysBorrowed`]. Pages already copied that way in the source are always copied
+    /// right away, since the owned frames of such grants are never shared.
+    pub fn copy_from_phys(
+        base: Frame,
+        span: PageSpan,
+        flags: PageFlags<RmmA>,
+        strategy: CloneStrategy,
+        src_mapper: &PageMapper,
+        dst_mapper: &mut PageMapper,
+        dst_flusher: &mut impl GenericFlusher,
+    ) -> Result<Grant, Enomem> {
+        let eager = strategy == CloneStrategy::EagerCopy;
+        let mut cursor = LeafCursor::new();
+        let mut failed = false;
+
+        for (i, page) in span.pages().enumerate() {
+            let src_frame = src_mapper
+                .translate(page.start_address())
+                .map(|(phys, _)| Frame::containing(phys));
+            let owned = src_frame.is_some_and(|frame| get_page_info(frame).is_some());
+            if !eager && !owned {
+                // Faulted in from the borrowed frame on the first access.
+                continue;
+            }
+
+            let Ok(new_frame) = init_frame(RefCount::One, None) else {
+                failed = true;
                 break;
             };
             unsafe {
-                map_result.ignore();
+                copy_frame_to_frame_directly(new_frame, src_frame.unwrap_or(base.next_by(i)));
+            }
+
+            let mapped = unsafe {
+                cursor.map_phys(dst_mapper, page.start_address(), new_frame.base(), flags)
+            };
+            if !mapped {
+                unsafe {
+                    deallocate_frame(new_frame);
+                }
+                failed = true;
+                break;
+            }
+
+            dst_flusher.queue(new_frame, None, TlbShootdownActions::NEW_MAPPING);
+        }
+
+        if failed {
+            // No grant owns the frames copied so far yet, so free them here.
+            for page in span.pages() {
+                let Some((phys, _, flush)) =
+                    (unsafe { dst_mapper.unmap_phys(page.start_address(), true) })
+                else {
+                    continue;
+                };
+                unsafe {
+                    flush.ignore();
+                }
+                dst_flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
             }
-
-            dst_flusher.queue(src_frame, None, TlbShootdownActions::NEW_MAPPING);
+            return Err(Enomem);
         }
 
         Ok(Grant {
-            base: dst_base,
+            base: span.base,
             info: GrantInfo {
-                page_count,
+                page_count: span.count,
                 flags,
+                ever_flags: flags,
                 mapped: true,
-                provider: match mode {
-                    CopyMappingsMode::Owned { cow_file_ref } => Provider::Allocated {
-                        cow_file_ref,
+                access_generation: access_generation(),
+                clone_strategy: strategy,
+                provider: if eager {
+                    Provider::Allocated {
+                        cow_file_ref: None,
                         phys_contiguous: false,
-                    },
-                    CopyMappingsMode::Borrowed => Provider::AllocatedShared {
-                        is_pinned_userscheme_borrow: false,
-                    },
+                    }
+                } else {
+                    Provider::PhysBorrowed {
+                        base,
+                        private: true,
+                    }
                 },
             },
         })
     }
-    /// Move a grant between two address spaces.
+    /// Move a grant between two address spaces, or within one if `dst_mapper` is `None`, mapping
+    /// it with `flags` at `dst_base`. The frames themselves stay the same, so that the provider,
+    /// including any file or address space it refers to, is moved along unchanged. Pages not yet
+    /// present are left to be faulted in at the new location.
+    ///
+    /// Pages that were read-only, e.g. still shared copy-on-write, remain so even if `flags`
+    /// permit writing, so that the first write still faults.
     pub fn transfer(
         mut self,
         dst_base: Page,
@@ -1754,6 +4382,7 @@ impl Grant {
         src_flusher: &mut Flusher,
         dst_flusher: &mut impl GenericFlusher,
     ) -> Result<Grant> {
+        assert!(self.info.mapped);
         assert!(!self.info.is_pinned());
 
         for src_page in self.span().pages() {
@@ -1761,8 +4390,7 @@ impl Grant {
 
             let unmap_parents = true;
 
-            // TODO: Validate flags?
-            let Some((phys, _flags, flush)) =
+            let Some((phys, src_flags, flush)) =
                 (unsafe { src_mapper.unmap_phys(src_page.start_address(), unmap_parents) })
             else {
                 continue;
@@ -1774,10 +4402,12 @@ impl Grant {
 
             let dst_mapper = dst_mapper.as_deref_mut().unwrap_or(&mut *src_mapper);
 
+            let dst_flags = flags.write(flags.has_write() && src_flags.has_write());
+
             // TODO: Preallocate to handle OOM?
             let flush = unsafe {
                 dst_mapper
-                    .map_phys(dst_page.start_address(), phys, flags)
+                    .map_phys(dst_page.start_address(), phys, dst_flags)
                     .expect("TODO: OOM")
             };
             unsafe {
@@ -1791,6 +4421,7 @@ impl Grant {
         }
 
         self.base = dst_base;
+        self.info.set_flags(flags);
         Ok(self)
     }
 
@@ -1803,11 +4434,24 @@ impl Grant {
     ) {
         assert!(self.info.mapped);
 
+        let private = matches!(
+            self.info.provider,
+            Provider::PhysBorrowed { private: true, .. }
+        );
+
         for page in self.span().pages() {
             unsafe {
                 // Lazy mappings don't require remapping, as info.flags will be updated.
+                //
+                // Frames still shared copy-on-write stay read-only, so that the first write
+                // still faults and copies them rather than modifying the other copies. The same
+                // goes for the borrowed frames of private copies of physical memory.
                 let Some((old_flags, phys, flush)) =
-                    mapper.remap_with(page.start_address(), |_| flags)
+                    mapper.remap_with_full(page.start_address(), |phys, _| {
+                        let writable = get_page_info(Frame::containing(phys))
+                            .map_or(!private, |info| info.allows_writable());
+                        (phys, flags.write(flags.has_write() && writable))
+                    })
                 else {
                     continue;
                 };
@@ -1821,7 +4465,7 @@ impl Grant {
             }
         }
 
-        self.info.flags = flags;
+        self.info.set_flags(flags);
     }
     #[must_use = "will not unmap itself"]
     pub fn unmap(
@@ -1873,6 +4517,7 @@ impl Grant {
             Provider::External { .. } => None,
             Provider::PhysBorrowed { .. } => None,
             Provider::FmapBorrowed { .. } => Some(true),
+            Provider::Emulated { .. } => None,
         };
 
         if is_phys_contiguous {
@@ -1963,7 +4608,10 @@ impl Grant {
             base: span.base,
             info: GrantInfo {
                 flags: self.info.flags,
+                ever_flags: self.info.ever_flags,
                 mapped: self.info.mapped,
+                access_generation: self.info.access_generation,
+                clone_strategy: self.info.clone_strategy,
                 page_count: span.count,
                 provider: match self.info.provider {
                     Provider::External {
@@ -1984,13 +4632,16 @@ impl Grant {
                     Provider::AllocatedShared { .. } => Provider::AllocatedShared {
                         is_pinned_userscheme_borrow: false,
                     },
-                    Provider::PhysBorrowed { base } => {
-                        Provider::PhysBorrowed { base: base.clone() }
+                    Provider::PhysBorrowed { base, private } => {
+                        Provider::PhysBorrowed { base, private }
                     }
                     Provider::FmapBorrowed { ref file_ref, .. } => Provider::FmapBorrowed {
                         file_ref: file_ref.clone(),
                         pin_refcount: 0,
                     },
+                    Provider::Emulated { ref handler } => Provider::Emulated {
+                        handler: Arc::clone(handler),
+                    },
                 },
             },
         });
@@ -1998,7 +4649,7 @@ impl Grant {
         let middle_page_offset = before_grant.as_ref().map_or(0, |g| g.info.page_count);
 
         match self.info.provider {
-            Provider::PhysBorrowed { ref mut base } => *base = base.next_by(middle_page_offset),
+            Provider::PhysBorrowed { ref mut base, .. } => *base = base.next_by(middle_page_offset),
             Provider::FmapBorrowed {
                 ref mut file_ref, ..
             }
@@ -2010,14 +4661,18 @@ impl Grant {
                 cow_file_ref: None, ..
             }
             | Provider::AllocatedShared { .. }
-            | Provider::External { .. } => (),
+            | Provider::External { .. }
+            | Provider::Emulated { .. } => (),
         }
 
         let after_grant = after_span.map(|span| Grant {
             base: span.base,
             info: GrantInfo {
                 flags: self.info.flags,
+                ever_flags: self.info.ever_flags,
                 mapped: self.info.mapped,
+                access_generation: self.info.access_generation,
+                clone_strategy: self.info.clone_strategy,
                 page_count: span.count,
                 provider: match self.info.provider {
                     Provider::Allocated {
@@ -2049,8 +4704,9 @@ impl Grant {
                         is_pinned_userscheme_borrow: false,
                     },
 
-                    Provider::PhysBorrowed { base } => Provider::PhysBorrowed {
+                    Provider::PhysBorrowed { base, private } => Provider::PhysBorrowed {
                         base: base.next_by(this_span.count),
+                        private,
                     },
                     Provider::FmapBorrowed { ref file_ref, .. } => Provider::FmapBorrowed {
                         file_ref: GrantFileRef {
@@ -2059,6 +4715,9 @@ impl Grant {
                         },
                         pin_refcount: 0,
                     },
+                    Provider::Emulated { ref handler } => Provider::Emulated {
+                        handler: Arc::clone(handler),
+                    },
                 },
             },
         });
@@ -2070,6 +4729,21 @@ impl Grant {
     }
 }
 impl GrantInfo {
+    /// Whether the memory is owned by the grant, i.e. allocated rather than borrowed.
+    pub fn is_owned(&self) -> bool {
+        matches!(
+            self.provider,
+            Provider::Allocated { .. } | Provider::AllocatedShared { .. }
+        )
+    }
+    /// Whether the memory is shared with whatever else maps it (MAP_SHARED), including clones of
+    /// the address space, rather than private and copy-on-write (MAP_PRIVATE).
+    pub fn is_shared(&self) -> bool {
+        !matches!(
+            self.provider,
+            Provider::Allocated { .. } | Provider::PhysBorrowed { private: true, .. }
+        )
+    }
     pub fn is_pinned(&self) -> bool {
         matches!(
             self.provider,
@@ -2115,6 +4789,42 @@ impl GrantInfo {
     pub fn page_count(&self) -> usize {
         self.page_count
     }
+    pub fn clone_strategy(&self) -> CloneStrategy {
+        self.clone_strategy
+    }
+    /// The [`access_generation`] of the last page fault in this grant, or of its creation.
+    pub fn access_generation(&self) -> u64 {
+        self.access_generation
+    }
+    /// Whether the grant can be switched to `strategy`. Copying, whether lazily or eagerly, is only
+    /// possible for memory owned by the grant, and sharing only for memory that is not private.
+    pub fn can_have_clone_strategy(&self, strategy: CloneStrategy) -> bool {
+        match strategy {
+            CloneStrategy::DontInherit => true,
+            // Physically borrowed memory can only be copied if it is not MMIO, which the grant
+            // owner has to know.
+            CloneStrategy::Cow => matches!(
+                self.provider,
+                Provider::Allocated {
+                    phys_contiguous: false,
+                    ..
+                } | Provider::PhysBorrowed { .. }
+            ),
+            CloneStrategy::EagerCopy => matches!(
+                self.provider,
+                Provider::Allocated {
+                    phys_contiguous: false,
+                    ..
+                } | Provider::PhysBorrowed { .. }
+            ),
+            CloneStrategy::Share => !matches!(
+                self.provider,
+                Provider::Allocated { .. }
+                    | Provider::FmapBorrowed { .. }
+                    | Provider::PhysBorrowed { private: true, .. }
+            ),
+        }
+    }
     pub fn can_have_flags(&self, flags: MapFlags) -> bool {
         // TODO: read (some architectures support execute-only pages)
         let is_downgrade = (self.flags.has_write() || !flags.contains(MapFlags::PROT_WRITE))
@@ -2126,8 +4836,33 @@ impl GrantInfo {
         }
     }
 
+    /// Change the protection of the grant, also recording it in the protections it has ever had.
+    fn set_flags(&mut self, flags: PageFlags<RmmA>) {
+        self.flags = flags;
+        self.ever_flags = self
+            .ever_flags
+            .write(self.ever_flags.has_write() || flags.has_write())
+            .execute(self.ever_flags.has_execute() || flags.has_execute());
+    }
+
+    /// Whether changing the protection to `flags` would break the strict W^X policy, i.e. make
+    /// the grant writable and executable at once, or executable after it has ever been writable,
+    /// or vice versa.
+    pub fn violates_wx(&self, flags: MapFlags) -> bool {
+        let write = flags.contains(MapFlags::PROT_WRITE);
+        let exec = flags.contains(MapFlags::PROT_EXEC);
+
+        (write && exec)
+            || (write && self.ever_flags.has_execute())
+            || (exec && self.ever_flags.has_write())
+    }
+
     pub fn can_be_merged_if_adjacent(&self, with: &Self) -> bool {
-        if self.mapped != with.mapped || self.flags.data() != with.flags.data() {
+        if self.mapped != with.mapped
+            || self.flags.data() != with.flags.data()
+            || self.ever_flags.data() != with.ever_flags.data()
+            || self.clone_strategy != with.clone_strategy
+        {
             return false;
         }
 
@@ -2179,12 +4914,14 @@ impl GrantInfo {
                 flags |= GrantFlags::GRANT_SHARED;
                 flags.set(GrantFlags::GRANT_PINNED, is_pinned_userscheme_borrow);
             }
-            Provider::PhysBorrowed { .. } => {
-                flags |= GrantFlags::GRANT_SHARED | GrantFlags::GRANT_PHYS;
+            Provider::PhysBorrowed { private, .. } => {
+                flags |= GrantFlags::GRANT_PHYS;
+                flags.set(GrantFlags::GRANT_SHARED, !private);
             }
             Provider::FmapBorrowed { .. } => {
                 flags |= GrantFlags::GRANT_SHARED | GrantFlags::GRANT_SCHEME;
             }
+            Provider::Emulated { .. } => flags |= GrantFlags::GRANT_SHARED,
         }
 
         flags
@@ -2207,11 +4944,29 @@ impl Drop for GrantInfo {
     #[track_caller]
     fn drop(&mut self) {
         // XXX: This will not show the address...
-        assert!(
-            !self.mapped,
-            "Grant dropped while still mapped: {:#x?}",
-            self
-        );
+        if cfg!(debug_assertions) {
+            assert!(
+                !self.mapped,
+                "Grant dropped while still mapped: {:#x?}",
+                self
+            );
+        } else if self.mapped {
+            // Panicking in a destructor during address space teardown is worse than leaking, so
+            // leak whatever the still-mapped pages may depend on, such as the file or the borrowed
+            // address space, and carry on.
+            log::error!(
+                "Grant dropped while still mapped, leaking it: {:#x?} (at {})",
+                self,
+                core::panic::Location::caller()
+            );
+            core::mem::forget(core::mem::replace(
+                &mut self.provider,
+                Provider::PhysBorrowed {
+                    base: Frame::containing(PhysicalAddress::new(0)),
+                    private: false,
+                },
+            ));
+        }
     }
 }
 
@@ -2244,22 +4999,72 @@ impl Drop for AddrSpace {
 
 impl Drop for Table {
     fn drop(&mut self) {
+        let frame = Frame::containing(self.utable.table().phys());
+
         if self.utable.is_current() {
-            // TODO: Do not flush (we immediately context switch after exit(), what else is there
-            // to do?). Instead, we can garbage-collect such page tables in the idle kernel context
-            // before it waits for interrupts. Or maybe not, depends on what future benchmarks will
-            // indicate.
+            // We immediately context switch after exit(), so rather than flushing here, leave the
+            // table loaded and let the idle context of this CPU free it. Only fall back to
+            // switching to the empty table if too many tables are already pending.
+            if DEFERRED_TABLE_FREES
+                .lock()
+                .try_push((crate::cpu_id(), frame))
+                .is_ok()
+            {
+                return;
+            }
             unsafe {
                 RmmA::set_table(TableKind::User, super::empty_cr3());
             }
         }
         unsafe {
-            deallocate_frame(Frame::containing(self.utable.table().phys()));
+            deallocate_frame(frame);
+        }
+    }
+}
+
+const DEFERRED_TABLE_FREES_LEN: usize = 64;
+
+/// Root page tables that were still loaded when their address space was dropped, along with the
+/// CPU that had them loaded. Since an address space cannot be dropped while another CPU is using
+/// it, only that CPU can still point at the table.
+static DEFERRED_TABLE_FREES: spin::Mutex<
+    ArrayVec<(LogicalCpuId, Frame), DEFERRED_TABLE_FREES_LEN>,
+> = spin::Mutex::new(ArrayVec::new_const());
+
+/// Free the root page tables this CPU deferred freeing in [`Table::drop`]. Called by the idle
+/// context, which does not have an address space of its own.
+///
+/// # Safety
+///
+/// The current context must not have a user address space.
+pub unsafe fn free_deferred_tables() {
+    let cpu_id = crate::cpu_id();
+    let mut frames = ArrayVec::<Frame, DEFERRED_TABLE_FREES_LEN>::new();
+
+    DEFERRED_TABLE_FREES.lock().retain(|(owner, frame)| {
+        if *owner != cpu_id {
+            return true;
+        }
+        frames.push(*frame);
+        false
+    });
+
+    if frames.is_empty() {
+        return;
+    }
+
+    unsafe {
+        // The switch to the idle context retains the previous table if the exiting context had
+        // already released its address space, so it may still be loaded.
+        RmmA::set_table(TableKind::User, super::empty_cr3());
+
+        for frame in frames {
+            deallocate_frame(frame);
         }
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AccessMode {
     Read,
     Write,
@@ -2318,9 +5123,12 @@ fn cow(
         });
     }
 
-    let new_frame = init_frame(initial_rc)?;
+    let new_frame = init_frame(initial_rc, None)?;
 
-    if old_frame != the_zeroed_frame().0 {
+    // Newly allocated frames are already zeroed, so there is nothing to copy if the old frame is
+    // too. Scanning is cheaper than copying, since it only reads, and pages that are not zeroed
+    // rarely get far before a nonzero word is found.
+    if old_frame != the_zeroed_frame().0 && !frame_is_zeroed(old_frame) {
         unsafe {
             copy_frame_to_frame_directly(new_frame, old_frame);
         }
@@ -2337,8 +5145,9 @@ fn map_zeroed(
     page: Page,
     page_flags: PageFlags<RmmA>,
     _writable: bool,
+    hint: Option<NodeHint>,
 ) -> Result<Frame, PfError> {
-    let new_frame = init_frame(RefCount::One)?;
+    let new_frame = init_frame(RefCount::One, hint)?;
 
     unsafe {
         mapper
@@ -2350,33 +5159,274 @@ fn map_zeroed(
     Ok(new_frame)
 }
 
+/// Reads the contents of the swapped out `page` into a new frame. Called without holding the
+/// address space lock, as the backend may take a while.
+fn read_swapped(swapped: &SwappedPage, page: Page) -> Result<RaiiFrame, PfError> {
+    let frame = RaiiFrame::allocate().map_err(|_| PfError::Oom)?;
+    swapped.read(frame.get()).map_err(|error| {
+        log::error!("Failed to read {:?} from swap: {:?}", page, error);
+        PfError::Segv
+    })?;
+    Ok(frame)
+}
+
+/// Maps `frame`, read from `swapped` by [`read_swapped`], at `page` with the flags of its grant.
+/// Does nothing if the page no longer refers to `swapped`, i.e. was swapped in or unmapped while
+/// reading, in which case the frame is released.
+fn map_swapped_in(
+    addr_space: &mut AddrSpace,
+    page: Page,
+    swapped: &Arc<SwappedPage>,
+    frame: RaiiFrame,
+) -> Result<(), PfError> {
+    if !addr_space
+        .grants
+        .swapped(page)
+        .is_some_and(|current| Arc::ptr_eq(&current, swapped))
+    {
+        return Ok(());
+    }
+    let flags = addr_space
+        .grants
+        .contains(page)
+        .expect("swapped out page must belong to a grant")
+        .1
+        .flags();
+    unsafe {
+        // The page was not present before, so there is nothing to flush.
+        addr_space
+            .table
+            .utable
+            .map_phys(page.start_address(), frame.get().base(), flags)
+            .ok_or(PfError::Oom)?
+            .ignore();
+    }
+    // The reference is now owned by the page tables, and the slot is released once no clone
+    // refers to it anymore.
+    core::mem::forget(frame);
+    addr_space.grants.page_states.remove(page);
+    Ok(())
+}
+
+/// Maps the frame of `page` back, with the flags of its grant, if the page is being swapped out.
+/// The page then no longer refers to the [`SwappingOut`] state, which tells swap_out to discard
+/// what it wrote. On failure, the page remains marked.
+fn cancel_swap_out(addr_space: &mut AddrSpace, page: Page) -> Result<(), Enomem> {
+    let Some(PageState::SwappingOut(swapping_out)) = addr_space.grants.page_states.get(page) else {
+        return Ok(());
+    };
+    let flags = addr_space
+        .grants
+        .contains(page)
+        .expect("page being swapped out must belong to a grant")
+        .1
+        .flags();
+    unsafe {
+        // The page was not present before, so there is nothing to flush.
+        addr_space
+            .table
+            .utable
+            .map_phys(page.start_address(), swapping_out.frame.base(), flags)
+            .ok_or(Enomem)?
+            .ignore();
+    }
+    // The reference is now owned by the page tables again.
+    swapping_out.mapped_back.store(true, Ordering::Relaxed);
+    addr_space.grants.page_states.remove(page);
+    Ok(())
+}
+
 pub unsafe fn copy_frame_to_frame_directly(dst: Frame, src: Frame) {
     // Optimized exact-page-size copy function?
 
     // TODO: For new frames, when the kernel's linear phys=>virt mappings are 4k, this is almost
     // guaranteed to cause either one (or two) TLB misses.
 
-    let dst = unsafe { RmmA::phys_to_virt(dst.base()).data() as *mut u8 };
-    let src = unsafe { RmmA::phys_to_virt(src.base()).data() as *const u8 };
+    // Copying a frame onto itself would violate the nonoverlapping requirement below.
+    debug_assert_ne!(dst, src, "copying frame onto itself");
+
+    let dst = unsafe { RmmA::phys_to_virt(dst.base()).data() };
+    let src = unsafe { RmmA::phys_to_virt(src.base()).data() };
+
+    // Both pages must lie entirely within the linear physmap.
+    let in_physmap = |virt: usize| {
+        virt >= crate::PHYS_OFFSET && virt % PAGE_SIZE == 0 && virt.checked_add(PAGE_SIZE).is_some()
+    };
+    debug_assert!(in_physmap(dst), "dst {dst:#x} outside of physmap");
+    debug_assert!(in_physmap(src), "src {src:#x} outside of physmap");
+
+    let dst = dst as *mut u8;
+    let src = src as *const u8;
 
     unsafe {
         dst.copy_from_nonoverlapping(src, PAGE_SIZE);
     }
 }
 
-pub fn try_correcting_page_tables(faulting_page: Page, access: AccessMode) -> Result<(), PfError> {
+/// Check whether every byte of `frame` is zero.
+///
+/// The words of each cache line are OR-reduced together before being compared, rather than
+/// returning at the first nonzero word, which allows the compiler to vectorize the inner loop
+/// where the target supports it.
+fn frame_is_zeroed(frame: Frame) -> bool {
+    const WORDS_PER_LINE: usize = 64 / core::mem::size_of::<usize>();
+
+    let words = unsafe {
+        core::slice::from_raw_parts(
+            RmmA::phys_to_virt(frame.base()).data() as *const usize,
+            PAGE_SIZE / core::mem::size_of::<usize>(),
+        )
+    };
+    words
+        .chunks_exact(WORDS_PER_LINE)
+        .all(|line| line.iter().fold(0, |acc, word| acc | word) == 0)
+}
+
+/// Reports a user page fault that occurred while no address space was set, which is a kernel bug.
+#[cold]
+fn report_fault_without_addrsp(faulting_page: Page, access: AccessMode) {
+    let has_context = PercpuBlock::current()
+        .switch_internals
+        .has_current_context();
+    let reason = if has_context {
+        // The context is either a kernel context, or its address space was already released.
+        "in a context without an address space"
+    } else {
+        "while no context was running"
+    };
+
+    if cfg!(debug_assertions) {
+        log::error!(
+            "User page fault ({:?}) at {:p} on CPU {} {}",
+            access,
+            faulting_page.start_address().data() as *const u8,
+            crate::cpu_id(),
+            reason,
+        );
+        unsafe {
+            crate::panic::stack_trace();
+        }
+    } else {
+        log::debug!("User page fault {}.", reason);
+    }
+}
+
+/// Try to resolve a fault at `faulting_address`. `user_stack` is the interrupted userspace
+/// context, if the fault was caused by userspace, which is required to emulate accesses to
+/// emulated grants.
+pub fn try_correcting_page_tables(
+    faulting_address: VirtualAddress,
+    access: AccessMode,
+    user_stack: Option<&mut dyn ArchIntCtx>,
+) -> Result<(), PfError> {
+    let faulting_page = Page::containing_address(faulting_address);
     let Ok(addr_space_lock) = AddrSpace::current() else {
-        log::debug!("User page fault without address space being set.");
+        report_fault_without_addrsp(faulting_page, access);
         return Err(PfError::Segv);
     };
 
     let lock = &addr_space_lock;
-    let (_, flush, _) = correct_inner(lock, lock.acquire_write(), faulting_page, access, 0)?;
+
+    let emulated = lock
+        .acquire_read()
+        .grants
+        .containing_span(faulting_page)
+        .and_then(|(_, info)| match info.provider {
+            Provider::Emulated { ref handler } => Some((Arc::clone(handler), info.flags())),
+            _ => None,
+        });
+    if let Some((handler, grant_flags)) = emulated {
+        let Some(stack) = user_stack else {
+            log::debug!("Emulated grant accessed by the kernel.");
+            return Err(PfError::Segv);
+        };
+        return emulate_access(&handler, grant_flags, stack, faulting_address, access);
+    }
+
+    let userfault_handler = lock
+        .acquire_read()
+        .userfault_handler_for(faulting_page, access);
+    if let Some(handler) = userfault_handler {
+        if !handler.is_alive() {
+            return Err(PfError::Segv);
+        }
+        // The access fails if the context is being killed, which then happens on the way back to
+        // userspace.
+        if !handler.await_fault(&crate::context::current(), faulting_page, access) {
+            return Err(PfError::Segv);
+        }
+        // The handler died while this context was blocked.
+        if !handler.is_alive() {
+            return Err(PfError::Segv);
+        }
+        // Otherwise retry the access, which will fault again if the page was not filled.
+        return Ok(());
+    }
+
+    let (_, flush, _) = match correct_inner(lock, lock.acquire_write(), faulting_page, access, 0) {
+        // Out of memory, but there may be MADV_FREE pages in this address space to discard.
+        Err(PfError::Oom) if lock.reclaim_freeable() > 0 => {
+            correct_inner(lock, lock.acquire_write(), faulting_page, access, 0)?
+        }
+        result => result?,
+    };
 
     flush.flush();
 
     Ok(())
 }
+/// Emulate an access to an emulated grant, by decoding the faulting instruction, forwarding the
+/// access to `handler`, and skipping the instruction once the handler has completed it.
+fn emulate_access(
+    handler: &UserFaultHandler,
+    grant_flags: PageFlags<RmmA>,
+    stack: &mut dyn ArchIntCtx,
+    faulting_address: VirtualAddress,
+    access: AccessMode,
+) -> Result<(), PfError> {
+    let permitted = match access {
+        AccessMode::Read => true,
+        AccessMode::Write => grant_flags.has_write(),
+        AccessMode::InstrFetch => false,
+    };
+    if !permitted || !handler.is_alive() {
+        return Err(PfError::Segv);
+    }
+
+    let Some(info) = crate::arch::decode::decode_access(stack.ip()) else {
+        log::debug!("Could not decode access to emulated grant.");
+        return Err(PfError::Segv);
+    };
+    // Accesses spanning two pages cannot be forwarded as one, as the other page may not belong to
+    // the grant.
+    if info.is_write != (access == AccessMode::Write)
+        || faulting_address.data() % PAGE_SIZE + usize::from(info.size) > PAGE_SIZE
+    {
+        log::debug!("Unsupported access to emulated grant: {:?}", info);
+        return Err(PfError::Segv);
+    }
+
+    let write_value = if info.is_write {
+        Some(info.store_value(stack).ok_or(PfError::Segv)?)
+    } else {
+        None
+    };
+    // As with faults, the access fails if the handler dies or the context is being killed.
+    let value = handler
+        .await_emulated(
+            &crate::context::current(),
+            faulting_address,
+            info.size,
+            write_value,
+        )
+        .ok_or(PfError::Segv)?;
+    if !info.is_write {
+        info.complete_load(stack, value).ok_or(PfError::Segv)?;
+    }
+    stack.set_ip(stack.ip() + usize::from(info.len));
+
+    Ok(())
+}
 fn correct_inner<'l>(
     addr_space_lock: &'l Arc<AddrSpaceWrapper>,
     mut addr_space_guard: RwLockWriteGuard<'l, AddrSpace>,
@@ -2385,14 +5435,31 @@ fn correct_inner<'l>(
     recursion_level: u32,
 ) -> Result<(Frame, PageFlush<RmmA>, RwLockWriteGuard<'l, AddrSpace>), PfError> {
     let mut addr_space = &mut *addr_space_guard;
-    let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
 
-    let Some((grant_base, grant_info)) = addr_space.grants.contains(faulting_page) else {
+    cancel_swap_out(addr_space, faulting_page).map_err(|Enomem| PfError::Oom)?;
+
+    // A page read back from swap is then handled like any other present page. Like for fmap, the
+    // lock is not held while waiting for the backend, so the page may have been swapped out again
+    // by the time it is re-acquired.
+    while let Some(swapped) = addr_space.grants.swapped(faulting_page) {
+        drop(addr_space_guard);
+        let frame = read_swapped(&swapped, faulting_page)?;
+        addr_space_guard = addr_space_lock.acquire_write();
+        addr_space = &mut *addr_space_guard;
+        map_swapped_in(addr_space, faulting_page, &swapped, frame)?;
+    }
+
+    let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_pending);
+
+    if let Some((_, grant_info)) = addr_space.grants.containing_span_mut(faulting_page) {
+        grant_info.access_generation = access_generation();
+    }
+    let Some((grant_span, grant_info)) = addr_space.grants.containing_span(faulting_page) else {
         log::debug!("Lacks grant");
         return Err(PfError::Segv);
     };
 
-    let pages_from_grant_start = faulting_page.offset_from(grant_base);
+    let pages_from_grant_start = faulting_page.offset_from(grant_span.base);
 
     let grant_flags = grant_info.flags();
     match access {
@@ -2411,6 +5478,20 @@ fn correct_inner<'l>(
         _ => (),
     }
 
+    // Owned pages in armed write barrier spans only become writable once the barrier allows it.
+    let barrier = addr_space
+        .write_barrier
+        .as_ref()
+        .filter(|registration| grant_info.is_owned() && registration.covers(faulting_page));
+    if let Some(registration) = barrier
+        && access == AccessMode::Write
+        && registration.barrier.on_write(faulting_page).is_err()
+    {
+        log::debug!("Write rejected by write barrier.");
+        return Err(PfError::Segv);
+    }
+    let barrier_read_only = barrier.is_some() && access != AccessMode::Write;
+
     // By now, the memory at the faulting page is actually valid, but simply not yet mapped, either
     // at all, or with the required flags.
 
@@ -2429,6 +5510,7 @@ fn correct_inner<'l>(
     // TODO: Readahead backwards, i.e. MAP_GROWSDOWN.
 
     let mut allow_writable = true;
+    let node_hint = Some(addr_space.numa_policy.hint(faulting_page));
 
     let frame = match grant_info.provider {
         Provider::Allocated { .. } | Provider::AllocatedShared { .. }
@@ -2438,6 +5520,9 @@ fn correct_inner<'l>(
                 Some((_, None)) => unreachable!("allocated page needs frame to be valid"),
                 Some((frame, Some(info))) => {
                     if info.allows_writable() {
+                        // Writing to a page marked with MADV_FREE cancels the free, and the page
+                        // is no longer clean where the hardware has no dirty bit.
+                        info.remove_flags(FrameFlags::FREEABLE | FrameFlags::CLEAN);
                         frame
                     } else {
                         let result = cow(frame, info, RefKind::Cow)?;
@@ -2447,12 +5532,9 @@ fn correct_inner<'l>(
                         result.new_frame
                     }
                 }
-                _ => map_zeroed(
-                    &mut addr_space.table.utable,
-                    faulting_page,
-                    grant_flags,
-                    true,
-                )?,
+                // Newly allocated frames are already zeroed, and the frame is mapped with the
+                // final flags below.
+                _ => init_frame(RefCount::One, node_hint)?,
             }
         }
 
@@ -2466,23 +5548,48 @@ fn correct_inner<'l>(
                     // Keep in mind that allow_writable must always be true if this code is reached
                     // for AllocatedShared, since shared pages cannot be mapped lazily (without
                     // using AddrSpace backrefs).
-                    allow_writable = page_info.allows_writable();
+                    allow_writable = page_info.allows_writable()
+                        && !page_info.flags().contains(FrameFlags::FREEABLE);
 
                     frame
                 }
 
                 None => {
                     // TODO: the zeroed page first, readonly?
-                    map_zeroed(
-                        &mut addr_space.table.utable,
-                        faulting_page,
-                        grant_flags,
-                        false,
-                    )?
+                    init_frame(RefCount::One, node_hint)?
+                }
+            }
+        }
+        Provider::PhysBorrowed {
+            base,
+            private: false,
+        } => base.next_by(pages_from_grant_start),
+        Provider::PhysBorrowed {
+            base,
+            private: true,
+        } => {
+            let borrowed = base.next_by(pages_from_grant_start);
+            match faulting_pageinfo_opt {
+                // Already copied by an earlier write.
+                Some((frame, Some(_))) => frame,
+                _ if access == AccessMode::Write => {
+                    let new_frame = init_frame(RefCount::One, node_hint)?;
+                    unsafe {
+                        copy_frame_to_frame_directly(new_frame, borrowed);
+                    }
+                    if faulting_pageinfo_opt.is_some() {
+                        // Other CPUs may still read the borrowed frame through stale entries.
+                        flusher.queue(borrowed, None, TlbShootdownActions::MOVE);
+                    }
+                    new_frame
+                }
+                // Reads see the borrowed memory until the first write.
+                _ => {
+                    allow_writable = false;
+                    borrowed
                 }
             }
         }
-        Provider::PhysBorrowed { base } => base.next_by(pages_from_grant_start),
         Provider::External {
             address_space: ref foreign_address_space,
             src_base,
@@ -2495,7 +5602,9 @@ fn correct_inner<'l>(
             }
 
             let mut guard = foreign_address_space.acquire_upgradeable_read();
-            let src_page = src_base.next_by(pages_from_grant_start);
+            let src_page = src_base
+                .checked_next_by(pages_from_grant_start)
+                .ok_or(PfError::Segv)?;
 
             if let Some(_) = guard.grants.contains(src_page) {
                 let src_frame = if let Some((phys, _)) =
@@ -2531,8 +5640,10 @@ fn correct_inner<'l>(
 
                     addr_space_guard = addr_space_lock.acquire_write();
                     addr_space = &mut *addr_space_guard;
-                    flusher =
-                        Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
+                    flusher = Flusher::with_cpu_set(
+                        &mut addr_space.used_by,
+                        &addr_space_lock.tlb_pending,
+                    );
                     guard = foreign_address_space.acquire_upgradeable_read();
 
                     frame
@@ -2579,14 +5690,19 @@ fn correct_inner<'l>(
 
                 // TODO: Should this be called?
                 log::warn!("Mapped zero page since grant didn't exist");
+                let hint = Some(guard.numa_policy.hint(src_page));
                 map_zeroed(
                     &mut guard.table.utable,
                     src_page,
                     grant_flags,
                     access == AccessMode::Write,
+                    hint,
                 )?
             }
         }
+        // Emulated accesses never get here, unless they could not be decoded, or the kernel itself
+        // accessed the grant.
+        Provider::Emulated { .. } => return Err(PfError::Segv),
         // TODO: NonfatalInternalError if !MAP_LAZY and this page fault occurs.
         Provider::FmapBorrowed { ref file_ref, .. } => {
             let file_ref = file_ref.clone();
@@ -2628,15 +5744,28 @@ fn correct_inner<'l>(
 
             addr_space_guard = addr_space_lock.acquire_write();
             addr_space = &mut *addr_space_guard;
-            flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
+            flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_pending);
 
             log::info!("Got frame {:?} from external fmap", frame);
 
-            frame
+            // Another thread may have faulted in or prefetched the page while the address space
+            // was unlocked, in which case its mapping is kept, along with its reference.
+            if let Some((phys, _)) = addr_space
+                .table
+                .utable
+                .translate(faulting_page.start_address())
+            {
+                release_fmap_frame(frame);
+                Frame::containing(phys)
+            } else {
+                frame
+            }
         }
     };
 
-    let new_flags = grant_flags.write(grant_flags.has_write() && allow_writable);
+    // The faulting page is only mapped here, with its final flags, by every provider above.
+    let new_flags =
+        grant_flags.write(grant_flags.has_write() && allow_writable && !barrier_read_only);
     let Some(flush) = (unsafe {
         addr_space
             .table
@@ -2651,6 +5780,98 @@ fn correct_inner<'l>(
     Ok((frame, flush, addr_space_guard))
 }
 
+/// Caches the last-level page table covering the most recently accessed address, so that
+/// operating on consecutive pages only walks the page table from the root once per leaf table,
+/// rather than once per page. All TLB flushing is left to the caller.
+///
+/// With the `per_page_copy_mappings` feature, every operation instead goes through the regular
+/// mapper functions, as a reference implementation.
+struct LeafCursor {
+    leaf: Option<PageTable<RmmA>>,
+}
+impl LeafCursor {
+    fn new() -> Self {
+        Self { leaf: None }
+    }
+    fn leaf(&mut self, mapper: &PageMapper, addr: VirtualAddress) -> Option<&mut PageTable<RmmA>> {
+        if !self
+            .leaf
+            .as_ref()
+            .is_some_and(|leaf| leaf.index_of(addr).is_some())
+        {
+            let mut table = mapper.table();
+            while table.level() > 0 {
+                table = table.next(table.index_of(addr)?)?;
+            }
+            self.leaf = Some(table);
+        }
+        self.leaf.as_mut()
+    }
+    fn translate(
+        &mut self,
+        mapper: &PageMapper,
+        addr: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+        if cfg!(feature = "per_page_copy_mappings") {
+            return mapper.translate(addr);
+        }
+        let leaf = self.leaf(mapper, addr)?;
+        let entry = leaf.entry(leaf.index_of(addr)?)?;
+
+        Some((entry.address().ok()?, entry.flags()))
+    }
+    /// Write-protect the page at `addr` if present, returning its frame.
+    unsafe fn revoke_write(
+        &mut self,
+        mapper: &mut PageMapper,
+        addr: VirtualAddress,
+    ) -> Option<PhysicalAddress> {
+        if cfg!(feature = "per_page_copy_mappings") {
+            let (_, phys, flush) = mapper.remap_with(addr, |flags| flags.write(false))?;
+            flush.ignore();
+            return Some(phys);
+        }
+        let leaf = self.leaf(mapper, addr)?;
+        let i = leaf.index_of(addr)?;
+        let entry = leaf.entry(i)?;
+        let phys = entry.address().ok()?;
+
+        leaf.set_entry(
+            i,
+            PageEntry::new(phys.data(), entry.flags().write(false).data()),
+        );
+        Some(phys)
+    }
+    /// Map a previously unmapped page, returning false if out of memory.
+    unsafe fn map_phys(
+        &mut self,
+        mapper: &mut PageMapper,
+        addr: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: PageFlags<RmmA>,
+    ) -> bool {
+        if !cfg!(feature = "per_page_copy_mappings") {
+            if let Some(leaf) = self.leaf(mapper, addr)
+                && let Some(i) = leaf.index_of(addr)
+            {
+                leaf.set_entry(
+                    i,
+                    PageEntry::new(phys.data(), flags.data() | RmmA::ENTRY_FLAG_PRESENT),
+                );
+                return true;
+            }
+        }
+        // The leaf table does not exist yet, which the mapper will allocate.
+        match mapper.map_phys(addr, phys, flags) {
+            Some(flush) => {
+                flush.ignore();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum MmapMode {
     Cow,
@@ -2665,9 +5886,46 @@ pub struct BorrowedFmapSource<'a> {
     pub addr_space_guard: RwLockWriteGuard<'a, AddrSpace>,
 }
 
+/// Notifies the schemes of the file grants that have been unmapped, once the address space lock
+/// has been released. The ranges of each file are sent in a single batch, with adjacent ranges
+/// merged, and the file is closed afterwards if this was its last reference.
 pub fn handle_notify_files(notify_files: Vec<UnmapResult>) {
+    let mut batches: Vec<(Arc<RwLock<FileDescription>>, Vec<FunmapRange>)> = Vec::new();
+
     for file in notify_files {
-        let _ = file.unmap();
+        let Some(GrantFileRef {
+            base_offset,
+            description,
+        }) = file.file_desc
+        else {
+            continue;
+        };
+        let range = FunmapRange {
+            offset: base_offset,
+            size: file.size,
+            flags: file.flags,
+        };
+        // Additional references to the same description are dropped here, so that only the
+        // last one is left to close the file.
+        match batches
+            .iter_mut()
+            .find(|(batch_desc, _)| Arc::ptr_eq(batch_desc, &description))
+        {
+            Some((_, ranges)) => ranges.push(range),
+            None => batches.push((description, vec![range])),
+        }
+    }
+
+    for (description, mut ranges) in batches {
+        ranges.sort_unstable_by_key(|range| range.offset);
+        ranges.dedup_by(|next, prev| {
+            let adjacent = prev.offset + prev.size == next.offset && prev.flags == next.flags;
+            if adjacent {
+                prev.size += next.size;
+            }
+            adjacent
+        });
+        let _ = funmap_and_close(description, &ranges);
     }
 }
 
@@ -2729,7 +5987,7 @@ struct FlusherState<'addrsp> {
     pagequeue: ArrayVec<PageQueueEntry, 32>,
     dirty: bool,
 
-    ackword: &'addrsp AtomicU32,
+    pending: &'addrsp LogicalCpuSet,
 }
 
 enum PageQueueEntry {
@@ -2743,28 +6001,30 @@ enum PageQueueEntry {
     },
 }
 
+/// Flusher for user address spaces. It only ever flushes user-space TLB entries, on every CPU the
+/// address space is active on, see [`flush_user_tlb`](crate::paging::mapper::flush_user_tlb).
 pub struct Flusher<'guard, 'addrsp> {
     active_cpus: &'guard mut LogicalCpuSet,
     state: FlusherState<'addrsp>,
 }
 impl<'guard, 'addrsp> Flusher<'guard, 'addrsp> {
-    fn with_cpu_set(set: &'guard mut LogicalCpuSet, ackword: &'addrsp AtomicU32) -> Self {
+    fn with_cpu_set(set: &'guard mut LogicalCpuSet, pending: &'addrsp LogicalCpuSet) -> Self {
         Self {
             active_cpus: set,
             state: FlusherState {
                 pagequeue: ArrayVec::new(),
                 dirty: false,
-                ackword,
+                pending,
             },
         }
     }
     fn detach(mut self) -> FlusherState<'addrsp> {
-        static DUMMY: AtomicU32 = AtomicU32::new(0);
+        static DUMMY: LogicalCpuSet = LogicalCpuSet::empty();
         let state = core::mem::replace(
             &mut self.state,
             FlusherState {
                 pagequeue: ArrayVec::new(),
-                ackword: &DUMMY,
+                pending: &DUMMY,
                 dirty: false,
             },
         );
@@ -2779,28 +6039,43 @@ impl<'guard, 'addrsp> Flusher<'guard, 'addrsp> {
             return;
         }
 
-        self.state.ackword.store(0, Ordering::SeqCst);
-
-        let mut affected_cpu_count = 0;
-
         let current_cpu_id = crate::cpu_id();
 
+        // The bit must be set before the IPI is sent, so that the target sees it when handling
+        // the shootdown.
         for cpu_id in self.active_cpus.iter_mut() {
             if cpu_id == current_cpu_id {
                 continue;
             }
 
+            self.state.pending.atomic_set(cpu_id);
             crate::percpu::shootdown_tlb_ipi(Some(cpu_id));
-            affected_cpu_count += 1;
         }
 
         if self.active_cpus.contains(current_cpu_id) {
-            rmm::PageFlushAll::<RmmA>::new().flush();
+            unsafe {
+                crate::paging::mapper::flush_user_tlb();
+            }
         }
 
-        while self.state.ackword.load(Ordering::SeqCst) < affected_cpu_count {
+        // The frames below can only be freed once no CPU can access them through stale entries.
+        let mut spins = 0;
+        while !self.state.pending.is_empty() {
             PercpuBlock::current().maybe_handle_tlb_shootdown();
             core::hint::spin_loop();
+
+            spins += 1;
+            if spins == SHOOTDOWN_WARN_SPINS {
+                log::warn!(
+                    "TLB shootdown still waiting for CPUs {}",
+                    self.state.pending.to_string()
+                );
+            } else if spins == SHOOTDOWN_PANIC_SPINS {
+                panic!(
+                    "TLB shootdown timed out, CPUs {} did not respond",
+                    self.state.pending.to_string()
+                );
+            }
         }
 
         for entry in pages {
@@ -2845,6 +6120,61 @@ impl Drop for Flusher<'_, '_> {
         self.flush();
     }
 }
+
+/// Flusher for bulk operations spanning many grants. Unlike [`Flusher`], whose bounded queue
+/// forces a shootdown every few freed frames, it keeps the frames to free on the heap, so that the
+/// whole operation results in a single shootdown when it is flushed or dropped. Since user TLB
+/// flushes are always full flushes, nothing but these frames needs to be remembered.
+///
+/// If the queue cannot grow, it falls back to flushing right away.
+pub struct DeferredFlusher<'guard, 'addrsp> {
+    inner: Flusher<'guard, 'addrsp>,
+    frees: Vec<(Frame, Option<NonZeroUsize>)>,
+}
+impl<'guard, 'addrsp> DeferredFlusher<'guard, 'addrsp> {
+    fn new(inner: Flusher<'guard, 'addrsp>) -> Self {
+        Self {
+            inner,
+            frees: Vec::new(),
+        }
+    }
+    // NOTE: Lock must be held, which must be guaranteed by the caller.
+    pub fn flush(&mut self) {
+        self.inner.flush();
+
+        for (base, phys_contiguous_count) in self.frees.drain(..) {
+            handle_free_action(base, phys_contiguous_count);
+        }
+    }
+}
+impl GenericFlusher for DeferredFlusher<'_, '_> {
+    fn queue(
+        &mut self,
+        frame: Frame,
+        phys_contiguous_count: Option<NonZeroUsize>,
+        actions: TlbShootdownActions,
+    ) {
+        if (actions & !TlbShootdownActions::NEW_MAPPING).is_empty() {
+            return;
+        }
+        self.inner.state.dirty = true;
+
+        if !actions.contains(TlbShootdownActions::FREE) {
+            return;
+        }
+        if self.frees.try_reserve(1).is_ok() {
+            self.frees.push((frame, phys_contiguous_count));
+        } else {
+            self.flush();
+            handle_free_action(frame, phys_contiguous_count);
+        }
+    }
+}
+impl Drop for DeferredFlusher<'_, '_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
 bitflags::bitflags! {
     pub struct TlbShootdownActions: usize {
         // Delay the deallocation of one or more contiguous frames.
@@ -11,6 +11,7 @@ use syscall::ENOMEM;
 use crate::{
     context::memory::AddrSpaceWrapper,
     cpu_set::LogicalCpuSet,
+    interrupt,
     paging::{RmmA, RmmArch, TableKind},
     percpu::PercpuBlock,
     sync::WaitMap,
@@ -23,7 +24,7 @@ use self::{
 };
 pub use self::{
     context::{BorrowedHtBuf, Context, Status, WaitpidKey},
-    switch::switch,
+    switch::{switch, try_switch, SwitchResult},
 };
 
 #[cfg(target_arch = "aarch64")]
@@ -57,12 +58,24 @@ pub mod memory;
 /// Process handling - TODO move to userspace
 pub mod process;
 
+/// Per-CPU run queues
+pub mod run_queue;
+
 /// Signal handling
 pub mod signal;
 
+/// Swapping of anonymous memory
+pub mod swap;
+
 /// Timeout handling
 pub mod timeout;
 
+/// Userspace page fault handling
+pub mod userfault;
+
+/// Write barriers
+pub mod write_barrier;
+
 pub use self::switch::switch_finish_hook;
 
 /// Maximum context files
@@ -70,12 +83,19 @@ pub const CONTEXT_MAX_FILES: usize = 65_536;
 
 pub use self::arch::empty_cr3;
 
+#[cfg(target_arch = "x86_64")]
+pub use self::arch::{PortableFloatRegisters, PORTABLE_FX_SIZE};
+
 static KMAIN_PROCESS: Once<Arc<RwLock<Process>>> = Once::new();
 
 // Set of weak references to all contexts available for scheduling. The only strong references are
 // the context file descriptors.
 static CONTEXTS: RwLock<BTreeSet<ContextRef>> = RwLock::new(BTreeSet::new());
 
+/// Set up context management on this CPU. The context that is currently running (kmain) becomes
+/// this CPU's idle context, which the scheduler falls back to when nothing else is runnable, and
+/// which is therefore not in any run queue. It has no address space, so switching to it loads the
+/// empty user page table, and it is expected to continue with [`idle_loop`].
 pub fn init() {
     let pid = ProcessId::new(0);
     let process = KMAIN_PROCESS.call_once(|| {
@@ -98,6 +118,7 @@ pub fn init() {
     context.status = Status::Runnable;
     context.running = true;
     context.cpu_id = Some(crate::cpu_id());
+    context.set_last_cpu(crate::cpu_id());
 
     let context_lock = Arc::new(RwSpinlock::new(context));
 
@@ -114,6 +135,38 @@ pub fn init() {
     }
 }
 
+/// Body of the idle context of each CPU. Runs any context that is runnable, and otherwise halts
+/// the CPU until the next interrupt arrives.
+pub fn idle_loop() -> ! {
+    loop {
+        unsafe {
+            interrupt::disable();
+            match try_switch() {
+                SwitchResult::Switched => {
+                    interrupt::enable_and_nop();
+                }
+                SwitchResult::WouldBlock => {
+                    // Another CPU is switching; let pending interrupts such as TLB shootdowns in
+                    // before retrying.
+                    interrupt::enable_and_nop();
+                }
+                SwitchResult::AllContextsIdle => {
+                    PercpuBlock::current().drop_lazy_addrsp();
+                    memory::free_deferred_tables();
+
+                    if crate::softirq::run_pending() {
+                        // The deferred work may have woken up contexts.
+                        interrupt::enable_and_nop();
+                    } else {
+                        // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
+                        interrupt::enable_and_halt();
+                    }
+                }
+            }
+        }
+    }
+}
+
 /// Get the global schemes list, const
 pub fn contexts() -> RwLockReadGuard<'static, BTreeSet<ContextRef>> {
     CONTEXTS.read()
@@ -124,31 +177,82 @@ pub fn contexts_mut() -> RwLockWriteGuard<'static, BTreeSet<ContextRef>> {
     CONTEXTS.write()
 }
 
+/// Get the context running on this CPU. This only reads the per-CPU current context, which the
+/// context switch updates, and does not take the global contexts lock.
 pub fn current() -> Arc<RwSpinlock<Context>> {
     PercpuBlock::current()
         .switch_internals
         .with_context(|context| Arc::clone(context))
 }
+/// Id of the context running on this CPU. Unlike [`current`], this neither clones the reference
+/// nor locks anything.
+pub fn current_context_id() -> ContextId {
+    PercpuBlock::current()
+        .switch_internals
+        .with_context(ContextId::of)
+}
 pub fn is_current(context: &Arc<RwSpinlock<Context>>) -> bool {
     PercpuBlock::current()
         .switch_internals
         .with_context(|current| Arc::ptr_eq(context, current))
 }
 
+/// Freeze `context_lock` (see [`Context::freeze`]) and wait until it has stopped running, so that
+/// its saved state is coherent. A frozen current context only stops once it returns to userspace,
+/// so in that case this returns immediately. Also returns if the context is thawed meanwhile.
+pub fn freeze(context_lock: &Arc<RwSpinlock<Context>>) {
+    if context_lock.write().freeze() || is_current(context_lock) {
+        return;
+    }
+    loop {
+        {
+            let context = context_lock.read();
+            if context.is_stopped() || !context.is_frozen() {
+                return;
+            }
+        }
+        interrupt::pause();
+        PercpuBlock::current().maybe_handle_tlb_shootdown();
+    }
+}
+/// Switch away from the current context for as long as it is frozen, unless it is being killed.
+/// Called before returning to userspace.
+pub fn stop_if_frozen() {
+    while {
+        let context = current().read();
+        context.is_frozen() && !context.being_sigkilled
+    } {
+        switch();
+    }
+}
+
 pub fn current_pid() -> Result<ProcessId> {
     Ok(current().read().pid)
 }
 
+int_like!(ContextId, usize);
+impl ContextId {
+    /// Contexts are identified by the address of their lock, which is also the order of the
+    /// contexts list. Ids are unique among live contexts, but may be reused once a context is
+    /// freed.
+    pub fn of(context: &Arc<RwSpinlock<Context>>) -> Self {
+        Self::new(Arc::as_ptr(context) as usize)
+    }
+}
+
 pub struct ContextRef(pub Arc<RwSpinlock<Context>>);
 impl ContextRef {
     pub fn upgrade(&self) -> Option<Arc<RwSpinlock<Context>>> {
         Some(Arc::clone(&self.0))
     }
+    pub fn id(&self) -> ContextId {
+        ContextId::of(&self.0)
+    }
 }
 
 impl Ord for ContextRef {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        Ord::cmp(&Arc::as_ptr(&self.0), &Arc::as_ptr(&other.0))
+        Ord::cmp(&self.id(), &other.id())
     }
 }
 impl PartialOrd for ContextRef {
@@ -191,6 +295,37 @@ pub fn spawn(
 
         context.kstack = Some(stack);
         context.userspace = userspace_allowed;
+
+        run_queue::enqueue(&context_lock, &mut context);
     }
     Ok(context_lock)
 }
+
+/// Spawn a kernel-only context, such as a driver or worker thread, running `func` on a kernel
+/// stack of at least `stack_size` bytes. Unlike [`spawn`], the context is runnable right away.
+///
+/// The context belongs to the kernel (kmain) process without being one of its threads, has no
+/// address space, and never enters usermode, so it is neither accounted for any user memory nor
+/// ever delivered signals. Switching to it keeps the previous address space loaded lazily. Since
+/// there is no frame to return to, `func` must never return.
+pub fn spawn_kernel(func: extern "C" fn(), stack_size: usize) -> Result<Arc<RwSpinlock<Context>>> {
+    let stack = Kstack::with_size(stack_size)?;
+    let process = KMAIN_PROCESS
+        .get()
+        .expect("context::init must be called before spawning kernel contexts");
+
+    let mut context = Context::new(ProcessId::new(0), Arc::clone(process))?;
+    context.arch.setup_initial_call(&stack, func, false);
+    context.kstack = Some(stack);
+    context.userspace = false;
+    context.status = Status::Runnable;
+
+    let context_lock = Arc::try_new(RwSpinlock::new(context)).map_err(|_| Error::new(ENOMEM))?;
+
+    CONTEXTS
+        .write()
+        .insert(ContextRef(Arc::clone(&context_lock)));
+    run_queue::enqueue(&context_lock, &mut context_lock.write());
+
+    Ok(context_lock)
+}
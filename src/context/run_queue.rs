@@ -0,0 +1,155 @@
+//! Per-CPU run queues.
+//!
+//! Every schedulable context is in the run queue of exactly one CPU, its home CPU
+//! ([`Context::cpu_id`]), whatever its status. The scheduler of each CPU only scans its own queue,
+//! rather than a global list of all contexts. Blocked contexts remain queued, so that waking them,
+//! including timed wakeups, stays as cheap as updating their status.
+//!
+//! Contexts are placed on the least loaded CPU their affinity allows when spawned, and moved
+//! when their affinity is changed to exclude their home CPU. A running context is only moved once
+//! its CPU switches away from it, so that while running, its home CPU is always the CPU it is
+//! running on.
+//!
+//! Idle CPUs do not steal contexts from busier ones yet. Until switching no longer takes the
+//! global switch lock, contexts stay pinned to their home CPU, and per-CPU state such as the lazily
+//! kept address space and the frame cache can rely on that.
+//!
+//! Contexts are only moved while locked, which is also when their home CPU is updated. Whoever
+//! wakes a context can thus always find the CPU that is going to schedule it, even if that is not
+//! the CPU it last ran on.
+//!
+//! Lock ordering: while holding a run queue lock, contexts are only ever try-locked, and no other
+//! run queue is locked. Affinity masks that allow no CPU are rejected, so every context has a
+//! home CPU it can run on.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use spinning_top::{guard::ArcRwSpinlockWriteGuard, RwSpinlock};
+
+use crate::{
+    cpu_set::{LogicalCpuId, LogicalCpuSet, MAX_CPU_COUNT},
+    ipi::{ipi_single, IpiKind},
+};
+
+use super::Context;
+
+struct RunQueue {
+    contexts: Mutex<VecDeque<Arc<RwSpinlock<Context>>>>,
+    /// Number of queued contexts, which can be read without locking the queue.
+    len: AtomicUsize,
+}
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            contexts: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+        }
+    }
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+    fn push(&self, context_lock: Arc<RwSpinlock<Context>>) {
+        let mut contexts = self.contexts.lock();
+        contexts.push_back(context_lock);
+        self.len.store(contexts.len(), Ordering::Relaxed);
+    }
+    fn remove(&self, context_lock: &Arc<RwSpinlock<Context>>) -> bool {
+        let mut contexts = self.contexts.lock();
+        let Some(index) = contexts.iter().position(|c| Arc::ptr_eq(c, context_lock)) else {
+            return false;
+        };
+        contexts.remove(index);
+        self.len.store(contexts.len(), Ordering::Relaxed);
+        true
+    }
+}
+
+const EMPTY: RunQueue = RunQueue::new();
+static RUN_QUEUES: [RunQueue; MAX_CPU_COUNT as usize] = [EMPTY; MAX_CPU_COUNT as usize];
+
+fn queue(cpu_id: LogicalCpuId) -> &'static RunQueue {
+    &RUN_QUEUES[cpu_id.get() as usize]
+}
+
+/// The CPU allowed by `affinity` with the fewest queued contexts, preferring the current CPU.
+fn least_loaded(affinity: &LogicalCpuSet) -> Option<LogicalCpuId> {
+    let current = crate::cpu_id();
+    (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&id| affinity.atomic_contains(id))
+        .min_by_key(|&id| (queue(id).len(), id != current))
+}
+
+/// Lock the first context in `contexts` for which `runnable` returns true, skipping contexts
+/// locked elsewhere.
+fn find_runnable(
+    contexts: &VecDeque<Arc<RwSpinlock<Context>>>,
+    mut runnable: impl FnMut(&mut Context) -> bool,
+) -> Option<(usize, ArcRwSpinlockWriteGuard<Context>)> {
+    contexts
+        .iter()
+        .enumerate()
+        .find_map(|(index, context_lock)| {
+            let mut guard = context_lock.try_write_arc()?;
+            runnable(&mut *guard).then_some((index, guard))
+        })
+}
+
+/// Add a newly spawned context to the run queue of the least loaded CPU its affinity allows, and
+/// wake that CPU up if the context is already runnable.
+pub fn enqueue(context_lock: &Arc<RwSpinlock<Context>>, context: &mut Context) {
+    let cpu_id = least_loaded(&context.sched_affinity).unwrap_or_else(crate::cpu_id);
+    context.cpu_id = Some(cpu_id);
+    queue(cpu_id).push(Arc::clone(context_lock));
+
+    if context.status.is_runnable() && cpu_id != crate::cpu_id() {
+        ipi_single(IpiKind::Wakeup, cpu_id);
+    }
+}
+
+/// Remove an exiting context from its run queue.
+pub fn dequeue(context_lock: &Arc<RwSpinlock<Context>>, context: &Context) {
+    if let Some(cpu_id) = context.cpu_id {
+        queue(cpu_id).remove(context_lock);
+    }
+}
+
+/// Move a context to the least loaded CPU its affinity allows, if it no longer allows its home
+/// CPU. The context must not be running, unless the caller is switching away from it. Contexts
+/// not in any run queue, such as idle contexts, are left alone.
+pub fn rehome(context_lock: &Arc<RwSpinlock<Context>>, context: &mut Context) {
+    let Some(home) = context.cpu_id else {
+        return;
+    };
+    if context.sched_affinity.atomic_contains(home) {
+        return;
+    }
+    let Some(cpu_id) = least_loaded(&context.sched_affinity) else {
+        return;
+    };
+    if queue(home).remove(context_lock) {
+        context.cpu_id = Some(cpu_id);
+        queue(cpu_id).push(Arc::clone(context_lock));
+
+        if context.status.is_runnable() && cpu_id != crate::cpu_id() {
+            ipi_single(IpiKind::Wakeup, cpu_id);
+        }
+    }
+}
+
+/// Lock the first context in the run queue of `cpu_id` for which `runnable` returns true, and
+/// move it to the back of the queue, so that the contexts of a CPU take turns.
+pub fn pick(
+    cpu_id: LogicalCpuId,
+    runnable: impl FnMut(&mut Context) -> bool,
+) -> Option<ArcRwSpinlockWriteGuard<Context>> {
+    let mut contexts = queue(cpu_id).contexts.lock();
+    let (index, guard) = find_runnable(&contexts, runnable)?;
+
+    if let Some(context_lock) = contexts.remove(index) {
+        contexts.push_back(context_lock);
+    }
+    Some(guard)
+}
@@ -1,3 +1,18 @@
+//! Signal delivery.
+//!
+//! Signal masks and pending sets are not kept in [`Context`](super::Context), but in the
+//! `Sigcontrol` and `SigProcControl` structs shared with userspace (see
+//! [`SignalState`](super::context::SignalState)). Each thread word holds the pending bits in its
+//! low half and the allowed (unmasked) bits in its high half, so userspace implements
+//! `sigprocmask` without entering the kernel, and restores the saved mask itself when the handler
+//! returns through the trampoline. Senders set pending bits with `fetch_or`, which
+//! makes raising an already pending standard signal idempotent, while realtime signals are
+//! additionally queued in `rtqs`.
+//!
+//! The kernel only redirects a context to its handler, in [`signal_handler`], when there is a
+//! pending signal that is not masked and delivery is not inhibited. Masked signals stay pending
+//! until userspace unmasks them and checks for pending signals again.
+
 use core::sync::atomic::Ordering;
 
 use crate::{
@@ -51,6 +66,9 @@ pub fn signal_handler() {
 
     let sigh_instr_ptr = st.user_handler.get();
 
+    // The handler must return to the start of an interrupted critical section, not its middle.
+    context.rseq_abort();
+
     let Some(regs) = context.regs_mut() else {
         // TODO: is this even reachable?
         log::trace!("No registers, returning");
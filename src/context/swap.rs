@@ -0,0 +1,79 @@
+//! Swapping of anonymous memory to a backing store.
+//!
+//! Pages of private anonymous grants can be written out to a swap backend and their frames freed
+//! (see [`AddrSpaceWrapper::swap_out`]). The address space remembers the slot each page was
+//! written to, and the page fault handler reads the page back into a new frame on the next access.
+//! Neither holds the address space lock while the backend is busy. Forks and copy-on-write
+//! snapshots refer to the same slots rather than reading every page back in, so a slot is only
+//! released once the last address space referring to it has swapped it in or unmapped it.
+//!
+//! Scheme requests can currently only refer to user memory, so a backend cannot yet be provided by
+//! a userspace scheme directly. Until then, backends are implemented in the kernel and registered
+//! with [`set_backend`].
+//!
+//! [`AddrSpaceWrapper::swap_out`]: super::memory::AddrSpaceWrapper::swap_out
+
+use alloc::sync::Arc;
+use core::fmt;
+
+use spin::RwLock;
+use syscall::error::*;
+
+use crate::memory::Frame;
+
+/// Identifies a page-sized slot in the swap backend.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct SwapSlot(pub u64);
+
+pub trait SwapBackend: Send + Sync {
+    /// Reserve a slot for a single page.
+    fn allocate_slot(&self) -> Result<SwapSlot>;
+    /// Release a slot, whose contents are no longer needed.
+    fn free_slot(&self, slot: SwapSlot);
+    /// Write the contents of `frame` to `slot`.
+    fn write_page(&self, slot: SwapSlot, frame: Frame) -> Result<()>;
+    /// Read the contents of `slot` into `frame`.
+    fn read_page(&self, slot: SwapSlot, frame: Frame) -> Result<()>;
+}
+
+static BACKEND: RwLock<Option<Arc<dyn SwapBackend>>> = RwLock::new(None);
+
+/// Set the backend used for swapping out pages from now on. Pages already swapped out remain
+/// associated with the backend they were written to.
+pub fn set_backend(backend: Option<Arc<dyn SwapBackend>>) {
+    *BACKEND.write() = backend;
+}
+
+/// The current swap backend, if any.
+pub fn backend() -> Option<Arc<dyn SwapBackend>> {
+    BACKEND.read().clone()
+}
+
+/// A page that was written to a swap backend. Dropping it releases its slot.
+pub struct SwappedPage {
+    backend: Arc<dyn SwapBackend>,
+    slot: SwapSlot,
+}
+impl SwappedPage {
+    /// Write the contents of `frame` to a new slot of `backend`.
+    pub fn write(backend: Arc<dyn SwapBackend>, frame: Frame) -> Result<Self> {
+        let slot = backend.allocate_slot()?;
+        let this = Self { backend, slot };
+        this.backend.write_page(slot, frame)?;
+        Ok(this)
+    }
+    /// Read the contents of the page back into `frame`.
+    pub fn read(&self, frame: Frame) -> Result<()> {
+        self.backend.read_page(self.slot, frame)
+    }
+}
+impl Drop for SwappedPage {
+    fn drop(&mut self) {
+        self.backend.free_slot(self.slot);
+    }
+}
+impl fmt::Debug for SwappedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SwappedPage").field(&self.slot).finish()
+    }
+}
@@ -1,8 +1,7 @@
 use core::{
     cell::{Cell, RefCell},
     mem,
-    ops::Bound,
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
 
 use alloc::sync::Arc;
@@ -10,14 +9,16 @@ use spinning_top::{guard::ArcRwSpinlockWriteGuard, RwSpinlock};
 use syscall::PtraceFlags;
 
 use crate::{
-    context::{arch, contexts, Context},
-    cpu_set::LogicalCpuId,
+    context::{arch, run_queue, Context},
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
     interrupt,
+    irql::{self, Irql},
     percpu::PercpuBlock,
     ptrace, time,
 };
 
-use super::ContextRef;
+#[cfg(feature = "kstack_canary")]
+use super::context::KSTACK_CANARY;
 
 enum UpdateResult {
     CanSwitch,
@@ -30,12 +31,19 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
         return UpdateResult::Skip;
     }
 
+    // Ignore contexts stopped by a debugger, whatever their status, unless they are being killed
+    if context.is_frozen() && !context.being_sigkilled {
+        return UpdateResult::Skip;
+    }
+
     // Ignore contexts assigned to other CPUs
     if !context.sched_affinity.contains(cpu_id) {
         return UpdateResult::Skip;
     }
 
     //TODO: HACK TO WORKAROUND HANGS BY PINNING TO ONE CPU
+    // Contexts only run on their home CPU, until switching no longer takes the global switch lock.
+    // Only changing the affinity moves them, see run_queue::rehome.
     if !context.cpu_id.map_or(true, |x| x == cpu_id) {
         return UpdateResult::Skip;
     }
@@ -70,101 +78,310 @@ pub fn tick() {
     let new_ticks = ticks_cell.get() + 1;
     ticks_cell.set(new_ticks);
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if new_ticks >= 3 {
-        switch();
+    #[cfg(feature = "graphical_debug")]
+    crate::devices::graphical_debug::flush();
+
+    // Switch after 3 ticks (about 6.75 ms), unless the priority level is raised or softirqs are
+    // being run, in which case the context is preempted on the first tick after that.
+    if new_ticks >= 3 && irql::current() == Irql::PASSIVE && !crate::softirq::is_running() {
+        if switch() == SwitchResult::Switched {
+            // The context was preempted, so any critical section it was in has to be restarted.
+            crate::context::current().write().rseq_abort();
+        }
         crate::context::signal::signal_handler();
     }
 }
 
+/// Number of buckets in the switch latency histogram. Bucket `i` counts switches that took
+/// `[2^i, 2^(i+1))` cycles, with bucket 0 also counting zero-cycle switches.
+pub const SWITCH_LATENCY_BUCKETS: usize = 32;
+
+static SWITCH_TIMING: AtomicBool = AtomicBool::new(false);
+static SWITCH_LATENCY: [AtomicU64; SWITCH_LATENCY_BUCKETS] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; SWITCH_LATENCY_BUCKETS]
+};
+
+/// Enables or disables measuring the latency of the low-level switch, from just before the
+/// previous context's state is saved, until the next context has released the switch locks.
+/// When disabled, this costs a single branch per switch. Controlled by writing `0` or `1` to
+/// `debug:ctl-switch-timing`, and the histogram is read from `sys:switch_latency`.
+pub fn set_switch_timing(enabled: bool) {
+    SWITCH_TIMING.store(enabled, Ordering::Relaxed);
+}
+/// Returns the switch latency histogram, in cycles, accumulated over all CPUs while timing was
+/// enabled.
+pub fn switch_latency_histogram() -> [u64; SWITCH_LATENCY_BUCKETS] {
+    core::array::from_fn(|i| SWITCH_LATENCY[i].load(Ordering::Relaxed))
+}
+pub fn reset_switch_latency_histogram() {
+    for bucket in &SWITCH_LATENCY {
+        bucket.store(0, Ordering::Relaxed);
+    }
+}
+fn record_switch_latency(start: u64) {
+    let Some(end) = crate::arch::pmu::timestamp() else {
+        return;
+    };
+    let cycles = end.saturating_sub(start);
+    let bucket = (cycles.checked_ilog2().unwrap_or(0) as usize).min(SWITCH_LATENCY_BUCKETS - 1);
+    SWITCH_LATENCY[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Panics if the kernel stack of the current context has overflowed, as detected by its canary
+/// having been overwritten. Only a load and a comparison on the fast path.
+#[cfg(feature = "kstack_canary")]
+#[inline]
+pub fn check_kstack_canary() {
+    let address = PercpuBlock::current().switch_internals.kstack_canary.get();
+    if address != 0 && unsafe { (address as *const usize).read_volatile() } != KSTACK_CANARY {
+        kstack_overflow(address);
+    }
+}
+#[cfg(not(feature = "kstack_canary"))]
+#[inline(always)]
+pub fn check_kstack_canary() {}
+
+#[cfg(feature = "kstack_canary")]
+#[cold]
+fn kstack_overflow(address: usize) -> ! {
+    panic!("kernel stack overflow: canary at {address:#x} was overwritten");
+}
+
 pub unsafe extern "C" fn switch_finish_hook() {
-    if let Some(switch_result) = PercpuBlock::current().switch_internals.switch_result.take() {
+    let switch_internals = &PercpuBlock::current().switch_internals;
+
+    if let Some(switch_result) = switch_internals.switch_result.take() {
         drop(switch_result);
     } else {
         // TODO: unreachable_unchecked()?
         crate::arch::stop::emergency_reset();
     }
-    arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
+    if let Some(start) = switch_internals.switch_start.take() {
+        record_switch_latency(start);
+    }
+    arch::CONTEXT_SWITCH_LOCK.store(NO_OWNER, Ordering::SeqCst);
     crate::percpu::switch_arch_hook();
 }
 
+/// Value of [`arch::CONTEXT_SWITCH_LOCK`] while no CPU holds it. Otherwise, it holds the ID of the
+/// CPU that does, so that the owner is always consistent with the lock itself.
+pub const NO_OWNER: u32 = u32::MAX;
+
+/// Time at which the context switch lock was last acquired, in nanoseconds. Only meaningful while
+/// the lock is held, as it is not reset on release.
+static SWITCH_LOCK_SINCE: AtomicU64 = AtomicU64::new(0);
+/// CPUs that have stopped for good, and will thus never release the locks they hold.
+static DEAD_CPUS: LogicalCpuSet = LogicalCpuSet::empty();
+/// CPUs that a watchdog found stuck while holding the context switch lock, and asked to stop.
+static STOP_REQUESTED: LogicalCpuSet = LogicalCpuSet::empty();
+static SWITCH_LOCK_RECOVERY: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Number of times [`switch`] retries acquiring the context switch lock between two checks of
+/// whether its owner is stuck. Retries are counted rather than time measured, as the clock may not
+/// advance while the CPU driving it is waiting for the lock as well.
+const SWITCH_WATCHDOG_SPINS: usize = 1 << 26;
+
+/// Returns the CPU currently holding the context switch lock, if any, and since when.
+pub fn switch_lock_owner() -> Option<(LogicalCpuId, u64)> {
+    let owner = arch::CONTEXT_SWITCH_LOCK.load(Ordering::SeqCst);
+    let since = SWITCH_LOCK_SINCE.load(Ordering::SeqCst);
+    (owner != NO_OWNER).then(|| (LogicalCpuId::new(owner), since))
+}
+/// Marks the current CPU as stopped for good, e.g. after a panic, allowing
+/// [`recover_switch_lock`] to release the context switch lock if this CPU held it.
+pub fn mark_cpu_dead() {
+    DEAD_CPUS.atomic_set(crate::cpu_id());
+}
+/// Force-releases the context switch lock if it is held by a CPU marked with [`mark_cpu_dead`],
+/// which would otherwise make every other CPU wait forever in [`switch`]. Returns whether the lock
+/// was released. Called when a CPU panics, and by the watchdog in [`switch`], once the CPU it
+/// found stuck has stopped.
+///
+/// The contexts the dead CPU was switching between remain locked, and will never run again.
+pub fn recover_switch_lock() -> bool {
+    let Some(_guard) = SWITCH_LOCK_RECOVERY.try_lock() else {
+        return false;
+    };
+    let Some((owner, since)) = switch_lock_owner() else {
+        return false;
+    };
+    if !DEAD_CPUS.atomic_contains(owner) {
+        return false;
+    }
+
+    log::error!(
+        "Releasing the context switch lock held by CPU {} since {} ns, which has stopped",
+        owner.get(),
+        since
+    );
+    // A dead CPU cannot acquire the lock again, so the exchange only fails if it has been released
+    // in the meantime, in which case it must not be released on behalf of the next owner.
+    arch::CONTEXT_SWITCH_LOCK
+        .compare_exchange(owner.get(), NO_OWNER, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+}
+/// Called by [`switch`] every [`SWITCH_WATCHDOG_SPINS`] failed attempts to acquire the context
+/// switch lock. If the same acquisition as in the previous call, recorded in `seen`, still holds
+/// the lock, its owner is considered stuck. On x86, it is then asked to stop using an NMI, which
+/// reaches it even with interrupts disabled, after which the lock is released. Elsewhere, the
+/// lock is only released if its owner has panicked.
+fn switch_lock_watchdog(seen: &mut Option<(LogicalCpuId, u64)>) {
+    let Some((owner, since)) = switch_lock_owner() else {
+        *seen = None;
+        return;
+    };
+    if seen.replace((owner, since)) != Some((owner, since)) {
+        return;
+    }
+    if DEAD_CPUS.atomic_contains(owner) {
+        recover_switch_lock();
+        return;
+    }
+    if STOP_REQUESTED.atomic_contains(owner) {
+        return;
+    }
+    log::error!(
+        "CPU {} has held the context switch lock since {} ns",
+        owner.get(),
+        since
+    );
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        STOP_REQUESTED.atomic_set(owner);
+        crate::ipi::ipi_nmi(owner);
+    }
+}
+/// Called from the NMI handler. If a watchdog found this CPU stuck while holding the context
+/// switch lock, prints `dump` as a diagnostic, and stops the CPU for good, releasing the lock.
+/// Returns otherwise, including if the lock has been released since.
+pub fn handle_watchdog_nmi(dump: impl FnOnce()) {
+    let cpu_id = crate::cpu_id();
+    if !STOP_REQUESTED.atomic_contains(cpu_id) {
+        return;
+    }
+    if arch::CONTEXT_SWITCH_LOCK.load(Ordering::SeqCst) != cpu_id.get() {
+        STOP_REQUESTED.atomic_clear(cpu_id);
+        return;
+    }
+    println!(
+        "CPU {} stuck while holding the context switch lock",
+        cpu_id.get()
+    );
+    dump();
+    mark_cpu_dead();
+    recover_switch_lock();
+    // Done last, as this CPU may have stopped while holding a freelist lock.
+    crate::memory::drain_frame_cache_for_offline();
+    loop {
+        unsafe {
+            interrupt::disable();
+            interrupt::halt();
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SwitchResult {
+    /// Another context ran, and has since switched back to this one.
     Switched,
+    /// No other context was runnable, so the current context kept running.
     AllContextsIdle,
+    /// Another CPU was switching contexts, so nothing was done. Only returned by [`try_switch`].
+    WouldBlock,
 }
 
-/// Switch to the next context, picked by the scheduler.
+/// Switch to the next context, picked by the scheduler, waiting for other CPUs that are
+/// switching at the same time.
 ///
 /// This is not memory-unsafe to call, but do NOT call this while holding locks!
 pub fn switch() -> SwitchResult {
     let percpu = PercpuBlock::current();
+    let mut spins = 0;
+    let mut seen = None;
+
+    loop {
+        match try_switch() {
+            SwitchResult::WouldBlock => {
+                interrupt::pause();
+                percpu.maybe_handle_tlb_shootdown();
+
+                spins += 1;
+                if spins == SWITCH_WATCHDOG_SPINS {
+                    spins = 0;
+                    switch_lock_watchdog(&mut seen);
+                }
+            }
+            result => return result,
+        }
+    }
+}
 
-    //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
-    percpu.switch_internals.pit_ticks.set(0);
+/// Like [`switch`], but returns [`SwitchResult::WouldBlock`] instead of waiting if another CPU is
+/// switching contexts.
+///
+/// This is not memory-unsafe to call, but do NOT call this while holding locks!
+pub fn try_switch() -> SwitchResult {
+    let percpu = PercpuBlock::current();
+    assert_eq!(
+        percpu.irql.get(),
+        Irql::PASSIVE,
+        "switching contexts at a raised IRQL"
+    );
 
     // Set the global lock to avoid the unsafe operations below from causing issues
     // TODO: Better memory orderings?
-    while arch::CONTEXT_SWITCH_LOCK
-        .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+    if arch::CONTEXT_SWITCH_LOCK
+        .compare_exchange(
+            NO_OWNER,
+            percpu.cpu_id.get(),
+            Ordering::SeqCst,
+            Ordering::Relaxed,
+        )
         .is_err()
     {
-        interrupt::pause();
-        percpu.maybe_handle_tlb_shootdown();
+        return SwitchResult::WouldBlock;
     }
 
+    //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
+    percpu.switch_internals.pit_ticks.set(0);
+
     let cpu_id = crate::cpu_id();
     let switch_time = crate::time::monotonic();
+    SWITCH_LOCK_SINCE.store(switch_time as u64, Ordering::Relaxed);
+    let pmu_counters = crate::arch::pmu::read();
 
     let mut switch_context_opt = None;
     {
-        let contexts = contexts();
-
         // Lock previous context
         let prev_context_lock = crate::context::current();
         let prev_context_guard = prev_context_lock.write_arc();
 
         let idle_context = percpu.switch_internals.idle_context();
-        let mut skip_idle = true;
-
-        // Locate next context
-        for next_context_lock in contexts
-            // Include all contexts with IDs greater than the current...
-            .range((
-                Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-                Bound::Unbounded,
-            ))
-            .chain(
-                contexts
-                    // ... and all contexts with IDs less than the current...
-                    .range((
-                        Bound::Unbounded,
-                        Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-                    )),
+
+        // Update state of a candidate and check if runnable. Candidates are locked without
+        // waiting, and skipped if locked elsewhere, which in particular skips the current context.
+        // This also keeps the contexts locked by a CPU that stopped mid-switch from stalling every
+        // other CPU, see `recover_switch_lock`.
+        let runnable = |context: &mut Context| {
+            matches!(
+                unsafe { update_runnable(context, cpu_id) },
+                UpdateResult::CanSwitch
             )
-            .filter_map(|r| r.upgrade())
-            .chain(Some(Arc::clone(&idle_context)))
-        // ... but not the current context, which is already locked
-        {
-            if Arc::ptr_eq(&next_context_lock, &idle_context) && skip_idle {
-                // Skip idle process the first time it shows up
-                skip_idle = false;
-                continue;
-            }
+        };
 
-            // Lock next context
-            let mut next_context_guard = next_context_lock.write_arc();
-
-            // Update state of next context and check if runnable
-            if let UpdateResult::CanSwitch =
-                unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
-            {
-                // Store locks for previous and next context
-                switch_context_opt = Some((prev_context_guard, next_context_guard));
-                break;
-            } else {
-                continue;
-            }
+        // Locate next context in the run queue of this CPU, and otherwise fall back to the idle
+        // context
+        let next_context_guard = run_queue::pick(cpu_id, runnable)
+            .or_else(|| {
+                let mut guard = idle_context.try_write_arc()?;
+                runnable(&mut *guard).then_some(guard)
+            });
+
+        if let Some(next_context_guard) = next_context_guard {
+            // Store locks for previous and next context
+            switch_context_opt = Some((prev_context_guard, next_context_guard));
         }
     };
 
@@ -172,16 +389,27 @@ pub fn switch() -> SwitchResult {
     if let Some((mut prev_context_guard, mut next_context_guard)) = switch_context_opt {
         // TODO: Update timestamps in switch_to
 
+        // If the affinity of the old context was changed to exclude this CPU while it was running,
+        // move it elsewhere now that it is switched away from
+        if !prev_context_guard.sched_affinity.atomic_contains(cpu_id) {
+            let prev_context_lock =
+                Arc::clone(ArcRwSpinlockWriteGuard::rwlock(&prev_context_guard));
+            run_queue::rehome(&prev_context_lock, &mut prev_context_guard);
+        }
+
         // Set old context as not running and update CPU time
         let prev_context = &mut *prev_context_guard;
         prev_context.running = false;
         prev_context.cpu_time += switch_time.saturating_sub(prev_context.switch_time);
+        prev_context.account_pmu(pmu_counters);
 
         // Set new context as running and set switch time
         let next_context = &mut *next_context_guard;
         next_context.running = true;
         next_context.cpu_id = Some(cpu_id);
+        next_context.set_last_cpu(cpu_id);
         next_context.switch_time = switch_time;
+        next_context.pmu_switch = pmu_counters;
 
         let percpu = PercpuBlock::current();
         unsafe {
@@ -216,6 +444,9 @@ pub fn switch() -> SwitchResult {
         *percpu.ptrace_session.borrow_mut() = ptrace_session;
         percpu.ptrace_flags.set(ptrace_flags);
         prev_context.inside_syscall = percpu.inside_syscall.replace(next_context.inside_syscall);
+        prev_context.page_fault_depth = percpu
+            .page_fault_depth
+            .replace(next_context.page_fault_depth);
 
         #[cfg(feature = "syscall_debug")]
         {
@@ -231,6 +462,24 @@ pub fn switch() -> SwitchResult {
             .being_sigkilled
             .set(next_context.being_sigkilled);
 
+        if SWITCH_TIMING.load(Ordering::Relaxed) {
+            percpu
+                .switch_internals
+                .switch_start
+                .set(crate::arch::pmu::timestamp());
+        }
+
+        #[cfg(feature = "kstack_canary")]
+        {
+            check_kstack_canary();
+            percpu.switch_internals.kstack_canary.set(
+                next_context
+                    .kstack
+                    .as_ref()
+                    .map_or(0, |kstack| kstack.canary_address()),
+            );
+        }
+
         unsafe {
             arch::switch_to(prev_context, next_context);
         }
@@ -244,7 +493,7 @@ pub fn switch() -> SwitchResult {
         SwitchResult::Switched
     } else {
         // No target was found, unset global lock and return
-        arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
+        arch::CONTEXT_SWITCH_LOCK.store(NO_OWNER, Ordering::SeqCst);
 
         SwitchResult::AllContextsIdle
     }
@@ -255,12 +504,21 @@ pub struct ContextSwitchPercpu {
     switch_result: Cell<Option<SwitchResultInner>>,
     pit_ticks: Cell<usize>,
 
+    // The context running on this CPU. Holding a strong reference ensures it cannot be freed
+    // while it is current, even if it has already been removed from the contexts list.
     current_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
     // The idle process
     idle_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
     pub(crate) being_sigkilled: Cell<bool>,
+
+    // Timestamp taken before the low-level switch, if switch timing is enabled
+    switch_start: Cell<Option<u64>>,
+
+    // Canary address of the current context's kernel stack, or 0 if it has none
+    #[cfg(feature = "kstack_canary")]
+    kstack_canary: Cell<usize>,
 }
 impl ContextSwitchPercpu {
     pub fn with_context<T>(&self, f: impl FnOnce(&Arc<RwSpinlock<Context>>) -> T) -> T {
@@ -270,6 +528,9 @@ impl ContextSwitchPercpu {
             .as_ref()
             .expect("not inside of context"))
     }
+    pub fn has_current_context(&self) -> bool {
+        self.current_ctxt.borrow().is_some()
+    }
     pub unsafe fn set_current_context(&self, new: Arc<RwSpinlock<Context>>) {
         *self.current_ctxt.borrow_mut() = Some(new);
     }
@@ -0,0 +1,330 @@
+//! Userspace page fault handling, similar to Linux's userfaultfd.
+//!
+//! An address space can have a single handler registered, covering a set of page spans. Faults on
+//! pages in those spans that are not yet present are not resolved by the kernel. Instead the
+//! faulting context is blocked, and an event is queued on the handler. The handler reads the
+//! event, fills the page (see [`AddrSpaceWrapper::userfault_fill`]), which in turn wakes the
+//! faulting contexts so they can retry the access. If the handler goes away while contexts are
+//! still waiting, they are woken and the fault fails with a segfault. The wait can also be
+//! interrupted by SIGKILL, so that a context waiting on an unresponsive handler can be killed.
+//!
+//! The handler can also map emulated grants (see [`AddrSpaceWrapper::userfault_map_emulated`]),
+//! for trap-and-emulate devices. Those are never backed by memory. Instead, every access to them
+//! is decoded and forwarded to the handler as an event carrying its size and, for writes, the
+//! value written. The faulting context stays blocked until the handler completes the access,
+//! providing the value read, after which the instruction is skipped.
+//!
+//! [`AddrSpaceWrapper::userfault_fill`]: super::memory::AddrSpaceWrapper::userfault_fill
+//! [`AddrSpaceWrapper::userfault_map_emulated`]:
+//! super::memory::AddrSpaceWrapper::userfault_map_emulated
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    context::{
+        self,
+        memory::{AccessMode, PageSpan},
+        Context,
+    },
+    paging::{Page, VirtualAddress},
+    sync::WaitQueue,
+};
+
+/// Register a span, arguments `[base, size]`.
+pub const USERFAULT_OP_REGISTER: usize = 0;
+/// Unregister a span previously registered, arguments `[base, size]`.
+pub const USERFAULT_OP_UNREGISTER: usize = 1;
+/// Fill a page and wake its waiters, arguments `[page address, source buffer address]`.
+pub const USERFAULT_OP_FILL: usize = 2;
+/// Wake the waiters on a page without filling it, arguments `[page address]`.
+pub const USERFAULT_OP_WAKE: usize = 3;
+/// Map an emulated grant, arguments `[base, size, protection flags]`.
+pub const USERFAULT_OP_MAP_EMULATED: usize = 4;
+/// Complete the oldest pending emulated access at an address, arguments `[address, value]`. The
+/// value is only used for reads.
+pub const USERFAULT_OP_COMPLETE: usize = 5;
+
+/// Set in [`UserFaultEvent::flags`] if the faulting access was a write.
+pub const USERFAULT_FLAG_WRITE: usize = 1 << 0;
+/// Set in [`UserFaultEvent::flags`] if the faulting access was an instruction fetch.
+pub const USERFAULT_FLAG_EXEC: usize = 1 << 1;
+/// Set in [`UserFaultEvent::flags`] if the event is an access to an emulated grant, which has to
+/// be completed using [`USERFAULT_OP_COMPLETE`].
+pub const USERFAULT_FLAG_EMULATE: usize = 1 << 2;
+
+/// Event read by the handler, one per blocked fault.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct UserFaultEvent {
+    /// Page-aligned address of the faulting page, or for emulated accesses, the exact address.
+    pub address: usize,
+    pub flags: usize,
+    /// Size of an emulated access in bytes, otherwise zero.
+    pub size: usize,
+    /// Value written by an emulated write, otherwise zero.
+    pub value: u64,
+}
+
+#[derive(Debug)]
+struct PendingAccess {
+    context: Weak<RwSpinlock<Context>>,
+    result: Arc<Mutex<Option<u64>>>,
+}
+
+#[derive(Debug)]
+pub struct UserFaultHandler {
+    events: WaitQueue<UserFaultEvent>,
+    waiters: Mutex<BTreeMap<Page, Vec<Weak<RwSpinlock<Context>>>>>,
+    /// Emulated accesses awaiting completion, by address, oldest first.
+    emulated: Mutex<BTreeMap<usize, VecDeque<PendingAccess>>>,
+    alive: AtomicBool,
+}
+
+impl UserFaultHandler {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            events: WaitQueue::new(),
+            waiters: Mutex::new(BTreeMap::new()),
+            emulated: Mutex::new(BTreeMap::new()),
+            alive: AtomicBool::new(true),
+        })
+    }
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+    pub fn events(&self) -> &WaitQueue<UserFaultEvent> {
+        &self.events
+    }
+
+    /// Block `context_lock`, which must be the current context, on a fault on `page`, queue a
+    /// fault event for it, and wait until the handler wakes it. Returns false if the wait was
+    /// instead interrupted by SIGKILL. The caller must not hold any locks.
+    pub fn await_fault(
+        &self,
+        context_lock: &Arc<RwSpinlock<Context>>,
+        page: Page,
+        access: AccessMode,
+    ) -> bool {
+        // Block before the event becomes visible, so that a wakeup racing with the switch is not
+        // lost.
+        {
+            let mut context = context_lock.write();
+            if !context.being_sigkilled {
+                context.block("userfault");
+            }
+        }
+
+        self.waiters
+            .lock()
+            .entry(page)
+            .or_default()
+            .push(Arc::downgrade(context_lock));
+
+        // The handler may have died between the caller checking and the waiter being recorded.
+        if !self.is_alive() {
+            self.wake(page);
+            return true;
+        }
+
+        let flags = match access {
+            AccessMode::Read => 0,
+            AccessMode::Write => USERFAULT_FLAG_WRITE,
+            AccessMode::InstrFetch => USERFAULT_FLAG_EXEC,
+        };
+        self.events.send(UserFaultEvent {
+            address: page.start_address().data(),
+            flags,
+            size: 0,
+            value: 0,
+        });
+
+        loop {
+            context::switch();
+
+            // Waking removes the waiter, so if it is still recorded, this was some other wakeup,
+            // such as a signal. Checking and blocking again is done with the waiters locked, so
+            // that a concurrent wakeup is not lost.
+            let mut waiters = self.waiters.lock();
+            let Some(page_waiters) = waiters.get_mut(&page) else {
+                return true;
+            };
+            let Some(index) = page_waiters
+                .iter()
+                .position(|waiter| waiter.as_ptr() == Arc::as_ptr(context_lock))
+            else {
+                return true;
+            };
+
+            let mut context = context_lock.write();
+            if context.being_sigkilled {
+                page_waiters.swap_remove(index);
+                if page_waiters.is_empty() {
+                    waiters.remove(&page);
+                }
+                return false;
+            }
+            context.block("userfault");
+        }
+    }
+
+    /// Block `context_lock`, which must be the current context, on an emulated access of `size`
+    /// bytes at `address`, writing `write_value` if it is a write, queue an event for it, and wait
+    /// until the handler completes it. Returns the value the access was completed with, or None if
+    /// the handler died or the wait was interrupted by SIGKILL. The caller must not hold any locks.
+    pub fn await_emulated(
+        &self,
+        context_lock: &Arc<RwSpinlock<Context>>,
+        address: VirtualAddress,
+        size: u8,
+        write_value: Option<u64>,
+    ) -> Option<u64> {
+        let result = Arc::new(Mutex::new(None));
+
+        // Like for faults, block before the event becomes visible.
+        {
+            let mut context = context_lock.write();
+            if !context.being_sigkilled {
+                context.block("userfault emulation");
+            }
+        }
+        self.emulated
+            .lock()
+            .entry(address.data())
+            .or_default()
+            .push_back(PendingAccess {
+                context: Arc::downgrade(context_lock),
+                result: Arc::clone(&result),
+            });
+
+        if self.is_alive() {
+            let mut flags = USERFAULT_FLAG_EMULATE;
+            if write_value.is_some() {
+                flags |= USERFAULT_FLAG_WRITE;
+            }
+            self.events.send(UserFaultEvent {
+                address: address.data(),
+                flags,
+                size: usize::from(size),
+                value: write_value.unwrap_or(0),
+            });
+        }
+
+        loop {
+            // The handler may have died before the access was queued, in which case nothing will
+            // wake this context.
+            if self.is_alive() {
+                context::switch();
+            }
+
+            // The result is set with the pending accesses locked, so that checking and blocking
+            // again cannot miss a completion.
+            let mut emulated = self.emulated.lock();
+            if let Some(value) = *result.lock() {
+                return Some(value);
+            }
+
+            let mut context = context_lock.write();
+            if !self.is_alive() || context.being_sigkilled {
+                if let Some(pending) = emulated.get_mut(&address.data()) {
+                    pending.retain(|access| !Arc::ptr_eq(&access.result, &result));
+                    if pending.is_empty() {
+                        emulated.remove(&address.data());
+                    }
+                }
+                context.unblock();
+                return None;
+            }
+            context.block("userfault emulation");
+        }
+    }
+
+    /// Complete the oldest pending emulated access at `address` with `value`, and wake the context
+    /// blocked on it. Returns false if there was none.
+    pub fn complete(&self, address: VirtualAddress, value: u64) -> bool {
+        let access = {
+            let mut emulated = self.emulated.lock();
+            let Some(pending) = emulated.get_mut(&address.data()) else {
+                return false;
+            };
+            let access = pending.pop_front();
+            if pending.is_empty() {
+                emulated.remove(&address.data());
+            }
+            let Some(access) = access else {
+                return false;
+            };
+            *access.result.lock() = Some(value);
+            access
+        };
+        wake_all(core::iter::once(access.context));
+        true
+    }
+
+    /// Wake all contexts blocked on a fault on `page`.
+    pub fn wake(&self, page: Page) {
+        let waiters = self.waiters.lock().remove(&page).unwrap_or_default();
+        wake_all(waiters);
+    }
+
+    /// Wake all contexts blocked on a fault within `span`.
+    pub fn wake_span(&self, span: PageSpan) {
+        let waiters = {
+            let mut guard = self.waiters.lock();
+            let pages = guard
+                .range(span.base..span.end())
+                .map(|(page, _)| *page)
+                .collect::<Vec<_>>();
+            pages
+                .into_iter()
+                .filter_map(|page| guard.remove(&page))
+                .flatten()
+                .collect::<Vec<_>>()
+        };
+        wake_all(waiters);
+    }
+
+    /// Mark the handler as dead, and wake all blocked contexts. Their faults will then fail.
+    pub fn kill(&self) {
+        self.alive.store(false, Ordering::Release);
+
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        wake_all(waiters.into_values().flatten());
+
+        let emulated = core::mem::take(&mut *self.emulated.lock());
+        wake_all(
+            emulated
+                .into_values()
+                .flatten()
+                .map(|access| access.context),
+        );
+    }
+}
+
+fn wake_all(waiters: impl IntoIterator<Item = Weak<RwSpinlock<Context>>>) {
+    for context_lock in waiters.into_iter().filter_map(|w| w.upgrade()) {
+        context_lock.write().unblock();
+    }
+}
+
+/// Userspace fault handler registration of an address space.
+#[derive(Debug)]
+pub struct UserFaultRegistration {
+    pub handler: Arc<UserFaultHandler>,
+    pub spans: Vec<PageSpan>,
+}
+
+impl UserFaultRegistration {
+    pub fn covers(&self, page: Page) -> bool {
+        self.spans
+            .iter()
+            .any(|span| span.intersects(PageSpan::new(page, 1)))
+    }
+}
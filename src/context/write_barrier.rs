@@ -0,0 +1,49 @@
+//! Programmable write barriers, e.g. for a garbage collector tracking which pages are mutated, or
+//! for software-managed copy-on-write.
+//!
+//! An address space can have a single [`WriteBarrier`] attached, together with a set of armed page
+//! spans. Pages of owned grants within those spans are mapped read-only even if the grant is
+//! writable, so that the first write to each page faults. The fault handler then lets the barrier
+//! decide whether to allow the write, and if so maps the page writable. Further writes to the page
+//! do not fault, until its span is armed again (see [`AddrSpaceWrapper::write_barrier_arm`]).
+//!
+//! Address spaces without a barrier attached only pay for checking that on page faults.
+//!
+//! [`AddrSpaceWrapper::write_barrier_arm`]: super::memory::AddrSpaceWrapper::write_barrier_arm
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+
+use syscall::error::*;
+
+use crate::{context::memory::PageSpan, paging::Page};
+
+pub trait WriteBarrier: Send + Sync {
+    /// Called on the first write to `page` since it was armed, before the page is made writable.
+    /// Returning an error rejects the write, which then fails as if the page was read-only.
+    ///
+    /// This runs in kernel context on the faulting CPU, with the address space write-locked, and
+    /// hence must not access that address space.
+    fn on_write(&self, page: Page) -> Result<()>;
+}
+
+/// Write barrier registration of an address space.
+pub struct WriteBarrierRegistration {
+    pub barrier: Arc<dyn WriteBarrier>,
+    pub spans: Vec<PageSpan>,
+}
+
+impl WriteBarrierRegistration {
+    pub fn covers(&self, page: Page) -> bool {
+        self.spans
+            .iter()
+            .any(|span| span.intersects(PageSpan::new(page, 1)))
+    }
+}
+impl fmt::Debug for WriteBarrierRegistration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBarrierRegistration")
+            .field("spans", &self.spans)
+            .finish_non_exhaustive()
+    }
+}
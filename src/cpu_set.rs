@@ -65,6 +65,13 @@ impl LogicalCpuSet {
         let (word, bit) = parts(id);
         *self.0[word].get_mut() & (1 << bit) != 0
     }
+    pub fn atomic_contains(&self, id: LogicalCpuId) -> bool {
+        let (word, bit) = parts(id);
+        self.0[word].load(Ordering::Acquire) & (1 << bit) != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|w| w.load(Ordering::Acquire) == 0)
+    }
     pub fn atomic_set(&self, id: LogicalCpuId) {
         let (word, bit) = parts(id);
         let _ = self.0[word].fetch_or(1 << bit, Ordering::Release);
@@ -81,6 +88,20 @@ impl LogicalCpuSet {
         self.0.each_ref().map(|w| w.load(Ordering::Acquire))
     }
 
+    /// Iterate over a snapshot of the set. Each word is read atomically, but CPUs may be added or
+    /// removed concurrently.
+    pub fn iter(&self) -> impl Iterator<Item = LogicalCpuId> {
+        let raw = self.to_raw();
+        (0..SET_WORDS).flat_map(move |i| {
+            (0..usize::BITS).filter_map(move |b| {
+                if raw[i] & 1 << b != 0 {
+                    Some(LogicalCpuId::new(i as u32 * usize::BITS + b))
+                } else {
+                    None
+                }
+            })
+        })
+    }
     pub fn iter_mut(&mut self) -> impl Iterator<Item = LogicalCpuId> + '_ {
         // TODO: Will this be optimized away?
         self.0.iter_mut().enumerate().flat_map(move |(i, w)| {
@@ -1,8 +1,4 @@
-use crate::{
-    context::Context,
-    paging::{RmmA, RmmArch, TableKind, PAGE_SIZE},
-};
-use spinning_top::RwSpinlock;
+use crate::paging::{RmmA, RmmArch, TableKind, PAGE_SIZE};
 
 //TODO: combine arches into one function (aarch64 one is newest)
 
@@ -22,12 +18,13 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
 
     let mut spaces = HashSet::new();
 
-    for (id, context_lock) in crate::context::contexts().iter() {
-        if target_id.map_or(false, |target_id| *id != target_id) {
+    for context_ref in crate::context::contexts().iter() {
+        let id = context_ref.id();
+        if target_id.map_or(false, |target_id| id != target_id) {
             continue;
         }
-        let context = context_lock.read();
-        println!("{}: {}", (*id).get(), context.name);
+        let context = context_ref.0.read();
+        println!("{:#x}: {}", id.get(), context.name);
 
         println!("status: {:?}", context.status);
         if !context.status_reason.is_empty() {
@@ -128,12 +125,13 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
 
     let old_table = RmmA::table(TableKind::User);
 
-    for (id, context_lock) in crate::context::contexts().iter() {
-        if target_id.map_or(false, |target_id| *id != target_id) {
+    for context_ref in crate::context::contexts().iter() {
+        let id = context_ref.id();
+        if target_id.map_or(false, |target_id| id != target_id) {
             continue;
         }
-        let context = context_lock.read();
-        println!("{}: {}", (*id).get(), context.name);
+        let context = context_ref.0.read();
+        println!("{:#x}: {}", id.get(), context.name);
 
         // Switch to context page table to ensure syscall debug and stack dump will work
         if let Some(ref space) = context.addr_space {
@@ -211,10 +209,9 @@ pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
 
 // Super unsafe due to page table switching and raw pointers!
 #[cfg(target_arch = "x86_64")]
-pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
+pub unsafe fn debugger(target_id: Option<crate::context::ContextId>) {
     use core::sync::atomic::Ordering;
 
-    use alloc::sync::Arc;
     use hashbrown::HashSet;
 
     use crate::memory::{get_page_info, the_zeroed_frame, RefCount};
@@ -235,12 +232,13 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
 
     let old_table = RmmA::table(TableKind::User);
 
-    for context_lock in crate::context::contexts().iter() {
-        if target_id.map_or(false, |target_id| Arc::as_ptr(&context_lock.0) != target_id) {
+    for context_ref in crate::context::contexts().iter() {
+        let id = context_ref.id();
+        if target_id.map_or(false, |target_id| id != target_id) {
             continue;
         }
-        let context = context_lock.0.read();
-        println!("{:p}: {}", Arc::as_ptr(&context_lock.0), context.name);
+        let context = context_ref.0.read();
+        println!("{:#x}: {}", id.get(), context.name);
 
         if let Some(ref head) = context.syscall_head {
             tree.insert(head.get(), (1, false));
@@ -275,6 +273,11 @@ pub unsafe fn debugger(target_id: Option<*const RwSpinlock<Context>>) {
         }
         if let Some(ref addr_space) = context.addr_space {
             let addr_space = addr_space.acquire_read();
+            print!("active on cpus:");
+            for cpu_id in addr_space.active_cpus() {
+                print!(" {}", cpu_id);
+            }
+            println!();
             if !addr_space.grants.is_empty() {
                 println!("grants:");
                 for (base, info) in addr_space.grants.iter() {
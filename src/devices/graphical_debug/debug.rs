@@ -1,15 +1,23 @@
-use core::{cmp, ptr};
+use core::slice;
 
 use super::Display;
 
 static FONT: &[u8] = include_bytes!("../../../res/unifont.font");
 
+/// Background of the panic screen, chosen to be impossible to mistake for regular output.
+const PANIC_BACKGROUND: u32 = 0x0000_00AA;
+
 pub struct DebugDisplay {
     pub(super) display: Display,
     x: usize,
     y: usize,
     w: usize,
     h: usize,
+    background: u32,
+    /// Wrap lines at word boundaries rather than at any character.
+    wrap_words: bool,
+    /// Whether the cell at `x`, `y` is currently inverted to show the cursor.
+    cursor_shown: bool,
 }
 
 impl DebugDisplay {
@@ -22,6 +30,47 @@ impl DebugDisplay {
             y: 0,
             w,
             h,
+            background: 0,
+            wrap_words: false,
+            cursor_shown: false,
+        }
+    }
+
+    /// Switch to a new framebuffer geometry, clearing the screen and restarting output from the
+    /// top. Returns `None`, leaving the display unchanged, if the new geometry is invalid.
+    pub(super) fn resize(
+        &mut self,
+        width: usize,
+        height: usize,
+        stride: usize,
+        onscreen_ptr: *mut u32,
+    ) -> Option<()> {
+        self.display.resize(width, height, stride, onscreen_ptr)?;
+
+        self.w = width / 8;
+        self.h = height / 16;
+        self.x = 0;
+        self.y = 0;
+        self.background = 0;
+        self.cursor_shown = false;
+
+        Some(())
+    }
+
+    /// Clear the screen to the panic background and restart output from the top, wrapping at word
+    /// boundaries from now on so that the panic message remains readable.
+    pub(super) fn begin_panic(&mut self) {
+        self.background = PANIC_BACKGROUND;
+        self.wrap_words = true;
+        self.x = 0;
+        self.y = 0;
+        self.cursor_shown = false;
+
+        let len = self.display.stride * self.display.height;
+        unsafe {
+            slice::from_raw_parts_mut(self.display.data_mut(), len).fill(self.background);
+            self.display
+                .mark_dirty(0, 0, self.display.width, self.display.height);
         }
     }
 
@@ -39,7 +88,7 @@ impl DebugDisplay {
 
             unsafe {
                 self.display
-                    .sync(0, 0, self.display.width, self.display.height);
+                    .mark_dirty(0, 0, self.display.width, self.display.height);
             }
 
             self.y = new_y;
@@ -49,17 +98,43 @@ impl DebugDisplay {
             self.char(self.x * 8, self.y * 16, c, 0xFFFFFF);
 
             unsafe {
-                self.display.sync(self.x * 8, self.y * 16, 8, 16);
+                self.display.mark_dirty(self.x * 8, self.y * 16, 8, 16);
             }
 
             self.x += 1;
         }
     }
 
+    /// Show or hide the cursor at the current position by inverting its cell. The cursor is only
+    /// drawn once there is a back buffer, as inverting reads the pixels back.
+    fn set_cursor(&mut self, shown: bool) {
+        if self.cursor_shown == shown || (shown && !self.display.has_back_buffer()) {
+            return;
+        }
+        self.display.invert_region(self.x * 8, self.y * 16, 8, 16);
+        self.cursor_shown = shown;
+    }
+
     pub fn write(&mut self, buf: &[u8]) {
-        for &b in buf {
+        self.set_cursor(false);
+        for (i, &b) in buf.iter().enumerate() {
+            let word_start =
+                !b.is_ascii_whitespace() && (i == 0 || buf[i - 1].is_ascii_whitespace());
+
+            if self.wrap_words && word_start {
+                let len = buf[i..]
+                    .iter()
+                    .take_while(|b| !b.is_ascii_whitespace())
+                    .count();
+
+                // Move the word to the next line, unless it would not fit on a line of its own.
+                if self.x > 0 && self.x + len > self.w && len <= self.w {
+                    self.write_char('\n');
+                }
+            }
             self.write_char(b as char);
         }
+        self.set_cursor(true);
     }
 
     /// Draw a character
@@ -84,14 +159,25 @@ impl DebugDisplay {
         }
     }
 
-    /// Scroll the screen
+    /// Scroll the screen up by `lines` pixel rows, clearing the rows that become free. Scrolling
+    /// by the height of the screen or more clears the whole screen.
     fn scroll(&mut self, lines: usize) {
-        let offset = cmp::min(self.display.height, lines) * self.display.stride;
-        let size = (self.display.stride * self.display.height) - offset;
+        if lines == 0 {
+            return;
+        }
+
+        let len = self.display.stride * self.display.height;
         unsafe {
             let ptr = self.display.data_mut();
-            ptr::copy(ptr.add(offset), ptr, size);
-            ptr::write_bytes(ptr.add(size), 0, offset);
+            if lines >= self.display.height {
+                slice::from_raw_parts_mut(ptr, len).fill(self.background);
+                return;
+            }
+
+            let offset = lines * self.display.stride;
+            let size = len - offset;
+            ptr.copy_from(ptr.add(offset), size);
+            slice::from_raw_parts_mut(ptr.add(size), offset).fill(self.background);
         }
     }
 }
@@ -1,33 +1,127 @@
 use alloc::boxed::Box;
 use core::{ptr, slice};
 
+use crate::paging::PhysicalAddress;
+
+/// Layout of a single pixel in the framebuffer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 32 bits per pixel, `0x00RRGGBB` in native endianness, as supplied by the bootloader.
+    Xrgb8888,
+}
+
+/// Description of the device memory backing a display, for handing it over to a userspace
+/// driver.
+#[derive(Clone, Copy, Debug)]
+pub struct FramebufferInfo {
+    pub phys: PhysicalAddress,
+    pub width: usize,
+    pub height: usize,
+    /// Distance between the start of two consecutive rows, in pixels.
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
 /// A display
 pub(super) struct Display {
     pub(super) width: usize,
     pub(super) height: usize,
     pub(super) stride: usize,
+    phys: PhysicalAddress,
+    format: PixelFormat,
     onscreen_ptr: *mut u32,
     offscreen: Option<Box<[u32]>>,
+    /// If set, `mark_dirty` only accumulates the dirty region, and copying it onscreen is left to
+    /// a later `flush`.
+    deferred: bool,
+    /// Accumulated dirty region not yet synced onscreen, as `(x0, y0, x1, y1)`, exclusive.
+    dirty: Option<(usize, usize, usize, usize)>,
 }
 
 unsafe impl Send for Display {}
 
 impl Display {
+    /// Create a display over an already mapped framebuffer located at `phys`, returning `None`
+    /// if the firmware supplied geometry is inconsistent or would overflow when computing the
+    /// buffer size.
     pub(super) fn new(
         width: usize,
         height: usize,
         stride: usize,
+        phys: PhysicalAddress,
         onscreen_ptr: *mut u32,
-    ) -> Display {
+    ) -> Option<Display> {
+        let len = Self::checked_len(width, height, stride, onscreen_ptr)?;
+
         unsafe {
-            ptr::write_bytes(onscreen_ptr, 0, stride * height);
+            ptr::write_bytes(onscreen_ptr, 0, len);
         }
-        Display {
+        Some(Display {
             width,
             height,
             stride,
+            phys,
+            format: PixelFormat::Xrgb8888,
             onscreen_ptr,
             offscreen: None,
+            deferred: false,
+            dirty: None,
+        })
+    }
+
+    /// Number of pixels in a framebuffer of the given geometry, if it is consistent and its byte
+    /// size does not overflow.
+    fn checked_len(
+        width: usize,
+        height: usize,
+        stride: usize,
+        onscreen_ptr: *mut u32,
+    ) -> Option<usize> {
+        if onscreen_ptr.is_null() || !onscreen_ptr.is_aligned() || width > stride {
+            return None;
+        }
+        let len = stride.checked_mul(height)?;
+        // The byte size must also be representable, as the framebuffer is mapped byte-wise.
+        len.checked_mul(core::mem::size_of::<u32>())?;
+
+        Some(len)
+    }
+
+    /// Switch to a framebuffer with a new geometry, e.g. after a mode change, and clear it. The
+    /// back buffer, if any, is reallocated and anything not yet flushed is discarded. Returns
+    /// `None`, leaving the display unchanged, if the new geometry is invalid.
+    pub(super) fn resize(
+        &mut self,
+        width: usize,
+        height: usize,
+        stride: usize,
+        onscreen_ptr: *mut u32,
+    ) -> Option<()> {
+        let len = Self::checked_len(width, height, stride, onscreen_ptr)?;
+
+        unsafe {
+            ptr::write_bytes(onscreen_ptr, 0, len);
+        }
+        self.width = width;
+        self.height = height;
+        self.stride = stride;
+        self.onscreen_ptr = onscreen_ptr;
+        self.dirty = None;
+        if self.offscreen.is_some() {
+            self.offscreen = Some(vec![0; len].into_boxed_slice());
+        }
+
+        Some(())
+    }
+
+    /// The framebuffer currently displayed, including its physical address.
+    pub(super) fn framebuffer_info(&self) -> FramebufferInfo {
+        FramebufferInfo {
+            phys: self.phys,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+            format: self.format,
         }
     }
 
@@ -37,6 +131,10 @@ impl Display {
         self.offscreen = Some(onscreen.to_vec().into_boxed_slice());
     }
 
+    pub(super) fn has_back_buffer(&self) -> bool {
+        self.offscreen.is_some()
+    }
+
     pub(super) fn data_mut(&mut self) -> *mut u32 {
         match &mut self.offscreen {
             Some(offscreen) => offscreen.as_mut_ptr(),
@@ -44,6 +142,61 @@ impl Display {
         }
     }
 
+    /// Enable or disable deferred flushing. Disabling it flushes any pending dirty region.
+    pub(super) fn set_deferred(&mut self, deferred: bool) {
+        self.deferred = deferred;
+        if !deferred {
+            self.flush();
+        }
+    }
+
+    /// Mark a region as modified, syncing it immediately unless flushing is deferred, unsafe
+    /// because it trusts provided x, y, w, h
+    pub(super) unsafe fn mark_dirty(&mut self, x: usize, y: usize, w: usize, h: usize) {
+        if !self.deferred {
+            self.sync(x, y, w, h);
+            return;
+        }
+        let (x1, y1) = (x + w, y + h);
+        self.dirty = Some(match self.dirty {
+            Some((dx0, dy0, dx1, dy1)) => (dx0.min(x), dy0.min(y), dx1.max(x1), dy1.max(y1)),
+            None => (x, y, x1, y1),
+        });
+    }
+
+    /// Invert the color of every pixel in a region, clipped to the screen, e.g. to highlight a
+    /// selection. Inverting the same region twice restores it.
+    ///
+    /// This reads back the pixels, which is very slow from the framebuffer itself, so it should
+    /// only be used once a back buffer is allocated by `heap_init`.
+    pub(super) fn invert_region(&mut self, x: usize, y: usize, w: usize, h: usize) {
+        let x1 = x.saturating_add(w).min(self.width);
+        let y1 = y.saturating_add(h).min(self.height);
+        if x >= x1 || y >= y1 {
+            return;
+        }
+
+        let data = self.data_mut();
+        for row in y..y1 {
+            let row = unsafe { slice::from_raw_parts_mut(data.add(row * self.stride + x), x1 - x) };
+            for pixel in row {
+                *pixel ^= 0xFF_FFFF;
+            }
+        }
+        unsafe {
+            self.mark_dirty(x, y, x1 - x, y1 - y);
+        }
+    }
+
+    /// Copy the accumulated dirty region onscreen
+    pub(super) fn flush(&mut self) {
+        if let Some((x0, y0, x1, y1)) = self.dirty.take() {
+            unsafe {
+                self.sync(x0, y0, x1 - x0, y1 - y0);
+            }
+        }
+    }
+
     /// Sync from offscreen to onscreen, unsafe because it trusts provided x, y, w, h
     pub(super) unsafe fn sync(&mut self, x: usize, y: usize, w: usize, mut h: usize) {
         if let Some(offscreen) = &self.offscreen {
@@ -1,8 +1,11 @@
 use core::str;
 use spin::Mutex;
 
+use crate::paging::PhysicalAddress;
+
 pub use self::debug::DebugDisplay;
 use self::display::Display;
+pub use self::display::{FramebufferInfo, PixelFormat};
 
 pub mod debug;
 pub mod display;
@@ -48,20 +51,33 @@ pub fn init(env: &[u8]) {
         }
     }
 
-    *FRAMEBUFFER.lock() = (phys, virt, stride * height * 4);
-
     if phys == 0 || virt == 0 || width == 0 || height == 0 || stride == 0 {
+        *FRAMEBUFFER.lock() = (phys, virt, 0);
         println!("Framebuffer not found");
         return;
     }
 
+    let Some(size) = stride
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(4))
+    else {
+        *FRAMEBUFFER.lock() = (phys, virt, 0);
+        println!("Framebuffer size overflows");
+        return;
+    };
+    *FRAMEBUFFER.lock() = (phys, virt, size);
+
     println!(
         "Framebuffer {}x{} stride {} at {:X} mapped to {:X}",
         width, height, stride, phys, virt
     );
 
     {
-        let display = Display::new(width, height, stride, virt as *mut u32);
+        let phys = PhysicalAddress::new(phys);
+        let Some(display) = Display::new(width, height, stride, phys, virt as *mut u32) else {
+            println!("Framebuffer geometry invalid");
+            return;
+        };
         let debug_display = DebugDisplay::new(display);
         *DEBUG_DISPLAY.lock() = Some(debug_display);
     }
@@ -71,7 +87,97 @@ pub fn init(env: &[u8]) {
 pub fn init_heap() {
     if let Some(debug_display) = &mut *DEBUG_DISPLAY.lock() {
         debug_display.display.heap_init();
+        // With a back buffer, writes no longer need to touch the framebuffer right away.
+        debug_display.display.set_deferred(true);
+    }
+}
+
+/// Update the geometry after the framebuffer mode was changed at runtime. The framebuffer is
+/// assumed to still start at the same address, and must fit in the originally mapped size,
+/// otherwise the display is disabled rather than writing out of bounds.
+pub fn resize(width: usize, height: usize, stride: usize) {
+    let (_phys, virt, size) = *FRAMEBUFFER.lock();
+
+    let mut guard = DEBUG_DISPLAY.lock();
+    let Some(debug_display) = &mut *guard else {
+        return;
+    };
+
+    let fits = stride
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .is_some_and(|new_size| new_size <= size);
+
+    if !fits
+        || debug_display
+            .resize(width, height, stride, virt as *mut u32)
+            .is_none()
+    {
+        *guard = None;
+        drop(guard);
+        println!("Framebuffer geometry invalid after mode change");
+        return;
+    }
+    drop(guard);
+
+    println!(
+        "Framebuffer resized to {}x{} stride {}",
+        width, height, stride
+    );
+}
+
+/// The framebuffer used by the debug display, if any, so that a userspace driver can map the
+/// same device memory when taking over the display, see `sys:framebuffer`.
+pub fn framebuffer_info() -> Option<FramebufferInfo> {
+    DEBUG_DISPLAY
+        .lock()
+        .as_ref()
+        .map(|debug_display| debug_display.display.framebuffer_info())
+}
+
+/// Copy pending output onscreen. Called periodically from the timer tick, and skipped if the
+/// display is currently in use, as the next tick will pick it up anyway.
+pub fn flush() {
+    if let Some(mut guard) = DEBUG_DISPLAY.try_lock() {
+        if let Some(debug_display) = &mut *guard {
+            debug_display.display.flush();
+        }
+    }
+}
+
+/// Switch to the panic screen. Pending output is copied onscreen and further output is no longer
+/// deferred, so that the panic message and stack trace printed afterwards are guaranteed to be
+/// visible.
+pub fn begin_panic() {
+    // The code that panicked may have been holding the display. Wait a bit in case it is another
+    // CPU, but since nothing is going to release it after a panic on this CPU, eventually take it
+    // over rather than deadlocking. Never block, in case another CPU grabs it again meanwhile.
+    let guard = (0..1_000_000)
+        .find_map(|_| {
+            core::hint::spin_loop();
+            DEBUG_DISPLAY.try_lock()
+        })
+        .or_else(|| unsafe {
+            DEBUG_DISPLAY.force_unlock();
+            DEBUG_DISPLAY.try_lock()
+        });
+    let Some(mut guard) = guard else {
+        return;
+    };
+
+    if let Some(debug_display) = &mut *guard {
+        debug_display.display.set_deferred(false);
+        debug_display.begin_panic();
+    }
+}
+
+/// Stop using the display, if rendering a panic itself panicked.
+pub fn abandon() {
+    unsafe {
+        DEBUG_DISPLAY.force_unlock();
     }
+    // Its state cannot be trusted, so avoid running any destructors.
+    core::mem::forget(DEBUG_DISPLAY.lock().take());
 }
 
 #[allow(unused)]
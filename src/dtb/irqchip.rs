@@ -1,7 +1,12 @@
 use super::travel_interrupt_ctrl;
-use crate::{arch::device::irqchip::new_irqchip, cpu_set::LogicalCpuId, scheme::irq::irq_trigger};
+use crate::{
+    arch::device::irqchip::new_irqchip,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    scheme::irq::irq_trigger,
+};
 use alloc::{boxed::Box, vec::Vec};
 use byteorder::{ByteOrder, BE};
+use core::sync::atomic::{AtomicU32, Ordering};
 use fdt::{node::NodeProperty, Fdt};
 use log::{debug, error};
 use syscall::{Error, Result};
@@ -21,10 +26,18 @@ pub trait InterruptController: InterruptHandler {
     fn irq_ack(&mut self) -> u32;
     fn irq_eoi(&mut self, irq_num: u32);
     fn irq_enable(&mut self, irq_num: u32);
-    #[allow(unused)]
     fn irq_disable(&mut self, irq_num: u32);
     fn irq_xlate(&self, irq_data: &[u32; 3]) -> Result<usize>;
     fn irq_to_virq(&self, hwirq: u32) -> Option<usize>;
+    /// Whether `hwirq` may be reserved by drivers. Controllers return false for interrupts used
+    /// internally, such as IPIs, and for interrupt IDs with a special meaning.
+    fn irq_is_assignable(&self, _hwirq: u32) -> bool {
+        true
+    }
+    /// Whether `hwirq` is banked per CPU, so that each CPU can reserve it independently.
+    fn irq_is_per_cpu(&self, _hwirq: u32) -> bool {
+        false
+    }
 }
 
 pub struct IrqConnection {
@@ -243,7 +256,6 @@ impl IrqChipCore {
         self.irq_chip_list.chips[ic_idx].ic.irq_enable(hwirq)
     }
 
-    #[allow(unused)]
     pub fn irq_disable(&mut self, virq: u32) {
         let irq_desc = &self.irq_desc[virq as usize];
         let ic_idx = irq_desc.basic.ic_idx;
@@ -286,6 +298,27 @@ impl IrqChipCore {
             .init_inner3(fdt_opt, &mut self.irq_desc, roots);
     }
 
+    /// Whether `virq` is connected to a controller, not already claimed by the kernel or a child
+    /// controller, and allowed to be reserved according to the controller.
+    pub fn irq_is_assignable(&self, virq: u32) -> bool {
+        let Some(desc) = self.irq_desc.get(virq as usize) else {
+            return false;
+        };
+        desc.basic.used
+            && desc.handler.is_none()
+            && desc.basic.child_ic_idx.is_none()
+            && self.irq_chip_list.chips[desc.basic.ic_idx]
+                .ic
+                .irq_is_assignable(desc.basic.ic_irq)
+    }
+
+    pub fn irq_is_per_cpu(&self, virq: u32) -> bool {
+        let desc = &self.irq_desc[virq as usize];
+        self.irq_chip_list.chips[desc.basic.ic_idx]
+            .ic
+            .irq_is_per_cpu(desc.basic.ic_irq)
+    }
+
     pub fn phandle_to_ic_idx(&self, phandle: u32) -> Option<usize> {
         self.irq_chip_list
             .chips
@@ -347,24 +380,56 @@ pub fn register_irq(virq: u32, handler: Box<dyn InterruptHandler>) {
     }
 }
 
+pub type IrqReservations = [AtomicU32; 8];
+
+const NO_RESERVATION: AtomicU32 = AtomicU32::new(0);
+const INIT_RESERVATIONS: IrqReservations = [NO_RESERVATION; 8];
+
+/// Bitmaps of reserved virqs, indexed by CPU. Interrupts that are not banked per CPU (such as GIC
+/// SPIs) can only be reserved once system-wide, and are tracked in the BSP's bitmap.
+static RESERVATIONS: [IrqReservations; MAX_CPU_COUNT as usize] =
+    [INIT_RESERVATIONS; MAX_CPU_COUNT as usize];
+
+fn reservation_word(cpu_id: LogicalCpuId, index: u8) -> (&'static AtomicU32, u32) {
+    let cpu_id = if unsafe { IRQ_CHIP.irq_is_per_cpu(index.into()) } {
+        cpu_id
+    } else {
+        LogicalCpuId::BSP
+    };
+    let word = &RESERVATIONS[cpu_id.get() as usize][usize::from(index / 32)];
+
+    (word, 1 << (index % 32))
+}
+
 #[inline]
-pub fn is_reserved(_cpu_id: LogicalCpuId, index: u8) -> bool {
-    unsafe { IRQ_CHIP.irq_desc[index as usize].basic.used }
+pub fn is_reserved(cpu_id: LogicalCpuId, index: u8) -> bool {
+    if !unsafe { IRQ_CHIP.irq_is_assignable(index.into()) } {
+        return true;
+    }
+    let (word, bit) = reservation_word(cpu_id, index);
+
+    word.load(Ordering::Acquire) & bit != 0
 }
 
 #[inline]
-pub fn set_reserved(_cpu_id: LogicalCpuId, index: u8, reserved: bool) {
+pub fn set_reserved(cpu_id: LogicalCpuId, index: u8, reserved: bool) {
+    if !unsafe { IRQ_CHIP.irq_is_assignable(index.into()) } {
+        return;
+    }
+    let (word, bit) = reservation_word(cpu_id, index);
+
+    // TODO: Banked interrupts are only enabled or disabled for the calling CPU.
     unsafe {
-        IRQ_CHIP.irq_desc[index as usize].basic.used = reserved;
         if reserved {
-            IRQ_CHIP.irq_enable(index as u32);
+            word.fetch_or(bit, Ordering::AcqRel);
+            IRQ_CHIP.irq_enable(index.into());
         } else {
-            IRQ_CHIP.irq_enable(index as u32);
+            word.fetch_and(!bit, Ordering::AcqRel);
+            IRQ_CHIP.irq_disable(index.into());
         }
     }
 }
 
-pub fn available_irqs_iter(_cpu_id: LogicalCpuId) -> impl Iterator<Item = u8> + 'static {
-    error!("available_irqs_iter has been called");
-    0..0
+pub fn available_irqs_iter(cpu_id: LogicalCpuId) -> impl Iterator<Item = u8> + 'static {
+    (0..=u8::MAX).filter(move |&index| !is_reserved(cpu_id, index))
 }
@@ -0,0 +1,89 @@
+//! Interrupt priority levels ("IRQLs").
+//!
+//! Rather than disabling interrupts entirely, code can raise the priority level of the current CPU
+//! using [`raise_irql`], which masks only the interrupts at or below the new level. More urgent
+//! interrupts, such as the scheduler tick and IPIs while at [`Irql::DEVICE`], can still be
+//! delivered. The previous level is restored using [`lower_irql`].
+//!
+//! Softirqs (see [`crate::softirq`]) only run at [`Irql::PASSIVE`]. Work raised while the level is
+//! higher is deferred until it is lowered back to passive.
+//!
+//! Levels correspond to the x86 task priority classes, i.e. the upper four bits of the interrupt
+//! vector, so that level `n` masks all vectors below `(n + 1) * 16`. Device interrupts are on the
+//! vectors below class 14, while the PIT, the local APIC timer and the scheduler tick IPI are in
+//! class 14, and the other IPIs and the local APIC error interrupt in class 15. The task priority
+//! does not apply to interrupts from the legacy PIC, which are thus never masked if the I/O APIC
+//! is not used. The RISC-V PLIC assigns the same priority to all external interrupts, so any level
+//! above passive masks all of them, but never the timer. On AArch64, the level is not yet enforced
+//! by the interrupt controller.
+//!
+//! The level belongs to the CPU rather than to the running context, and must be lowered again
+//! before switching contexts. Preemption is therefore deferred while it is raised.
+
+use crate::{interrupt, percpu::PercpuBlock, softirq};
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Irql(u8);
+impl Irql {
+    /// No interrupts are masked, and softirqs may run.
+    pub const PASSIVE: Self = Self(0);
+    /// Masks the device interrupts, both legacy and MSI, except for the PIT.
+    pub const DEVICE: Self = Self(13);
+    /// Additionally masks the scheduler tick, i.e. the PIT, the local APIC timer and the tick IPI.
+    pub const CLOCK: Self = Self(14);
+    /// Masks all maskable interrupts, including IPIs.
+    pub const HIGH: Self = Self(15);
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+/// The priority level of this CPU.
+pub fn current() -> Irql {
+    PercpuBlock::current().irql.get()
+}
+
+/// Raise the priority level of this CPU to `level`, which must not be below the current level,
+/// masking the interrupts at or below it. Returns the previous level, to be restored using
+/// [`lower_irql`].
+pub fn raise_irql(level: Irql) -> Irql {
+    let irql = &PercpuBlock::current().irql;
+    let prev = irql.get();
+    assert!(level >= prev, "raising IRQL from {prev:?} to {level:?}");
+
+    if level != prev {
+        unsafe {
+            interrupt::set_priority_mask(level.0);
+        }
+        irql.set(level);
+    }
+    prev
+}
+
+/// Lower the priority level of this CPU to `level`, as returned by [`raise_irql`], unmasking the
+/// interrupts above it. When lowering to [`Irql::PASSIVE`], the softirqs deferred in the meantime
+/// are run. Returns the previous level.
+///
+/// # Safety
+///
+/// Same as for [`softirq::run_pending`]: must be called with interrupts disabled, and without
+/// holding any locks.
+pub unsafe fn lower_irql(level: Irql) -> Irql {
+    let irql = &PercpuBlock::current().irql;
+    let prev = irql.get();
+    assert!(level <= prev, "lowering IRQL from {prev:?} to {level:?}");
+
+    if level != prev {
+        irql.set(level);
+        unsafe {
+            interrupt::set_priority_mask(level.0);
+        }
+        if level == Irql::PASSIVE {
+            unsafe {
+                softirq::run_pending();
+            }
+        }
+    }
+    prev
+}
@@ -67,10 +67,7 @@ extern crate bitflags;
 use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::{
-    context::{
-        process::{new_process, ProcessInfo, INIT},
-        switch::SwitchResult,
-    },
+    context::process::{new_process, ProcessInfo, INIT},
     scheme::SchemeNamespace,
 };
 
@@ -128,6 +125,12 @@ use spinning_top::RwSpinlock;
 /// Memory management
 mod memory;
 
+/// Interrupt priority levels
+mod irql;
+
+/// Kernel module registry
+mod module;
+
 /// Panic
 mod panic;
 
@@ -143,6 +146,9 @@ pub mod profiling;
 /// Schemes, filesystem handlers
 mod scheme;
 
+/// Deferred interrupt work
+mod softirq;
+
 /// Early init
 mod startup;
 
@@ -197,6 +203,9 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
     //Initialize the first context, stored in kernel/src/context/mod.rs
     context::init();
 
+    // All CPUs have their percpu blocks set up by now.
+    memory::enable_frame_cache();
+
     //Initialize global schemes, such as `acpi:`.
     scheme::init_globals();
 
@@ -241,7 +250,7 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
         }
     }
 
-    run_userspace()
+    context::idle_loop()
 }
 
 /// This is the main kernel entry point for secondary CPUs
@@ -269,25 +278,8 @@ fn kmain_ap(cpu_id: crate::cpu_set::LogicalCpuId) -> ! {
     #[cfg(feature = "profiling")]
     profiling::ready_for_profiling();
 
-    run_userspace();
+    context::idle_loop();
 }
-fn run_userspace() -> ! {
-    loop {
-        unsafe {
-            interrupt::disable();
-            match context::switch() {
-                SwitchResult::Switched => {
-                    interrupt::enable_and_nop();
-                }
-                SwitchResult::AllContextsIdle => {
-                    // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
-                    interrupt::enable_and_halt();
-                }
-            }
-        }
-    }
-}
-
 /// Allow exception handlers to send signal to arch-independent kernel
 pub fn ksignal(signal: usize) {
     let current = context::current();
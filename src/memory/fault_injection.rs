@@ -0,0 +1,37 @@
+//! Deterministic failure injection for exercising the error paths of the memory code, such as the
+//! OOM handling of the page fault handler, CoW and address space cloning, which are otherwise
+//! nearly impossible to reach without real memory pressure.
+//!
+//! Each counter is disarmed when zero. When armed with `n`, the `n`th subsequent operation fails,
+//! after which the counter is disarmed again.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+static FAIL_ALLOCATION: AtomicUsize = AtomicUsize::new(0);
+static FAIL_TRANSLATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes the `n`th subsequent frame allocation fail, or disarms the injection if `n` is zero.
+pub fn fail_nth_allocation(n: usize) {
+    FAIL_ALLOCATION.store(n, Ordering::SeqCst);
+}
+/// Makes the `n`th subsequent user address translation report the page as not resident, or
+/// disarms the injection if `n` is zero.
+pub fn fail_nth_translation(n: usize) {
+    FAIL_TRANSLATION.store(n, Ordering::SeqCst);
+}
+
+fn should_fail(counter: &AtomicUsize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .map_or(false, |n| n == 1)
+}
+
+pub(crate) fn allocation_armed() -> bool {
+    FAIL_ALLOCATION.load(Ordering::Relaxed) != 0
+}
+pub(crate) fn should_fail_allocation() -> bool {
+    should_fail(&FAIL_ALLOCATION)
+}
+pub(crate) fn should_fail_translation() -> bool {
+    should_fail(&FAIL_TRANSLATION)
+}
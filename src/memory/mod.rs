@@ -1,17 +1,22 @@
 //! # Memory management
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/allocating-frames.html)
 
+#[cfg(feature = "fault_injection")]
+pub mod fault_injection;
 mod kernel_mapper;
+pub mod numa;
 
+use arrayvec::ArrayVec;
 use core::{
-    cell::SyncUnsafeCell,
+    cell::{RefCell, SyncUnsafeCell},
     mem,
     num::NonZeroUsize,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 pub use kernel_mapper::KernelMapper;
-use spin::Mutex;
+use numa::{NodeHint, NodeId, MAX_NUMA_NODES};
+use spin::{Mutex, MutexGuard};
 
 pub use crate::paging::{PhysicalAddress, RmmA, RmmArch, PAGE_MASK, PAGE_SIZE};
 use crate::{
@@ -20,10 +25,16 @@ use crate::{
         memory::{AccessMode, PfError},
     },
     kernel_executable_offsets::{__usercopy_end, __usercopy_start},
-    paging::Page,
-    syscall::error::{Error, ENOMEM},
+    paging::{entry::EntryFlags, PageMapper},
+    percpu::PercpuBlock,
+    syscall::{
+        error::{Error, ENOMEM},
+        usercopy::UserSliceRo,
+    },
+};
+use rmm::{
+    BumpAllocator, FrameAllocator, FrameCount, FrameUsage, PageFlush, TableKind, VirtualAddress,
 };
-use rmm::{BumpAllocator, FrameAllocator, FrameCount, FrameUsage, TableKind, VirtualAddress};
 
 /// Available physical memory areas
 pub(crate) static AREAS: SyncUnsafeCell<[rmm::MemoryArea; 512]> = SyncUnsafeCell::new(
@@ -42,19 +53,26 @@ pub(crate) fn areas() -> &'static [rmm::MemoryArea] {
     unsafe { &(&*AREAS.get())[..AREA_COUNT.get().read().into()] }
 }
 
+/// Number of frames currently allocated from the frame allocator, excluding frames held in the
+/// per-CPU frame caches.
+static USED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// Number of frames managed by the frame allocator, set once when the sections are initialized.
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
 /// Get the number of frames available
 pub fn free_frames() -> usize {
-    total_frames() - used_frames()
+    total_frames().saturating_sub(used_frames())
 }
 
 /// Get the number of frames used
 pub fn used_frames() -> usize {
     // TODO: Include bump allocator static pages?
-    FREELIST.lock().used_frames
+    USED_FRAMES.load(Ordering::Relaxed)
 }
+/// Get the total number of frames managed by the frame allocator
 pub fn total_frames() -> usize {
     // TODO: Include bump allocator static pages?
-    sections().iter().map(|section| section.frames.len()).sum()
+    TOTAL_FRAMES.load(Ordering::Relaxed)
 }
 
 /// Allocate a range of frames
@@ -62,17 +80,60 @@ pub fn allocate_p2frame(order: u32) -> Option<Frame> {
     allocate_p2frame_complex(order, (), None, order).map(|(f, _)| f)
 }
 pub fn allocate_frame() -> Option<Frame> {
-    allocate_p2frame(0)
+    allocate_frame_cached().or_else(|| allocate_p2frame(0))
 }
-// TODO: Flags, strategy
+/// Allocate a single frame from the node of `hint`, or if the hint is not strict, from any other
+/// node if that node has no free memory.
+pub fn allocate_frame_on(hint: NodeHint) -> Option<Frame> {
+    // The frame cache only holds frames of the local node.
+    if hint.node == numa::current_node()
+        && let Some(frame) = allocate_frame_cached()
+    {
+        return Some(frame);
+    }
+    allocate_p2frame_complex(0, (), Some(hint), 0).map(|(frame, _)| frame)
+}
+fn allocate_frame_cached() -> Option<Frame> {
+    // Bypass the cache while failures are being injected, so that every allocation is counted
+    // exactly once, by the caller.
+    #[cfg(feature = "fault_injection")]
+    if fault_injection::allocation_armed() {
+        return None;
+    }
+    let frame = FrameCache::current().and_then(FrameCache::pop)?;
+    unsafe {
+        (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+    }
+    Some(frame)
+}
+// TODO: Flags
+/// Allocate a block of `2^min_order` frames. Without a node hint, the nodes are tried in order,
+/// starting with node 0.
 pub fn allocate_p2frame_complex(
     _req_order: u32,
     _flags: (),
-    _strategy: Option<()>,
+    strategy: Option<NodeHint>,
     min_order: u32,
 ) -> Option<(Frame, usize)> {
-    let mut freelist = FREELIST.lock();
+    #[cfg(feature = "fault_injection")]
+    if fault_injection::should_fail_allocation() {
+        return None;
+    }
+    let (first, strict) = strategy.map_or((0, false), |hint| (hint.node.get(), hint.strict));
+    let node_count = numa::node_count();
+    let frame = (0..if strict { 1 } else { node_count })
+        .map(|i| (first + i) % node_count)
+        .find_map(|node| allocate_p2frame_locked(&mut FREELISTS[node].lock(), min_order))?;
+
+    unsafe {
+        (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE << min_order);
+    }
 
+    debug_assert!(frame.base().data() >= unsafe { ALLOCATOR_DATA.abs_off });
+
+    Some((frame, PAGE_SIZE << min_order))
+}
+fn allocate_p2frame_locked(freelist: &mut FreeList, min_order: u32) -> Option<Frame> {
     let Some((frame_order, frame)) = freelist
         .for_orders
         .iter()
@@ -131,23 +192,40 @@ pub fn allocate_p2frame_complex(
         freelist.for_orders[order as usize] = Some(hi);
     }
 
-    freelist.used_frames += 1 << min_order;
+    USED_FRAMES.fetch_add(1 << min_order, Ordering::Relaxed);
 
     info.mark_used();
-    drop(freelist);
 
-    unsafe {
-        (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE << min_order);
-    }
-
-    debug_assert!(frame.base().data() >= unsafe { ALLOCATOR_DATA.abs_off });
-
-    Some((frame, PAGE_SIZE << min_order))
+    Some(frame)
 }
 
 pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
-    let mut freelist = FREELIST.lock();
+    let node = numa::node_of(orig_frame);
+    deallocate_p2frame_locked(&mut FREELISTS[node.get()].lock(), orig_frame, order)
+}
+/// Frees single frames taken out of a [`FrameCache`], taking the freelist lock of a node only once
+/// for consecutive frames of that node.
+unsafe fn deallocate_cached_frames(frames: impl IntoIterator<Item = Frame>) {
+    let mut locked: Option<(NodeId, MutexGuard<'_, FreeList>)> = None;
+
+    for frame in frames {
+        let node = numa::node_of(frame);
+        if locked.as_ref().map(|(locked_node, _)| *locked_node) != Some(node) {
+            drop(locked.take());
+            locked = Some((node, FREELISTS[node.get()].lock()));
+        }
+        let (_, freelist) = locked.as_mut().unwrap();
+
+        USED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        unsafe {
+            deallocate_p2frame_locked(freelist, frame, 0);
+        }
+    }
+}
+/// `freelist` must be the freelist of the node `orig_frame` belongs to.
+unsafe fn deallocate_p2frame_locked(freelist: &mut FreeList, orig_frame: Frame, order: u32) {
     let mut largest_order = order;
+    let node = numa::node_of(orig_frame);
 
     let mut current = orig_frame;
 
@@ -170,6 +248,10 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
             // (i.e. there aren't 1 << merge_order additional pages).
             break;
         };
+        if numa::node_of(sibling) != node {
+            // Blocks never span several nodes, so that each node's freelist only has its own.
+            break;
+        }
 
         let PageInfoKind::Free(sib_info) = sib_info.kind() else {
             // The frame is currently in use (refcounted). It cannot be merged!
@@ -208,29 +290,234 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
 
         largest_order = merge_order + 1;
     }
-    get_page_info(current)
+    push_free_block(freelist, current, largest_order);
+
+    //log::info!("FREED {frame:?}+2^{order}");
+    USED_FRAMES.fetch_sub(1 << order, Ordering::Relaxed);
+}
+/// Marks the block of `2^order` frames at `new_head` free, and puts it at the head of the list of
+/// its order, without merging it with its siblings.
+fn push_free_block(freelist: &mut FreeList, new_head: Frame, order: u32) {
+    get_page_info(new_head)
         .expect("freeing frame without PageInfo")
-        .make_free(largest_order);
+        .make_free(order);
 
-    let new_head = current;
-    debug_assert!(new_head.is_aligned_to_order(largest_order));
+    debug_assert!(new_head.is_aligned_to_order(order));
 
-    if let Some(old_head) = freelist.for_orders[largest_order as usize].replace(new_head) {
+    if let Some(old_head) = freelist.for_orders[order as usize].replace(new_head) {
         //log::info!("HEAD {:p} FREED {:p} BARRIER {:p}", get_page_info(old_head).unwrap(), get_page_info(frame).unwrap(), unsafe { ALLOCATOR_DATA.abs_off as *const u8 });
         let old_head_info = get_free_alloc_page_info(old_head);
         let new_head_info = get_free_alloc_page_info(new_head);
 
-        new_head_info.set_next(P2Frame::new(Some(old_head), largest_order));
-        new_head_info.set_prev(P2Frame::new(None, largest_order));
-        old_head_info.set_prev(P2Frame::new(Some(new_head), largest_order));
+        new_head_info.set_next(P2Frame::new(Some(old_head), order));
+        new_head_info.set_prev(P2Frame::new(None, order));
+        old_head_info.set_prev(P2Frame::new(Some(new_head), order));
+    }
+}
+/// Moves the free blocks from the freelist of node 0, where [`init_sections`] puts all of them,
+/// to the freelists of their nodes, splitting the blocks that span several nodes.
+///
+/// # Safety
+///
+/// Must only be called by [`numa::init`], before other CPUs are started.
+unsafe fn distribute_free_frames() {
+    fn place(frame: Frame, order: u32) {
+        if order == 0 || numa::is_uniform(frame, 1 << order) {
+            let node = numa::node_of(frame);
+            push_free_block(&mut FREELISTS[node.get()].lock(), frame, order);
+        } else {
+            place(frame, order - 1);
+            place(frame.next_by(1 << (order - 1)), order - 1);
+        }
     }
 
-    //log::info!("FREED {frame:?}+2^{order}");
-    freelist.used_frames -= 1 << order;
+    // Detach the lists first, as blocks of node 0 are put back onto its freelist.
+    let detached = mem::take(&mut FREELISTS[0].lock().for_orders);
+
+    for (order, head) in detached.into_iter().enumerate() {
+        let mut next = head;
+        while let Some(frame) = next {
+            next = get_free_alloc_page_info(frame).next().frame();
+            place(frame, order as u32);
+        }
+    }
 }
 
 pub unsafe fn deallocate_frame(frame: Frame) {
-    deallocate_p2frame(frame, 0)
+    match FrameCache::current() {
+        Some(cache) if cache.push(frame) => (),
+        _ => deallocate_p2frame(frame, 0),
+    }
+}
+
+/// Allocate `count` physically contiguous frames, starting at a multiple of `align` frames, e.g.
+/// for DMA by devices without an IOMMU. Unlike [`allocate_p2frame`], `count` need not be a power of
+/// two; the frames are individually freeable with [`deallocate_frame`].
+///
+/// Fails with [`Enomem`] if no sufficiently large free block exists, even if enough frames are
+/// free in total, so that the caller can fall back to scattered frames. `align` must be a power
+/// of two.
+pub fn allocate_contiguous(count: usize, align: usize) -> Result<Frame, Enomem> {
+    if count == 0 || !align.is_power_of_two() {
+        return Err(Enomem);
+    }
+    let order = count
+        .checked_next_power_of_two()
+        .ok_or(Enomem)?
+        .trailing_zeros()
+        .max(align.trailing_zeros());
+    if order > MAX_ORDER {
+        return Err(Enomem);
+    }
+    #[cfg(feature = "fault_injection")]
+    if fault_injection::should_fail_allocation() {
+        return Err(Enomem);
+    }
+
+    let (mut freelist, frame) = (0..numa::node_count())
+        .find_map(|node| {
+            let mut freelist = FREELISTS[node].lock();
+            let frame = allocate_p2frame_locked(&mut freelist, order)?;
+            Some((freelist, frame))
+        })
+        .ok_or(Enomem)?;
+
+    // Only the first frame of an allocated block is marked used. Mark every frame that is kept,
+    // so that the unused tail below cannot be merged with them when freed, and so that each can
+    // be freed on its own.
+    for i in 0..count {
+        get_page_info(frame.next_by(i))
+            .expect("frame of allocated block lacks PageInfo")
+            .reset_used();
+    }
+
+    // Return the tail in the largest naturally aligned blocks possible.
+    let block_len = 1_usize << order;
+    let mut offset = count;
+    while offset < block_len {
+        let piece_order = offset.trailing_zeros();
+        let piece = frame.next_by(offset);
+
+        get_page_info(piece)
+            .expect("frame of allocated block lacks PageInfo")
+            .reset_used();
+        unsafe {
+            deallocate_p2frame_locked(&mut freelist, piece, piece_order);
+        }
+        offset += 1 << piece_order;
+    }
+    drop(freelist);
+
+    unsafe {
+        (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, count * PAGE_SIZE);
+    }
+
+    Ok(frame)
+}
+
+/// Try to coalesce free frames into larger blocks, returning the number of frames released to
+/// the buddy allocator. Call this before retrying a failed [`allocate_contiguous`].
+///
+/// Currently, this only returns the frames of this CPU's [`FrameCache`], which the allocator
+/// otherwise treats as used and therefore cannot merge. Migrating movable pages would further
+/// require a reverse mapping from frames to the address spaces mapping them, which does not
+/// exist yet.
+pub fn try_compact_frames() -> usize {
+    FrameCache::current().map_or(0, FrameCache::drain)
+}
+/// Returns the frames cached by this CPU to the global freelist, as it is about to stop running
+/// kernel code for good, e.g. after panicking.
+pub fn drain_frame_cache_for_offline() {
+    if let Some(cache) = FrameCache::current() {
+        cache.drain();
+    }
+}
+
+const FRAME_CACHE_CAPACITY: usize = 64;
+const FRAME_CACHE_BATCH: usize = FRAME_CACHE_CAPACITY / 2;
+
+/// Whether the per-CPU frame caches can be used, i.e. whether the percpu blocks are set up.
+static FRAME_CACHE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Per-CPU cache of single free frames, so that the common case of allocating or freeing one
+/// frame (e.g. every anonymous page fault) does not need to take the global freelist lock.
+///
+/// Cached frames are still marked used in their PageInfo, so the buddy allocator will not merge
+/// them, but they are not counted in [`used_frames`].
+#[derive(Debug, Default)]
+pub struct FrameCache {
+    frames: RefCell<ArrayVec<Frame, FRAME_CACHE_CAPACITY>>,
+}
+impl FrameCache {
+    fn current() -> Option<&'static Self> {
+        if !FRAME_CACHE_ENABLED.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(&PercpuBlock::current().frame_cache)
+    }
+    fn pop(&self) -> Option<Frame> {
+        // If the cache is already borrowed, this is a reentrant call from an interrupt handler,
+        // which will have to use the global freelist instead.
+        let mut frames = self.frames.try_borrow_mut().ok()?;
+
+        if frames.is_empty() {
+            // Only cache frames of the local node, see allocate_frame_on.
+            let mut freelist = FREELISTS[numa::current_node().get()].lock();
+            for _ in 0..FRAME_CACHE_BATCH {
+                let Some(frame) = allocate_p2frame_locked(&mut freelist, 0) else {
+                    break;
+                };
+                frames.push(frame);
+            }
+            USED_FRAMES.fetch_sub(frames.len(), Ordering::Relaxed);
+        }
+        let frame = frames.pop()?;
+
+        get_page_info(frame)
+            .expect("cached frame lacks PageInfo")
+            .reset_used();
+        USED_FRAMES.fetch_add(1, Ordering::Relaxed);
+
+        Some(frame)
+    }
+    /// Returns false if the frame could not be cached and must be freed globally instead.
+    fn push(&self, frame: Frame) -> bool {
+        if numa::node_of(frame) != numa::current_node() {
+            return false;
+        }
+        let Ok(mut frames) = self.frames.try_borrow_mut() else {
+            return false;
+        };
+
+        if frames.is_full() {
+            unsafe {
+                deallocate_cached_frames(frames.drain(FRAME_CACHE_BATCH..));
+            }
+        }
+        frames.push(frame);
+        USED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+
+        true
+    }
+    /// Returns all frames cached by this CPU to the global freelist, and how many there were. Must
+    /// be called before a CPU stops running kernel code, or its cached frames are effectively
+    /// leaked, see [`drain_frame_cache_for_offline`]. Returns 0 if the cache is in use, i.e. when
+    /// called from an interrupt handler, or on a CPU that stopped while allocating.
+    pub fn drain(&self) -> usize {
+        let Ok(mut frames) = self.frames.try_borrow_mut() else {
+            return 0;
+        };
+        let count = frames.len();
+
+        unsafe {
+            deallocate_cached_frames(frames.drain(..));
+        }
+        count
+    }
+}
+/// Enables the per-CPU frame caches, once every CPU that may allocate has its percpu block.
+pub fn enable_frame_cache() {
+    FRAME_CACHE_ENABLED.store(true, Ordering::Relaxed);
 }
 
 const ORDER_COUNT: u32 = 11;
@@ -302,21 +589,27 @@ impl Frame {
     }
     #[track_caller]
     pub fn next_by(self, n: usize) -> Self {
-        Self {
-            physaddr: self
-                .physaddr
-                .get()
-                .checked_add(n * PAGE_SIZE)
-                .and_then(NonZeroUsize::new)
-                .expect("overflow or null in Frame::next_by"),
-        }
+        self.checked_next_by(n)
+            .expect("overflow or null in Frame::next_by")
     }
+    /// Returns the frame `n` frames after this one, or `None` if its address would overflow.
+    pub fn checked_next_by(self, n: usize) -> Option<Self> {
+        Some(Self {
+            physaddr: n
+                .checked_mul(PAGE_SIZE)
+                .and_then(|bytes| self.physaddr.get().checked_add(bytes))
+                .and_then(NonZeroUsize::new)?,
+        })
+    }
+    #[track_caller]
     pub fn offset_from(self, from: Self) -> usize {
-        self.physaddr
-            .get()
-            .checked_sub(from.physaddr.get())
+        self.checked_offset_from(from)
             .expect("overflow in Frame::offset_from")
-            / PAGE_SIZE
+    }
+    /// Returns the number of frames from `from` to this frame, or `None` if `from` comes after
+    /// this frame.
+    pub fn checked_offset_from(self, from: Self) -> Option<usize> {
+        Some(self.physaddr.get().checked_sub(from.physaddr.get())? / PAGE_SIZE)
     }
     pub fn is_aligned_to_order(self, order: u32) -> bool {
         self.base().data() % (PAGE_SIZE << order) == 0
@@ -338,7 +631,7 @@ pub struct RaiiFrame {
 }
 impl RaiiFrame {
     pub fn allocate() -> Result<Self, Enomem> {
-        init_frame(RefCount::One)
+        init_frame(RefCount::One, None)
             .map_err(|_| Enomem)
             .map(|inner| Self { inner })
     }
@@ -431,6 +724,13 @@ bitflags::bitflags! {
     #[derive(Debug)]
     pub struct FrameFlags: usize {
         const NONE = 0;
+
+        // The page was marked with MADV_FREE, and its contents may be discarded on reclaim, unless
+        // it is written to first.
+        const FREEABLE = 1 << 0;
+        // Only used where the hardware has no dirty bit: write access to the page was revoked when
+        // clearing its dirty bit, and it has not been written to since, see `HW_DIRTY`.
+        const CLEAN = 1 << 1;
     }
 }
 
@@ -447,12 +747,12 @@ struct AllocatorData {
 #[derive(Debug)]
 struct FreeList {
     for_orders: [Option<Frame>; ORDER_COUNT as usize],
-    used_frames: usize,
 }
-static FREELIST: Mutex<FreeList> = Mutex::new(FreeList {
+const EMPTY_FREELIST: Mutex<FreeList> = Mutex::new(FreeList {
     for_orders: [None; ORDER_COUNT as usize],
-    used_frames: 0,
 });
+/// Freelist of each NUMA node. Until the topology is known, all free frames are in the first one.
+static FREELISTS: [Mutex<FreeList>; MAX_NUMA_NODES] = [EMPTY_FREELIST; MAX_NUMA_NODES];
 
 pub struct Section {
     base: Frame,
@@ -713,7 +1013,11 @@ fn init_sections(mut allocator: BumpAllocator<RmmA>) {
         free.set_next(P2Frame::new(None, order as u32));
     }
 
-    FREELIST.lock().for_orders = first_pages.map(|pair| pair.map(|(frame, _)| frame));
+    FREELISTS[0].lock().for_orders = first_pages.map(|pair| pair.map(|(frame, _)| frame));
+    TOTAL_FRAMES.store(
+        sections.iter().map(|section| section.frames.len()).sum(),
+        Ordering::Relaxed,
+    );
 
     //debug_freelist();
     log::info!("Initial freelist consistent");
@@ -816,6 +1120,26 @@ impl PageInfo {
 
         RefCount::from_raw(refcount)
     }
+    /// Resets a used frame to the state of a newly allocated one, with a single reference and no
+    /// flags.
+    fn reset_used(&self) {
+        self.refcount.store(RC_USED_NOT_FREE, Ordering::Relaxed);
+        self.next.store(0, Ordering::Relaxed);
+    }
+    // For used frames, the otherwise free-list-only "next" word stores the FrameFlags.
+    pub fn flags(&self) -> FrameFlags {
+        debug_assert!(self.refcount().is_some(), "using flags on free page!");
+        FrameFlags::from_bits_truncate(self.next.load(Ordering::Relaxed))
+    }
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        debug_assert!(self.refcount().is_some(), "using insert_flags on free page!");
+        self.next.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+    /// Clears the given flags, returning the flags that were set beforehand.
+    pub fn remove_flags(&self, flags: FrameFlags) -> FrameFlags {
+        debug_assert!(self.refcount().is_some(), "using remove_flags on free page!");
+        FrameFlags::from_bits_truncate(self.next.fetch_and(!flags.bits(), Ordering::Relaxed))
+    }
     fn make_free(&self, order: u32) -> PageInfoFree<'_> {
         // Order needs to be known so we don't for example merge A: [A] A A A B: [B] U U U into a
         // 2^3 page (if U indicates "used").
@@ -947,7 +1271,87 @@ bitflags! {
 
 pub trait ArchIntCtx {
     fn ip(&self) -> usize;
+    fn set_ip(&mut self, ip: usize);
     fn recover_and_efault(&mut self);
+    /// Value of the general purpose register `reg`, numbered as in instruction encodings, or None
+    /// if there is no such register.
+    fn reg(&self, reg: u8) -> Option<usize>;
+    /// Set the general purpose register `reg`, numbered as in instruction encodings. Returns None
+    /// if there is no such register.
+    fn set_reg(&mut self, reg: u8, value: usize) -> Option<()>;
+}
+
+/// Data access performed by a faulting instruction, as decoded by
+/// [`decode_access`](crate::arch::decode::decode_access), for emulating accesses to trapping
+/// (e.g. MMIO) pages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccessInfo {
+    /// Width of the access, in bytes.
+    pub size: u8,
+    pub is_write: bool,
+    /// Whether a load narrower than the register is sign extended rather than zero extended.
+    pub sign_extend: bool,
+    /// Register loaded into or stored from, or immediate stored.
+    pub operand: AccessOperand,
+    /// Width of the register written by a load, in bytes. Following x86, a register written with
+    /// fewer than 4 bytes keeps its upper bits, whereas any wider write clears them.
+    pub reg_size: u8,
+    /// Length of the instruction, in bytes, to skip it once emulated.
+    pub len: u8,
+}
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessOperand {
+    /// Architectural number of the register.
+    Reg(u8),
+    /// Immediate, already sign extended to the access size where the architecture does so.
+    Imm(u64),
+}
+impl AccessInfo {
+    fn size_mask(bytes: u8) -> u64 {
+        u64::MAX >> (64 - u32::from(bytes) * 8)
+    }
+    /// The value written by the access, taken from the register or immediate operand.
+    pub fn store_value(&self, stack: &dyn ArchIntCtx) -> Option<u64> {
+        let value = match self.operand {
+            AccessOperand::Reg(reg) => stack.reg(reg)? as u64,
+            AccessOperand::Imm(imm) => imm,
+        };
+        Some(value & Self::size_mask(self.size))
+    }
+    /// Write `value`, as loaded from memory, to the destination register, extending it as the
+    /// instruction would have.
+    pub fn complete_load(&self, stack: &mut dyn ArchIntCtx, value: u64) -> Option<()> {
+        let AccessOperand::Reg(reg) = self.operand else {
+            return None;
+        };
+        let bits = u32::from(self.size) * 8;
+        let mut value = value & Self::size_mask(self.size);
+        if self.sign_extend && bits < 64 {
+            value = (((value << (64 - bits)) as i64) >> (64 - bits)) as u64;
+        }
+        value &= Self::size_mask(self.reg_size);
+        if self.reg_size < 4 {
+            let old = stack.reg(reg)? as u64;
+            value |= old & !Self::size_mask(self.reg_size);
+        }
+        stack.set_reg(reg, value as usize)
+    }
+}
+
+/// Read the instruction bytes at `ip` in the current address space into `buf`, returning how
+/// many could be read. Instructions may end right before an unmapped page, so if the whole buffer
+/// cannot be read, only the rest of the page `ip` is on is.
+pub fn read_instruction_bytes(ip: usize, buf: &mut [u8]) -> Option<usize> {
+    let mut read = |len: usize| {
+        UserSliceRo::ro(ip, len)
+            .and_then(|slice| slice.copy_to_slice(&mut buf[..len]))
+            .is_ok()
+    };
+    if read(buf.len()) {
+        return Some(buf.len());
+    }
+    let to_page_end = core::cmp::min(PAGE_SIZE - ip % PAGE_SIZE, buf.len());
+    read(to_page_end).then_some(to_page_end)
 }
 
 pub fn page_fault_handler(
@@ -955,8 +1359,7 @@ pub fn page_fault_handler(
     code: GenericPfFlags,
     faulting_address: VirtualAddress,
 ) -> Result<(), Segv> {
-    let faulting_page = Page::containing_address(faulting_address);
-
+    let _depth_guard = PageFaultDepthGuard::enter(faulting_address);
     let usercopy_region = __usercopy_start()..__usercopy_end();
 
     // TODO: Most likely not necessary, but maybe also check that the faulting address is not too
@@ -985,7 +1388,8 @@ pub fn page_fault_handler(
     }
 
     if address_is_user && (caused_by_user || is_usercopy) {
-        match context::memory::try_correcting_page_tables(faulting_page, mode) {
+        let user_stack = caused_by_user.then_some(&mut *stack as &mut dyn ArchIntCtx);
+        match context::memory::try_correcting_page_tables(faulting_address, mode, user_stack) {
             Ok(()) => return Ok(()),
             Err(PfError::Oom) => todo!("oom"),
             Err(PfError::Segv | PfError::RecursionLimitExceeded) => (),
@@ -1000,6 +1404,37 @@ pub fn page_fault_handler(
 
     Err(Segv)
 }
+/// Maximum nesting of page faults on one CPU (within the same context), before the kernel gives
+/// up. A page fault while correcting a page fault is already suspicious; deeper nesting almost
+/// certainly means the fault handler itself keeps faulting.
+const MAX_PAGE_FAULT_DEPTH: u32 = 2;
+
+struct PageFaultDepthGuard;
+impl PageFaultDepthGuard {
+    fn enter(faulting_address: VirtualAddress) -> Self {
+        let depth = &PercpuBlock::current().page_fault_depth;
+        let new_depth = depth.get() + 1;
+        depth.set(new_depth);
+
+        if new_depth > MAX_PAGE_FAULT_DEPTH {
+            // Reset first, so that a fault while panicking does not end up here again.
+            depth.set(0);
+            panic!(
+                "recursive page fault at {:#x} (depth {})",
+                faulting_address.data(),
+                new_depth
+            );
+        }
+        Self
+    }
+}
+impl Drop for PageFaultDepthGuard {
+    fn drop(&mut self) {
+        let depth = &PercpuBlock::current().page_fault_depth;
+        depth.set(depth.get().saturating_sub(1));
+    }
+}
+
 static THE_ZEROED_FRAME: SyncUnsafeCell<Option<(Frame, &'static PageInfo)>> =
     SyncUnsafeCell::new(None);
 
@@ -1012,8 +1447,13 @@ pub fn the_zeroed_frame() -> (Frame, &'static PageInfo) {
     }
 }
 
-pub fn init_frame(init_rc: RefCount) -> Result<Frame, PfError> {
-    let new_frame = allocate_frame().ok_or(PfError::Oom)?;
+/// Allocate a frame with the given refcount, from the node of `hint` if there is one.
+pub fn init_frame(init_rc: RefCount, hint: Option<NodeHint>) -> Result<Frame, PfError> {
+    let new_frame = match hint {
+        Some(hint) => allocate_frame_on(hint),
+        None => allocate_frame(),
+    }
+    .ok_or(PfError::Oom)?;
     let page_info = get_page_info(new_frame).unwrap_or_else(|| {
         panic!(
             "all allocated frames need an associated page info, {:?} didn't",
@@ -1046,3 +1486,108 @@ impl FrameAllocator for TheFrameAllocator {
         )
     }
 }
+
+/// Accessors for the accessed and dirty bits the CPU maintains in leaf page table entries. On
+/// architectures where the kernel does not let the hardware maintain them, pages are never
+/// reported as accessed, and write access stands in for the dirty bit, see [`HW_DIRTY`].
+pub trait AccessDirtyBits {
+    fn is_accessed(&self, addr: VirtualAddress) -> bool;
+    fn is_dirty(&self, addr: VirtualAddress) -> bool;
+
+    /// Clears the accessed bit of the page at `addr`, returning the flush that must be performed
+    /// (on all CPUs using the table) before the bit reliably reflects new accesses, or `None` if
+    /// the bit was not set.
+    unsafe fn clear_accessed(&mut self, addr: VirtualAddress) -> Option<PageFlush<RmmA>>;
+    /// Clears the dirty bit of the page at `addr`. As CPUs only set the bit when caching a
+    /// translation for writing, writes through stale TLB entries are not recorded until the
+    /// returned flush has been performed on all CPUs using the table. Returns `None` if there is
+    /// nothing to flush, which does not imply that the page was clean, see [`HW_DIRTY`].
+    unsafe fn clear_dirty(&mut self, addr: VirtualAddress) -> Option<PageFlush<RmmA>>;
+    /// Marks the page at `addr` dirty, for writes that did not go through this table, e.g. through
+    /// the physmap.
+    unsafe fn mark_dirty(&mut self, addr: VirtualAddress);
+}
+
+/// Whether the hardware maintains the dirty bit. Otherwise, clearing the dirty bit revokes write
+/// access instead, and marks the frame [`FrameFlags::CLEAN`], which the next write, faulting,
+/// removes again. Pages are then reported dirty unless both read-only and clean, so that pages
+/// whose write access was revoked otherwise, e.g. by mprotect, are not mistaken for clean ones.
+const HW_DIRTY: bool = EntryFlags::DIRTY.bits() != 0;
+
+/// Whether the page at `addr` is dirty, where the hardware has no dirty bit, see [`HW_DIRTY`].
+fn is_dirty_sw(mapper: &PageMapper, addr: VirtualAddress) -> bool {
+    mapper.translate(addr).is_some_and(|(phys, flags)| {
+        flags.has_write()
+            || !get_page_info(Frame::containing(phys))
+                .is_some_and(|info| info.flags().contains(FrameFlags::CLEAN))
+    })
+}
+
+fn has_entry_flag(mapper: &PageMapper, addr: VirtualAddress, flag: usize) -> bool {
+    flag != 0
+        && mapper
+            .translate(addr)
+            .map_or(false, |(_, flags)| flags.has_flag(flag))
+}
+unsafe fn clear_entry_flag(
+    mapper: &mut PageMapper,
+    addr: VirtualAddress,
+    flag: usize,
+) -> Option<PageFlush<RmmA>> {
+    if !has_entry_flag(mapper, addr, flag) {
+        return None;
+    }
+    let (_, _, flush) = unsafe { mapper.remap_with(addr, |flags| flags.custom_flag(flag, false))? };
+    Some(flush)
+}
+
+impl AccessDirtyBits for PageMapper {
+    fn is_accessed(&self, addr: VirtualAddress) -> bool {
+        has_entry_flag(self, addr, EntryFlags::ACCESSED.bits())
+    }
+    fn is_dirty(&self, addr: VirtualAddress) -> bool {
+        if HW_DIRTY {
+            has_entry_flag(self, addr, EntryFlags::DIRTY.bits())
+        } else {
+            is_dirty_sw(self, addr)
+        }
+    }
+    unsafe fn clear_accessed(&mut self, addr: VirtualAddress) -> Option<PageFlush<RmmA>> {
+        unsafe { clear_entry_flag(self, addr, EntryFlags::ACCESSED.bits()) }
+    }
+    unsafe fn clear_dirty(&mut self, addr: VirtualAddress) -> Option<PageFlush<RmmA>> {
+        if HW_DIRTY {
+            return unsafe { clear_entry_flag(self, addr, EntryFlags::DIRTY.bits()) };
+        }
+        let (phys, flags) = self.translate(addr)?;
+        if let Some(info) = get_page_info(Frame::containing(phys)) {
+            info.insert_flags(FrameFlags::CLEAN);
+        }
+        if !flags.has_write() {
+            return None;
+        }
+        let (_, _, flush) = unsafe { self.remap_with(addr, |flags| flags.write(false))? };
+        Some(flush)
+    }
+    unsafe fn mark_dirty(&mut self, addr: VirtualAddress) {
+        if !HW_DIRTY {
+            if let Some((phys, _)) = self.translate(addr)
+                && let Some(info) = get_page_info(Frame::containing(phys))
+            {
+                info.remove_flags(FrameFlags::CLEAN);
+            }
+            return;
+        }
+        if has_entry_flag(self, addr, EntryFlags::DIRTY.bits()) {
+            return;
+        }
+        // CPUs only ever set the bit themselves, so stale entries without it need no flush.
+        if let Some((_, _, flush)) = unsafe {
+            self.remap_with(addr, |flags| {
+                flags.custom_flag(EntryFlags::DIRTY.bits(), true)
+            })
+        } {
+            flush.ignore();
+        }
+    }
+}
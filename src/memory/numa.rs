@@ -0,0 +1,181 @@
+//! NUMA topology, and the policies deciding which node the frames of an address space are
+//! allocated from.
+//!
+//! The topology is read from the ACPI SRAT if present (see [`init`]). Until then, or if there is
+//! no such table, all memory and all CPUs belong to node 0, and allocation behaves exactly as on
+//! a machine without NUMA. Each node has its own buddy freelist, and a free block never spans
+//! several nodes.
+
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    paging::{Page, PhysicalAddress, PAGE_SIZE},
+    percpu::PercpuBlock,
+};
+
+use super::Frame;
+
+/// Maximum number of nodes. Memory and CPUs of any additional nodes are assigned to node 0.
+pub const MAX_NUMA_NODES: usize = 8;
+/// Maximum number of physical memory ranges with a known node.
+const MAX_NUMA_RANGES: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeId(u8);
+impl NodeId {
+    pub const ZERO: Self = Self(0);
+
+    pub fn new(id: usize) -> Option<Self> {
+        (id < MAX_NUMA_NODES).then_some(Self(id as u8))
+    }
+    pub fn get(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+/// The node a frame allocation should be satisfied from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NodeHint {
+    pub node: NodeId,
+    /// Fail rather than falling back to other nodes, if the node has no free memory.
+    pub strict: bool,
+}
+
+/// Which node pages of an address space are allocated from when faulted in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NumaPolicy {
+    /// The node of the faulting CPU, or any other node if it has no free memory.
+    #[default]
+    Local,
+    /// All nodes in turn by page address, so that the pages of large buffers are spread over the
+    /// nodes evenly.
+    Interleave,
+    /// Only the given node.
+    Bind(NodeId),
+}
+impl NumaPolicy {
+    pub fn hint(self, page: Page) -> NodeHint {
+        match self {
+            Self::Local => NodeHint {
+                node: current_node(),
+                strict: false,
+            },
+            Self::Interleave => NodeHint {
+                node: NodeId((page.start_address().data() / PAGE_SIZE % node_count()) as u8),
+                strict: false,
+            },
+            Self::Bind(node) => NodeHint { node, strict: true },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NodeRange {
+    base: usize,
+    end: usize,
+    node: NodeId,
+}
+
+static RANGES: SyncUnsafeCell<[NodeRange; MAX_NUMA_RANGES]> = SyncUnsafeCell::new(
+    [NodeRange {
+        base: 0,
+        end: 0,
+        node: NodeId::ZERO,
+    }; MAX_NUMA_RANGES],
+);
+static RANGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NODE_COUNT: AtomicUsize = AtomicUsize::new(1);
+const NODE_ZERO: AtomicU8 = AtomicU8::new(0);
+static CPU_NODES: [AtomicU8; MAX_CPU_COUNT as usize] = [NODE_ZERO; MAX_CPU_COUNT as usize];
+
+fn ranges() -> &'static [NodeRange] {
+    // SAFETY: The ranges are only written by init, before RANGE_COUNT is published.
+    unsafe { &(&*RANGES.get())[..RANGE_COUNT.load(Ordering::Acquire)] }
+}
+
+/// Number of nodes, which is 1 unless a topology was registered.
+pub fn node_count() -> usize {
+    NODE_COUNT.load(Ordering::Relaxed)
+}
+
+/// The node `frame` belongs to. Frames outside of the ranges described by the topology belong
+/// to node 0.
+pub fn node_of(frame: Frame) -> NodeId {
+    let addr = frame.base().data();
+    ranges()
+        .iter()
+        .find(|range| (range.base..range.end).contains(&addr))
+        .map_or(NodeId::ZERO, |range| range.node)
+}
+
+/// Whether all `count` frames starting at `base` belong to the same node.
+pub(super) fn is_uniform(base: Frame, count: usize) -> bool {
+    let start = base.base().data();
+    let end = start + count * PAGE_SIZE;
+    let inside = |addr: usize| start < addr && addr < end;
+
+    ranges()
+        .iter()
+        .all(|range| !inside(range.base) && !inside(range.end))
+}
+
+pub fn cpu_node(cpu: LogicalCpuId) -> NodeId {
+    NodeId(CPU_NODES[cpu.get() as usize].load(Ordering::Relaxed))
+}
+
+/// The node of the CPU this runs on.
+pub fn current_node() -> NodeId {
+    // Also avoids accessing the percpu block before it has been set up.
+    if node_count() == 1 {
+        return NodeId::ZERO;
+    }
+    cpu_node(PercpuBlock::current().cpu_id)
+}
+
+/// Register the NUMA topology, and move the free frames to the freelists of their nodes.
+///
+/// # Safety
+///
+/// Must be called at most once, before any other CPU has been started.
+pub unsafe fn init(
+    memory: impl IntoIterator<Item = (PhysicalAddress, usize, NodeId)>,
+    cpus: impl IntoIterator<Item = (LogicalCpuId, NodeId)>,
+) {
+    let ranges = unsafe { &mut *RANGES.get() };
+    let mut range_count = 0;
+    let mut node_count = 1;
+
+    for (base, size, node) in memory {
+        if range_count == MAX_NUMA_RANGES {
+            log::warn!("Too many NUMA memory ranges, assigning the rest to node 0");
+            break;
+        }
+        ranges[range_count] = NodeRange {
+            base: base.data(),
+            end: base.data() + size,
+            node,
+        };
+        range_count += 1;
+        node_count = node_count.max(node.get() + 1);
+    }
+    for (cpu, node) in cpus {
+        if cpu.get() < MAX_CPU_COUNT {
+            CPU_NODES[cpu.get() as usize].store(node.0, Ordering::Relaxed);
+            node_count = node_count.max(node.get() + 1);
+        }
+    }
+
+    RANGE_COUNT.store(range_count, Ordering::Release);
+    NODE_COUNT.store(node_count, Ordering::Relaxed);
+
+    if node_count > 1 {
+        unsafe {
+            super::distribute_free_frames();
+        }
+    }
+    log::info!("NUMA: {} nodes, {} memory ranges", node_count, range_count);
+}
@@ -0,0 +1,71 @@
+//! Registry of the kernel modules mapped into the higher half.
+//!
+//! Modules are mapped using the kernel mapper rather than the user grant machinery, and only
+//! registered here so that addresses within them, e.g. return addresses in a stack trace, can be
+//! resolved to `module+offset`.
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::{
+    paging::VirtualAddress,
+    syscall::error::{Error, Result, EEXIST, EINVAL, ENOENT, ENOSPC},
+};
+
+const MAX_MODULES: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    pub name: &'static str,
+    pub base: VirtualAddress,
+    pub size: usize,
+}
+
+impl Module {
+    fn contains(&self, addr: VirtualAddress) -> bool {
+        addr.data()
+            .checked_sub(self.base.data())
+            .map_or(false, |offset| offset < self.size)
+    }
+}
+
+static MODULES: Mutex<ArrayVec<Module, MAX_MODULES>> = Mutex::new(ArrayVec::new_const());
+
+/// Registers a module occupying `[base, base + size)` in kernel space.
+#[allow(unused)]
+pub fn register_module(name: &'static str, base: VirtualAddress, size: usize) -> Result<()> {
+    let end = base.data().checked_add(size).ok_or(Error::new(EINVAL))?;
+    if size == 0 || base.data() < crate::USER_END_OFFSET {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut modules = MODULES.lock();
+    if modules
+        .iter()
+        .any(|module| base.data() < module.base.data() + module.size && module.base.data() < end)
+    {
+        return Err(Error::new(EEXIST));
+    }
+    modules
+        .try_push(Module { name, base, size })
+        .map_err(|_| Error::new(ENOSPC))
+}
+
+/// Unregisters the module previously registered at `base`, returning it.
+#[allow(unused)]
+pub fn unregister_module(base: VirtualAddress) -> Result<Module> {
+    let mut modules = MODULES.lock();
+    let index = modules
+        .iter()
+        .position(|module| module.base == base)
+        .ok_or(Error::new(ENOENT))?;
+    Ok(modules.swap_remove(index))
+}
+
+/// Returns the module containing `addr`, along with the offset of `addr` within it. Returns `None`
+/// without waiting if the registry is locked, since this is called when printing stack traces.
+pub fn module_at(addr: VirtualAddress) -> Option<(Module, usize)> {
+    let modules = MODULES.try_lock()?;
+    let module = *modules.iter().find(|module| module.contains(addr))?;
+    Some((module, addr.data() - module.base.data()))
+}
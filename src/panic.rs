@@ -8,9 +8,11 @@ use rustc_demangle::demangle;
 use crate::{
     arch::{consts::USER_END_OFFSET, interrupt::trace::StackTrace},
     context, cpu_id,
+    cpu_set::LogicalCpuSet,
     elf::Elf,
     interrupt,
     memory::KernelMapper,
+    module::module_at,
     start::KERNEL_SIZE,
     syscall,
 };
@@ -19,14 +21,40 @@ use crate::{
 #[cfg(not(test))]
 #[panic_handler]
 fn rust_begin_unwind(info: &PanicInfo) -> ! {
+    // Tracked per CPU, as other CPUs may panic concurrently without anything being wrong with the
+    // panic handler itself.
+    static PANICKING: LogicalCpuSet = LogicalCpuSet::empty();
+
+    let cpu_id = cpu_id();
+    if PANICKING.atomic_contains(cpu_id) {
+        // Panicked while handling a panic on this CPU, possibly while rendering it. Only try the
+        // simplest possible output this time.
+        #[cfg(feature = "graphical_debug")]
+        crate::devices::graphical_debug::abandon();
+
+        println!("KERNEL PANIC WHILE PANICKING: {}", info);
+        halt();
+    }
+    PANICKING.atomic_set(cpu_id);
+
+    // Render everything up to and including the panic message and stack trace on a dedicated
+    // screen.
+    #[cfg(feature = "graphical_debug")]
+    crate::devices::graphical_debug::begin_panic();
+
     println!("KERNEL PANIC: {}", info);
 
+    // This CPU will never release the context switch lock if it was switching, so let the other
+    // CPUs continue.
+    context::switch::mark_cpu_dead();
+    context::switch::recover_switch_lock();
+
     unsafe {
         stack_trace();
     }
     let context_lock = context::current();
 
-    println!("CPU {}, CID {:p}", cpu_id(), context_lock);
+    println!("CPU {}, CID {:p}", cpu_id, context_lock);
 
     // This could deadlock, but at this point we are going to halt anyways
     {
@@ -38,7 +66,14 @@ fn rust_begin_unwind(info: &PanicInfo) -> ! {
         }
     }
 
+    // Like above, this deadlocks if the panic happened in the frame allocator.
+    crate::memory::drain_frame_cache_for_offline();
+
     println!("HALT");
+    halt();
+}
+
+fn halt() -> ! {
     loop {
         unsafe {
             interrupt::halt();
@@ -71,6 +106,9 @@ pub unsafe fn stack_trace() {
                     break;
                 } else {
                     println!("  FP {:>016x}: PC {:>016x}", frame_.fp, pc);
+                    if let Some((module, offset)) = module_at(VirtualAddress::new(pc)) {
+                        println!("    {}+{:#x}", module.name, offset);
+                    }
                     symbol_trace(pc);
                     frame = frame_.next();
                 }
@@ -10,7 +10,10 @@ use syscall::PtraceFlags;
 use crate::{
     context::{empty_cr3, memory::AddrSpaceWrapper, switch::ContextSwitchPercpu},
     cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    irql::Irql,
+    memory::FrameCache,
     ptrace::Session,
+    softirq::SoftirqQueue,
 };
 
 #[cfg(feature = "syscall_debug")]
@@ -25,9 +28,21 @@ pub struct PercpuBlock {
     pub switch_internals: ContextSwitchPercpu,
 
     pub current_addrsp: RefCell<Option<Arc<AddrSpaceWrapper>>>,
+    /// Address space of the last user context, whose page table is kept loaded while running
+    /// kernel-only contexts, so that switching back to it does not require flushing the TLB. This
+    /// CPU stays in its `used_by` set, and unloads the table on the first TLB shootdown.
+    pub lazy_addrsp: RefCell<Option<LazyAddrSpace>>,
     pub new_addrsp_tmp: Cell<Option<Arc<AddrSpaceWrapper>>>,
     pub wants_tlb_shootdown: AtomicBool,
 
+    /// Cache of free single frames, see [`FrameCache`].
+    pub frame_cache: FrameCache,
+
+    /// Deferred interrupt work, see [`crate::softirq`].
+    pub softirqs: SoftirqQueue,
+    /// Interrupt priority level, see [`crate::irql`].
+    pub irql: Cell<Irql>,
+
     // TODO: Put mailbox queues here, e.g. for TLB shootdown? Just be sure to 128-byte align it
     // first to avoid cache invalidation.
     #[cfg(feature = "profiling")]
@@ -36,6 +51,9 @@ pub struct PercpuBlock {
     pub ptrace_flags: Cell<PtraceFlags>,
     pub ptrace_session: RefCell<Option<Weak<Session>>>,
     pub inside_syscall: Cell<bool>,
+    /// Nesting depth of the page fault handler. Like `inside_syscall`, this is saved and restored
+    /// on context switch, since faults can block (e.g. waiting for a scheme fmap).
+    pub page_fault_depth: Cell<u32>,
 
     #[cfg(feature = "syscall_debug")]
     pub syscall_debug_info: Cell<SyscallDebugInfo>,
@@ -92,56 +110,232 @@ pub fn shootdown_tlb_ipi(target: Option<LogicalCpuId>) {
         }
     }
 }
+/// Reference to an address space that a CPU keeps loaded lazily, see
+/// [`PercpuBlock::lazy_addrsp`]. The CPU stays in the `used_by` set of the address space until the
+/// holder is given up through [`Self::resume`], [`Self::into_replaced`] or [`Self::release`].
+pub struct LazyAddrSpace {
+    addrsp: Arc<AddrSpaceWrapper>,
+    /// Whether the table has been replaced by `empty_cr3`, or is about to be replaced by another.
+    unloaded: bool,
+}
+impl LazyAddrSpace {
+    fn new(addrsp: Arc<AddrSpaceWrapper>) -> Self {
+        Self {
+            addrsp,
+            unloaded: false,
+        }
+    }
+    pub fn addrsp(&self) -> &Arc<AddrSpaceWrapper> {
+        &self.addrsp
+    }
+    /// Stop using the table, until a context using the address space runs here again.
+    unsafe fn unload(&mut self) {
+        if !core::mem::replace(&mut self.unloaded, true) {
+            crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
+        }
+    }
+    /// Make the address space current again, reloading its table if it was unloaded.
+    unsafe fn resume(self) -> Arc<AddrSpaceWrapper> {
+        if self.unloaded {
+            self.addrsp.acquire_read().table.utable.make_current();
+        }
+        self.addrsp
+    }
+    /// Give up the address space as another table is loaded. The caller must remove this CPU from
+    /// its `used_by` set once the new table is loaded.
+    fn into_replaced(self) -> Arc<AddrSpaceWrapper> {
+        self.addrsp
+    }
+    /// Unload the table and remove this CPU from the `used_by` set. If
+    /// [`AddrSpaceWrapper::release_lazy_holders`] is waiting for this CPU, it holds a reference of
+    /// its own until this CPU responds, so the reference held here is dropped first, as it must
+    /// not be the last one.
+    unsafe fn release(mut self, cpu_id: LogicalCpuId) {
+        self.unload();
+
+        if !self.addrsp.lazy_release_requested(cpu_id) {
+            self.addrsp.remove_cpu(cpu_id);
+            return;
+        }
+        let addrsp = Arc::as_ptr(&self.addrsp);
+        drop(self);
+        // SAFETY: The waiter keeps the address space alive until remove_cpu has acknowledged the
+        // release, which is the last access.
+        (*addrsp).remove_cpu(cpu_id);
+    }
+}
+
 impl PercpuBlock {
     pub fn maybe_handle_tlb_shootdown(&self) {
         if self.wants_tlb_shootdown.swap(false, Ordering::Relaxed) == false {
             return;
         }
 
+        // Only acknowledge the shootdowns requested before flushing. Those requested afterwards
+        // may be about changes the flush missed, and set `wants_tlb_shootdown` again anyway.
+        let is_pending = |addrsp: Option<&Arc<AddrSpaceWrapper>>| {
+            addrsp.is_some_and(|addrsp| addrsp.tlb_pending.atomic_contains(self.cpu_id))
+        };
+        let current_pending = is_pending(self.current_addrsp.borrow().as_ref());
+        let lazy_pending = is_pending(
+            self.lazy_addrsp
+                .borrow()
+                .as_ref()
+                .map(LazyAddrSpace::addrsp),
+        );
+
         // TODO: Finer-grained flush
         unsafe {
-            crate::paging::RmmA::invalidate_all();
+            crate::paging::mapper::flush_user_tlb();
         }
 
         if let Some(ref addrsp) = &*self.current_addrsp.borrow() {
-            addrsp.tlb_ack.fetch_add(1, Ordering::Release);
+            if current_pending {
+                addrsp.tlb_pending.atomic_clear(self.cpu_id);
+            }
+            addrsp.ack_lazy_release(self.cpu_id);
+        }
+
+        // The lazily loaded address space is being modified. Rather than keep tracking its
+        // changes, stop using its table until a context using it runs here again.
+        let mut lazy = self.lazy_addrsp.borrow_mut();
+        if let Some(ref mut held) = *lazy {
+            unsafe {
+                held.unload();
+            }
+            if lazy_pending {
+                held.addrsp.tlb_pending.atomic_clear(self.cpu_id);
+            }
+        }
+        // The last context using it may be about to drop its reference, which must then be the
+        // last one.
+        if lazy
+            .as_ref()
+            .is_some_and(|held| held.addrsp.lazy_release_requested(self.cpu_id))
+        {
+            let held = lazy.take().unwrap();
+            drop(lazy);
+            unsafe {
+                held.release(self.cpu_id);
+            }
         }
     }
 }
 pub unsafe fn switch_arch_hook() {
     let percpu = PercpuBlock::current();
 
-    let cur_addrsp = percpu.current_addrsp.borrow();
     let next_addrsp = percpu.new_addrsp_tmp.take();
+    drop(percpu.switch_addrsp(next_addrsp, true));
+}
+impl PercpuBlock {
+    /// Make `next` the address space of this CPU, moving this CPU from the `used_by` set of the
+    /// previous address space to that of `next`, and loading its page table. Returns the previous
+    /// address space, which the caller can drop once it no longer relies on its mappings.
+    ///
+    /// When switching from a user address space to a kernel-only context, the previous address
+    /// space is instead kept loaded lazily (see `lazy_addrsp`), if `allow_lazy` is set and it is
+    /// not being torn down.
+    ///
+    /// # Safety
+    ///
+    /// The caller must be about to run a context using `next`, and must not access user memory
+    /// of the previous address space after this call.
+    pub unsafe fn switch_addrsp(
+        &self,
+        next: Option<Arc<AddrSpaceWrapper>>,
+        allow_lazy: bool,
+    ) -> Option<Arc<AddrSpaceWrapper>> {
+        let retain_pgtbl = match (&*self.current_addrsp.borrow(), &next) {
+            (Some(ref p), Some(ref n)) => Arc::ptr_eq(p, n),
+            (Some(_), None) | (None, Some(_)) => false,
+            (None, None) => true,
+        };
+        if retain_pgtbl {
+            // If we are not switching to a different address space, we can simply return early.
+            return next;
+        }
+        let enter_lazy = allow_lazy
+            && next.is_none()
+            && self
+                .current_addrsp
+                .borrow()
+                .as_ref()
+                .is_some_and(|prev| !prev.is_dying());
+        if enter_lazy {
+            *self.lazy_addrsp.borrow_mut() = self
+                .current_addrsp
+                .borrow_mut()
+                .take()
+                .map(LazyAddrSpace::new);
+            return None;
+        }
+        let leave_lazy = match (&*self.lazy_addrsp.borrow(), &next) {
+            (Some(ref l), Some(ref n)) => Arc::ptr_eq(l.addrsp(), n),
+            _ => false,
+        };
+        if leave_lazy {
+            // Switching back to the lazily loaded address space, which is still in `used_by`.
+            let held = self.lazy_addrsp.borrow_mut().take().unwrap();
+            *self.current_addrsp.borrow_mut() = Some(held.resume());
+            return None;
+        }
+        // Loading another table always flushes the non-global TLB entries, as PCIDs are not used,
+        // so there is nothing to gain from tracking whether either address space was modified in
+        // the meantime. The only switches that can keep the TLB are those to kernel-only contexts
+        // and back, which the lazy path above already handles, reloading only after a shootdown.
+        //
+        // Any lazily loaded table is about to be replaced, so from now on shootdowns must only
+        // acknowledge it, until it is removed from `used_by` below.
+        if let Some(ref mut held) = *self.lazy_addrsp.borrow_mut() {
+            held.unloaded = true;
+        }
 
-    let retain_pgtbl = match (&*cur_addrsp, &next_addrsp) {
-        (Some(ref p), Some(ref n)) => Arc::ptr_eq(p, n),
-        (Some(_), None) | (None, Some(_)) => false,
-        (None, None) => true,
-    };
-    if retain_pgtbl {
-        // If we are not switching to a different address space, we can simply return early.
-    }
-    if let Some(ref prev_addrsp) = &*cur_addrsp {
-        prev_addrsp
-            .acquire_read()
-            .used_by
-            .atomic_clear(percpu.cpu_id);
-    }
+        let prev_addrsp = core::mem::replace(&mut *self.current_addrsp.borrow_mut(), next);
 
-    drop(cur_addrsp);
+        if let Some(next_addrsp) = &*self.current_addrsp.borrow() {
+            let next = next_addrsp.acquire_read();
 
-    // Tell future TLB shootdown handlers that old_addrsp_tmp is no longer the current address
-    // space.
-    *percpu.current_addrsp.borrow_mut() = next_addrsp;
+            next.used_by.atomic_set(self.cpu_id);
+            next.table.utable.make_current();
+        } else {
+            crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
+        }
 
-    if let Some(next_addrsp) = &*percpu.current_addrsp.borrow() {
-        let next = next_addrsp.acquire_read();
+        let prev_addrsp = prev_addrsp.or_else(|| {
+            self.lazy_addrsp
+                .borrow_mut()
+                .take()
+                .map(LazyAddrSpace::into_replaced)
+        });
 
-        next.used_by.atomic_set(percpu.cpu_id);
-        next.table.utable.make_current();
-    } else {
-        crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
+        // Tell future TLB shootdown handlers that the previous address space is no longer the
+        // current address space. This must happen only after its table has been unloaded, as
+        // shootdowns may otherwise skip this CPU while it can still access stale entries.
+        if let Some(ref prev_addrsp) = prev_addrsp {
+            prev_addrsp.remove_cpu(self.cpu_id);
+        }
+
+        prev_addrsp
+    }
+
+    /// Unload the lazily loaded address space if no context uses it anymore, or if it is being
+    /// torn down, so that it can be freed. Called before the CPU goes idle.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with interrupts disabled, from a kernel-only context.
+    pub unsafe fn drop_lazy_addrsp(&self) {
+        let mut lazy = self.lazy_addrsp.borrow_mut();
+        let Some(ref held) = *lazy else {
+            return;
+        };
+        if Arc::strong_count(held.addrsp()) > 1 && !held.addrsp().is_dying() {
+            return;
+        }
+        let held = lazy.take().unwrap();
+        drop(lazy);
+
+        held.release(self.cpu_id);
     }
 }
 impl PercpuBlock {
@@ -150,11 +344,16 @@ impl PercpuBlock {
             cpu_id,
             switch_internals: Default::default(),
             current_addrsp: RefCell::new(None),
+            lazy_addrsp: RefCell::new(None),
             new_addrsp_tmp: Cell::new(None),
             wants_tlb_shootdown: AtomicBool::new(false),
+            frame_cache: FrameCache::default(),
+            softirqs: SoftirqQueue::default(),
+            irql: Cell::new(Irql::PASSIVE),
             ptrace_flags: Cell::new(Default::default()),
             ptrace_session: RefCell::new(None),
             inside_syscall: Cell::new(false),
+            page_fault_depth: Cell::new(0),
 
             #[cfg(feature = "syscall_debug")]
             syscall_debug_info: Cell::new(SyscallDebugInfo::default()),
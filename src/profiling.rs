@@ -8,7 +8,7 @@ use alloc::boxed::Box;
 
 use crate::{
     cpu_set::LogicalCpuId,
-    idt::Idt,
+    idt::{Idt, LAPIC_TIMER_VECTOR},
     interrupt,
     interrupt::{irq::aux_timer, InterruptStack},
     percpu::PercpuBlock,
@@ -222,19 +222,17 @@ pub fn maybe_run_profiling_helper_forever(cpu_id: LogicalCpuId) {
         return;
     }
     unsafe {
-        for i in 33..255 {
-            crate::idt::IDTS
-                .write()
-                .as_mut()
-                .unwrap()
-                .get_mut(&cpu_id)
-                .unwrap()
-                .entries[i]
-                .set_func(crate::interrupt::ipi::wakeup);
+        // Only acknowledge any other interrupt, be it from a device or an IPI.
+        {
+            let mut idts = crate::idt::IDTS.write();
+            let idt = idts.as_mut().unwrap().get_mut(&cpu_id).unwrap();
+            for vector in (32..255).filter(|&vector| vector != LAPIC_TIMER_VECTOR) {
+                idt.entries[usize::from(vector)].set_func(crate::interrupt::ipi::wakeup);
+            }
         }
 
         let apic = &mut crate::device::local_apic::the_local_apic();
-        apic.set_lvt_timer((0b01 << 17) | 32);
+        apic.set_lvt_timer((0b01 << 17) | u32::from(LAPIC_TIMER_VECTOR));
         apic.set_div_conf(0b1011);
         apic.set_init_count(0xffff_f);
 
@@ -254,6 +252,6 @@ pub fn maybe_setup_timer(idt: &mut Idt, cpu_id: LogicalCpuId) {
     if cpu_id != PROFILER_CPU {
         return;
     }
-    idt.entries[32].set_func(aux_timer);
-    idt.set_reserved_mut(32, true);
+    idt.entries[usize::from(LAPIC_TIMER_VECTOR)].set_func(aux_timer);
+    idt.set_reserved_mut(LAPIC_TIMER_VECTOR, true);
 }
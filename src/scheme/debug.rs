@@ -48,6 +48,11 @@ enum SpecialFds {
 
     #[cfg(feature = "profiling")]
     CtlProfiling = !0 - 3,
+
+    CtlSwitchTiming = !0 - 4,
+
+    #[cfg(feature = "graphical_debug")]
+    CtlGraphicalDebugMode = !0 - 5,
 }
 
 impl KernelScheme for DebugScheme {
@@ -76,6 +81,11 @@ impl KernelScheme for DebugScheme {
             #[cfg(feature = "profiling")]
             "ctl-profiling" => SpecialFds::CtlProfiling as usize,
 
+            "ctl-switch-timing" => SpecialFds::CtlSwitchTiming as usize,
+
+            #[cfg(feature = "graphical_debug")]
+            "ctl-graphical-debug-mode" => SpecialFds::CtlGraphicalDebugMode as usize,
+
             _ => return Err(Error::new(ENOENT)),
         };
 
@@ -117,7 +127,9 @@ impl KernelScheme for DebugScheme {
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        if handle.num == SpecialFds::DisableGraphicalDebug as usize {
+        if handle.num == SpecialFds::DisableGraphicalDebug as usize
+            || handle.num == SpecialFds::CtlSwitchTiming as usize
+        {
             return Err(Error::new(EBADF));
         }
 
@@ -126,6 +138,11 @@ impl KernelScheme for DebugScheme {
             return Err(Error::new(EBADF));
         }
 
+        #[cfg(feature = "graphical_debug")]
+        if handle.num == SpecialFds::CtlGraphicalDebugMode as usize {
+            return Err(Error::new(EBADF));
+        }
+
         #[cfg(feature = "profiling")]
         if handle.num != SpecialFds::Default as usize {
             return crate::profiling::drain_buffer(
@@ -165,6 +182,31 @@ impl KernelScheme for DebugScheme {
             return Ok(1);
         }
 
+        // The histogram itself is read from sys:switch_latency.
+        if handle.num == SpecialFds::CtlSwitchTiming as usize {
+            let mut dst = [0];
+            buf.copy_to_slice(&mut dst)?;
+
+            match dst[0] {
+                b'0' => crate::context::switch::set_switch_timing(false),
+                b'1' => crate::context::switch::set_switch_timing(true),
+                b'r' => crate::context::switch::reset_switch_latency_histogram(),
+                _ => return Err(Error::new(EINVAL)),
+            }
+
+            return Ok(1);
+        }
+
+        // Written by the display driver after changing the mode, as the new width, height and
+        // stride in pixels.
+        #[cfg(feature = "graphical_debug")]
+        if handle.num == SpecialFds::CtlGraphicalDebugMode as usize {
+            let [width, height, stride] = unsafe { buf.read_exact::<[usize; 3]>()? };
+            graphical_debug::resize(width, height, stride);
+
+            return Ok(3 * core::mem::size_of::<usize>());
+        }
+
         if handle.num != SpecialFds::Default as usize
             && handle.num != SpecialFds::NoPreserve as usize
         {
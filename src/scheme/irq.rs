@@ -43,7 +43,7 @@ const BASE_IRQ_COUNT: u8 = 16;
 
 /// These are the extended IRQs, 16..=223 (interrupt vectors 48..=255). Some of them are reserved
 /// for other devices, and some other interrupt vectors like 0x80 (software interrupts) and
-/// 0x40..=0x43 (IPI).
+/// 0xE0..=0xFF (the timers and IPIs).
 ///
 /// Since these are non-sharable, they must be opened with O_CREAT, which then reserves them. They
 /// are only freed when the file descriptor is closed.
@@ -204,12 +204,23 @@ impl IrqScheme {
     }
 }
 
+#[cfg(not(dtb))]
 const fn irq_to_vector(irq: u8) -> u8 {
     irq + 32
 }
+#[cfg(not(dtb))]
 const fn vector_to_irq(vector: u8) -> u8 {
     vector - 32
 }
+// Interrupt controllers found in the device tree are reserved by virq directly.
+#[cfg(dtb)]
+const fn irq_to_vector(irq: u8) -> u8 {
+    irq
+}
+#[cfg(dtb)]
+const fn vector_to_irq(vector: u8) -> u8 {
+    vector
+}
 
 impl crate::scheme::KernelScheme for IrqScheme {
     fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
@@ -6,7 +6,7 @@ use rmm::PhysicalAddress;
 use crate::{
     context::{
         file::InternalFlags,
-        memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
+        memory::{handle_notify_files, AddrSpaceWrapper, CurrentAddrSpace, Grant, PageSpan},
     },
     memory::{free_frames, used_frames, Frame, PAGE_SIZE},
     paging::VirtualAddress,
@@ -45,6 +45,23 @@ bitflags! {
     struct HandleFlags: u16 {
         // TODO: below 32 bits?
         const PHYS_CONTIGUOUS = 1;
+        // Align the virtual base of mappings to the size of huge pages.
+        const ALIGN_2M = 2;
+        const ALIGN_1G = 4;
+    }
+}
+impl HandleFlags {
+    /// Flags that only affect where mappings are placed, which is up to any caller to choose.
+    const UNPRIVILEGED: Self = Self::ALIGN_2M.union(Self::ALIGN_1G);
+
+    fn alignment(self) -> usize {
+        if self.contains(Self::ALIGN_1G) {
+            1 << 30
+        } else if self.contains(Self::ALIGN_2M) {
+            1 << 21
+        } else {
+            PAGE_SIZE
+        }
     }
 }
 
@@ -69,10 +86,12 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
 }
 
 impl MemoryScheme {
+    /// Map zeroed memory. Unless the mapping is fixed, its base is a multiple of `align`.
     pub fn fmap_anonymous(
         addr_space: &Arc<AddrSpaceWrapper>,
         map: &Map,
         is_phys_contiguous: bool,
+        align: usize,
     ) -> Result<usize> {
         let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
             .ok_or(Error::new(EINVAL))?;
@@ -85,10 +104,11 @@ impl MemoryScheme {
             return Err(Error::new(EOPNOTSUPP));
         }
 
-        let page = addr_space.acquire_write().mmap(
+        let page = addr_space.acquire_write().mmap_aligned(
             &addr_space,
             (map.address != 0).then_some(span.base),
             page_count,
+            align,
             map.flags,
             &mut notify_files,
             |dst_page, flags, mapper, flusher| {
@@ -111,6 +131,12 @@ impl MemoryScheme {
 
         Ok(page.start_address().data())
     }
+    /// Map `size` bytes of physical memory at `physical_address`, which must be page-aligned.
+    ///
+    /// Device MMIO regions are often not a multiple of the page size, so `size` is rounded up to
+    /// the next page. The whole tail page is mapped, so the bytes between the end of the region
+    /// and the end of the page remain accessible, and whatever the bus returns for them is
+    /// readable by the caller.
     pub fn physmap(
         physical_address: usize,
         size: usize,
@@ -119,24 +145,28 @@ impl MemoryScheme {
     ) -> Result<usize> {
         // TODO: Check physical_address against the real MAXPHYADDR.
         let end = 1 << 52;
-        if (physical_address.saturating_add(size) as u64) > end || physical_address % PAGE_SIZE != 0
-        {
+        if physical_address % PAGE_SIZE != 0 {
             return Err(Error::new(EINVAL));
         }
-
-        if size % PAGE_SIZE != 0 {
-            log::warn!(
-                "physmap size {} is not multiple of PAGE_SIZE {}",
+        let rounded_size = size
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::new(EINVAL))?;
+        if (physical_address.saturating_add(rounded_size) as u64) > end {
+            return Err(Error::new(EINVAL));
+        }
+        if rounded_size != size {
+            log::debug!(
+                "physmap of {:#x} bytes at {:#x} rounded up to {:#x} bytes",
                 size,
-                PAGE_SIZE
+                physical_address,
+                rounded_size
             );
-            return Err(Error::new(EINVAL));
         }
-        let page_count = NonZeroUsize::new(size.div_ceil(PAGE_SIZE)).ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(rounded_size / PAGE_SIZE).ok_or(Error::new(EINVAL))?;
 
-        let current_addrsp = AddrSpace::current()?;
+        let current_addrsp = CurrentAddrSpace::get()?;
 
-        let base_page = current_addrsp.acquire_write().mmap_anywhere(
+        let base_page = current_addrsp.write().mmap_anywhere(
             &current_addrsp,
             page_count,
             flags,
@@ -206,6 +236,8 @@ impl KernelScheme for MemoryScheme {
             .filter_map(|ty_str| match ty_str {
                 //"32" => HandleFlags::BELOW_4G,
                 "phys_contiguous" => Some(Some(HandleFlags::PHYS_CONTIGUOUS)),
+                "align_2m" => Some(Some(HandleFlags::ALIGN_2M)),
+                "align_1g" => Some(Some(HandleFlags::ALIGN_1G)),
                 "" => None,
                 _ => Some(None),
             })
@@ -214,7 +246,7 @@ impl KernelScheme for MemoryScheme {
 
         // TODO: Support arches with other default memory types?
         if ctx.uid != 0
-            && (!flags.is_empty()
+            && (!flags.difference(HandleFlags::UNPRIVILEGED).is_empty()
                 || !matches!(
                     (handle_ty, mem_ty),
                     (HandleTy::Allocated, MemoryType::Writeback)
@@ -246,6 +278,7 @@ impl KernelScheme for MemoryScheme {
                 addr_space,
                 map,
                 flags.contains(HandleFlags::PHYS_CONTIGUOUS),
+                flags.alignment(),
             ),
             HandleTy::PhysBorrow => Self::physmap(map.offset, map.size, map.flags, mem_ty),
         }
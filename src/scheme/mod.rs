@@ -380,6 +380,29 @@ pub fn schemes_mut() -> RwLockWriteGuard<'static, SchemeList> {
     SCHEMES.call_once(init_schemes).write()
 }
 
+/// A range of a file to unmap, see [`KernelScheme::kfunmap_batch`].
+#[derive(Clone, Copy, Debug)]
+pub struct FunmapRange {
+    pub offset: usize,
+    pub size: usize,
+    pub flags: MunmapFlags,
+}
+
+/// Unmaps `ranges` with one [`KernelScheme::kfunmap`] call each, returning the first error after
+/// trying all ranges.
+pub fn funmap_each(
+    scheme: &(impl KernelScheme + ?Sized),
+    number: usize,
+    ranges: &[FunmapRange],
+) -> Result<()> {
+    let mut result = Ok(());
+    for range in ranges {
+        let range_result = scheme.kfunmap(number, range.offset, range.size, range.flags);
+        result = result.and(range_result);
+    }
+    result
+}
+
 #[allow(unused_variables)]
 pub trait KernelScheme: Send + Sync + 'static {
     fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
@@ -398,6 +421,13 @@ pub trait KernelScheme: Send + Sync + 'static {
     fn kfunmap(&self, number: usize, offset: usize, size: usize, flags: MunmapFlags) -> Result<()> {
         Err(Error::new(EOPNOTSUPP))
     }
+    /// Like [`kfunmap`](Self::kfunmap), for several ranges of the same file at once. Schemes
+    /// that can handle them in a single request should override this, by default the ranges are
+    /// unmapped one at a time, see [`funmap_each`]. The first error is returned, after trying all
+    /// ranges.
+    fn kfunmap_batch(&self, number: usize, ranges: &[FunmapRange]) -> Result<()> {
+        funmap_each(self, number, ranges)
+    }
 
     fn kdup(&self, old_id: usize, buf: UserSliceRo, _caller: CallerCtx) -> Result<OpenResult> {
         Err(Error::new(EOPNOTSUPP))
@@ -2,10 +2,14 @@ use crate::{
     arch::paging::{Page, VirtualAddress},
     context::{
         self,
-        context::{HardBlockedReason, SignalState},
+        context::{HardBlockedReason, RseqRegion, SignalState},
         file::{FileDescriptor, InternalFlags},
-        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, MapFlagsMask, PageSpan},
         process::{self, Process, ProcessId, ProcessInfo, ProcessStatus},
+        userfault::{
+            UserFaultHandler, USERFAULT_OP_COMPLETE, USERFAULT_OP_FILL, USERFAULT_OP_MAP_EMULATED,
+            USERFAULT_OP_REGISTER, USERFAULT_OP_UNREGISTER, USERFAULT_OP_WAKE,
+        },
         Context, Status,
     },
     memory::PAGE_SIZE,
@@ -89,9 +93,25 @@ fn try_stop_context<T>(
     ret
 }
 
+/// Like [`try_stop_context`], for accessing the FPU state. It is saved to `kfx` when a context is
+/// switched away from, so a context running on another CPU must be stopped first. The live state
+/// of the calling context itself is never saved there, so that fails with EBUSY.
+fn try_stop_context_fpu<T>(
+    context_ref: Arc<RwSpinlock<Context>>,
+    callback: impl FnOnce(&mut Context) -> Result<T>,
+) -> Result<T> {
+    if context::is_current(&context_ref) {
+        return Err(Error::new(EBUSY));
+    }
+    try_stop_context(context_ref, callback)
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum RegsKind {
     Float,
+    /// FPU state in the fixed layout of [`context::PortableFloatRegisters`].
+    #[cfg(target_arch = "x86_64")]
+    FloatPortable,
     Int,
     Env,
 }
@@ -151,6 +171,12 @@ enum ContextHandle {
     SchedAffinity,
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
+    MapFlagsMask(Arc<AddrSpaceWrapper>),
+    Rseq,
+    UserFault {
+        addrspace: Arc<AddrSpaceWrapper>,
+        handler: Arc<UserFaultHandler>,
+    },
 }
 #[derive(Clone)]
 enum Handle {
@@ -183,7 +209,8 @@ impl Handle {
                     | ContextHandle::AddrSpace { .. }
                     | ContextHandle::CurrentAddrSpace
                     | ContextHandle::CurrentFiletable
-                    | ContextHandle::Sighandler,
+                    | ContextHandle::Sighandler
+                    | ContextHandle::UserFault { .. },
                 ..
             }
         )
@@ -288,6 +315,8 @@ impl<const FULL: bool> ProcScheme<FULL> {
             "current-addrspace" => (ContextHandle::CurrentAddrSpace, false),
             "current-filetable" => (ContextHandle::CurrentFiletable, false),
             "regs/float" => (ContextHandle::Regs(RegsKind::Float), false),
+            #[cfg(target_arch = "x86_64")]
+            "regs/float-portable" => (ContextHandle::Regs(RegsKind::FloatPortable), false),
             "regs/int" => (ContextHandle::Regs(RegsKind::Int), false),
             "regs/env" => (ContextHandle::Regs(RegsKind::Env), false),
             "name" => (ContextHandle::Name, true),
@@ -303,7 +332,29 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 )),
                 false,
             ),
+            "map-flags-mask" => (
+                ContextHandle::MapFlagsMask(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                false,
+            ),
             "sched-affinity" => (ContextHandle::SchedAffinity, true),
+            "rseq" => (ContextHandle::Rseq, false),
+            "userfault" => {
+                let addrspace = Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                );
+                let handler = UserFaultHandler::new();
+                addrspace.userfault_attach(Arc::clone(&handler))?;
+
+                (ContextHandle::UserFault { addrspace, handler }, false)
+            }
             "status" => (ContextHandle::Status, false),
             "signal" => (ContextHandle::Signal, false),
             _ => return Ok(None),
@@ -554,22 +605,32 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                         new_ip,
                     },
             } => {
-                let _ = try_stop_context(context, |context: &mut Context| {
+                let old = try_stop_context(context, |context: &mut Context| {
                     let regs = context.regs_mut().ok_or(Error::new(EBADFD))?;
                     regs.set_instr_pointer(new_ip);
                     regs.set_stack_pointer(new_sp);
 
                     Ok(context.set_addr_space(Some(new)))
                 })?;
+                if let Some(old) = old {
+                    old.release_lazy_holders();
+                }
                 let _ = ptrace::send_event(crate::syscall::ptrace_event!(
                     PTRACE_EVENT_ADDRSPACE_SWITCH,
                     0
                 ));
             }
             Handle::Context {
-                kind: ContextHandle::AddrSpace { addrspace } | ContextHandle::MmapMinAddr(addrspace),
+                kind:
+                    ContextHandle::AddrSpace { addrspace }
+                    | ContextHandle::MmapMinAddr(addrspace)
+                    | ContextHandle::MapFlagsMask(addrspace),
                 ..
             } => drop(addrspace),
+            Handle::Context {
+                kind: ContextHandle::UserFault { addrspace, handler },
+                ..
+            } => addrspace.userfault_detach(&handler),
 
             Handle::Context {
                 kind: ContextHandle::AwaitingFiletableChange { new_ft },
@@ -627,6 +688,20 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
                 let requested_dst_base = (map.address != 0).then_some(requested_dst_page);
 
+                // A private mapping is a copy-on-write snapshot.
+                if map.flags.contains(MapFlags::MAP_PRIVATE) && !consume {
+                    let mut notify_files = Vec::new();
+                    let result = dst_addr_space.map_cow_from(
+                        addrspace,
+                        src_span,
+                        requested_dst_base,
+                        map.flags,
+                        &mut notify_files,
+                    );
+                    handle_notify_files(notify_files);
+                    return Ok(result?.start_address().data());
+                }
+
                 let mut src_addr_space = addrspace.acquire_write();
 
                 let src_page_count = NonZeroUsize::new(src_span.count).ok_or(Error::new(EINVAL))?;
@@ -745,6 +820,8 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 context.read().pid.get(),
                 match kind {
                     ContextHandle::Regs(RegsKind::Float) => "regs/float",
+                    #[cfg(target_arch = "x86_64")]
+                    ContextHandle::Regs(RegsKind::FloatPortable) => "regs/float-portable",
                     ContextHandle::Regs(RegsKind::Int) => "regs/int",
                     ContextHandle::Regs(RegsKind::Env) => "regs/env",
                     ContextHandle::Name => "name",
@@ -755,7 +832,10 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     ContextHandle::CurrentFiletable => "current-filetable",
                     ContextHandle::OpenViaDup => "open-via-dup",
                     ContextHandle::MmapMinAddr(_) => "mmap-min-addr",
+                    ContextHandle::MapFlagsMask(_) => "map-flags-mask",
                     ContextHandle::SchedAffinity => "sched-affinity",
+                    ContextHandle::Rseq => "rseq",
+                    ContextHandle::UserFault { .. } => "userfault",
 
                     _ => return Err(Error::new(EOPNOTSUPP)),
                 }
@@ -876,6 +956,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                         addrspace: addrspace.try_clone()?,
                     },
                     b"mmap-min-addr" => ContextHandle::MmapMinAddr(Arc::clone(addrspace)),
+                    b"map-flags-mask" => ContextHandle::MapFlagsMask(Arc::clone(addrspace)),
 
                     _ if buf.starts_with(GRANT_FD_PREFIX) => {
                         let string = core::str::from_utf8(&buf[GRANT_FD_PREFIX.len()..])
@@ -1236,7 +1317,7 @@ impl ContextHandle {
                 RegsKind::Float => {
                     let regs = unsafe { buf.read_exact::<FloatRegisters>()? };
 
-                    try_stop_context(context, |context| {
+                    try_stop_context_fpu(context, |context| {
                         // NOTE: The kernel will never touch floats
 
                         // Ignore the rare case of floating point
@@ -1246,6 +1327,16 @@ impl ContextHandle {
                         Ok(mem::size_of::<FloatRegisters>())
                     })
                 }
+                #[cfg(target_arch = "x86_64")]
+                RegsKind::FloatPortable => {
+                    let regs = unsafe { buf.read_exact::<[u8; context::PORTABLE_FX_SIZE]>()? };
+
+                    try_stop_context_fpu(context, |context| {
+                        context.set_portable_fx_regs(&regs);
+
+                        Ok(context::PORTABLE_FX_SIZE)
+                    })
+                }
                 RegsKind::Int => {
                     let regs = unsafe { buf.read_exact::<IntRegisters>()? };
 
@@ -1307,6 +1398,13 @@ impl ContextHandle {
 
                     let addrsp = Arc::clone(context.read().addr_space()?);
 
+                    // Catch bad handler addresses here, rather than as a fault during delivery.
+                    for handler in [data.user_handler, data.excp_handler] {
+                        if handler != 0 {
+                            addrsp.check_executable(VirtualAddress::new(handler))?;
+                        }
+                    }
+
                     Some(SignalState {
                         threadctl_off: validate_off(
                             data.thread_control_addr,
@@ -1429,13 +1527,114 @@ impl ContextHandle {
                 addrspace.acquire_write().mmap_min = val;
                 Ok(mem::size_of::<usize>())
             }
+            Self::MapFlagsMask(ref addrspace) => {
+                let mut args = buf.usizes();
+                let clear = args.next().ok_or(Error::new(EINVAL))??;
+                let force = args.next().ok_or(Error::new(EINVAL))??;
+
+                let mask = MapFlagsMask {
+                    clear: MapFlags::from_bits(clear).ok_or(Error::new(EINVAL))?,
+                    force: MapFlags::from_bits(force).ok_or(Error::new(EINVAL))?,
+                };
+                // Only root can loosen the mask.
+                let privileged = process::current()?.read().euid == 0;
+                addrspace.set_map_flags_mask(mask, privileged)?;
+
+                Ok(2 * mem::size_of::<usize>())
+            }
             Self::SchedAffinity => {
                 let mask = unsafe { buf.read_exact::<crate::cpu_set::RawMask>()? };
 
-                context.write().sched_affinity.override_from(&mask);
+                // The context could never run again, if no CPU were allowed.
+                let mut affinity = crate::cpu_set::LogicalCpuSet::empty();
+                affinity.override_from(&mask);
+                if !(0..crate::cpu_count())
+                    .any(|id| affinity.contains(crate::cpu_set::LogicalCpuId::new(id)))
+                {
+                    return Err(Error::new(EINVAL));
+                }
+
+                let mut guard = context.write();
+                guard.sched_affinity.override_from(&mask);
+                // A running context is moved once its CPU switches away from it.
+                if !guard.running {
+                    context::run_queue::rehome(&context, &mut guard);
+                }
 
                 Ok(mem::size_of_val(&mask))
             }
+            Self::Rseq => {
+                let mut args = buf.usizes();
+                let start = args.next().ok_or(Error::new(EINVAL))??;
+                let end = args.next().ok_or(Error::new(EINVAL))??;
+                let abort = args.next().ok_or(Error::new(EINVAL))??;
+
+                // Writing an empty section unregisters it.
+                let rseq = if start == end {
+                    None
+                } else if start > end
+                    || end > crate::USER_END_OFFSET
+                    || abort >= crate::USER_END_OFFSET
+                    || (start..end).contains(&abort)
+                {
+                    return Err(Error::new(EINVAL));
+                } else {
+                    Some(RseqRegion { start, end, abort })
+                };
+                context.write().rseq = rseq;
+
+                Ok(3 * mem::size_of::<usize>())
+            }
+            Self::UserFault { addrspace, handler } => {
+                let mut chunks = buf.usizes();
+                let mut words_read = 0;
+                let mut next = || {
+                    words_read += 1;
+                    chunks.next().ok_or(Error::new(EINVAL))
+                };
+
+                match next()?? {
+                    op @ USERFAULT_OP_REGISTER | op @ USERFAULT_OP_UNREGISTER => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.userfault_register(
+                            &handler,
+                            PageSpan::new(page, page_count),
+                            op == USERFAULT_OP_REGISTER,
+                        )?;
+                    }
+                    USERFAULT_OP_FILL => {
+                        let (page, _) = crate::syscall::validate_region(next()??, PAGE_SIZE)?;
+                        let src = UserSliceRo::new(next()??, PAGE_SIZE)?;
+
+                        addrspace.userfault_fill(&handler, page, src)?;
+                    }
+                    USERFAULT_OP_WAKE => {
+                        let (page, _) = crate::syscall::validate_region(next()??, PAGE_SIZE)?;
+
+                        handler.wake(page);
+                    }
+                    USERFAULT_OP_MAP_EMULATED => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let flags = MapFlags::from_bits(next()??).ok_or(Error::new(EINVAL))?;
+                        let page_count = NonZeroUsize::new(page_count).ok_or(Error::new(EINVAL))?;
+
+                        addrspace.userfault_map_emulated(&handler, page, page_count, flags)?;
+                    }
+                    USERFAULT_OP_COMPLETE => {
+                        let address = VirtualAddress::new(next()??);
+                        let value = next()?? as u64;
+
+                        if !handler.complete(address, value) {
+                            return Err(Error::new(EINVAL));
+                        }
+                    }
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                Ok(words_read * mem::size_of::<usize>())
+            }
             ContextHandle::Status => {
                 let mut args = buf.usizes();
 
@@ -1479,9 +1678,7 @@ impl ContextHandle {
                             .ok_or(Error::new(EINVAL))?,
                         false,
                     )?;
-                    for r in res {
-                        let _ = r.unmap();
-                    }
+                    handle_notify_files(res);
                     Ok(3 * mem::size_of::<usize>())
                 } else {
                     Ok(mem::size_of::<usize>())
@@ -1528,22 +1725,32 @@ impl ContextHandle {
             ContextHandle::Regs(kind) => {
                 union Output {
                     float: FloatRegisters,
+                    #[cfg(target_arch = "x86_64")]
+                    float_portable: [u8; context::PORTABLE_FX_SIZE],
                     int: IntRegisters,
                     env: EnvRegisters,
                 }
 
                 let (output, size) = match kind {
-                    RegsKind::Float => {
-                        let context = context.read();
+                    RegsKind::Float => try_stop_context_fpu(context, |context| {
                         // NOTE: The kernel will never touch floats
 
-                        (
+                        Ok((
                             Output {
                                 float: context.get_fx_regs(),
                             },
                             mem::size_of::<FloatRegisters>(),
-                        )
-                    }
+                        ))
+                    })?,
+                    #[cfg(target_arch = "x86_64")]
+                    RegsKind::FloatPortable => try_stop_context_fpu(context, |context| {
+                        Ok((
+                            Output {
+                                float_portable: context.get_portable_fx_regs(),
+                            },
+                            context::PORTABLE_FX_SIZE,
+                        ))
+                    })?,
                     RegsKind::Int => try_stop_context(context, |context| match context.regs() {
                         None => {
                             assert!(!context.running, "try_stop_context is broken, clearly");
@@ -1616,12 +1823,41 @@ impl ContextHandle {
                 buf.write_usize(addrspace.acquire_read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            ContextHandle::MapFlagsMask(ref addrspace) => {
+                let mask = addrspace.acquire_read().map_flags_mask;
+
+                let mut words = buf.in_exact_chunks(mem::size_of::<usize>());
+                for word in [mask.clear.bits(), mask.force.bits()] {
+                    words
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(word)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
             ContextHandle::SchedAffinity => {
                 let mask = context.read().sched_affinity.to_raw();
 
                 buf.copy_exactly(crate::cpu_set::mask_as_bytes(&mask))?;
                 Ok(mem::size_of_val(&mask))
-            } // TODO: Replace write() with SYS_DUP_FORWARD.
+            }
+            ContextHandle::Rseq => {
+                let rseq = context.read().rseq;
+                let (start, end, abort) = rseq.map_or((0, 0, 0), |r| (r.start, r.end, r.abort));
+
+                let mut words = buf.in_exact_chunks(mem::size_of::<usize>());
+                for word in [start, end, abort] {
+                    words
+                        .next()
+                        .ok_or(Error::new(EINVAL))?
+                        .write_usize(word)?;
+                }
+                Ok(3 * mem::size_of::<usize>())
+            }
+            ContextHandle::UserFault { handler, .. } => handler
+                .events()
+                .receive_into_user(buf, true, "ProcScheme::read (userfault)"),
+            // TODO: Replace write() with SYS_DUP_FORWARD.
 
             // TODO: Find a better way to switch address spaces, since they also require switching
             // the instruction and stack pointer. Maybe remove `<pid>/regs` altogether and replace it
@@ -83,12 +83,7 @@ pub fn resource() -> Result<Vec<u8>> {
                 memory += kstack.len();
             }
             if let Ok(addr_space) = context.addr_space() {
-                for (_base, info) in addr_space.acquire_read().grants.iter() {
-                    // TODO: method
-                    if matches!(info.provider, context::memory::Provider::Allocated { .. }) {
-                        memory += info.page_count() * PAGE_SIZE;
-                    }
-                }
+                memory += addr_space.acquire_read().allocated_pages() * PAGE_SIZE;
             }
 
             let memory_string = if memory >= 1024 * 1024 * 1024 {
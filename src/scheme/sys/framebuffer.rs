@@ -0,0 +1,28 @@
+use crate::{
+    devices::graphical_debug::{self, PixelFormat},
+    syscall::error::{Error, Result, ENODEV},
+};
+use alloc::vec::Vec;
+
+/// Framebuffer of the graphical debug display, for a userspace driver taking it over. Uses the
+/// same hexadecimal variables the bootloader passes in the environment.
+pub fn resource() -> Result<Vec<u8>> {
+    let info = graphical_debug::framebuffer_info().ok_or(Error::new(ENODEV))?;
+    let format = match info.format {
+        PixelFormat::Xrgb8888 => "XRGB8888",
+    };
+
+    Ok(format!(
+        "FRAMEBUFFER_ADDR={:X}\n\
+         FRAMEBUFFER_WIDTH={:X}\n\
+         FRAMEBUFFER_HEIGHT={:X}\n\
+         FRAMEBUFFER_STRIDE={:X}\n\
+         FRAMEBUFFER_FORMAT={}\n",
+        info.phys.data(),
+        info.width,
+        info.height,
+        info.stride,
+        format
+    )
+    .into_bytes())
+}
@@ -27,11 +27,14 @@ mod block;
 mod context;
 mod cpu;
 mod exe;
+#[cfg(feature = "graphical_debug")]
+mod framebuffer;
 mod iostat;
 mod irq;
 mod log;
 mod scheme;
 mod scheme_num;
+mod switch_latency;
 mod syscall;
 mod uname;
 
@@ -53,11 +56,14 @@ const FILES: &[(&'static str, SysFn)] = &[
     ("context", context::resource),
     ("cpu", cpu::resource),
     ("exe", exe::resource),
+    #[cfg(feature = "graphical_debug")]
+    ("framebuffer", framebuffer::resource),
     ("iostat", iostat::resource),
     ("irq", irq::resource),
     ("log", log::resource),
     ("scheme", scheme::resource),
     ("scheme_num", scheme_num::resource),
+    ("switch_latency", switch_latency::resource),
     ("syscall", syscall::resource),
     ("uname", uname::resource),
     ("env", || Ok(Vec::from(crate::init_env()))),
@@ -0,0 +1,16 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{context::switch, syscall::error::Result};
+
+/// Context switch latency histogram, one line per bucket with its lower bound in cycles. Timing
+/// is enabled through `debug:ctl-switch-timing`.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    for (i, count) in switch::switch_latency_histogram().iter().enumerate() {
+        let _ = writeln!(string, "{}: {}", 1_u64 << i, count);
+    }
+
+    Ok(string.into_bytes())
+}
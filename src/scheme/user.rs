@@ -25,8 +25,9 @@ use crate::{
         context::HardBlockedReason,
         file::{FileDescription, FileDescriptor, InternalFlags},
         memory::{
-            AddrSpace, AddrSpaceWrapper, BorrowedFmapSource, Grant, GrantFileRef, MmapMode,
-            PageSpan, DANGLING,
+            handle_notify_files, release_fmap_frame, take_fmap_frame, AddrSpace,
+            AddrSpaceWrapper, BorrowedFmapSource, Grant, GrantFileRef, MmapMode, PageSpan,
+            DANGLING,
         },
         process, BorrowedHtBuf, Context, Status,
     },
@@ -44,7 +45,7 @@ use crate::{
     },
 };
 
-use super::{CallerCtx, FileHandle, KernelScheme, OpenResult};
+use super::{CallerCtx, FileHandle, FunmapRange, KernelScheme, OpenResult};
 
 pub struct UserInner {
     root_id: SchemeId,
@@ -59,8 +60,19 @@ pub struct UserInner {
     states: Mutex<Slab<State>>,
 
     unmounting: AtomicBool,
+    // Whether munmap requests with several ranges can be sent, see `MUNMAP_BATCH`. Cleared once
+    // the scheme rejects one.
+    batched_munmap: AtomicBool,
 }
 
+/// Set in the flags argument of an [`Opcode::Munmap`] request carrying several ranges, whose
+/// arguments are then the file, the address and the number of ranges, each being three words:
+/// the offset, the size and the [`MunmapFlags`]. Schemes that do not know this flag are expected
+/// to reject it with EINVAL or EOPNOTSUPP, after which ranges are sent one at a time instead.
+const MUNMAP_BATCH: usize = 1 << (usize::BITS - 1);
+/// Maximum number of ranges in a batched munmap request, which has to fit in one page.
+const MUNMAP_BATCH_MAX: usize = PAGE_SIZE / (3 * size_of::<usize>());
+
 enum State {
     Waiting {
         context: Weak<RwSpinlock<Context>>,
@@ -70,6 +82,14 @@ enum State {
     },
     Responded(Response),
     Fmap(Weak<RwSpinlock<Context>>),
+    // Page requested by fadvise, to be mapped into the address space once provided, rather than
+    // returned to a blocked context.
+    FmapPrefetch {
+        addr_space: Weak<AddrSpaceWrapper>,
+        page: Page,
+        description: Weak<RwLock<FileDescription>>,
+        offset: usize,
+    },
     Placeholder,
 }
 
@@ -210,6 +230,7 @@ impl UserInner {
             context,
             todo: WaitQueue::new(),
             unmounting: AtomicBool::new(false),
+            batched_munmap: AtomicBool::new(v2),
             states: Mutex::new(Slab::with_capacity(32)),
         }
     }
@@ -378,7 +399,9 @@ impl UserInner {
                     }
 
                     // invalid state
-                    old_state @ (State::Placeholder | State::Fmap(_)) => {
+                    old_state @ (State::Placeholder
+                    | State::Fmap(_)
+                    | State::FmapPrefetch { .. }) => {
                         *o = old_state;
                         return Err(Error::new(EBADFD));
                     }
@@ -890,13 +913,50 @@ impl UserInner {
     ) -> Result<()> {
         log::info!("REQUEST FMAP");
 
+        self.send_fmap_request(
+            State::Fmap(Arc::downgrade(&context::current())),
+            id,
+            required_page_count,
+            flags,
+        )
+    }
+    /// Like `request_fmap`, but without blocking the current context. Once provided, the page is
+    /// mapped at `page` in `addr_space`, see [`AddrSpaceWrapper::fill_fmap_prefetch`].
+    pub fn request_fmap_prefetch(
+        &self,
+        id: usize,
+        offset: usize,
+        flags: MapFlags,
+        addr_space: Weak<AddrSpaceWrapper>,
+        page: Page,
+        description: Weak<RwLock<FileDescription>>,
+    ) -> Result<()> {
+        self.send_fmap_request(
+            State::FmapPrefetch {
+                addr_space,
+                page,
+                description,
+                offset,
+            },
+            id,
+            1,
+            flags,
+        )
+    }
+    fn send_fmap_request(
+        &self,
+        state: State,
+        id: usize,
+        required_page_count: usize,
+        flags: MapFlags,
+    ) -> Result<()> {
         let tag = self.next_id()?;
         let mut states = self.states.lock();
-        states[tag as usize] = State::Fmap(Arc::downgrade(&context::current()));
+        states[tag as usize] = state;
 
         /*self.todo.send(Packet {
             id: packet_id,
-            pid: context::context_id().into(),
+            pid: context::current_context_id().into(),
             a: KSMSG_MMAP,
             b: id,
             c: flags.bits(),
@@ -999,7 +1059,7 @@ impl UserInner {
                     return Err(Error::new(EINVAL));
                 }
 
-                let context = {
+                let state = {
                     let mut states = self.states.lock();
 
                     match states.get_mut(tag as usize) {
@@ -1013,23 +1073,51 @@ impl UserInner {
                                 *o = old_state;
                                 return Err(Error::new(EINVAL));
                             }
-                            State::Fmap(context) => {
+                            state @ (State::Fmap(_) | State::FmapPrefetch { .. }) => {
                                 states.remove(tag as usize);
-                                context
+                                state
                             }
                         },
                         None => return Err(Error::new(EINVAL)),
                     }
                 };
 
-                let context = context.upgrade().ok_or(Error::new(ESRCH))?;
+                // The reference is taken for both fault and prefetch requests alike, and then
+                // owned by whoever maps the frame, or releases it.
+                let frame = {
+                    let addr_space = AddrSpace::current()?;
+                    let guard = addr_space.acquire_read();
+                    let (phys, _) = guard
+                        .table
+                        .utable
+                        .translate(base_addr)
+                        .ok_or(Error::new(EFAULT))?;
+                    take_fmap_frame(Frame::containing(phys))?
+                };
 
-                let (frame, _) = AddrSpace::current()?
-                    .acquire_read()
-                    .table
-                    .utable
-                    .translate(base_addr)
-                    .ok_or(Error::new(EFAULT))?;
+                let context = match state {
+                    State::Fmap(context) => context,
+                    State::FmapPrefetch {
+                        addr_space,
+                        page,
+                        description,
+                        offset,
+                    } => {
+                        // The address space may have gone away, which is not the scheme's fault.
+                        match addr_space.upgrade() {
+                            Some(addr_space) => {
+                                addr_space.fill_fmap_prefetch(page, &description, offset, frame)?
+                            }
+                            None => release_fmap_frame(frame),
+                        }
+                        return Ok(());
+                    }
+                    _ => unreachable!(),
+                };
+                let Some(context) = context.upgrade() else {
+                    release_fmap_frame(frame);
+                    return Err(Error::new(ESRCH));
+                };
 
                 let mut context = context.write();
                 match context.status {
@@ -1038,7 +1126,9 @@ impl UserInner {
                     } => context.status = Status::Runnable,
                     _ => (),
                 }
-                context.fmap_ret = Some(Frame::containing(frame));
+                if let Some(old) = context.fmap_ret.replace(frame) {
+                    release_fmap_frame(old);
+                }
             }
             ParsedCqe::TriggerFevent { number, flags } => {
                 event::trigger(self.scheme_id, number, flags)
@@ -1056,7 +1146,7 @@ impl UserInner {
                 // invalid state
                 State::Placeholder => return Err(Error::new(EBADFD)),
                 // invalid scheme to kernel call
-                old_state @ (State::Responded(_) | State::Fmap(_)) => {
+                old_state @ (State::Responded(_) | State::Fmap(_) | State::FmapPrefetch { .. }) => {
                     *o = old_state;
                     return Err(Error::new(EINVAL));
                 }
@@ -1270,9 +1360,7 @@ impl UserInner {
             },
         )?;
 
-        for map in notify_files {
-            let _ = map.unmap();
-        }
+        handle_notify_files(notify_files);
 
         Ok(dst_base.start_address().data())
     }
@@ -1692,6 +1780,42 @@ impl KernelScheme for UserScheme {
             Response::Fd(_) => Err(Error::new(EIO)),
         }
     }
+    fn kfunmap_batch(&self, number: usize, ranges: &[FunmapRange]) -> Result<()> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        if ranges.len() < 2 || !inner.batched_munmap.load(Ordering::Relaxed) {
+            return super::funmap_each(self, number, ranges);
+        }
+
+        let mut result = Ok(());
+        for (i, batch) in ranges.chunks(MUNMAP_BATCH_MAX).enumerate() {
+            let mut buf = Vec::with_capacity(batch.len() * 3 * size_of::<usize>());
+            for range in batch {
+                for word in [range.offset, range.size, range.flags.bits()] {
+                    buf.extend_from_slice(&word.to_ne_bytes());
+                }
+            }
+            let mut address = inner.copy_and_capture_tail(&buf)?;
+            let ctx = process::current()?.read().caller_ctx();
+            let res = inner.call_extended(
+                ctx,
+                None,
+                Opcode::Munmap,
+                [number, address.base(), batch.len(), MUNMAP_BATCH],
+                address.span(),
+            );
+            match res {
+                Ok(Response::Regular(_, _)) => (),
+                Ok(Response::Fd(_)) => result = result.and(Err(Error::new(EIO))),
+                Err(err) if i == 0 && matches!(err.errno, EINVAL | EOPNOTSUPP) => {
+                    // The scheme does not support batches, so nothing has been unmapped yet.
+                    inner.batched_munmap.store(false, Ordering::Relaxed);
+                    return super::funmap_each(self, number, ranges);
+                }
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result
+    }
     fn ksendfd(
         &self,
         number: usize,
@@ -0,0 +1,129 @@
+//! Deferred interrupt work ("softirqs", or bottom halves).
+//!
+//! Interrupt handlers run with interrupts disabled, and should therefore only do the minimum
+//! amount of work, such as acknowledging the device. Anything more expensive can be deferred using
+//! [`raise`], which queues a work item on the current CPU. The queue is drained with interrupts
+//! enabled when the interrupt handler returns, and additionally at syscall exit and before the CPU
+//! goes idle, in case an item was raised somewhere the queue could not be drained.
+//!
+//! Since the kernel otherwise only runs with interrupts enabled in usermode and in the idle loop,
+//! work items can be interrupted by hard interrupts, and must not take locks that interrupt
+//! handlers may also take.
+//!
+//! Work items only run while the CPU is at [`Irql::PASSIVE`]. If the priority level is raised,
+//! they are deferred until [`irql::lower_irql`] returns to it.
+
+use core::cell::{Cell, RefCell};
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    interrupt,
+    irql::{self, Irql},
+    percpu::PercpuBlock,
+};
+
+/// Maximum number of pending work items per CPU.
+const QUEUE_LEN: usize = 32;
+
+/// Maximum number of times the queue is refilled while draining, before the rest of the work is
+/// left for the next drain, so that an interrupt storm cannot keep the CPU from returning.
+const MAX_RESTARTS: usize = 4;
+
+/// A deferred work item, called with its `arg`.
+///
+/// Work items run with interrupts enabled, so they can be interrupted by hard interrupts at any
+/// point, and must not take locks that interrupt handlers may also take. Preemption is deferred
+/// until the queue is drained (see [`is_running`]), so they always finish on the CPU they were
+/// raised on, but they must not block or switch contexts themselves.
+pub type SoftirqFn = fn(usize);
+
+#[derive(Clone, Copy)]
+struct WorkItem {
+    func: SoftirqFn,
+    arg: usize,
+}
+
+impl WorkItem {
+    fn same_as(&self, other: &Self) -> bool {
+        self.func as usize == other.func as usize && self.arg == other.arg
+    }
+}
+
+#[derive(Default)]
+pub struct SoftirqQueue {
+    pending: RefCell<ArrayVec<WorkItem, QUEUE_LEN>>,
+    running: Cell<bool>,
+    dropped: Cell<usize>,
+}
+
+/// Queue `func(arg)` to run on this CPU after the current interrupt handler. If the same work is
+/// already pending, it is only run once. Must be called with interrupts disabled.
+pub fn raise(func: SoftirqFn, arg: usize) {
+    let queue = &PercpuBlock::current().softirqs;
+    let item = WorkItem { func, arg };
+
+    let mut pending = queue.pending.borrow_mut();
+    if pending.iter().any(|queued| queued.same_as(&item)) {
+        return;
+    }
+    if pending.try_push(item).is_err() {
+        // Work items are expected to process everything available (e.g. an entire receive ring),
+        // so dropping is only a problem if nothing of the same kind is already pending.
+        queue.dropped.set(queue.dropped.get() + 1);
+    }
+}
+
+/// Whether this CPU is currently draining its queue. The scheduler tick does not preempt the
+/// current context while this is the case, as the queue belongs to the CPU.
+pub fn is_running() -> bool {
+    PercpuBlock::current().softirqs.running.get()
+}
+
+/// Number of work items that could not be queued on this CPU, because the queue was full.
+pub fn dropped() -> usize {
+    PercpuBlock::current().softirqs.dropped.get()
+}
+
+/// Run the pending work items of this CPU, with interrupts enabled (see [`SoftirqFn`] for what
+/// that means for the work items). Returns whether any work was done. Does nothing if the priority
+/// level of this CPU is above [`Irql::PASSIVE`].
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled, and without holding any locks. Interrupts are disabled
+/// again when this returns.
+pub unsafe fn run_pending() -> bool {
+    if irql::current() != Irql::PASSIVE {
+        return false;
+    }
+    let queue = &PercpuBlock::current().softirqs;
+
+    // A nested interrupt arriving while draining must not drain recursively.
+    if queue.running.replace(true) {
+        return false;
+    }
+
+    let mut did_work = false;
+
+    for _ in 0..MAX_RESTARTS {
+        let batch = core::mem::take(&mut *queue.pending.borrow_mut());
+        if batch.is_empty() {
+            break;
+        }
+        did_work = true;
+
+        unsafe {
+            interrupt::enable_and_nop();
+        }
+        for item in batch {
+            (item.func)(item.arg);
+        }
+        unsafe {
+            interrupt::disable();
+        }
+    }
+
+    queue.running.set(false);
+    did_work
+}
@@ -363,15 +363,25 @@ unsafe fn map_memory<A: Arch>(areas: &[MemoryArea], mut bump_allocator: &mut Bum
 
         let (phys, virt, size) = *FRAMEBUFFER.lock();
 
-        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
-        for i in 0..pages {
-            let phys = PhysicalAddress::new(phys + i * PAGE_SIZE);
-            let virt = VirtualAddress::new(virt + i * PAGE_SIZE);
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let mut offset = 0;
+        while offset < size {
+            let phys = PhysicalAddress::new(phys + offset);
+            let virt = VirtualAddress::new(virt + offset);
+
+            // Use large pages for the aligned middle part, to save TLB entries when scrolling.
+            #[cfg(target_arch = "x86_64")]
+            if size - offset >= LARGE_PAGE_SIZE && map_large_wc(&mut mapper, virt, phys) {
+                offset += LARGE_PAGE_SIZE;
+                continue;
+            }
+
             let flags = PageFlags::new().write(true).write_combining(true);
             let flush = mapper
                 .map_phys(virt, phys, flags)
                 .expect("failed to map frame");
             flush.ignore(); // Not the active table
+            offset += PAGE_SIZE;
         }
     }
 
@@ -388,6 +398,59 @@ unsafe fn map_memory<A: Arch>(areas: &[MemoryArea], mut bump_allocator: &mut Bum
     mapper.make_current();
 }
 
+#[cfg(all(feature = "graphical_debug", target_arch = "x86_64"))]
+const LARGE_PAGE_SIZE: usize = 2 * MEGABYTE;
+
+/// Maps a single write-combining 2 MiB page at `virt`, returning false if the addresses are not
+/// suitably aligned or the region is already (partially) mapped with 4 KiB pages.
+#[cfg(all(feature = "graphical_debug", target_arch = "x86_64"))]
+unsafe fn map_large_wc<A: Arch, F: rmm::FrameAllocator>(
+    mapper: &mut PageMapper<A, F>,
+    virt: VirtualAddress,
+    phys: PhysicalAddress,
+) -> bool {
+    use rmm::PageEntry;
+
+    // In PD entries, bit 7 is PS rather than PAT, and PAT instead moves to bit 12. PAT index 4
+    // (PAT=1, PCD=0, PWT=0) is configured as write-combining by init_pat.
+    const ENTRY_FLAG_PAT_LARGE: usize = 1 << 12;
+
+    if virt.data() % LARGE_PAGE_SIZE != 0 || phys.data() % LARGE_PAGE_SIZE != 0 {
+        return false;
+    }
+
+    let table_flags = A::ENTRY_FLAG_READWRITE | A::ENTRY_FLAG_DEFAULT_TABLE;
+    let mut table = mapper.table();
+
+    // Walk down to the page directory, allocating intermediate tables as needed.
+    while table.level() > 1 {
+        let i = table.index_of(virt).expect("virtual address not in table");
+
+        table = match table.next(i) {
+            Some(next) => next,
+            None => {
+                let Some(next_phys) = mapper.allocator_mut().allocate_one() else {
+                    return false;
+                };
+                table.set_entry(i, PageEntry::new(next_phys.data(), table_flags));
+                table.next(i).expect("newly created table missing")
+            }
+        };
+    }
+
+    let i = table.index_of(virt).expect("virtual address not in table");
+    if table.entry(i).map_or(false, |entry| entry.present()) {
+        return false;
+    }
+
+    let flags = PageFlags::<A>::new().write(true).data()
+        | EntryFlags::HUGE_PAGE.bits()
+        | ENTRY_FLAG_PAT_LARGE;
+    table.set_entry(i, PageEntry::new(phys.data(), flags));
+
+    true
+}
+
 pub unsafe fn init(low_limit: Option<usize>, high_limit: Option<usize>) {
     let physmem_limit = MemoryEntry {
         start: align_up(low_limit.unwrap_or(0)),
@@ -31,6 +31,27 @@ impl WaitCondition {
         len
     }
 
+    // Notify the longest waiting context that is still blocked, returning whether there was one.
+    // Waiters that have already been woken some other way, e.g. by a signal, are skipped so that
+    // the notification is not lost on them. They are left queued, so that their wait still finds
+    // itself there and reports that it was not notified.
+    pub fn notify_one(&self) -> bool {
+        let mut contexts = self.contexts.lock();
+        let mut i = 0;
+        while i < contexts.len() {
+            let Some(context_ref) = contexts[i].upgrade() else {
+                contexts.remove(i);
+                continue;
+            };
+            if context_ref.write().unblock() {
+                contexts.remove(i);
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
     // Notify as though a signal woke the waiters
     pub unsafe fn notify_signal(&self) -> usize {
         let contexts = self.contexts.lock();
@@ -44,6 +65,11 @@ impl WaitCondition {
     }
 
     // Wait until notified. Unlocks guard when blocking is ready. Returns false if resumed by a signal or the notify_signal function
+    //
+    // This is the condition variable pattern: the caller checks its condition under guard, and
+    // calls this if it does not hold yet. The context is marked blocked and queued before guard is
+    // released, so a notify racing with the release, i.e. before the switch, unblocks it and the
+    // switch returns right away, instead of the wakeup being lost.
     pub fn wait<T>(&self, guard: T, reason: &'static str) -> bool {
         let current_context_ref = context::current();
         {
@@ -7,7 +7,7 @@ use crate::{
     context::{
         self,
         file::{FileDescription, FileDescriptor, InternalFlags},
-        memory::{AddrSpace, PageSpan},
+        memory::{handle_notify_files, AddrSpace, PageSpan},
         process,
     },
     paging::{Page, VirtualAddress, PAGE_SIZE},
@@ -445,10 +445,7 @@ pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
         .ok_or(Error::new(EINVAL))?;
     let unpin = false;
     let notify = addr_space.munmap(span, unpin)?;
-
-    for map in notify {
-        let _ = map.unmap();
-    }
+    handle_notify_files(notify);
 
     Ok(0)
 }
@@ -15,7 +15,7 @@ use syscall::EINTR;
 use crate::{
     context::{
         self,
-        memory::{AddrSpace, AddrSpaceWrapper},
+        memory::{AddrSpace, AddrSpaceWrapper, CurrentAddrSpace},
         Context,
     },
     memory::PhysicalAddress,
@@ -70,11 +70,11 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
 }
 
 pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) -> Result<usize> {
-    let current_addrsp = AddrSpace::current()?;
+    let current_addrsp = CurrentAddrSpace::get()?;
 
     // Keep the address space locked so we can safely read from the physical address. Unlock it
     // before context switching.
-    let addr_space_guard = current_addrsp.acquire_read();
+    let addr_space_guard = current_addrsp.read();
 
     let target_virtaddr = VirtualAddress::new(addr);
     let target_physaddr = validate_and_translate_virt(&*addr_space_guard, target_virtaddr)
@@ -155,7 +155,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                     target_physaddr,
                     target_virtaddr,
                     context_lock,
-                    addr_space: Arc::downgrade(&current_addrsp),
+                    addr_space: Arc::downgrade(&*current_addrsp),
                 });
             }
 
@@ -182,7 +182,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                 while i < futexes.len() && woken < val {
                     if futexes[i].target_physaddr != target_physaddr
                         && (futexes[i].target_virtaddr != target_virtaddr
-                            || !Arc::downgrade(&current_addrsp).ptr_eq(&futexes[i].addr_space))
+                            || !Arc::downgrade(&*current_addrsp).ptr_eq(&futexes[i].addr_space))
                     {
                         i += 1;
                         continue;
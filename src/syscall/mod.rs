@@ -28,6 +28,7 @@ use crate::percpu::PercpuBlock;
 
 use crate::{
     context::{memory::AddrSpace, process::ProcessId},
+    memory::PAGE_SIZE,
     scheme::{memory::MemoryScheme, FileHandle, SchemeNamespace},
 };
 
@@ -91,7 +92,7 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
                 let addrspace = AddrSpace::current()?;
                 let map = unsafe { UserSlice::ro(c, d)?.read_exact::<Map>()? };
                 if b == !0 {
-                    MemoryScheme::fmap_anonymous(&addrspace, &map, false)
+                    MemoryScheme::fmap_anonymous(&addrspace, &map, false, PAGE_SIZE)
                 } else {
                     file_op_generic(fd, |scheme, number| {
                         scheme.kfmap(number, &addrspace, &map, false)
@@ -255,6 +256,12 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
     if percpu.switch_internals.being_sigkilled.get() {
         exit(SIGKILL);
     }
+    // The context may have been frozen during the syscall, e.g. by itself.
+    crate::context::stop_if_frozen();
+
+    unsafe {
+        crate::softirq::run_pending();
+    }
 
     // errormux turns Result<usize> into -errno
     Error::mux(result)
@@ -40,9 +40,7 @@ pub fn exit_this_context() -> ! {
         let mut context = context_lock.write();
         close_files = Arc::try_unwrap(mem::take(&mut context.files))
             .map_or_else(|_| Vec::new(), RwLock::into_inner);
-        addrspace_opt = context
-            .set_addr_space(None)
-            .and_then(|a| Arc::try_unwrap(a).ok());
+        addrspace_opt = context.set_addr_space(None);
         drop(context.syscall_head.take());
         drop(context.syscall_tail.take());
     }
@@ -53,9 +51,18 @@ pub fn exit_this_context() -> ! {
             let _ = file.close();
         }
     }
-    drop(addrspace_opt);
+    // If this was the last context using it, no other CPU must keep it alive, as it would then
+    // be freed there from the idle context, where unmapping files cannot block.
+    if let Some(ref addrspace) = addrspace_opt {
+        addrspace.release_lazy_holders();
+    }
+    drop(addrspace_opt.and_then(|a| Arc::try_unwrap(a).ok()));
     // TODO: Should status == Status::HardBlocked be handled differently?
-    context_lock.write().status = context::Status::Dead;
+    {
+        let mut context = context_lock.write();
+        context.status = context::Status::Dead;
+        context::run_queue::dequeue(&context_lock, &context);
+    }
     let _ = context::contexts_mut().remove(&ContextRef(context_lock));
     context::switch();
     unreachable!();
@@ -89,6 +96,12 @@ pub fn exit(status: usize) -> ! {
         Some(ptrace_event!(PTRACE_STOP_EXIT, status)),
     );
 
+    // Other threads may still be running until they have been waited for below, but must not be
+    // able to create new mappings while the address space is being torn down.
+    if let Ok(addr_space) = AddrSpace::current() {
+        addr_space.mark_dying();
+    }
+
     let current_pid;
     let current_ruid;
     {